@@ -0,0 +1,185 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use rust_decimal::Decimal;
+use std::hint::black_box;
+use std::io::{BufWriter, LineWriter};
+use transaction_engine::input::{self, Input};
+use transaction_engine::io::{CsvAccountsWriter, CsvDialect, OutputSink, OutputVersion};
+use transaction_engine::pipeline::{self, RawInterest, RawSource};
+use transaction_engine::{
+    CsvSource, Engine, EngineConfig, Transaction, TransactionSource, TransactionType, io,
+};
+
+/// Deposits and withdrawals spread over a thousand clients
+fn workload(rows: u32) -> String {
+    let mut data = String::from("type,client,tx,amount\n");
+    for tx in 0..rows {
+        let client = tx % 1000;
+        if tx % 3 == 2 {
+            data.push_str(&format!("withdrawal,{client},{tx},0.5\n"));
+        } else {
+            data.push_str(&format!("deposit,{client},{tx},1.2345\n"));
+        }
+    }
+    data
+}
+
+fn plain_path(c: &mut Criterion) {
+    let data = workload(100_000);
+    let mut group = c.benchmark_group("plain_path");
+
+    group.bench_function("deserialize_iterator", |b| {
+        b.iter(|| {
+            let source = CsvSource::new(data.as_bytes());
+            let mut engine = Engine::new(EngineConfig::default());
+            engine.process(source.transactions());
+            black_box(engine.into_accounts())
+        })
+    });
+    group.bench_function("raw_pipeline_no_sinks", |b| {
+        b.iter(|| {
+            let rows = RawSource::new(data.as_bytes(), CsvDialect::default(), 0, RawInterest::NONE);
+            let mut engine = Engine::new(EngineConfig::default());
+            pipeline::run(&mut engine, rows, &mut []).unwrap();
+            black_box(engine.into_accounts())
+        })
+    });
+    group.finish();
+}
+
+/// The ways of reading CSV rows into transactions, each reusing a single
+/// record for all rows: serde's iterator of the reader, `CsvSource` and
+/// `RawSource` with the pipeline
+fn csv_ingestion(c: &mut Criterion) {
+    let data = workload(100_000);
+    let mut group = c.benchmark_group("csv_ingestion");
+
+    group.bench_function("reader_deserialize", |b| {
+        b.iter(|| {
+            let mut reader = io::csv_reader(data.as_bytes());
+            let transactions = reader
+                .deserialize::<Transaction>()
+                .map(|row| row.map_err(Into::into));
+            let mut engine = Engine::new(EngineConfig::default());
+            engine.process(transactions);
+            black_box(engine.into_accounts())
+        })
+    });
+    group.bench_function("reused_string_record", |b| {
+        b.iter(|| {
+            let mut engine = Engine::new(EngineConfig::default());
+            engine.process(CsvSource::new(data.as_bytes()).transactions());
+            black_box(engine.into_accounts())
+        })
+    });
+    group.bench_function("reused_byte_record", |b| {
+        b.iter(|| {
+            let rows = RawSource::new(data.as_bytes(), CsvDialect::default(), 0, RawInterest::NONE);
+            let mut engine = Engine::new(EngineConfig::default());
+            pipeline::run(&mut engine, rows, &mut []).unwrap();
+            black_box(engine.into_accounts())
+        })
+    });
+    group.finish();
+}
+
+/// Parsing rows through serde against the byte parser of `--fast-parse`,
+/// without applying them
+fn fast_parse(c: &mut Criterion) {
+    let data = workload(100_000);
+    let mut group = c.benchmark_group("fast_parse");
+
+    for (name, fast) in [("serde", false), ("bytes", true)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let rows =
+                    RawSource::new(data.as_bytes(), CsvDialect::default(), 0, RawInterest::NONE)
+                        .fast_parse(fast);
+                black_box(rows.filter(|row| row.parsed.is_ok()).count())
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Buffered reads of a file against reading it through a memory map
+fn mmap_path(c: &mut Criterion) {
+    let path = std::env::temp_dir().join("transaction_engine_bench.csv");
+    std::fs::write(&path, workload(100_000)).unwrap();
+    let path = path.to_str().unwrap();
+    let mut group = c.benchmark_group("mmap_path");
+
+    for (name, open) in [
+        (
+            "buffered_read",
+            input::open as fn(&str) -> std::io::Result<Vec<Input>>,
+        ),
+        ("mmap", input::open_mapped),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut inputs = open(path).unwrap();
+                let rows = RawSource::new(
+                    inputs[0].reader().unwrap(),
+                    CsvDialect::default(),
+                    0,
+                    RawInterest::NONE,
+                );
+                let mut engine = Engine::new(EngineConfig::default());
+                pipeline::run(&mut engine, rows, &mut []).unwrap();
+                black_box(engine.into_accounts())
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Writing a million accounts through stdout's line buffering against a
+/// large buffer of its own, as the accounts output does
+fn accounts_output(c: &mut Criterion) {
+    let mut engine = Engine::new(EngineConfig::default());
+    engine.process((0..1_000_000).map(|client| {
+        Ok(Transaction {
+            kind: TransactionType::Deposit,
+            client_id: client,
+            id: client,
+            amount: Some(Decimal::new(12345, 4)),
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        })
+    }));
+    let clients = engine.into_accounts();
+    let mut group = c.benchmark_group("accounts_output");
+    group.sample_size(10);
+
+    let sink = || std::fs::File::create("/dev/null").unwrap();
+    group.bench_function("line_buffered", |b| {
+        b.iter(|| {
+            CsvAccountsWriter::new(LineWriter::new(sink()), OutputVersion::V1)
+                .write_accounts(&clients)
+                .unwrap()
+        })
+    });
+    group.bench_function("buffered", |b| {
+        b.iter(|| {
+            CsvAccountsWriter::new(
+                BufWriter::with_capacity(256 * 1024, sink()),
+                OutputVersion::V1,
+            )
+            .write_accounts(&clients)
+            .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    plain_path,
+    csv_ingestion,
+    fast_parse,
+    mmap_path,
+    accounts_output
+);
+criterion_main!(benches);
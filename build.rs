@@ -0,0 +1,11 @@
+//! The protobuf code is checked in under `src/proto`, so building does not
+//! need protoc. The `regen-proto` feature regenerates it from `proto/`.
+
+fn main() {
+    println!("cargo:rerun-if-changed=proto/transaction.proto");
+    #[cfg(feature = "regen-proto")]
+    prost_build::Config::new()
+        .out_dir("src/proto")
+        .compile_protos(&["proto/transaction.proto"], &["proto"])
+        .expect("protoc failed to compile proto/transaction.proto");
+}
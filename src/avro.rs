@@ -0,0 +1,953 @@
+//! Transactions read from Avro object container files, as Kafka Connect
+//! archives the transaction topic.
+//!
+//! Records are decoded by the writer schema of the file, taking the fields
+//! `type` (string or enum), `client` and `tx` (int or long) and `amount`
+//! (string or decimal), each possibly in a union with null. Other fields
+//! are decoded and skipped, so fields added to the schema later do not
+//! break reading. Blocks are read one at a time, uncompressed or
+//! compressed with deflate or zstandard. Recursive types are not
+//! supported.
+
+use crate::io::TransactionSource;
+use crate::model::{ClientId, Transaction, TransactionType};
+use crate::pipeline::{ParseFailure, RawInterest, RawRecord, RawTx};
+use anyhow::{Result, anyhow, bail};
+use csv::ByteRecord;
+use flate2::read::DeflateDecoder;
+use rust_decimal::Decimal;
+use serde_json::Value as Json;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufReader, Read};
+use std::str::FromStr;
+
+/// Start of an object container file
+const MAGIC: &[u8] = b"Obj\x01";
+
+/// Length of the marker following the header and every block
+const SYNC_LEN: usize = 16;
+
+/// Fields of the records which make up a transaction
+const FIELDS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Avro schema, with named types resolved
+#[derive(Debug, Clone, PartialEq)]
+enum Schema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    /// Bytes, holding a decimal of the scale if so annotated
+    Bytes(Option<u32>),
+    String,
+    Record(String, Vec<(String, Schema)>),
+    Enum(String, Vec<String>),
+    Array(Box<Schema>),
+    Map(Box<Schema>),
+    Union(Vec<Schema>),
+    /// Fixed size bytes, holding a decimal of the scale if so annotated
+    Fixed(String, usize, Option<u32>),
+}
+
+impl fmt::Display for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schema::Null => f.write_str("null"),
+            Schema::Boolean => f.write_str("boolean"),
+            Schema::Int => f.write_str("int"),
+            Schema::Long => f.write_str("long"),
+            Schema::Float => f.write_str("float"),
+            Schema::Double => f.write_str("double"),
+            Schema::Bytes(None) | Schema::Fixed(_, _, None) => f.write_str("bytes"),
+            Schema::Bytes(Some(_)) | Schema::Fixed(_, _, Some(_)) => f.write_str("decimal"),
+            Schema::String => f.write_str("string"),
+            Schema::Record(name, _) => write!(f, "record {name}"),
+            Schema::Enum(name, _) => write!(f, "enum {name}"),
+            Schema::Array(items) => write!(f, "array of {items}"),
+            Schema::Map(values) => write!(f, "map of {values}"),
+            Schema::Union(branches) => {
+                let branches: Vec<_> = branches.iter().map(Schema::to_string).collect();
+                write!(f, "union of {}", branches.join(" or "))
+            }
+        }
+    }
+}
+
+impl Schema {
+    fn parse(json: &Json) -> Result<Self, String> {
+        Self::parse_in(json, None, &mut HashMap::new())
+    }
+
+    /// Schema of `json` within a namespace, with the named types defined so
+    /// far
+    fn parse_in(
+        json: &Json,
+        namespace: Option<&str>,
+        names: &mut HashMap<String, Schema>,
+    ) -> Result<Self, String> {
+        let object = match json {
+            Json::String(name) => return Self::named(name, namespace, names),
+            Json::Array(branches) => {
+                return branches
+                    .iter()
+                    .map(|branch| Self::parse_in(branch, namespace, names))
+                    .collect::<Result<_, _>>()
+                    .map(Schema::Union);
+            }
+            Json::Object(object) => object,
+            other => return Err(format!("invalid schema {other}")),
+        };
+        let kind = match object.get("type") {
+            Some(Json::String(kind)) => kind.as_str(),
+            Some(other) => return Self::parse_in(other, namespace, names),
+            None => return Err("schema without a type".to_string()),
+        };
+        let scale = (object.get("logicalType").and_then(Json::as_str) == Some("decimal"))
+            .then(|| object.get("scale").and_then(Json::as_u64).unwrap_or(0) as u32);
+        let schema_of = |key: &str| {
+            object
+                .get(key)
+                .ok_or_else(|| format!("{kind} schema without {key}"))
+        };
+        let schema = match kind {
+            "record" | "error" | "enum" | "fixed" => {
+                let name = object
+                    .get("name")
+                    .and_then(Json::as_str)
+                    .ok_or_else(|| format!("{kind} schema without a name"))?;
+                let namespace = object.get("namespace").and_then(Json::as_str).or(namespace);
+                let (name, namespace) = match (name.rsplit_once('.'), namespace) {
+                    (Some((namespace, _)), _) => (name.to_string(), Some(namespace)),
+                    (None, Some(namespace)) => (format!("{namespace}.{name}"), Some(namespace)),
+                    (None, None) => (name.to_string(), None),
+                };
+                let schema = match kind {
+                    "enum" => Schema::Enum(
+                        name.clone(),
+                        schema_of("symbols")?
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|symbol| symbol.as_str().map(str::to_string))
+                            .collect(),
+                    ),
+                    "fixed" => {
+                        let size = schema_of("size")?
+                            .as_u64()
+                            .ok_or_else(|| format!("fixed {name} without a valid size"))?;
+                        Schema::Fixed(name.clone(), size as usize, scale)
+                    }
+                    _ => {
+                        let fields = schema_of("fields")?
+                            .as_array()
+                            .ok_or_else(|| format!("record {name} without a list of fields"))?;
+                        let fields = fields
+                            .iter()
+                            .map(|field| {
+                                let field_name =
+                                    field.get("name").and_then(Json::as_str).ok_or_else(|| {
+                                        format!("field of record {name} without a name")
+                                    })?;
+                                let schema = field.get("type").ok_or_else(|| {
+                                    format!("field {field_name} of record {name} without a type")
+                                })?;
+                                Ok((
+                                    field_name.to_string(),
+                                    Self::parse_in(schema, namespace, names)?,
+                                ))
+                            })
+                            .collect::<Result<_, String>>()?;
+                        Schema::Record(name.clone(), fields)
+                    }
+                };
+                names.insert(name, schema.clone());
+                schema
+            }
+            "array" => Schema::Array(Box::new(Self::parse_in(
+                schema_of("items")?,
+                namespace,
+                names,
+            )?)),
+            "map" => Schema::Map(Box::new(Self::parse_in(
+                schema_of("values")?,
+                namespace,
+                names,
+            )?)),
+            "bytes" => Schema::Bytes(scale),
+            other => Self::named(other, namespace, names)?,
+        };
+        Ok(schema)
+    }
+
+    /// Primitive type or named type defined before
+    fn named(
+        name: &str,
+        namespace: Option<&str>,
+        names: &HashMap<String, Schema>,
+    ) -> Result<Self, String> {
+        Ok(match name {
+            "null" => Schema::Null,
+            "boolean" => Schema::Boolean,
+            "int" => Schema::Int,
+            "long" => Schema::Long,
+            "float" => Schema::Float,
+            "double" => Schema::Double,
+            "bytes" => Schema::Bytes(None),
+            "string" => Schema::String,
+            _ => {
+                let full_name = match namespace {
+                    Some(namespace) if !name.contains('.') => format!("{namespace}.{name}"),
+                    _ => name.to_string(),
+                };
+                names
+                    .get(&full_name)
+                    .or_else(|| names.get(name))
+                    .cloned()
+                    .ok_or_else(|| format!("unknown type {name}"))?
+            }
+        })
+    }
+
+    /// Whether values of the schema, or of every branch of a union other
+    /// than null, can be read into the transaction field
+    fn fits(&self, field: &str) -> bool {
+        match self {
+            Schema::Union(branches) => {
+                let mut branches = branches.iter().filter(|b| **b != Schema::Null).peekable();
+                branches.peek().is_some() && branches.all(|b| b.fits(field))
+            }
+            Schema::String | Schema::Enum(..) => field == "type" || field == "amount",
+            Schema::Int | Schema::Long => field == "client" || field == "tx",
+            Schema::Bytes(Some(_)) | Schema::Fixed(_, _, Some(_)) => field == "amount",
+            _ => false,
+        }
+    }
+}
+
+/// The writer schema of a file does not hold transactions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaMismatch {
+    /// Transaction fields which are missing or of an unusable type, with
+    /// their types
+    pub offending: Vec<String>,
+    /// Fields of the records, with their types
+    pub found: Vec<String>,
+}
+
+impl fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unusable fields {}, expected fields type (string or enum), client and tx \
+             (int or long) and amount (string or decimal), found {}",
+            self.offending.join(", "),
+            self.found.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for SchemaMismatch {}
+
+/// Fields of the records of a writer schema, if they hold transactions.
+/// The amount is optional, like the `amount` column of CSV.
+fn check_schema(schema: &Schema) -> Result<Vec<(String, Schema)>, SchemaMismatch> {
+    let fields = match schema {
+        Schema::Record(_, fields) => fields.as_slice(),
+        _ => &[],
+    };
+    let offending: Vec<String> = FIELDS
+        .into_iter()
+        .filter_map(
+            |name| match fields.iter().find(|(field, _)| field == name) {
+                Some((_, schema)) if schema.fits(name) => None,
+                Some((_, schema)) => Some(format!("{name} ({schema})")),
+                None if name == "amount" => None,
+                None => Some(format!("{name} (missing)")),
+            },
+        )
+        .collect();
+    if !offending.is_empty() {
+        return Err(SchemaMismatch {
+            offending,
+            found: match schema {
+                Schema::Record(..) => fields
+                    .iter()
+                    .map(|(name, schema)| format!("{name} ({schema})"))
+                    .collect(),
+                other => vec![other.to_string()],
+            },
+        });
+    }
+    Ok(fields.to_vec())
+}
+
+/// Value of a decoded field, as far as transactions need it
+#[derive(Debug, Clone, Default, PartialEq)]
+enum Value {
+    /// Null, or a field the records do not have
+    #[default]
+    Null,
+    Long(i64),
+    /// A string or the symbol of an enum
+    Text(String),
+    /// Big endian two's complement unscaled value, with its scale
+    Decimal(Vec<u8>, u32),
+    /// Any value transactions do not read
+    Other,
+}
+
+impl Value {
+    /// Field as it would appear in CSV
+    fn text(&self) -> String {
+        match self {
+            Value::Long(value) => value.to_string(),
+            Value::Text(text) => text.clone(),
+            Value::Decimal(..) => self
+                .decimal()
+                .map_or_else(|_| String::new(), |d| d.to_string()),
+            Value::Null | Value::Other => String::new(),
+        }
+    }
+
+    fn decimal(&self) -> Result<Decimal, String> {
+        let Value::Decimal(bytes, scale) = self else {
+            return Err("not a decimal".to_string());
+        };
+        let out_of_range = || "amount out of range".to_string();
+        if bytes.len() > 16 {
+            return Err(out_of_range());
+        }
+        // Sign extend to the 16 bytes of an i128
+        let fill = if bytes.first().is_some_and(|b| b & 0x80 != 0) {
+            0xff
+        } else {
+            0
+        };
+        let mut unscaled = [fill; 16];
+        unscaled[16 - bytes.len()..].copy_from_slice(bytes);
+        Decimal::try_from_i128_with_scale(i128::from_be_bytes(unscaled), *scale)
+            .map_err(|_| out_of_range())
+    }
+}
+
+/// Take `n` bytes of a block
+fn take<'a>(data: &'a [u8], pos: &mut usize, n: usize) -> Result<&'a [u8], String> {
+    let end = pos
+        .checked_add(n)
+        .filter(|&end| end <= data.len())
+        .ok_or("unexpected end of block")?;
+    let bytes = &data[*pos..end];
+    *pos = end;
+    Ok(bytes)
+}
+
+/// Zigzag encoded variable length long of a block
+fn long(data: &[u8], pos: &mut usize) -> Result<i64, String> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = take(data, pos, 1)?[0];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+    }
+    Err("invalid variable length number".to_string())
+}
+
+fn length(data: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let length = long(data, pos)?;
+    usize::try_from(length).map_err(|_| format!("invalid length {length}"))
+}
+
+/// Decode a value of the schema from a block
+fn decode(schema: &Schema, data: &[u8], pos: &mut usize) -> Result<Value, String> {
+    Ok(match schema {
+        Schema::Null => Value::Null,
+        Schema::Boolean => take(data, pos, 1).map(|_| Value::Other)?,
+        Schema::Int => {
+            let value = long(data, pos)?;
+            i32::try_from(value).map_err(|_| format!("int {value} out of range"))?;
+            Value::Long(value)
+        }
+        Schema::Long => Value::Long(long(data, pos)?),
+        Schema::Float => take(data, pos, 4).map(|_| Value::Other)?,
+        Schema::Double => take(data, pos, 8).map(|_| Value::Other)?,
+        Schema::Bytes(scale) => {
+            let n = length(data, pos)?;
+            let bytes = take(data, pos, n)?;
+            match scale {
+                Some(scale) => Value::Decimal(bytes.to_vec(), *scale),
+                None => Value::Other,
+            }
+        }
+        Schema::String => {
+            let n = length(data, pos)?;
+            let bytes = take(data, pos, n)?;
+            Value::Text(
+                String::from_utf8(bytes.to_vec()).map_err(|_| "string is not UTF-8".to_string())?,
+            )
+        }
+        Schema::Record(_, fields) => {
+            for (_, schema) in fields {
+                decode(schema, data, pos)?;
+            }
+            Value::Other
+        }
+        Schema::Enum(name, symbols) => {
+            let index = long(data, pos)?;
+            let symbol = usize::try_from(index)
+                .ok()
+                .and_then(|index| symbols.get(index))
+                .ok_or_else(|| format!("index {index} out of range for enum {name}"))?;
+            Value::Text(symbol.clone())
+        }
+        Schema::Array(items) => {
+            decode_blocks(data, pos, |data, pos| decode(items, data, pos).map(drop))?;
+            Value::Other
+        }
+        Schema::Map(values) => {
+            decode_blocks(data, pos, |data, pos| {
+                decode(&Schema::String, data, pos)?;
+                decode(values, data, pos).map(drop)
+            })?;
+            Value::Other
+        }
+        Schema::Union(branches) => {
+            let index = long(data, pos)?;
+            let branch = usize::try_from(index)
+                .ok()
+                .and_then(|index| branches.get(index))
+                .ok_or_else(|| format!("index {index} out of range for a union"))?;
+            decode(branch, data, pos)?
+        }
+        Schema::Fixed(_, size, scale) => {
+            let bytes = take(data, pos, *size)?;
+            match scale {
+                Some(scale) => Value::Decimal(bytes.to_vec(), *scale),
+                None => Value::Other,
+            }
+        }
+    })
+}
+
+/// Decode the items of an array or map, which come in blocks ended by an
+/// empty one
+fn decode_blocks(
+    data: &[u8],
+    pos: &mut usize,
+    mut item: impl FnMut(&[u8], &mut usize) -> Result<(), String>,
+) -> Result<(), String> {
+    loop {
+        let count = match long(data, pos)? {
+            0 => return Ok(()),
+            // A negative count is followed by the size of the block
+            count if count < 0 => {
+                long(data, pos)?;
+                count.unsigned_abs()
+            }
+            count => count as u64,
+        };
+        for _ in 0..count {
+            item(data, pos)?;
+        }
+    }
+}
+
+/// Values of the transaction fields of a record, in the order of [`FIELDS`]
+fn decode_record(
+    fields: &[(String, Schema)],
+    data: &[u8],
+    pos: &mut usize,
+) -> Result<[Value; 4], String> {
+    let mut values: [Value; 4] = Default::default();
+    for (name, schema) in fields {
+        let value = decode(schema, data, pos)?;
+        if let Some(i) = FIELDS.iter().position(|field| field == name) {
+            values[i] = value;
+        }
+    }
+    Ok(values)
+}
+
+fn transaction(values: &[Value; 4]) -> Result<Transaction, String> {
+    let [kind, client, tx, amount] = values;
+    let kind = match kind {
+        Value::Text(name) => TransactionType::from_alias(name.trim())
+            .ok_or_else(|| format!("unknown transaction type {name}"))?,
+        _ => return Err("missing type".to_string()),
+    };
+    let client_id = match client {
+        Value::Long(client) => {
+            ClientId::try_from(*client).map_err(|_| format!("client {client} out of range"))?
+        }
+        _ => return Err("missing client".to_string()),
+    };
+    let id = match tx {
+        Value::Long(tx) => u32::try_from(*tx).map_err(|_| format!("tx {tx} out of range"))?,
+        _ => return Err("missing tx".to_string()),
+    };
+    let amount = match amount {
+        Value::Text(text) if text.trim().is_empty() => None,
+        Value::Text(text) => Some(
+            Decimal::from_str(text.trim()).map_err(|e| format!("invalid amount {text}: {e}"))?,
+        ),
+        Value::Decimal(..) => Some(amount.decimal()?),
+        _ => None,
+    };
+    Ok(Transaction {
+        kind,
+        client_id,
+        id,
+        amount,
+        to_client: None,
+        fee: None,
+        currency: None,
+        timestamp: None,
+    })
+}
+
+/// Compression of the blocks of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Null,
+    Deflate,
+    Zstandard,
+}
+
+/// Zigzag encoded variable length long of a stream, `None` at its end
+fn read_long<R: Read>(reader: &mut R) -> io::Result<Option<i64>> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        match reader.read_exact(&mut byte) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            result => result?,
+        }
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some((value >> 1) as i64 ^ -((value & 1) as i64)));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "invalid variable length number",
+    ))
+}
+
+/// Bytes of a length of a stream
+fn read_bytes<R: Read>(reader: &mut R, length: i64) -> io::Result<Vec<u8>> {
+    let length = u64::try_from(length)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "negative length"))?;
+    let mut bytes = Vec::new();
+    reader.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn expect_long<R: Read>(reader: &mut R) -> io::Result<i64> {
+    read_long(reader)?.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+/// Reads the records of an Avro object container file into `RawTx`,
+/// numbering them from 1 by their index in the file.
+///
+/// A record which can not be decoded is rejected, along with the rest of
+/// its block, and reading goes on with the next block. The raw record of
+/// a row holds its fields as they would appear in CSV.
+pub struct AvroSource<R> {
+    reader: BufReader<R>,
+    sync: [u8; SYNC_LEN],
+    codec: Codec,
+    fields: Vec<(String, Schema)>,
+    block: Vec<u8>,
+    pos: usize,
+    /// Records of the block not read yet
+    remaining: u64,
+    row: u64,
+    source: u16,
+    interest: RawInterest,
+    done: bool,
+}
+
+impl<R: Read> AvroSource<R> {
+    /// Read the header of a container file, failing with a
+    /// [`SchemaMismatch`] if its records do not hold transactions
+    pub fn new(reader: R, source: u16, interest: RawInterest) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            bail!("not an Avro container file");
+        }
+        let mut metadata = HashMap::new();
+        loop {
+            let count = match expect_long(&mut reader)? {
+                0 => break,
+                count if count < 0 => {
+                    expect_long(&mut reader)?;
+                    count.unsigned_abs()
+                }
+                count => count as u64,
+            };
+            for _ in 0..count {
+                let length = expect_long(&mut reader)?;
+                let key = String::from_utf8(read_bytes(&mut reader, length)?)?;
+                let length = expect_long(&mut reader)?;
+                metadata.insert(key, read_bytes(&mut reader, length)?);
+            }
+        }
+        let schema = metadata
+            .get("avro.schema")
+            .ok_or_else(|| anyhow!("Avro file without a schema"))?;
+        let schema = Schema::parse(&serde_json::from_slice(schema)?)
+            .map_err(|e| anyhow!("invalid Avro schema: {e}"))?;
+        let codec = match metadata.get("avro.codec").map(Vec::as_slice) {
+            None | Some(b"null") => Codec::Null,
+            Some(b"deflate") => Codec::Deflate,
+            Some(b"zstandard") => Codec::Zstandard,
+            Some(other) => bail!(
+                "unsupported Avro codec {}, expected null, deflate or zstandard",
+                String::from_utf8_lossy(other)
+            ),
+        };
+        let fields = check_schema(&schema)?;
+        let mut sync = [0; SYNC_LEN];
+        reader.read_exact(&mut sync)?;
+        Ok(Self {
+            reader,
+            sync,
+            codec,
+            fields,
+            block: Vec::new(),
+            pos: 0,
+            remaining: 0,
+            row: 0,
+            source,
+            interest,
+            done: false,
+        })
+    }
+
+    /// Move on to the next block holding records, false once there is none
+    fn next_block(&mut self) -> Result<bool> {
+        loop {
+            let Some(count) = read_long(&mut self.reader)? else {
+                return Ok(false);
+            };
+            let count =
+                u64::try_from(count).map_err(|_| anyhow!("invalid record count {count}"))?;
+            let length = expect_long(&mut self.reader)?;
+            let data = read_bytes(&mut self.reader, length)?;
+            let mut sync = [0; SYNC_LEN];
+            self.reader.read_exact(&mut sync)?;
+            if sync != self.sync {
+                bail!("sync marker mismatch after a block, the file is corrupt");
+            }
+            if count == 0 {
+                continue;
+            }
+            self.block = match self.codec {
+                Codec::Null => data,
+                Codec::Deflate => {
+                    let mut block = Vec::new();
+                    DeflateDecoder::new(data.as_slice()).read_to_end(&mut block)?;
+                    block
+                }
+                Codec::Zstandard => zstd::decode_all(data.as_slice())?,
+            };
+            self.pos = 0;
+            self.remaining = count;
+            return Ok(true);
+        }
+    }
+}
+
+impl<R: Read> Iterator for AvroSource<R> {
+    type Item = RawTx;
+
+    fn next(&mut self) -> Option<RawTx> {
+        if self.done {
+            return None;
+        }
+        while self.remaining == 0 {
+            match self.next_block() {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.done = true;
+                    return None;
+                }
+                Err(e) => {
+                    // The reader can not recover from errors reading a block
+                    self.done = true;
+                    return Some(RawTx {
+                        record: None,
+                        row: self.row + 1,
+                        source: self.source,
+                        parsed: Err(ParseFailure {
+                            message: e.to_string(),
+                            fatal: true,
+                        }),
+                    });
+                }
+            }
+        }
+        self.remaining -= 1;
+        self.row += 1;
+        let row = self.row;
+        let (parsed, record) = match decode_record(&self.fields, &self.block, &mut self.pos) {
+            Ok(values) => {
+                let record = (!self.interest.is_empty()).then(|| {
+                    RawRecord::Csv(ByteRecord::from(
+                        values.iter().map(Value::text).collect::<Vec<_>>(),
+                    ))
+                });
+                (transaction(&values), record)
+            }
+            Err(message) => {
+                // Where the next record starts is unknown, the rest of the
+                // block is skipped, keeping the records numbered by index
+                let skipped = std::mem::take(&mut self.remaining);
+                self.row += skipped;
+                let message = if skipped > 0 {
+                    format!("{message}, skipped the {skipped} records after it in its block")
+                } else {
+                    message
+                };
+                (Err(message), None)
+            }
+        };
+        Some(RawTx {
+            record,
+            row,
+            source: self.source,
+            parsed: parsed.map_err(|message| ParseFailure {
+                message,
+                fatal: false,
+            }),
+        })
+    }
+}
+
+impl<R: Read> TransactionSource for AvroSource<R> {
+    fn transactions(self) -> impl Iterator<Item = Result<Transaction>> {
+        self.map(|raw| raw.parsed.map_err(|failure| anyhow!(failure.message)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::CsvSource;
+    use rust_decimal::dec;
+    use std::io::Write;
+
+    const SYNC: [u8; SYNC_LEN] = *b"0123456789abcdef";
+
+    fn long(value: i64) -> Vec<u8> {
+        let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+        let mut bytes = Vec::new();
+        while zigzag >= 0x80 {
+            bytes.push(zigzag as u8 | 0x80);
+            zigzag >>= 7;
+        }
+        bytes.push(zigzag as u8);
+        bytes
+    }
+
+    fn bytes(value: &[u8]) -> Vec<u8> {
+        [long(value.len() as i64), value.to_vec()].concat()
+    }
+
+    /// Container file of blocks of records, each given as its count and
+    /// encoded records
+    fn container(schema: &str, codec: &str, blocks: &[(i64, Vec<u8>)]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.extend(long(2));
+        for (key, value) in [("avro.schema", schema), ("avro.codec", codec)] {
+            file.extend(bytes(key.as_bytes()));
+            file.extend(bytes(value.as_bytes()));
+        }
+        file.extend(long(0));
+        file.extend(SYNC);
+        for (count, data) in blocks {
+            let data = match codec {
+                "deflate" => {
+                    let mut encoder =
+                        flate2::write::DeflateEncoder::new(Vec::new(), Default::default());
+                    encoder.write_all(data).unwrap();
+                    encoder.finish().unwrap()
+                }
+                "zstandard" => zstd::encode_all(data.as_slice(), 0).unwrap(),
+                _ => data.clone(),
+            };
+            file.extend(long(*count));
+            file.extend(bytes(&data));
+            file.extend(SYNC);
+        }
+        file
+    }
+
+    fn rows(file: &[u8]) -> Vec<RawTx> {
+        AvroSource::new(file, 0, RawInterest::default())
+            .unwrap()
+            .collect()
+    }
+
+    #[test]
+    fn test_avro_matches_csv() {
+        // Fields besides the transaction ones, as added by a later version
+        // of the schema, are skipped
+        let schema = r#"{"type": "record", "name": "Transaction", "namespace": "bank",
+            "fields": [
+                {"name": "type", "type": {"type": "enum", "name": "Kind",
+                    "symbols": ["deposit", "withdrawal", "dispute"]}},
+                {"name": "client", "type": "int"},
+                {"name": "channel", "type": "string"},
+                {"name": "tx", "type": "long"},
+                {"name": "tags", "type": {"type": "map",
+                    "values": {"type": "array", "items": "Kind"}}},
+                {"name": "amount", "type": ["null", "string"]}
+            ]}"#;
+        let record = |kind: i64, tx: i64, amount: Option<&str>| {
+            let tags = if tx == 1 {
+                [long(1), bytes(b"seen"), long(1), long(2), long(0), long(0)].concat()
+            } else {
+                long(0)
+            };
+            let amount = match amount {
+                Some(amount) => [long(1), bytes(amount.as_bytes())].concat(),
+                None => long(0),
+            };
+            [long(kind), long(1), bytes(b"web"), long(tx), tags, amount].concat()
+        };
+        let blocks = [
+            (
+                2,
+                [record(0, 1, Some("1.0")), record(0, 2, Some("2.5"))].concat(),
+            ),
+            (
+                2,
+                [record(1, 3, Some("0.1234")), record(2, 2, None)].concat(),
+            ),
+        ];
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.5\n\
+                   withdrawal,1,3,0.1234\ndispute,1,2,\n";
+        let from_csv: Vec<_> = CsvSource::new(csv.as_bytes())
+            .transactions()
+            .map(Result::unwrap)
+            .collect();
+
+        for codec in ["null", "deflate", "zstandard"] {
+            let file = container(schema, codec, &blocks);
+            let from_avro: Vec<_> = AvroSource::new(file.as_slice(), 0, RawInterest::default())
+                .unwrap()
+                .transactions()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(from_avro, from_csv, "{codec}");
+        }
+    }
+
+    #[test]
+    fn test_avro_rejects_records() {
+        let schema = r#"{"type": "record", "name": "Transaction", "fields": [
+                {"name": "type", "type": "string"},
+                {"name": "client", "type": "long"},
+                {"name": "tx", "type": "long"},
+                {"name": "amount", "type": ["null", {"type": "bytes",
+                    "logicalType": "decimal", "precision": 10, "scale": 4}]}
+            ]}"#;
+        let record = |kind: &str, client: i64, tx: i64, unscaled: &[u8]| {
+            [
+                bytes(kind.as_bytes()),
+                long(client),
+                long(tx),
+                long(1),
+                bytes(unscaled),
+            ]
+            .concat()
+        };
+        let blocks = [
+            (
+                3,
+                [
+                    record("deposit", 1, 1, &[0x27, 0x10]),
+                    record("payout", 1, 2, &[0x27, 0x10]),
+                    record("deposit", 5_000_000_000, 3, &[0x27, 0x10]),
+                ]
+                .concat(),
+            ),
+            // The fifth record is cut short, taking the sixth with it
+            (
+                3,
+                [record("withdrawal", 1, 4, &[0xfe]), bytes(b"deposit")].concat(),
+            ),
+            (1, record("deposit", 1, 7, &[0x13, 0x88])),
+        ];
+        let rows = rows(&container(schema, "null", &blocks));
+        let results: Vec<_> = rows
+            .iter()
+            .map(|raw| {
+                (
+                    raw.row,
+                    raw.parsed
+                        .as_ref()
+                        .map(|tx| (tx.id, tx.amount))
+                        .map_err(|failure| failure.message.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (1, Ok((1, Some(dec!(1.0000))))),
+                (2, Err("unknown transaction type payout")),
+                (3, Err("client 5000000000 out of range")),
+                (4, Ok((4, Some(dec!(-0.0002))))),
+                (
+                    5,
+                    Err("unexpected end of block, skipped the 1 records after it in its block")
+                ),
+                (7, Ok((7, Some(dec!(0.5000))))),
+            ]
+        );
+        assert!(
+            rows.iter()
+                .all(|raw| raw.parsed.as_ref().is_ok_and(|_| true)
+                    || raw.parsed.as_ref().is_err_and(|failure| !failure.fatal))
+        );
+    }
+
+    #[test]
+    fn test_avro_schema_mismatch() {
+        let schema = r#"{"type": "record", "name": "Transaction", "fields": [
+                {"name": "type", "type": "string"},
+                {"name": "client", "type": ["null", "string"]},
+                {"name": "amount", "type": "double"}
+            ]}"#;
+        let error = AvroSource::new(
+            container(schema, "null", &[]).as_slice(),
+            0,
+            RawInterest::default(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "unusable fields client (union of null or string), tx (missing), \
+             amount (double), expected fields type (string or enum), client and tx \
+             (int or long) and amount (string or decimal), found type (string), \
+             client (union of null or string), amount (double)"
+        );
+
+        let error = AvroSource::new(&b"PAR1"[..], 0, RawInterest::default())
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "not an Avro container file");
+    }
+}
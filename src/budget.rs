@@ -0,0 +1,359 @@
+use crate::model::TransactionType;
+
+/// Storage accounting hooks consulted by the processing loop.
+///
+/// The default implementations never refuse anything, so the
+/// `Unbounded` budget compiles down to the plain processing loop.
+pub trait StorageBudget {
+    /// Whether a new record of the given kind should be stored
+    fn admit_record(&mut self, _kind: &TransactionType) -> bool {
+        true
+    }
+
+    fn client_added(&mut self) {}
+
+    fn record_stored(&mut self) {}
+
+    fn records_evicted(&mut self, _count: usize) {}
+
+    fn dispute_opened(&mut self) {}
+
+    fn dispute_closed(&mut self) {}
+
+    /// Whether terminal-state records should be evicted now
+    fn take_eviction(&mut self) -> bool {
+        false
+    }
+
+    /// Called when a referenced transaction record of the given kind is
+    /// not found. Returns true if the record may have been dropped by the
+    /// budget.
+    fn record_missing(&mut self, _kind: &TransactionType) -> bool {
+        false
+    }
+}
+
+/// Budget which never limits storage
+#[derive(Debug, Default, Clone)]
+pub struct Unbounded;
+
+impl StorageBudget for Unbounded {}
+
+#[cfg(feature = "mem-budget")]
+pub use accounting::*;
+
+#[cfg(feature = "mem-budget")]
+mod accounting {
+    use super::StorageBudget;
+    use crate::model::{Client, ClientId, TransactionRecord, TransactionType};
+    use std::mem::size_of;
+    use tracing::warn;
+
+    /// Approximate bytes used by one stored transaction record,
+    /// including its key and one hash map control byte.
+    pub const RECORD_BYTES: usize = size_of::<(u32, TransactionRecord)>() + 1;
+    /// Approximate bytes used by one client entry
+    pub const CLIENT_BYTES: usize = size_of::<(ClientId, Client)>() + 1;
+    /// Approximate bytes used by one open dispute entry
+    pub const DISPUTE_BYTES: usize = size_of::<u32>() + 1;
+
+    /// Degradation stages, entered strictly in this order.
+    ///
+    /// Every time the accounted bytes exceed the budget the engine moves
+    /// one stage further. Stages are never left again.
+    ///  * `SkipWithdrawalRecords`: withdrawals are applied but no longer
+    ///    stored. Duplicate id detection for withdrawals is lost, and
+    ///    reversals referencing an unknown transaction are rejected as
+    ///    `RecordEvicted`, since the withdrawal may not have been stored.
+    ///  * `EvictTerminalRecords`: stored records that can never be
+    ///    referenced again (withdrawals which can not be disputed,
+    ///    charged back records and records of locked clients) are evicted
+    ///    once. Their ids stay taken.
+    ///  * `SkipDepositRecords`: deposits are applied but no longer stored.
+    ///    Disputes referencing an unknown transaction are rejected as
+    ///    `RecordEvicted`, since the record may have been dropped.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum DegradationStage {
+        Normal,
+        SkipWithdrawalRecords,
+        EvictTerminalRecords,
+        SkipDepositRecords,
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq)]
+    pub struct BudgetStats {
+        pub skipped_withdrawal_records: u64,
+        pub evicted_records: u64,
+        pub skipped_deposit_records: u64,
+        pub record_evicted_rejects: u64,
+    }
+
+    /// Approximate byte accounting of the engine's stored state checked
+    /// against a fixed budget.
+    #[derive(Debug, Clone)]
+    pub struct MemoryBudget {
+        limit: usize,
+        used: usize,
+        stage: DegradationStage,
+        transitions: Vec<DegradationStage>,
+        eviction_pending: bool,
+        stats: BudgetStats,
+    }
+
+    impl MemoryBudget {
+        pub fn new(limit: usize) -> Self {
+            Self {
+                limit,
+                used: 0,
+                stage: DegradationStage::Normal,
+                transitions: Vec::new(),
+                eviction_pending: false,
+                stats: BudgetStats::default(),
+            }
+        }
+
+        pub fn used(&self) -> usize {
+            self.used
+        }
+
+        /// Stages entered so far, in order
+        pub fn transitions(&self) -> &[DegradationStage] {
+            &self.transitions
+        }
+
+        pub fn stats(&self) -> &BudgetStats {
+            &self.stats
+        }
+
+        fn charge(&mut self, bytes: usize) {
+            self.used += bytes;
+            if self.used > self.limit {
+                self.escalate();
+            }
+        }
+
+        fn escalate(&mut self) {
+            let next = match self.stage {
+                DegradationStage::Normal => DegradationStage::SkipWithdrawalRecords,
+                DegradationStage::SkipWithdrawalRecords => DegradationStage::EvictTerminalRecords,
+                DegradationStage::EvictTerminalRecords => DegradationStage::SkipDepositRecords,
+                // Nothing left to degrade, client storage can not be refused
+                DegradationStage::SkipDepositRecords => return,
+            };
+            warn!(
+                "Memory budget of {} bytes exceeded ({} used), switching to {:?}",
+                self.limit, self.used, next
+            );
+            self.stage = next;
+            self.transitions.push(next);
+            self.eviction_pending = next == DegradationStage::EvictTerminalRecords;
+        }
+    }
+
+    impl StorageBudget for MemoryBudget {
+        fn admit_record(&mut self, kind: &TransactionType) -> bool {
+            match kind {
+                TransactionType::Withdrawal
+                    if self.stage >= DegradationStage::SkipWithdrawalRecords =>
+                {
+                    self.stats.skipped_withdrawal_records += 1;
+                    false
+                }
+                TransactionType::Deposit if self.stage >= DegradationStage::SkipDepositRecords => {
+                    self.stats.skipped_deposit_records += 1;
+                    false
+                }
+                _ => true,
+            }
+        }
+
+        fn client_added(&mut self) {
+            self.charge(CLIENT_BYTES);
+        }
+
+        fn record_stored(&mut self) {
+            self.charge(RECORD_BYTES);
+        }
+
+        fn records_evicted(&mut self, count: usize) {
+            self.used = self.used.saturating_sub(count * RECORD_BYTES);
+            self.stats.evicted_records += count as u64;
+        }
+
+        fn dispute_opened(&mut self) {
+            self.charge(DISPUTE_BYTES);
+        }
+
+        fn dispute_closed(&mut self) {
+            self.used = self.used.saturating_sub(DISPUTE_BYTES);
+        }
+
+        fn take_eviction(&mut self) -> bool {
+            std::mem::take(&mut self.eviction_pending)
+        }
+
+        fn record_missing(&mut self, kind: &TransactionType) -> bool {
+            let dropped_from = match kind {
+                TransactionType::Withdrawal => DegradationStage::SkipWithdrawalRecords,
+                _ => DegradationStage::SkipDepositRecords,
+            };
+            if self.stage >= dropped_from {
+                self.stats.record_evicted_rejects += 1;
+                return true;
+            }
+            false
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mem-budget"))]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::engine::{Engine, RejectReason};
+    use crate::model::Transaction;
+
+    use rust_decimal::{Decimal, dec};
+
+    #[test]
+    fn test_budget_walks_through_stages_in_order() {
+        // Budget fits the clients plus a handful of records
+        let mut engine = Engine::with_budget(
+            EngineConfig::default(),
+            MemoryBudget::new(4 * CLIENT_BYTES + 6 * RECORD_BYTES),
+        );
+        let mut records: Vec<_> = (0..40u32)
+            .map(|id| {
+                let (kind, amount) = if id % 2 == 0 {
+                    (TransactionType::Deposit, dec!(2.5))
+                } else {
+                    (TransactionType::Withdrawal, dec!(1.0))
+                };
+                Transaction {
+                    kind,
+                    client_id: id / 2 % 4,
+                    id,
+                    amount: Some(amount),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }
+            })
+            .collect();
+        // Disputes against the earliest and latest deposits
+        for id in [0u32, 38] {
+            records.push(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: id / 2 % 4,
+                id,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            });
+        }
+
+        let rejects: Vec<_> = records
+            .into_iter()
+            .filter_map(|tx| {
+                let id = tx.id;
+                engine.apply(tx).err().map(|reason| (id, reason))
+            })
+            .collect();
+
+        // Dispute against tx 38 was dropped with its record
+        assert_eq!(rejects, vec![(38, RejectReason::RecordEvicted)]);
+        let budget = engine.budget();
+        assert_eq!(
+            budget.transitions(),
+            &[
+                DegradationStage::SkipWithdrawalRecords,
+                DegradationStage::EvictTerminalRecords,
+                DegradationStage::SkipDepositRecords,
+            ]
+        );
+        let stats = budget.stats();
+        assert!(stats.skipped_withdrawal_records > 0);
+        assert!(stats.evicted_records > 0);
+        assert!(stats.skipped_deposit_records > 0);
+        assert_eq!(stats.record_evicted_rejects, 1);
+
+        // Every deposit and withdrawal was still applied
+        let clients = engine.into_accounts();
+        for client in clients.values() {
+            assert_eq!(client.available() + client.held(), client.total());
+        }
+        let total: Decimal = clients.values().map(|c| c.total()).sum();
+        let held: Decimal = clients.values().map(|c| c.held()).sum();
+        assert_eq!(total, dec!(30.0));
+        // Dispute against tx 0 still found its record
+        assert_eq!(held, dec!(2.5));
+    }
+
+    #[test]
+    fn test_reversal_of_skipped_withdrawal() {
+        let mut engine = Engine::with_budget(
+            EngineConfig::default(),
+            MemoryBudget::new(CLIENT_BYTES + RECORD_BYTES),
+        );
+        let transaction = |kind, id, amount| Transaction {
+            kind,
+            client_id: 1,
+            id,
+            amount,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+        let records = [
+            transaction(TransactionType::Deposit, 1, Some(dec!(10))),
+            transaction(TransactionType::Withdrawal, 2, Some(dec!(1))),
+            transaction(TransactionType::Withdrawal, 3, Some(dec!(2))),
+            transaction(TransactionType::Reversal, 3, None),
+            transaction(TransactionType::Reversal, 2, None),
+        ];
+
+        let rejects: Vec<_> = records
+            .into_iter()
+            .filter_map(|tx| {
+                let id = tx.id;
+                engine.apply(tx).err().map(|reason| (id, reason))
+            })
+            .collect();
+
+        // Only tx 2 was stored before the budget ran out
+        assert_eq!(rejects, vec![(3, RejectReason::RecordEvicted)]);
+        assert_eq!(
+            engine.budget().transitions(),
+            &[DegradationStage::SkipWithdrawalRecords]
+        );
+        assert_eq!(engine.account(1).unwrap().total(), dec!(8));
+    }
+
+    #[test]
+    fn test_unlimited_budget_never_degrades() {
+        let mut engine =
+            Engine::with_budget(EngineConfig::default(), MemoryBudget::new(usize::MAX));
+        let records = (0..100u32).map(|id| {
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id,
+                amount: Some(dec!(1)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            })
+        });
+
+        engine.process(records);
+
+        assert!(engine.budget().transitions().is_empty());
+        assert_eq!(engine.budget().stats(), &BudgetStats::default());
+        assert_eq!(engine.account(1).unwrap().total(), dec!(100));
+    }
+}
@@ -0,0 +1,662 @@
+//! Transactions read from columnar data, Parquet files as landed by the
+//! data lake exports and Arrow IPC streams as written by the Spark jobs.
+//!
+//! Columns are matched by name, `type` holding strings, `client` and `tx`
+//! integers and `amount` decimals or strings, or for Arrow also floats.
+//! Other columns are ignored. Rows are read a record batch at a time, so
+//! memory stays bounded by the batch size rather than the file.
+
+use crate::io::TransactionSource;
+use crate::model::{ClientId, Transaction, TransactionType};
+use crate::pipeline::{ParseFailure, RawInterest, RawRecord, RawTx};
+use anyhow::{Result, anyhow};
+use arrow_array::cast::AsArray;
+use arrow_array::types::Int64Type;
+use arrow_array::{
+    Array, ArrayRef, Decimal128Array, Float64Array, Int64Array, RecordBatch, RecordBatchReader,
+    StringArray,
+};
+use arrow_cast::cast;
+use arrow_ipc::reader::StreamReader;
+use arrow_schema::{DataType, Schema};
+use csv::ByteRecord;
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::file::reader::ChunkReader;
+use rust_decimal::Decimal;
+use std::fmt;
+use std::io::{self, BufReader, Read};
+use std::str::FromStr;
+
+/// Rows read per record batch
+const BATCH_SIZE: usize = 8192;
+
+/// Start of an Arrow IPC file
+const ARROW_FILE_MAGIC: &[u8] = b"ARROW1";
+
+/// The columns of a file do not hold transactions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaMismatch {
+    /// Transaction columns which are missing or of an unusable type, with
+    /// their types
+    pub offending: Vec<String>,
+    /// Types accepted for the amount column
+    pub amount_types: &'static str,
+    /// Columns of the file, with their types
+    pub found: Vec<String>,
+}
+
+impl fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unusable columns {}, expected columns type (string), client (integer), \
+             tx (integer) and amount ({}), found {}",
+            self.offending.join(", "),
+            self.amount_types,
+            self.found.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for SchemaMismatch {}
+
+/// Check the schema holds the transaction columns with usable types.
+/// Float amounts are only usable with `float_amounts`.
+fn check_schema(schema: &Schema, float_amounts: bool) -> Result<(), SchemaMismatch> {
+    let is_string =
+        |t: &DataType| matches!(t, DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View);
+    let is_amount = |t: &DataType| {
+        is_string(t)
+            || matches!(t, DataType::Decimal128(_, s) if *s >= 0)
+            || (float_amounts && *t == DataType::Float64)
+    };
+    let usable = |name: &str, t: &DataType| match name {
+        "type" => is_string(t),
+        "client" | "tx" => t.is_integer(),
+        _ => is_amount(t),
+    };
+    let offending: Vec<String> = ["type", "client", "tx", "amount"]
+        .into_iter()
+        .filter_map(|name| match schema.field_with_name(name) {
+            Ok(field) if usable(name, field.data_type()) => None,
+            Ok(field) => Some(format!("{name} ({})", field.data_type())),
+            Err(_) => Some(format!("{name} (missing)")),
+        })
+        .collect();
+    if offending.is_empty() {
+        return Ok(());
+    }
+    Err(SchemaMismatch {
+        offending,
+        amount_types: if float_amounts {
+            "decimal, string or float"
+        } else {
+            "decimal or string"
+        },
+        found: schema
+            .fields()
+            .iter()
+            .map(|f| format!("{} ({})", f.name(), f.data_type()))
+            .collect(),
+    })
+}
+
+/// Amount column of a batch
+enum Amounts {
+    Decimal(Decimal128Array, u32),
+    Text(StringArray),
+    /// Read through the shortest text of the float, so e.g. 0.1 is the
+    /// decimal 0.1 rather than the nearest binary fraction
+    Float(Float64Array),
+}
+
+/// Transaction columns of a batch, cast to the types rows are read from
+struct Columns {
+    kind: StringArray,
+    client: Int64Array,
+    tx: Int64Array,
+    amount: Amounts,
+}
+
+impl Columns {
+    fn new(batch: &RecordBatch) -> Result<Self> {
+        let column = |name| -> Result<&ArrayRef> {
+            batch
+                .column_by_name(name)
+                .ok_or_else(|| anyhow!("column {name} missing from batch"))
+        };
+        let strings = |array: &ArrayRef| -> Result<StringArray> {
+            Ok(cast(array, &DataType::Utf8)?.as_string::<i32>().clone())
+        };
+        let integers = |array: &ArrayRef| -> Result<Int64Array> {
+            Ok(cast(array, &DataType::Int64)?
+                .as_primitive::<Int64Type>()
+                .clone())
+        };
+        let amount = column("amount")?;
+        let amount = match amount.data_type() {
+            DataType::Decimal128(_, scale) => {
+                Amounts::Decimal(amount.as_primitive().clone(), u32::try_from(*scale)?)
+            }
+            DataType::Float64 => Amounts::Float(amount.as_primitive().clone()),
+            _ => Amounts::Text(strings(amount)?),
+        };
+        Ok(Self {
+            kind: strings(column("type")?)?,
+            client: integers(column("client")?)?,
+            tx: integers(column("tx")?)?,
+            amount,
+        })
+    }
+
+    /// Fields of a row as they would appear in CSV
+    fn raw(&self, i: usize) -> ByteRecord {
+        let text = |array: &dyn Array, value: String| {
+            if array.is_null(i) {
+                String::new()
+            } else {
+                value
+            }
+        };
+        let amount = match &self.amount {
+            Amounts::Decimal(array, scale) => text(
+                array,
+                Decimal::try_from_i128_with_scale(array.value(i), *scale)
+                    .map_or_else(|_| array.value_as_string(i), |d| d.to_string()),
+            ),
+            Amounts::Text(array) => text(array, array.value(i).to_string()),
+            Amounts::Float(array) => text(array, array.value(i).to_string()),
+        };
+        ByteRecord::from(vec![
+            text(&self.kind, self.kind.value(i).to_string()),
+            text(&self.client, self.client.value(i).to_string()),
+            text(&self.tx, self.tx.value(i).to_string()),
+            amount,
+        ])
+    }
+
+    fn transaction(&self, i: usize) -> Result<Transaction, String> {
+        if self.kind.is_null(i) {
+            return Err("missing type".to_string());
+        }
+        let name = self.kind.value(i);
+        let kind = TransactionType::from_alias(name.trim())
+            .ok_or_else(|| format!("unknown transaction type {name}"))?;
+        if self.client.is_null(i) {
+            return Err("missing client".to_string());
+        }
+        let client_id = ClientId::try_from(self.client.value(i))
+            .map_err(|_| format!("client {} out of range", self.client.value(i)))?;
+        if self.tx.is_null(i) {
+            return Err("missing tx".to_string());
+        }
+        let id = u32::try_from(self.tx.value(i))
+            .map_err(|_| format!("tx {} out of range", self.tx.value(i)))?;
+        let amount = match &self.amount {
+            Amounts::Decimal(array, _) if array.is_null(i) => None,
+            Amounts::Decimal(array, scale) => Some(
+                Decimal::try_from_i128_with_scale(array.value(i), *scale)
+                    .map_err(|_| format!("amount {} out of range", array.value_as_string(i)))?,
+            ),
+            Amounts::Text(array) if array.is_null(i) || array.value(i).trim().is_empty() => None,
+            Amounts::Text(array) => Some(
+                Decimal::from_str(array.value(i).trim())
+                    .map_err(|e| format!("invalid amount {}: {e}", array.value(i)))?,
+            ),
+            Amounts::Float(array) if array.is_null(i) => None,
+            Amounts::Float(array) => {
+                let value = array.value(i);
+                Some(
+                    Decimal::from_str(&value.to_string())
+                        .map_err(|_| format!("amount {value} out of range"))?,
+                )
+            }
+        };
+        Ok(Transaction {
+            kind,
+            client_id,
+            id,
+            amount,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        })
+    }
+}
+
+/// Rows of a stream of record batches, numbered from 1
+struct BatchRows<B> {
+    batches: B,
+    batch: Option<(Columns, usize)>,
+    rows: usize,
+    row: u64,
+    source: u16,
+    interest: RawInterest,
+    done: bool,
+}
+
+impl<B: RecordBatchReader> BatchRows<B> {
+    fn new(batches: B, source: u16, interest: RawInterest) -> Self {
+        Self {
+            batches,
+            batch: None,
+            rows: 0,
+            row: 0,
+            source,
+            interest,
+            done: false,
+        }
+    }
+
+    /// Move on to the next batch holding rows, false once there is none
+    fn next_batch(&mut self) -> Result<bool> {
+        loop {
+            let Some(batch) = self.batches.next().transpose()? else {
+                return Ok(false);
+            };
+            if batch.num_rows() > 0 {
+                self.rows = batch.num_rows();
+                self.batch = Some((Columns::new(&batch)?, 0));
+                return Ok(true);
+            }
+        }
+    }
+}
+
+impl<B: RecordBatchReader> Iterator for BatchRows<B> {
+    type Item = RawTx;
+
+    fn next(&mut self) -> Option<RawTx> {
+        if self.done {
+            return None;
+        }
+        if self.batch.as_ref().is_none_or(|&(_, i)| i == self.rows) {
+            match self.next_batch() {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.done = true;
+                    return None;
+                }
+                Err(e) => {
+                    // The reader can not recover from errors reading a batch
+                    self.done = true;
+                    return Some(RawTx {
+                        record: None,
+                        row: self.row + 1,
+                        source: self.source,
+                        parsed: Err(ParseFailure {
+                            message: e.to_string(),
+                            fatal: true,
+                        }),
+                    });
+                }
+            }
+        }
+        let (columns, i) = self.batch.as_mut()?;
+        let row = *i;
+        *i += 1;
+        self.row += 1;
+        let parsed = columns.transaction(row).map_err(|message| ParseFailure {
+            message,
+            fatal: false,
+        });
+        let record = (!self.interest.is_empty()).then(|| RawRecord::Csv(columns.raw(row)));
+        Some(RawTx {
+            record,
+            row: self.row,
+            source: self.source,
+            parsed,
+        })
+    }
+}
+
+/// Reads the rows of a Parquet file into `RawTx`, numbering them from 1.
+///
+/// The raw record of a row holds its fields as they would appear in CSV,
+/// so quarantined rows can be fed again as CSV.
+pub struct ParquetSource(BatchRows<ParquetRecordBatchReader>);
+
+impl ParquetSource {
+    /// Open a Parquet file, failing with a [`SchemaMismatch`] if its
+    /// columns do not hold transactions
+    pub fn new<R: ChunkReader + 'static>(
+        reader: R,
+        source: u16,
+        interest: RawInterest,
+    ) -> Result<Self> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(reader)?;
+        check_schema(builder.schema(), false)?;
+        let batches = builder.with_batch_size(BATCH_SIZE).build()?;
+        Ok(Self(BatchRows::new(batches, source, interest)))
+    }
+}
+
+impl Iterator for ParquetSource {
+    type Item = RawTx;
+
+    fn next(&mut self) -> Option<RawTx> {
+        self.0.next()
+    }
+}
+
+impl TransactionSource for ParquetSource {
+    fn transactions(self) -> impl Iterator<Item = Result<Transaction>> {
+        self.map(|raw| raw.parsed.map_err(|failure| anyhow!(failure.message)))
+    }
+}
+
+/// Arrow IPC stream read from `R`, see [`ArrowSource::stream`]
+pub type ArrowStream<R> = StreamReader<BufReader<io::Chain<io::Cursor<Vec<u8>>, R>>>;
+
+/// Reads the rows of Arrow record batches into `RawTx` like
+/// [`ParquetSource`], a batch at a time as the writer sent them. Float64
+/// amounts are accepted unless rejected.
+pub struct ArrowSource<R>(BatchRows<R>);
+
+impl<R: RecordBatchReader> ArrowSource<R> {
+    /// Read the batches of an Arrow reader, failing with a
+    /// [`SchemaMismatch`] if its columns do not hold transactions, or hold
+    /// float amounts with `reject_floats`
+    pub fn new(reader: R, source: u16, interest: RawInterest, reject_floats: bool) -> Result<Self> {
+        check_schema(&reader.schema(), !reject_floats)?;
+        Ok(Self(BatchRows::new(reader, source, interest)))
+    }
+}
+
+impl<R: Read> ArrowSource<ArrowStream<R>> {
+    /// Read an Arrow IPC stream, or an Arrow IPC file, also known as
+    /// Feather. A file holds the stream after its magic, it is read the
+    /// same way without seeking to its footer.
+    pub fn stream(
+        mut reader: R,
+        source: u16,
+        interest: RawInterest,
+        reject_floats: bool,
+    ) -> Result<Self> {
+        let mut head = Vec::with_capacity(ARROW_FILE_MAGIC.len());
+        (&mut reader)
+            .take(ARROW_FILE_MAGIC.len() as u64)
+            .read_to_end(&mut head)?;
+        if head == ARROW_FILE_MAGIC {
+            // The magic is padded with zeros to the alignment of the
+            // writer, messages start with a continuation marker
+            head.clear();
+            let mut byte = [0];
+            loop {
+                let n = reader.read(&mut byte)?;
+                if n == 0 || byte[0] != 0 {
+                    head.extend_from_slice(&byte[..n]);
+                    break;
+                }
+            }
+        }
+        let reader = BufReader::new(io::Cursor::new(head).chain(reader));
+        Self::new(
+            StreamReader::try_new(reader, None)?,
+            source,
+            interest,
+            reject_floats,
+        )
+    }
+}
+
+impl<R: RecordBatchReader> Iterator for ArrowSource<R> {
+    type Item = RawTx;
+
+    fn next(&mut self) -> Option<RawTx> {
+        self.0.next()
+    }
+}
+
+impl<R: RecordBatchReader> TransactionSource for ArrowSource<R> {
+    fn transactions(self) -> impl Iterator<Item = Result<Transaction>> {
+        self.map(|raw| raw.parsed.map_err(|failure| anyhow!(failure.message)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::CsvSource;
+    use crate::{EngineConfig, process_transactions};
+    use arrow_array::{Decimal128Array, UInt16Array, UInt32Array};
+    use arrow_ipc::writer::{FileWriter, StreamWriter};
+    use bytes::Bytes;
+    use parquet::arrow::ArrowWriter;
+    use rust_decimal::dec;
+    use std::sync::Arc;
+
+    fn parquet(batch: RecordBatch) -> Bytes {
+        let mut writer = ArrowWriter::try_new(Vec::new(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        Bytes::from(writer.into_inner().unwrap())
+    }
+
+    fn transactions(batch: RecordBatch) -> Vec<Result<Transaction>> {
+        ParquetSource::new(parquet(batch), 0, RawInterest::NONE)
+            .unwrap()
+            .transactions()
+            .collect()
+    }
+
+    #[test]
+    fn test_parquet_matches_csv() {
+        let batch = RecordBatch::try_from_iter([
+            (
+                "type",
+                Arc::new(StringArray::from(vec![
+                    "deposit",
+                    "deposit",
+                    "withdrawal",
+                    "dispute",
+                ])) as ArrayRef,
+            ),
+            ("client", Arc::new(UInt16Array::from(vec![1, 1, 1, 1]))),
+            ("tx", Arc::new(UInt32Array::from(vec![1, 2, 3, 2]))),
+            (
+                "amount",
+                Arc::new(
+                    Decimal128Array::from(vec![Some(10_000), Some(25_000), Some(1_234), None])
+                        .with_precision_and_scale(10, 4)
+                        .unwrap(),
+                ),
+            ),
+        ])
+        .unwrap();
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.5\n\
+                   withdrawal,1,3,0.1234\ndispute,1,2,\n";
+
+        let from_parquet = transactions(batch);
+        let from_csv: Vec<_> = CsvSource::new(csv.as_bytes()).transactions().collect();
+        assert_eq!(
+            from_parquet
+                .iter()
+                .map(|t| t.as_ref().unwrap())
+                .collect::<Vec<_>>(),
+            from_csv
+                .iter()
+                .map(|t| t.as_ref().unwrap())
+                .collect::<Vec<_>>()
+        );
+        let (from_parquet, _) = process_transactions(from_parquet, EngineConfig::default());
+        let (from_csv, _) = process_transactions(from_csv, EngineConfig::default());
+        assert_eq!(from_parquet, from_csv);
+    }
+
+    #[test]
+    fn test_parquet_string_amounts_and_row_errors() {
+        let batch = RecordBatch::try_from_iter([
+            (
+                "type",
+                Arc::new(StringArray::from(vec![
+                    Some("deposit"),
+                    Some("payout"),
+                    None,
+                ])) as ArrayRef,
+            ),
+            ("client", Arc::new(Int64Array::from(vec![1, 70_000, 1]))),
+            ("tx", Arc::new(Int64Array::from(vec![1, 2, 3]))),
+            (
+                "amount",
+                Arc::new(StringArray::from(vec![Some(" 1.5"), Some("x"), None])),
+            ),
+            ("channel", Arc::new(StringArray::from(vec!["web"; 3]))),
+        ])
+        .unwrap();
+
+        let rows: Vec<_> = ParquetSource::new(parquet(batch), 0, RawInterest::QUARANTINE)
+            .unwrap()
+            .collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(
+            rows[0].parsed.as_ref().unwrap().amount,
+            Some(Decimal::new(15, 1))
+        );
+        assert_eq!(
+            rows[1].parsed.as_ref().unwrap_err().message,
+            "unknown transaction type payout"
+        );
+        assert_eq!(rows[2].parsed.as_ref().unwrap_err().message, "missing type");
+        assert_eq!(rows[2].row, 3);
+        assert_eq!(
+            rows[1].record,
+            Some(RawRecord::Csv(ByteRecord::from(vec![
+                "payout", "70000", "2", "x"
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_parquet_schema_mismatch() {
+        let batch = RecordBatch::try_from_iter([
+            (
+                "kind",
+                Arc::new(StringArray::from(vec!["deposit"])) as ArrayRef,
+            ),
+            ("client", Arc::new(StringArray::from(vec!["1"]))),
+        ])
+        .unwrap();
+        let e = ParquetSource::new(parquet(batch), 0, RawInterest::NONE)
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "unusable columns type (missing), client (Utf8), tx (missing), amount (missing), \
+             expected columns type (string), client (integer), tx (integer) and amount \
+             (decimal or string), found kind (Utf8), client (Utf8)"
+        );
+    }
+
+    fn arrow_stream(batches: &[RecordBatch]) -> Vec<u8> {
+        let mut writer = StreamWriter::try_new(Vec::new(), &batches[0].schema()).unwrap();
+        for batch in batches {
+            writer.write(batch).unwrap();
+        }
+        writer.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_arrow_matches_csv() {
+        let batch = |kinds: Vec<&str>, txs: Vec<u32>, amounts: Vec<Option<i128>>| {
+            // Batches of a stream share the schema of the first one
+            RecordBatch::try_from_iter_with_nullable([
+                (
+                    "type",
+                    Arc::new(StringArray::from(kinds)) as ArrayRef,
+                    false,
+                ),
+                (
+                    "client",
+                    Arc::new(UInt16Array::from(vec![1; txs.len()])),
+                    false,
+                ),
+                ("tx", Arc::new(UInt32Array::from(txs)), false),
+                (
+                    "amount",
+                    Arc::new(
+                        Decimal128Array::from(amounts)
+                            .with_precision_and_scale(10, 4)
+                            .unwrap(),
+                    ),
+                    true,
+                ),
+            ])
+            .unwrap()
+        };
+        let batches = [
+            batch(
+                vec!["deposit", "deposit"],
+                vec![1, 2],
+                vec![Some(10_000), Some(25_000)],
+            ),
+            batch(
+                vec!["withdrawal", "dispute"],
+                vec![3, 2],
+                vec![Some(1_234), None],
+            ),
+        ];
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.5\n\
+                   withdrawal,1,3,0.1234\ndispute,1,2,\n";
+        let from_csv: Vec<_> = CsvSource::new(csv.as_bytes())
+            .transactions()
+            .map(Result::unwrap)
+            .collect();
+
+        let mut file = FileWriter::try_new(Vec::new(), &batches[0].schema()).unwrap();
+        for batch in &batches {
+            file.write(batch).unwrap();
+        }
+        file.finish().unwrap();
+        // The stream and the file format read the same, across batches
+        for data in [arrow_stream(&batches), file.into_inner().unwrap()] {
+            let rows: Vec<_> = ArrowSource::stream(data.as_slice(), 0, RawInterest::NONE, false)
+                .unwrap()
+                .collect();
+            assert_eq!(rows.iter().map(|r| r.row).collect::<Vec<_>>(), [1, 2, 3, 4]);
+            let from_arrow: Vec<_> = rows.into_iter().map(|r| r.parsed.unwrap()).collect();
+            assert_eq!(from_arrow, from_csv);
+        }
+    }
+
+    #[test]
+    fn test_arrow_float_amounts() {
+        let batch = RecordBatch::try_from_iter([
+            (
+                "type",
+                Arc::new(StringArray::from(vec!["deposit"; 3])) as ArrayRef,
+            ),
+            ("client", Arc::new(Int64Array::from(vec![1, 1, 1]))),
+            ("tx", Arc::new(Int64Array::from(vec![1, 2, 3]))),
+            (
+                "amount",
+                Arc::new(Float64Array::from(vec![0.1, 1e-7, f64::NAN])),
+            ),
+        ])
+        .unwrap();
+        let data = arrow_stream(&[batch]);
+
+        let rows: Vec<_> = ArrowSource::stream(data.as_slice(), 0, RawInterest::NONE, false)
+            .unwrap()
+            .collect();
+        // Converted through their text, not the binary fraction
+        assert_eq!(rows[0].parsed.as_ref().unwrap().amount, Some(dec!(0.1)));
+        assert_eq!(
+            rows[1].parsed.as_ref().unwrap().amount,
+            Some(dec!(0.0000001))
+        );
+        assert_eq!(
+            rows[2].parsed.as_ref().unwrap_err().message,
+            "amount NaN out of range"
+        );
+
+        let e = ArrowSource::stream(data.as_slice(), 0, RawInterest::NONE, true)
+            .err()
+            .unwrap();
+        assert!(
+            e.to_string()
+                .starts_with("unusable columns amount (Float64), expected"),
+            "{e}"
+        );
+    }
+}
@@ -0,0 +1,491 @@
+use crate::model::ClientId;
+use chrono::Duration;
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+/// Policies of the engine.
+///
+/// The default configuration matches the behavior described in the
+/// README, every option changes one of those rules.
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    pub(crate) allow_negative_on_dispute: bool,
+    pub(crate) dispute_withdrawals: bool,
+    pub(crate) dispute_locked_accounts: bool,
+    pub(crate) max_open_disputes_per_client: Option<u32>,
+    pub(crate) redispute_policy: RedisputePolicy,
+    pub(crate) allow_zero_amounts: bool,
+    pub(crate) precision_policy: PrecisionPolicy,
+    pub(crate) tx_id_scope: TxIdScope,
+    pub(crate) duplicate_tx_id_policy: DuplicateTxIdPolicy,
+    pub(crate) lock_on_withdrawal_chargeback: bool,
+    pub(crate) strict_fields: bool,
+    pub(crate) strict_types: bool,
+    pub(crate) defer_unmatched_disputes: bool,
+    pub(crate) max_deferred_disputes: usize,
+    pub(crate) auto_unlock: bool,
+    pub(crate) dispute_ttl_events: Option<u32>,
+    pub(crate) locked_deposit_policy: LockedDepositPolicy,
+    pub(crate) allow_partial_disputes: bool,
+    pub(crate) adjust_locked_accounts: bool,
+    pub(crate) force_unlock: bool,
+    pub(crate) base_currency: String,
+    pub(crate) enforce_monotonic_timestamps: bool,
+    pub(crate) daily_withdrawal_limit: Option<Decimal>,
+    pub(crate) max_transaction_amount: Option<Decimal>,
+    pub(crate) flag_after_chargebacks: Option<u32>,
+    pub(crate) freeze_flagged: bool,
+    pub(crate) minimum_balance: Option<Decimal>,
+    pub(crate) auto_chargeback_after: Option<Duration>,
+}
+
+/// Currency of rows without a currency column
+pub const DEFAULT_BASE_CURRENCY: &str = "USD";
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            allow_negative_on_dispute: false,
+            dispute_withdrawals: false,
+            dispute_locked_accounts: true,
+            max_open_disputes_per_client: None,
+            redispute_policy: RedisputePolicy::default(),
+            allow_zero_amounts: false,
+            precision_policy: PrecisionPolicy::default(),
+            tx_id_scope: TxIdScope::default(),
+            duplicate_tx_id_policy: DuplicateTxIdPolicy::default(),
+            lock_on_withdrawal_chargeback: true,
+            strict_fields: false,
+            strict_types: false,
+            defer_unmatched_disputes: false,
+            max_deferred_disputes: 10_000,
+            auto_unlock: false,
+            dispute_ttl_events: None,
+            locked_deposit_policy: LockedDepositPolicy::default(),
+            allow_partial_disputes: false,
+            adjust_locked_accounts: true,
+            force_unlock: false,
+            base_currency: DEFAULT_BASE_CURRENCY.to_string(),
+            enforce_monotonic_timestamps: false,
+            daily_withdrawal_limit: None,
+            max_transaction_amount: None,
+            flag_after_chargebacks: None,
+            freeze_flagged: false,
+            minimum_balance: None,
+            auto_chargeback_after: None,
+        }
+    }
+}
+
+/// Whether a transaction may be disputed again once its dispute was
+/// closed. Charged back transactions are never disputed again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RedisputePolicy {
+    /// Resolved disputes may be reopened any number of times
+    #[default]
+    AllowAlways,
+    /// A resolved dispute can not be reopened
+    DenyAfterResolve,
+    /// At most one dispute per transaction, however it was closed
+    DenyAfterAnyClosure,
+}
+
+/// How deposit and withdrawal amounts with more decimal places than the
+/// four of the input format are handled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PrecisionPolicy {
+    /// Keep the amount as it is, the output may not reconcile after
+    /// formatting
+    #[default]
+    Passthrough,
+    /// Reject amounts with more than four decimal places
+    RejectExcess,
+    /// Round to this many decimal places, ties to even
+    RoundHalfEven(u32),
+}
+
+impl FromStr for PrecisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "passthrough" => Ok(PrecisionPolicy::Passthrough),
+            "reject-excess" => Ok(PrecisionPolicy::RejectExcess),
+            "round-half-even" => Ok(PrecisionPolicy::RoundHalfEven(4)),
+            other => Err(format!(
+                "unknown precision policy {other}, expected passthrough, \
+                 reject-excess or round-half-even"
+            )),
+        }
+    }
+}
+
+/// How amounts in scientific notation, like `1.2E3` from spreadsheet
+/// exports, are read. Applied by the readers of the input, the engine
+/// only sees decimals.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ScientificAmounts {
+    /// The row is malformed
+    #[default]
+    Reject,
+    /// The amount is expanded to a decimal, excess decimal places are then
+    /// up to the precision policy
+    Accept,
+}
+
+impl FromStr for ScientificAmounts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(ScientificAmounts::Reject),
+            "accept" => Ok(ScientificAmounts::Accept),
+            other => Err(format!(
+                "unknown scientific amounts policy {other}, expected reject or accept"
+            )),
+        }
+    }
+}
+
+/// What happens to deposits for a locked client
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LockedDepositPolicy {
+    /// The deposit is rejected as for a locked account
+    #[default]
+    Reject,
+    /// The deposit is stored and its amount held, until unlocking the
+    /// client releases it to the available funds
+    Hold,
+    /// The deposit is ignored, without being reported as rejected
+    Drop,
+}
+
+impl FromStr for LockedDepositPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(LockedDepositPolicy::Reject),
+            "hold" => Ok(LockedDepositPolicy::Hold),
+            "drop" => Ok(LockedDepositPolicy::Drop),
+            other => Err(format!(
+                "unknown locked deposit policy {other}, expected reject, hold or drop"
+            )),
+        }
+    }
+}
+
+/// Where transaction ids have to be unique
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TxIdScope {
+    /// Across all clients
+    #[default]
+    Global,
+    /// Within each client, different clients may reuse an id
+    PerClient,
+}
+
+impl TxIdScope {
+    /// Key of a stored transaction. With per-client ids the client takes
+    /// the upper half, so the lower half is always the transaction id.
+    pub(crate) fn key(self, client_id: ClientId, tx_id: u32) -> u64 {
+        match self {
+            TxIdScope::Global => u64::from(tx_id),
+            TxIdScope::PerClient => u64::from(client_id) << 32 | u64::from(tx_id),
+        }
+    }
+
+    /// Transaction id of a key, its lower half in either scope
+    pub(crate) fn tx_id(key: u64) -> u32 {
+        (key & u64::from(u32::MAX)) as u32
+    }
+}
+
+impl FromStr for TxIdScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(TxIdScope::Global),
+            "per-client" => Ok(TxIdScope::PerClient),
+            other => Err(format!(
+                "unknown transaction id scope {other}, expected global or per-client"
+            )),
+        }
+    }
+}
+
+/// Which earlier rows take the transaction id of a deposit, withdrawal,
+/// purchase, adjustment or transfer, so a later row reusing it is rejected
+/// as a duplicate
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateTxIdPolicy {
+    /// Only applied rows, the id of a rejected row can be used again
+    #[default]
+    RejectApplied,
+    /// Rejected rows as well, so a corrected row needs a new id
+    RejectAny,
+}
+
+impl FromStr for DuplicateTxIdPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-applied" => Ok(DuplicateTxIdPolicy::RejectApplied),
+            "reject-any" => Ok(DuplicateTxIdPolicy::RejectAny),
+            other => Err(format!(
+                "unknown duplicate transaction id policy {other}, expected reject-applied \
+                 or reject-any"
+            )),
+        }
+    }
+}
+
+impl FromStr for RedisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow-always" => Ok(RedisputePolicy::AllowAlways),
+            "deny-after-resolve" => Ok(RedisputePolicy::DenyAfterResolve),
+            "deny-after-any-closure" => Ok(RedisputePolicy::DenyAfterAnyClosure),
+            other => Err(format!(
+                "unknown redispute policy {other}, expected allow-always, \
+                 deny-after-resolve or deny-after-any-closure"
+            )),
+        }
+    }
+}
+
+impl EngineConfig {
+    pub fn builder() -> EngineConfigBuilder {
+        EngineConfigBuilder::default()
+    }
+
+    /// Whether only lowercase transaction types are accepted. The types
+    /// are parsed before the engine sees them, so this is applied by the
+    /// readers of the input, see [`crate::CsvSource::strict_types`].
+    pub fn strict_types(&self) -> bool {
+        self.strict_types
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EngineConfigBuilder {
+    config: EngineConfig,
+}
+
+impl EngineConfigBuilder {
+    /// Open disputes even if the client no longer has the disputed amount
+    /// available, driving available funds negative. Withdrawals are still
+    /// checked against the negative available funds, so the debt has to
+    /// be paid back before anything can be withdrawn again.
+    pub fn allow_negative_on_dispute(mut self, allow: bool) -> Self {
+        self.config.allow_negative_on_dispute = allow;
+        self
+    }
+
+    #[deprecated(note = "renamed to `allow_negative_on_dispute`")]
+    pub fn allow_negative_available(self, allow: bool) -> Self {
+        self.allow_negative_on_dispute(allow)
+    }
+
+    /// Allow disputes against withdrawals. The disputed amount is held
+    /// on top of the client's total until the dispute is closed.
+    pub fn dispute_withdrawals(mut self, allow: bool) -> Self {
+        self.config.dispute_withdrawals = allow;
+        self
+    }
+
+    /// Lock the client when a disputed withdrawal is charged back, on by
+    /// default. The withdrawn amount is credited back either way.
+    pub fn lock_on_withdrawal_chargeback(mut self, lock: bool) -> Self {
+        self.config.lock_on_withdrawal_chargeback = lock;
+        self
+    }
+
+    /// Keep processing disputes, resolves and chargebacks for locked
+    /// clients, on by default. Deposits and withdrawals are always
+    /// refused, when turned off every transaction of a locked client is.
+    pub fn dispute_locked_accounts(mut self, allow: bool) -> Self {
+        self.config.dispute_locked_accounts = allow;
+        self
+    }
+
+    /// Reject disputes once a client has this many open disputes.
+    /// Resolving or charging back a dispute allows a new one again.
+    pub fn max_open_disputes_per_client(mut self, max: Option<u32>) -> Self {
+        self.config.max_open_disputes_per_client = max;
+        self
+    }
+
+    /// Whether resolved disputes may be opened again
+    pub fn redispute_policy(mut self, policy: RedisputePolicy) -> Self {
+        self.config.redispute_policy = policy;
+        self
+    }
+
+    /// Accept deposits and withdrawals of zero, which are stored and can
+    /// be disputed like any other.
+    pub fn allow_zero_amounts(mut self, allow: bool) -> Self {
+        self.config.allow_zero_amounts = allow;
+        self
+    }
+
+    /// How amounts with more than four decimal places are ingested
+    pub fn precision_policy(mut self, policy: PrecisionPolicy) -> Self {
+        self.config.precision_policy = policy;
+        self
+    }
+
+    /// Whether transaction ids are unique across all clients or only
+    /// within each client
+    pub fn tx_id_scope(mut self, scope: TxIdScope) -> Self {
+        self.config.tx_id_scope = scope;
+        self
+    }
+
+    /// Whether the ids of rejected rows are taken like those of applied ones
+    pub fn duplicate_tx_id_policy(mut self, policy: DuplicateTxIdPolicy) -> Self {
+        self.config.duplicate_tx_id_policy = policy;
+        self
+    }
+
+    /// Reject disputes, resolves and chargebacks which carry an amount
+    pub fn strict_fields(mut self, strict: bool) -> Self {
+        self.config.strict_fields = strict;
+        self
+    }
+
+    /// Only accept the lowercase transaction types, without aliases
+    pub fn strict_types(mut self, strict: bool) -> Self {
+        self.config.strict_types = strict;
+        self
+    }
+
+    /// Park disputes, resolves and chargebacks referencing a transaction
+    /// which has not arrived yet, and apply them once it does
+    pub fn defer_unmatched_disputes(mut self, defer: bool) -> Self {
+        self.config.defer_unmatched_disputes = defer;
+        self
+    }
+
+    /// Maximum of parked dispute steps, further ones are rejected right
+    /// away. Defaults to 10000.
+    pub fn max_deferred_disputes(mut self, max: usize) -> Self {
+        self.config.max_deferred_disputes = max;
+        self
+    }
+
+    /// Unlock a client once none of its disputes is open anymore, instead
+    /// of keeping it locked for good after a chargeback. Deposits and
+    /// withdrawals are accepted again afterwards.
+    pub fn auto_unlock(mut self, unlock: bool) -> Self {
+        self.config.auto_unlock = unlock;
+        self
+    }
+
+    /// Resolve a dispute by itself once its client had this many further
+    /// transactions applied, at least one. Resolves and chargebacks for an
+    /// expired dispute are rejected.
+    pub fn dispute_ttl_events(mut self, events: Option<u32>) -> Self {
+        self.config.dispute_ttl_events = events;
+        self
+    }
+
+    /// Whether deposits for a locked client are rejected, held until the
+    /// client is unlocked, or dropped
+    pub fn locked_deposit_policy(mut self, policy: LockedDepositPolicy) -> Self {
+        self.config.locked_deposit_policy = policy;
+        self
+    }
+
+    /// Hold only the amount of a dispute row carrying one, which may not
+    /// exceed the disputed transaction. Resolves and chargebacks then move
+    /// that amount.
+    pub fn allow_partial_disputes(mut self, allow: bool) -> Self {
+        self.config.allow_partial_disputes = allow;
+        self
+    }
+
+    /// Apply adjustments to locked clients as well, on by default since
+    /// corrections are often meant for them
+    pub fn adjust_locked_accounts(mut self, allow: bool) -> Self {
+        self.config.adjust_locked_accounts = allow;
+        self
+    }
+
+    /// Let unlock rows unlock clients which still have open disputes,
+    /// which are refused otherwise
+    pub fn force_unlock(mut self, force: bool) -> Self {
+        self.config.force_unlock = force;
+        self
+    }
+
+    /// Currency of rows without a currency, which is what the balances of
+    /// the client itself are kept in. Every other currency gets an account
+    /// of its own.
+    pub fn base_currency(mut self, currency: &str) -> Self {
+        self.config.base_currency = currency.to_ascii_uppercase();
+        self
+    }
+
+    /// Reject rows with a timestamp earlier than the one of the last
+    /// applied row which had one, per source entered with
+    /// [`Engine::enter_source`](crate::Engine::enter_source). By default
+    /// timestamps are only stored.
+    pub fn enforce_monotonic_timestamps(mut self, enforce: bool) -> Self {
+        self.config.enforce_monotonic_timestamps = enforce;
+        self
+    }
+
+    /// Cap the withdrawals of a client per UTC calendar day and currency.
+    /// Withdrawals without a timestamp are rejected once a limit is set,
+    /// there is no day to count them on.
+    pub fn daily_withdrawal_limit(mut self, limit: Option<Decimal>) -> Self {
+        self.config.daily_withdrawal_limit = limit;
+        self
+    }
+
+    /// Reject deposits and withdrawals above this amount, after rounding.
+    /// The transaction id stays unused.
+    pub fn max_transaction_amount(mut self, max: Option<Decimal>) -> Self {
+        self.config.max_transaction_amount = max;
+        self
+    }
+
+    /// Flag a client once it had more than this many chargebacks. Flagged
+    /// clients are processed as before unless frozen with
+    /// [`freeze_flagged`](Self::freeze_flagged).
+    pub fn flag_after_chargebacks(mut self, max: Option<u32>) -> Self {
+        self.config.flag_after_chargebacks = max;
+        self
+    }
+
+    /// Reject withdrawals and outgoing transfers of flagged clients
+    pub fn freeze_flagged(mut self, freeze: bool) -> Self {
+        self.config.freeze_flagged = freeze;
+        self
+    }
+
+    /// Reject withdrawals and transfers which would leave less available
+    /// funds than this. Clients with a minimum balance of their own use
+    /// that instead, disputes are not limited.
+    pub fn minimum_balance(mut self, minimum: Option<Decimal>) -> Self {
+        self.config.minimum_balance = minimum;
+        self
+    }
+
+    /// Charge back a dispute once the timestamp of the stream passes the
+    /// one of its dispute row by more than this. Disputes opened by rows
+    /// without a timestamp never age out.
+    pub fn auto_chargeback_after(mut self, after: Option<Duration>) -> Self {
+        self.config.auto_chargeback_after = after;
+        self
+    }
+
+    pub fn build(self) -> EngineConfig {
+        self.config
+    }
+}
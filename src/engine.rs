@@ -0,0 +1,6592 @@
+use crate::budget::{StorageBudget, Unbounded};
+use crate::config::{
+    DuplicateTxIdPolicy, EngineConfig, LockedDepositPolicy, PrecisionPolicy, RedisputePolicy,
+    TxIdScope,
+};
+use crate::model::{
+    Client, ClientId, DisputeState, Timestamp, Transaction, TransactionRecord, TransactionType,
+};
+use crate::observer::{EngineObserver, NoObserver};
+use crate::snapshot::{EngineSnapshot, SNAPSHOT_VERSION, SnapshotError};
+use crate::verify::{Invariant, InvariantViolation};
+use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
+use anyhow::Result;
+use chrono::{Duration, NaiveDate};
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
+
+/// Reason a transaction was not applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    /// Deposit or withdrawal reusing the id of a stored transaction
+    DuplicateTxId,
+    /// Deposit or withdrawal without an amount
+    MissingAmount,
+    /// Dispute, resolve or chargeback carrying an amount, if configured to
+    /// reject those
+    UnexpectedAmount,
+    /// Deposit or withdrawal of a negative amount
+    NonPositiveAmount,
+    /// Deposit or withdrawal of zero, unless allowed by the configuration
+    ZeroAmount,
+    /// Dispute step parked until the transaction it references arrives
+    Deferred,
+    /// Balance would leave the range of representable amounts
+    Overflow,
+    /// Amount with more than four decimal places, if configured to reject
+    /// those
+    ExcessPrecision,
+    /// Not enough available funds for a withdrawal or dispute
+    InsufficientFunds,
+    /// Client account is locked by a chargeback
+    AccountLocked,
+    /// Referenced transaction does not exist
+    UnknownTx,
+    /// Referenced transaction may have been dropped by the memory budget
+    RecordEvicted,
+    /// Referenced transaction belongs to a different client
+    ClientMismatch,
+    /// Resolve or chargeback for a transaction which is not disputed
+    NotDisputed,
+    /// Dispute for a transaction which is already disputed
+    AlreadyDisputed,
+    /// Dispute for a transaction which was charged back before
+    AlreadyChargedBack,
+    /// Dispute reopening a closed dispute, which the redispute policy denies
+    RedisputeDenied,
+    /// Dispute for a transaction which is not a deposit
+    NotADeposit,
+    /// Dispute exceeding the maximum of open disputes per client
+    DisputeLimitReached,
+    /// Closing a dispute would drive held or total funds negative, which
+    /// only happens with corrupted state
+    InconsistentState,
+    /// Resolve or chargeback for a dispute which expired before
+    DisputeExpired,
+    /// Partial dispute for more than the amount of the transaction
+    ExcessDisputeAmount,
+    /// Transfer without a destination, or to its own source
+    InvalidDestination,
+    /// Reversal for a transaction which is not a withdrawal
+    NotAWithdrawal,
+    /// Reversal or dispute for a withdrawal which was reversed before
+    AlreadyReversed,
+    /// Timestamp earlier than the one of the last applied row, if
+    /// configured to reject those
+    OutOfOrder,
+    /// Withdrawal exceeding the daily withdrawal limit of the client
+    DailyLimitExceeded,
+    /// Withdrawal without a timestamp while a daily limit is configured
+    MissingTimestamp,
+    /// Deposit or withdrawal above the configured maximum amount
+    AmountAboveLimit,
+    /// Withdrawal or transfer of a flagged client, if configured to freeze
+    /// those
+    AccountFlagged,
+    /// Withdrawal or transfer leaving less than the minimum balance
+    BelowMinimumBalance,
+    /// Unlock for a client with open disputes, unless forced by the
+    /// configuration
+    OpenDisputes,
+    /// Release for more than the manually held funds
+    ExcessRelease,
+    /// Refund for a transaction which is not a purchase
+    NotAPurchase,
+    /// Refunds summing up to more than their purchase
+    ExcessRefund,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            RejectReason::DuplicateTxId => "duplicate transaction id",
+            RejectReason::MissingAmount => "missing amount",
+            RejectReason::UnexpectedAmount => "unexpected amount",
+            RejectReason::NonPositiveAmount => "amount not positive",
+            RejectReason::ZeroAmount => "zero amount",
+            RejectReason::Deferred => "deferred until the transaction arrives",
+            RejectReason::Overflow => "balance overflow",
+            RejectReason::ExcessPrecision => "amount has more than four decimal places",
+            RejectReason::InsufficientFunds => "insufficient funds",
+            RejectReason::AccountLocked => "account locked",
+            RejectReason::UnknownTx => "unknown transaction",
+            RejectReason::RecordEvicted => "transaction record evicted",
+            RejectReason::ClientMismatch => "transaction belongs to another client",
+            RejectReason::NotDisputed => "transaction not disputed",
+            RejectReason::AlreadyDisputed => "transaction already disputed",
+            RejectReason::AlreadyChargedBack => "transaction already charged back",
+            RejectReason::RedisputeDenied => "transaction may not be disputed again",
+            RejectReason::NotADeposit => "transaction is not a deposit",
+            RejectReason::DisputeLimitReached => "too many open disputes for client",
+            RejectReason::InconsistentState => "held funds below disputed amount",
+            RejectReason::DisputeExpired => "dispute expired",
+            RejectReason::ExcessDisputeAmount => "dispute amount exceeds transaction",
+            RejectReason::InvalidDestination => "invalid transfer destination",
+            RejectReason::NotAWithdrawal => "transaction is not a withdrawal",
+            RejectReason::AlreadyReversed => "withdrawal already reversed",
+            RejectReason::OutOfOrder => "timestamp earlier than the previous row",
+            RejectReason::DailyLimitExceeded => "daily withdrawal limit exceeded",
+            RejectReason::MissingTimestamp => "missing timestamp",
+            RejectReason::AmountAboveLimit => "amount above limit",
+            RejectReason::AccountFlagged => "account flagged",
+            RejectReason::BelowMinimumBalance => "below minimum balance",
+            RejectReason::OpenDisputes => "client has open disputes",
+            RejectReason::ExcessRelease => "release exceeds manually held funds",
+            RejectReason::NotAPurchase => "transaction is not a purchase",
+            RejectReason::ExcessRefund => "refunds exceed the purchase",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for RejectReason {}
+
+/// Effect of an applied transaction on the account of its client
+#[derive(Debug, Clone, PartialEq)]
+pub struct Applied {
+    pub client_id: ClientId,
+    /// Account before the transaction, default if it created the client
+    pub previous: Client,
+    pub current: Client,
+}
+
+/// Transactions of a client which were disputed at least once, and the
+/// ones among them which were charged back
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DisputeCounts {
+    pub disputed: u32,
+    pub charged_back: u32,
+}
+
+/// Records dropped by a settle row, and the ones kept
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Settlement {
+    /// Resolved or expired disputes
+    pub resolved: usize,
+    pub charged_back: usize,
+    /// Reversed withdrawals
+    pub reversed: usize,
+    pub retained: usize,
+}
+
+impl Settlement {
+    /// Number of records dropped
+    pub fn purged(&self) -> usize {
+        self.resolved + self.charged_back + self.reversed
+    }
+}
+
+impl Applied {
+    pub fn available_delta(&self) -> Decimal {
+        self.current.available() - self.previous.available()
+    }
+
+    pub fn held_delta(&self) -> Decimal {
+        self.current.held() - self.previous.held()
+    }
+
+    pub fn total_delta(&self) -> Decimal {
+        self.current.total() - self.previous.total()
+    }
+
+    /// Whether the transaction locked or unlocked the account
+    pub fn locked_flipped(&self) -> bool {
+        self.current.is_locked() != self.previous.is_locked()
+    }
+}
+
+/// Transaction which was not applied, along with its row in the input.
+///
+/// Rows are numbered by the source, see [`Engine::process_numbered`],
+/// otherwise they are the index of the transaction.
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    pub row: usize,
+    pub transaction: Transaction,
+    pub reason: RejectReason,
+    /// Available funds of the client when a withdrawal was rejected for
+    /// insufficient funds, the attempted amount is the one of the
+    /// transaction
+    pub available: Option<Decimal>,
+}
+
+/// Transaction engine holding all client accounts and the state needed
+/// to handle disputes.
+///
+/// Transactions are applied one at a time with [`Engine::apply`], so the
+/// engine can be driven from any event source.
+///
+/// Cloning is cheap for the stored transactions, they are only copied
+/// once either engine stores or drops a record.
+#[derive(Debug, Default, Clone)]
+pub struct Engine<B = Unbounded, O = NoObserver> {
+    clients: HashMap<ClientId, Client>,
+    /// Keyed by [`TxIdScope::key`](crate::config::TxIdScope), shared with
+    /// clones until either side stores or drops a record
+    transaction_records: Arc<HashMap<u64, TransactionRecord>>,
+    /// Keys of transactions without a stored record whose ids stay taken:
+    /// records dropped by a settle row, and rejected rows under
+    /// [`DuplicateTxIdPolicy::RejectAny`]
+    retired: HashSet<u64>,
+    /// Number of open disputes per client, clients without any are absent
+    open_disputes: HashMap<ClientId, u32>,
+    dispute_counts: HashMap<ClientId, DisputeCounts>,
+    /// Keys of the open disputes of each client which expire, may still
+    /// list disputes closed since
+    expiring: HashMap<ClientId, Vec<u64>>,
+    /// Deadlines and keys of the open disputes which are charged back
+    /// once they age out, may still list disputes closed since
+    aging: BTreeSet<(Timestamp, u64)>,
+    rejections: HashMap<RejectReason, u64>,
+    /// Duplicates identical to their stored transaction
+    replayed: u64,
+    /// Latest timestamp of an applied row
+    last_timestamp: Option<Timestamp>,
+    /// Source the rows are read from, as numbered by the caller
+    source: Option<u16>,
+    /// Latest timestamp of an applied row of the current source, which
+    /// monotonic timestamps are enforced against
+    source_timestamp: Option<Timestamp>,
+    /// Withdrawn amounts per client, currency and day, only kept with a
+    /// daily withdrawal limit
+    daily_withdrawn: HashMap<WithdrawalDay, Decimal>,
+    config: EngineConfig,
+    budget: B,
+    observer: O,
+    /// Undo entries of applied transactions, only kept once enabled with
+    /// [`Engine::with_journal`]
+    undo_journal: Option<Vec<UndoEntry>>,
+    deferred: DeferredSteps,
+}
+
+impl Engine {
+    pub fn new(config: EngineConfig) -> Self {
+        Self::with_budget(config, Unbounded)
+    }
+
+    /// Create an engine continuing from a snapshot of an earlier run
+    pub fn from_snapshot(
+        snapshot: EngineSnapshot,
+        config: EngineConfig,
+    ) -> Result<Self, SnapshotError> {
+        let mut engine = Self::new(config);
+        engine.restore(snapshot)?;
+        Ok(engine)
+    }
+
+    /// Combine two engines which processed separate shards of the input.
+    ///
+    /// Merging is only sound if the input was partitioned by client, so
+    /// every client and all of its transactions were processed by exactly
+    /// one shard. A client or transaction present in both engines with
+    /// different state is an error. The configuration of `self` is kept.
+    ///
+    /// The daily withdrawals of both engines are kept and the latest
+    /// timestamp of either is the one later rows are ordered against. The
+    /// unbounded budget of both keeps no accounting to merge.
+    pub fn merge(mut self, other: Engine) -> Result<Engine, MergeError> {
+        for (client_id, client) in &other.clients {
+            if self.clients.get(client_id).is_some_and(|c| c != client) {
+                return Err(MergeError::ClientConflict(*client_id));
+            }
+        }
+        for (&key, record) in other.transaction_records.iter() {
+            if self
+                .transaction_records
+                .get(&key)
+                .is_some_and(|r| r != record)
+            {
+                return Err(MergeError::TransactionConflict {
+                    client: record.client_id,
+                    tx: TxIdScope::tx_id(key),
+                });
+            }
+        }
+
+        self.clients.extend(other.clients);
+        Arc::make_mut(&mut self.transaction_records)
+            .extend(Arc::unwrap_or_clone(other.transaction_records));
+        self.retired.extend(other.retired);
+        self.open_disputes = count_open_disputes(&self.transaction_records);
+        self.dispute_counts.extend(other.dispute_counts);
+        self.expiring = expiring_disputes(&self.transaction_records);
+        self.aging = aging_disputes(&self.transaction_records, self.config.auto_chargeback_after);
+        let mut deferred = other.deferred;
+        for transaction in deferred.drain() {
+            let key = self
+                .config
+                .tx_id_scope
+                .key(transaction.client_id, transaction.id);
+            self.deferred.push(key, transaction);
+        }
+        for (reason, count) in other.rejections {
+            *self.rejections.entry(reason).or_default() += count;
+        }
+        self.replayed += other.replayed;
+        self.last_timestamp = self.last_timestamp.max(other.last_timestamp);
+        self.source_timestamp = self.source_timestamp.max(other.source_timestamp);
+        self.daily_withdrawn.extend(other.daily_withdrawn);
+        Ok(self)
+    }
+}
+
+/// Reason two engines could not be merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeError {
+    /// Client present in both engines with different balances
+    ClientConflict(ClientId),
+    /// Transaction present in both engines with different records or
+    /// dispute state, along with the client of its record in `other`
+    TransactionConflict { client: ClientId, tx: u32 },
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::ClientConflict(client) => {
+                write!(f, "client {client} differs between the merged engines")
+            }
+            MergeError::TransactionConflict { client, tx } => {
+                write!(
+                    f,
+                    "transaction {tx} of client {client} differs between the merged engines"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+impl<O: EngineObserver> Engine<Unbounded, O> {
+    /// Create an engine notifying `observer` about everything it does
+    pub fn with_observer(config: EngineConfig, observer: O) -> Self {
+        Self::with_parts(config, Unbounded, observer)
+    }
+}
+
+impl<B: StorageBudget> Engine<B> {
+    /// Create an engine accounting its stored state against `budget`
+    pub fn with_budget(config: EngineConfig, budget: B) -> Self {
+        Self::with_parts(config, budget, NoObserver)
+    }
+}
+
+impl<B: StorageBudget, O: EngineObserver> Engine<B, O> {
+    /// Create an engine with both a storage budget and an observer
+    pub fn with_parts(config: EngineConfig, budget: B, observer: O) -> Self {
+        Self {
+            clients: HashMap::new(),
+            transaction_records: Arc::new(HashMap::new()),
+            retired: HashSet::new(),
+            open_disputes: HashMap::new(),
+            dispute_counts: HashMap::new(),
+            expiring: HashMap::new(),
+            aging: BTreeSet::new(),
+            rejections: HashMap::new(),
+            replayed: 0,
+            last_timestamp: None,
+            source: None,
+            source_timestamp: None,
+            daily_withdrawn: HashMap::new(),
+            config,
+            budget,
+            observer,
+            undo_journal: None,
+            deferred: DeferredSteps::default(),
+        }
+    }
+
+    /// Keep an undo entry for every applied transaction, so they can be
+    /// rolled back with [`Engine::rollback`]
+    pub fn with_journal(mut self) -> Self {
+        self.undo_journal = Some(Vec::new());
+        self
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    pub fn budget(&self) -> &B {
+        &self.budget
+    }
+
+    pub fn account(&self, client_id: ClientId) -> Option<&Client> {
+        self.clients.get(&client_id)
+    }
+
+    /// Current accounts with their client ids, in no particular order
+    pub fn accounts(&self) -> impl Iterator<Item = (ClientId, &Client)> {
+        self.clients.iter().map(|(&id, client)| (id, client))
+    }
+
+    /// Ids of the transactions currently under dispute
+    pub fn disputed_transactions(&self) -> impl Iterator<Item = u32> {
+        self.transaction_records
+            .iter()
+            .filter(|(_, record)| record.is_disputed())
+            .map(|(&key, _)| key as u32)
+    }
+
+    /// Stored deposit or withdrawal of a client along with its dispute
+    /// state
+    pub fn transaction(&self, client_id: ClientId, tx_id: u32) -> Option<&TransactionRecord> {
+        self.transaction_records
+            .get(&self.config.tx_id_scope.key(client_id, tx_id))
+            .filter(|record| record.client_id == client_id)
+    }
+
+    pub fn into_accounts(self) -> HashMap<ClientId, Client> {
+        self.clients
+    }
+
+    /// Apply transactions to a copy of the engine and return the accounts
+    /// they would lead to, leaving this engine untouched.
+    ///
+    /// The observer is not told about simulated transactions. Stored
+    /// transactions are shared with the copy until a simulated transaction
+    /// stores one or changes its dispute state.
+    pub fn simulate(
+        &self,
+        transactions: impl IntoIterator<Item = Transaction>,
+    ) -> HashMap<ClientId, Client>
+    where
+        B: Clone,
+    {
+        let mut engine = Engine {
+            clients: self.clients.clone(),
+            transaction_records: Arc::clone(&self.transaction_records),
+            retired: self.retired.clone(),
+            open_disputes: self.open_disputes.clone(),
+            dispute_counts: self.dispute_counts.clone(),
+            expiring: self.expiring.clone(),
+            aging: self.aging.clone(),
+            rejections: HashMap::new(),
+            replayed: 0,
+            last_timestamp: self.last_timestamp,
+            source: self.source,
+            source_timestamp: self.source_timestamp,
+            daily_withdrawn: self.daily_withdrawn.clone(),
+            config: self.config.clone(),
+            budget: self.budget.clone(),
+            observer: NoObserver,
+            undo_journal: None,
+            deferred: self.deferred.clone(),
+        };
+        for transaction in transactions {
+            let _ = engine.apply(transaction);
+        }
+        engine.clients
+    }
+
+    /// Open accounts with existing balances before processing, replacing
+    /// any account with the same id. Locked accounts stay locked.
+    pub fn seed_accounts(&mut self, accounts: impl IntoIterator<Item = (ClientId, Client)>) {
+        self.clear_journal();
+        for (client_id, client) in accounts {
+            if self.clients.insert(client_id, client).is_none() {
+                self.budget.client_added();
+            }
+        }
+    }
+
+    /// Copy of the accounts, stored transactions and open disputes
+    pub fn snapshot(&self) -> EngineSnapshot {
+        EngineSnapshot {
+            version: SNAPSHOT_VERSION,
+            clients: self
+                .clients
+                .iter()
+                .map(|(&id, client)| (id, client.clone()))
+                .collect(),
+            transaction_records: self
+                .transaction_records
+                .iter()
+                .map(|(&id, record)| (id, record.clone()))
+                .collect(),
+            retired_transactions: self.retired.iter().copied().collect(),
+            disputed_transactions: BTreeSet::new(),
+            input_line: None,
+        }
+    }
+
+    /// Replace the state of the engine with a snapshot.
+    ///
+    /// The snapshot is validated against the configuration first, on error
+    /// the engine is left untouched.
+    pub fn restore(&mut self, snapshot: EngineSnapshot) -> Result<(), SnapshotError> {
+        if !(1..=SNAPSHOT_VERSION).contains(&snapshot.version) {
+            return Err(SnapshotError::UnsupportedVersion(snapshot.version));
+        }
+        if let Some((&client_id, _)) = snapshot.clients.iter().find(|(_, c)| !c.is_balanced()) {
+            return Err(SnapshotError::UnbalancedClient(client_id));
+        }
+        let scope = self.config.tx_id_scope;
+        if let Some((&key, _)) = snapshot
+            .transaction_records
+            .iter()
+            .find(|&(&key, record)| scope.key(record.client_id, key as u32) != key)
+        {
+            return Err(SnapshotError::TxIdScopeMismatch(key as u32));
+        }
+        let mut transaction_records: HashMap<u64, TransactionRecord> =
+            snapshot.transaction_records.into_iter().collect();
+        // Version 1 snapshots list the open disputes separately
+        for tx in &snapshot.disputed_transactions {
+            let Some(record) = transaction_records.get_mut(&u64::from(*tx)) else {
+                return Err(SnapshotError::UnknownDisputedTx(*tx));
+            };
+            record.state = DisputeState::Disputed;
+        }
+        let open_disputes = count_open_disputes(&transaction_records);
+        if let Some(max) = self.config.max_open_disputes_per_client
+            && let Some((&client_id, _)) = open_disputes.iter().find(|&(_, &open)| open > max)
+        {
+            return Err(SnapshotError::DisputeLimitExceeded(client_id));
+        }
+
+        for _ in 0..snapshot.clients.len() {
+            self.budget.client_added();
+        }
+        for _ in 0..transaction_records.len() {
+            self.budget.record_stored();
+        }
+        for _ in 0..open_disputes.values().sum::<u32>() {
+            self.budget.dispute_opened();
+        }
+        self.clients = snapshot.clients.into_iter().collect();
+        self.transaction_records = Arc::new(transaction_records);
+        self.retired = snapshot.retired_transactions.into_iter().collect();
+        self.dispute_counts = count_disputes(&self.transaction_records);
+        self.open_disputes = open_disputes;
+        self.expiring = expiring_disputes(&self.transaction_records);
+        self.aging = aging_disputes(&self.transaction_records, self.config.auto_chargeback_after);
+        self.clear_journal();
+        Ok(())
+    }
+
+    /// Reverse the last `n` applied transactions, newest first.
+    ///
+    /// Records evicted by a storage budget in the meantime are not brought
+    /// back. Nothing is rolled back if fewer than `n` entries are journaled.
+    pub fn rollback(&mut self, n: usize) -> Result<(), RollbackError> {
+        let Some(journal) = self.undo_journal.as_mut() else {
+            return Err(RollbackError::NotJournaled);
+        };
+        if n > journal.len() {
+            return Err(RollbackError::NotEnoughEntries {
+                requested: n,
+                available: journal.len(),
+            });
+        }
+        let entries = journal.split_off(journal.len() - n);
+        for entry in entries.into_iter().rev() {
+            self.undo(entry);
+        }
+        Ok(())
+    }
+
+    fn clear_journal(&mut self) {
+        if let Some(journal) = self.undo_journal.as_mut() {
+            journal.clear();
+        }
+    }
+
+    /// Capture everything applying `transaction` can change
+    fn undo_entry(&self, transaction: &Transaction) -> UndoEntry {
+        let key = self
+            .config
+            .tx_id_scope
+            .key(transaction.record_owner(), transaction.id);
+        let destination = transaction
+            .to_client
+            .filter(|_| transaction.kind == TransactionType::Transfer)
+            .map(|to_client| (to_client, self.clients.get(&to_client).cloned()));
+        let record = self.transaction_records.get(&key).cloned();
+        let dispute_owner = record
+            .as_ref()
+            .map_or(transaction.client_id, |r| r.client_id);
+        let expiring = self.expiring.get(&transaction.client_id).cloned();
+        let expiring_records = expiring
+            .iter()
+            .flatten()
+            .filter_map(|key| Some((*key, self.transaction_records.get(key)?.clone())))
+            .collect();
+        let currency = self.account_currency(transaction, key);
+        let daily_withdrawn = withdrawal_day(transaction, currency, &self.config).map(|day| {
+            let withdrawn = self.daily_withdrawn.get(&day).copied();
+            (day, withdrawn)
+        });
+        UndoEntry {
+            client_id: transaction.client_id,
+            client: self.clients.get(&transaction.client_id).cloned(),
+            key,
+            record,
+            dispute_owner,
+            open_disputes: self.open_disputes.get(&dispute_owner).copied(),
+            dispute_counts: self.dispute_counts.get(&dispute_owner).copied(),
+            expiring,
+            expiring_records,
+            destination,
+            last_timestamp: self.last_timestamp,
+            source_timestamp: self.source_timestamp,
+            daily_withdrawn,
+            aged: Vec::new(),
+        }
+    }
+
+    fn undo(&mut self, entry: UndoEntry) {
+        for aged in entry.aged.into_iter().rev() {
+            match aged.client {
+                Some(client) => self.clients.insert(aged.record.client_id, client),
+                None => self.clients.remove(&aged.record.client_id),
+            };
+            match aged.open_disputes {
+                Some(count) => self.open_disputes.insert(aged.record.client_id, count),
+                None => self.open_disputes.remove(&aged.record.client_id),
+            };
+            match aged.dispute_counts {
+                Some(counts) => self.dispute_counts.insert(aged.record.client_id, counts),
+                None => self.dispute_counts.remove(&aged.record.client_id),
+            };
+            self.aging.insert((aged.deadline, aged.key));
+            self.restore_record(aged.key, Some(aged.record));
+        }
+        match entry.client {
+            Some(client) => self.clients.insert(entry.client_id, client),
+            None => self.clients.remove(&entry.client_id),
+        };
+        if let Some((to_client, client)) = entry.destination {
+            match client {
+                Some(client) => self.clients.insert(to_client, client),
+                None => self.clients.remove(&to_client),
+            };
+        }
+        self.restore_record(entry.key, entry.record);
+        for (key, record) in entry.expiring_records {
+            self.restore_record(key, Some(record));
+        }
+        match entry.open_disputes {
+            Some(count) => self.open_disputes.insert(entry.dispute_owner, count),
+            None => self.open_disputes.remove(&entry.dispute_owner),
+        };
+        match entry.dispute_counts {
+            Some(counts) => self.dispute_counts.insert(entry.dispute_owner, counts),
+            None => self.dispute_counts.remove(&entry.dispute_owner),
+        };
+        match entry.expiring {
+            Some(keys) => self.expiring.insert(entry.client_id, keys),
+            None => self.expiring.remove(&entry.client_id),
+        };
+        self.last_timestamp = entry.last_timestamp;
+        self.source_timestamp = entry.source_timestamp;
+        if let Some((day, withdrawn)) = entry.daily_withdrawn {
+            match withdrawn {
+                Some(withdrawn) => self.daily_withdrawn.insert(day, withdrawn),
+                None => self.daily_withdrawn.remove(&day),
+            };
+        }
+    }
+
+    /// Put back a record as it was before, dropping it if there was none
+    fn restore_record(&mut self, key: u64, record: Option<TransactionRecord>) {
+        let was_disputed = self
+            .transaction_records
+            .get(&key)
+            .is_some_and(TransactionRecord::is_disputed);
+        let disputed = record.as_ref().is_some_and(TransactionRecord::is_disputed);
+        match record {
+            Some(record) => {
+                Arc::make_mut(&mut self.transaction_records).insert(key, record);
+            }
+            None => {
+                if Arc::make_mut(&mut self.transaction_records)
+                    .remove(&key)
+                    .is_some()
+                {
+                    self.budget.records_evicted(1);
+                }
+            }
+        }
+        if disputed && !was_disputed {
+            self.budget.dispute_opened();
+        } else if was_disputed && !disputed {
+            self.budget.dispute_closed();
+        }
+    }
+
+    /// Number of transactions rejected for `reason` so far
+    pub fn rejections(&self, reason: RejectReason) -> u64 {
+        self.rejections.get(&reason).copied().unwrap_or(0)
+    }
+
+    /// Withdrawal fees charged to all clients
+    pub fn fees_paid(&self) -> Decimal {
+        self.clients.values().map(Client::fees_paid).sum()
+    }
+
+    /// How many transactions of the client were disputed and charged back
+    pub fn dispute_counts(&self, client_id: ClientId) -> DisputeCounts {
+        self.dispute_counts
+            .get(&client_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Deposits and withdrawals rejected as duplicates of an identical
+    /// stored transaction, e.g. rows fed again after restoring a snapshot
+    pub fn replayed(&self) -> u64 {
+        self.replayed
+    }
+
+    /// Check the invariants of every client account, ordered by client id.
+    /// Negative available funds are only reported unless allowed by the
+    /// configuration, or beyond the credit line of the client.
+    pub fn verify(&self) -> Vec<InvariantViolation> {
+        let mut clients: Vec<_> = self.clients.iter().collect();
+        clients.sort_unstable_by_key(|(id, _)| **id);
+        let mut violations = Vec::new();
+        for (&client_id, client) in clients {
+            if !client.is_balanced() {
+                violations.push(InvariantViolation::new(
+                    client_id,
+                    Invariant::BalancedTotal,
+                    client,
+                ));
+            }
+            if client.held() < Decimal::ZERO {
+                violations.push(InvariantViolation::new(
+                    client_id,
+                    Invariant::NonNegativeHeld,
+                    client,
+                ));
+            }
+            let credit_limit = client.credit_limit().unwrap_or_default();
+            if !self.config.allow_negative_on_dispute && client.available() < -credit_limit {
+                violations.push(InvariantViolation::new(
+                    client_id,
+                    Invariant::NonNegativeAvailable,
+                    client,
+                ));
+            }
+        }
+        violations
+    }
+
+    /// Apply a single transaction, returning what it did to the client's
+    /// account or why it was rejected. A rejected transaction changes no
+    /// balance and opens no client or currency account. It may still leave
+    /// a mark: a withdrawal short of funds counts as failed, a dispute step
+    /// may be deferred, a replayed row is counted, and with
+    /// [`DuplicateTxIdPolicy::RejectAny`] its transaction id is taken.
+    ///
+    /// Deferred dispute steps are applied right after the transaction
+    /// they reference, the observer is told about their outcome.
+    pub fn apply(&mut self, current_transaction: Transaction) -> Result<Applied, RejectReason> {
+        // Settlements and accruals can not be undone, they clear the
+        // journal instead
+        let undo = (self.undo_journal.is_some()
+            && !matches!(
+                current_transaction.kind,
+                TransactionType::Settle | TransactionType::Accrue
+            ))
+        .then(|| self.undo_entry(&current_transaction));
+        let previous = self
+            .clients
+            .get(&current_transaction.client_id)
+            .cloned()
+            .unwrap_or_default();
+        let key = self
+            .config
+            .tx_id_scope
+            .key(current_transaction.record_owner(), current_transaction.id);
+        match self.apply_transaction(&current_transaction) {
+            Ok(()) => {
+                if let (Some(journal), Some(entry)) = (self.undo_journal.as_mut(), undo) {
+                    journal.push(entry);
+                }
+                if current_transaction.timestamp > self.last_timestamp {
+                    self.last_timestamp = current_transaction.timestamp;
+                }
+                if current_transaction.timestamp > self.source_timestamp {
+                    self.source_timestamp = current_transaction.timestamp;
+                }
+                self.expire_disputes(current_transaction.client_id, key);
+                self.charge_back_aged_disputes();
+                // Settle and accrue rows leave their client alone
+                let current = self
+                    .clients
+                    .get(&current_transaction.client_id)
+                    .cloned()
+                    .unwrap_or_default();
+                self.observer.on_applied(&current_transaction, &current);
+                let applied = Applied {
+                    client_id: current_transaction.client_id,
+                    previous,
+                    current,
+                };
+                if matches!(
+                    current_transaction.kind,
+                    TransactionType::Deposit
+                        | TransactionType::Withdrawal
+                        | TransactionType::Purchase
+                        | TransactionType::Transfer
+                ) && !self.deferred.is_empty()
+                {
+                    for transaction in self.deferred.take(key) {
+                        let _ = self.apply(transaction);
+                    }
+                }
+                Ok(applied)
+            }
+            Err(reason) => {
+                *self.rejections.entry(reason).or_default() += 1;
+                self.observer.on_rejected(&current_transaction, reason);
+                Err(reason)
+            }
+        }
+    }
+
+    fn apply_transaction(&mut self, current_transaction: &Transaction) -> Result<(), RejectReason> {
+        if self.budget.take_eviction() {
+            evict_terminal_records(
+                Arc::make_mut(&mut self.transaction_records),
+                &mut self.retired,
+                &self.clients,
+                &self.config,
+                &mut self.budget,
+            );
+        }
+        if self.config.enforce_monotonic_timestamps
+            && let (Some(timestamp), Some(last)) =
+                (current_transaction.timestamp, self.source_timestamp)
+            && timestamp < last
+        {
+            warn!("Timestamp {timestamp} is earlier than {last}");
+            return Err(RejectReason::OutOfOrder);
+        }
+        if current_transaction.kind == TransactionType::Settle {
+            let settlement = self.settle();
+            self.observer.on_settled(&settlement);
+            return Ok(());
+        }
+        if current_transaction.kind == TransactionType::Accrue {
+            return self.accrue(current_transaction);
+        }
+        let key = self
+            .config
+            .tx_id_scope
+            .key(current_transaction.client_id, current_transaction.id);
+        let currency = self.account_currency(current_transaction, key);
+        // Accounts opened for a rejected transaction are closed again, so
+        // it leaves no trace in the output
+        let client_id = current_transaction.client_id;
+        let new_client = !self.clients.contains_key(&client_id);
+        let new_account = currency.clone().filter(|currency| {
+            !new_client && self.clients[&client_id].currency(currency).is_none()
+        });
+        let result = self.apply_to_account(current_transaction, key, currency);
+        if let Err(reason) = result
+            && reason != RejectReason::DuplicateTxId
+            && self.config.duplicate_tx_id_policy == DuplicateTxIdPolicy::RejectAny
+            && let Some(key) = self.taken_key(current_transaction, key)
+        {
+            self.retired.insert(key);
+        }
+        if result.is_ok()
+            && let Some(client) = self.clients.get_mut(&client_id)
+        {
+            // A lock in any currency locks the client as a whole
+            match current_transaction.kind {
+                TransactionType::Chargeback => client.lock_if_any_account_locked(),
+                TransactionType::Lock => client.lock(),
+                _ => {}
+            }
+            match current_transaction.kind {
+                TransactionType::Resolve | TransactionType::Chargeback
+                    if self.config.auto_unlock =>
+                {
+                    unlock_if_settled(&self.open_disputes, client_id, client);
+                }
+                TransactionType::Unlock => client.unlock_all(),
+                _ => {}
+            }
+        }
+        // Rejected rows may still leave a mark, like a failed withdrawal
+        let untouched = |client: &Client| *client == Client::default();
+        if new_client {
+            if result.is_err() && self.clients.get(&client_id).is_some_and(untouched) {
+                self.clients.remove(&client_id);
+            } else {
+                self.budget.client_added();
+            }
+        } else if let (Err(_), Some(currency)) = (&result, new_account)
+            && let Some(client) = self.clients.get_mut(&client_id)
+            && client.currency(&currency).is_some_and(untouched)
+        {
+            client.close_account(&currency);
+        }
+        result
+    }
+
+    /// Key under which a transaction takes its id, `None` for rows which
+    /// reference the id of another transaction
+    fn taken_key(&self, transaction: &Transaction, key: u64) -> Option<u64> {
+        match transaction.kind {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Purchase
+            | TransactionType::Adjustment => Some(key),
+            TransactionType::Transfer => transaction
+                .to_client
+                .map(|to_client| self.config.tx_id_scope.key(to_client, transaction.id)),
+            _ => None,
+        }
+    }
+
+    /// Apply a transaction to the account of its client in `currency`,
+    /// opening the client and the account as needed
+    fn apply_to_account(
+        &mut self,
+        current_transaction: &Transaction,
+        key: u64,
+        currency: Option<String>,
+    ) -> Result<(), RejectReason> {
+        let client = self
+            .clients
+            .entry(current_transaction.client_id)
+            .or_default();
+        // Flags are kept for the client, across its currencies, and so is
+        // the lock of a chargeback in any of them
+        let flagged = client.is_flagged();
+        let owner_locked = client.is_locked();
+        // Dropped before the account is opened, so it leaves no trace
+        if current_transaction.kind == TransactionType::Deposit
+            && self.config.locked_deposit_policy == LockedDepositPolicy::Drop
+            && (owner_locked
+                || currency
+                    .as_deref()
+                    .and_then(|currency| client.currency(currency))
+                    .is_some_and(Client::is_locked))
+        {
+            debug!(
+                "Client {} is locked, deposit dropped",
+                current_transaction.client_id
+            );
+            return Ok(());
+        }
+        let client = client.account_mut(currency.as_deref());
+
+        let is_dispute_step = matches!(
+            current_transaction.kind,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        // Dispute steps and reversals refer to the stored amount, lock and
+        // unlock rows have none, an amount on the row hints at corrupted
+        // input
+        let partial_dispute = current_transaction.kind == TransactionType::Dispute
+            && self.config.allow_partial_disputes;
+        let amountless = is_dispute_step
+            || matches!(
+                current_transaction.kind,
+                TransactionType::Reversal | TransactionType::Lock | TransactionType::Unlock
+            );
+        if amountless
+            && !partial_dispute
+            && self.config.strict_fields
+            && current_transaction.amount.is_some()
+        {
+            error!(
+                "Unexpected amount for {} transaction",
+                current_transaction.kind
+            );
+            return Err(RejectReason::UnexpectedAmount);
+        }
+        if current_transaction.fee.is_some()
+            && !matches!(
+                current_transaction.kind,
+                TransactionType::Withdrawal | TransactionType::Purchase
+            )
+            && self.config.strict_fields
+        {
+            error!(
+                "Unexpected fee for {} transaction",
+                current_transaction.kind
+            );
+            return Err(RejectReason::UnexpectedAmount);
+        }
+        // Locked clients can not deposit or withdraw, earlier
+        // transactions may still be disputed unless configured otherwise
+        let held_deposit = current_transaction.kind == TransactionType::Deposit
+            && self.config.locked_deposit_policy == LockedDepositPolicy::Hold;
+        let correction = current_transaction.kind == TransactionType::Adjustment
+            && self.config.adjust_locked_accounts;
+        let allowed_while_locked = (self.config.dispute_locked_accounts && is_dispute_step)
+            || held_deposit
+            || correction
+            || current_transaction.kind == TransactionType::Unlock;
+        if (owner_locked || client.is_locked()) && !allowed_while_locked {
+            debug!("Client {} is locked", current_transaction.client_id);
+            return Err(RejectReason::AccountLocked);
+        }
+        if flagged
+            && self.config.freeze_flagged
+            && matches!(
+                current_transaction.kind,
+                TransactionType::Withdrawal | TransactionType::Purchase | TransactionType::Transfer
+            )
+        {
+            debug!("Client {} is flagged", current_transaction.client_id);
+            return Err(RejectReason::AccountFlagged);
+        }
+        match current_transaction.kind {
+            TransactionType::Deposit => {
+                if let Some(record) = self.transaction_records.get(&key) {
+                    // This transaction ID has been used before
+                    // There is some error
+                    warn!("Duplicate transaction id");
+                    if record.replayed_by(current_transaction) {
+                        self.replayed += 1;
+                    }
+                    return Err(RejectReason::DuplicateTxId);
+                }
+                if self.retired.contains(&key) {
+                    warn!("Duplicate transaction id of a retired transaction");
+                    return Err(RejectReason::DuplicateTxId);
+                }
+
+                let amount = checked_amount(current_transaction, &self.config)?;
+
+                if owner_locked || client.is_locked() {
+                    info!(
+                        "Holding deposit for locked client {}",
+                        current_transaction.client_id
+                    );
+                    client.deposit_locked(amount)?;
+                } else {
+                    client.deposit(amount)?;
+                }
+                if self.budget.admit_record(&current_transaction.kind) {
+                    Arc::make_mut(&mut self.transaction_records).insert(
+                        key,
+                        TransactionRecord::new(
+                            current_transaction.client_id,
+                            amount,
+                            current_transaction.kind,
+                        )
+                        .in_currency(currency)
+                        .at(current_transaction.timestamp),
+                    );
+                    self.budget.record_stored();
+                }
+            }
+            // Purchases are only told apart by the kind of their record,
+            // which refunds reference
+            TransactionType::Withdrawal | TransactionType::Purchase => {
+                if let Some(record) = self.transaction_records.get(&key) {
+                    // This transaction ID has been used before
+                    // There is some error
+                    warn!("Duplicate transaction id");
+                    if record.replayed_by(current_transaction) {
+                        self.replayed += 1;
+                    }
+                    return Err(RejectReason::DuplicateTxId);
+                }
+                if self.retired.contains(&key) {
+                    warn!("Duplicate transaction id of a retired transaction");
+                    return Err(RejectReason::DuplicateTxId);
+                }
+
+                let amount = checked_amount(current_transaction, &self.config)?;
+                let fee = checked_fee(current_transaction, &self.config)?;
+                let charged = amount.checked_add(fee).ok_or(RejectReason::Overflow)?;
+                let limited = match self.config.daily_withdrawal_limit {
+                    Some(limit) => {
+                        let Some(day) =
+                            withdrawal_day(current_transaction, currency.clone(), &self.config)
+                        else {
+                            error!("Withdrawal without a timestamp can not be limited");
+                            return Err(RejectReason::MissingTimestamp);
+                        };
+                        let withdrawn = self
+                            .daily_withdrawn
+                            .get(&day)
+                            .copied()
+                            .unwrap_or_default()
+                            .checked_add(amount)
+                            .ok_or(RejectReason::Overflow)?;
+                        if withdrawn > limit {
+                            info!("Unable to withdraw {amount}, daily limit of {limit} reached");
+                            return Err(RejectReason::DailyLimitExceeded);
+                        }
+                        Some((day, withdrawn))
+                    }
+                    None => None,
+                };
+                // Sufficient funds available for the amount and the fee,
+                // a credit line may take the available funds below zero
+                if client.spendable() < charged {
+                    info!(
+                        "Unable to withdraw {amount} plus a fee of {fee}. Insufficient funds for transaction, {} available",
+                        client.available()
+                    );
+                    client.record_failed_withdrawal();
+                    return Err(RejectReason::InsufficientFunds);
+                }
+                keep_minimum_balance(client, charged, &self.config)?;
+                // Only the amount is stored, a dispute never covers the fee
+                client.withdraw_with_fee(amount, fee)?;
+                if let Some((day, withdrawn)) = limited {
+                    self.daily_withdrawn.insert(day, withdrawn);
+                }
+
+                if self.budget.admit_record(&current_transaction.kind) {
+                    Arc::make_mut(&mut self.transaction_records).insert(
+                        key,
+                        TransactionRecord::new(
+                            current_transaction.client_id,
+                            amount,
+                            current_transaction.kind,
+                        )
+                        .in_currency(currency)
+                        .at(current_transaction.timestamp),
+                    );
+                    self.budget.record_stored();
+                }
+            }
+            TransactionType::Transfer => {
+                let Some(to_client) = current_transaction
+                    .to_client
+                    .filter(|&to_client| to_client != current_transaction.client_id)
+                else {
+                    error!("Transfer without a destination other than its source");
+                    return Err(RejectReason::InvalidDestination);
+                };
+                // Transfers are stored for the destination, which may dispute
+                // them like a deposit
+                let key = self
+                    .config
+                    .tx_id_scope
+                    .key(to_client, current_transaction.id);
+                if self.transaction_records.contains_key(&key) || self.retired.contains(&key) {
+                    warn!("Duplicate transaction id");
+                    return Err(RejectReason::DuplicateTxId);
+                }
+
+                let amount = checked_amount(current_transaction, &self.config)?;
+                // Like a withdrawal, a credit line may take the available
+                // funds of the source below zero
+                if client.spendable() < amount {
+                    info!(
+                        "Unable to transfer {amount}. Insufficient funds for transaction, {} available",
+                        client.available()
+                    );
+                    return Err(RejectReason::InsufficientFunds);
+                }
+                keep_minimum_balance(client, amount, &self.config)?;
+                let mut source = client.clone();
+                source.withdraw(amount)?;
+                // The destination is credited in the same currency
+                let mut destination_client =
+                    self.clients.get(&to_client).cloned().unwrap_or_default();
+                // A chargeback in any currency locks the destination
+                let destination_locked = destination_client.is_locked();
+                let destination = destination_client.account_mut(currency.as_deref());
+                if destination_locked || destination.is_locked() {
+                    debug!("Client {to_client} is locked");
+                    return Err(RejectReason::AccountLocked);
+                }
+                destination.deposit(amount)?;
+
+                if !self.clients.contains_key(&to_client) {
+                    self.budget.client_added();
+                }
+                *self
+                    .clients
+                    .entry(current_transaction.client_id)
+                    .or_default()
+                    .account_mut(currency.as_deref()) = source;
+                self.clients.insert(to_client, destination_client);
+                if self.budget.admit_record(&current_transaction.kind) {
+                    Arc::make_mut(&mut self.transaction_records).insert(
+                        key,
+                        TransactionRecord::new(to_client, amount, current_transaction.kind)
+                            .in_currency(currency)
+                            .at(current_transaction.timestamp),
+                    );
+                    self.budget.record_stored();
+                }
+            }
+            TransactionType::Dispute => {
+                // Check if transaction to be disputed exists
+                let Some(transaction_record) = self.transaction_records.get(&key) else {
+                    if self.budget.record_missing(&TransactionType::Deposit) {
+                        error!("Transaction record may have been evicted by the memory budget");
+                        return Err(RejectReason::RecordEvicted);
+                    }
+                    if self.defer(key, current_transaction) {
+                        return Err(RejectReason::Deferred);
+                    }
+                    error!("No such transaction exists");
+                    return Err(RejectReason::UnknownTx);
+                };
+
+                // Check for malicious client before revealing anything
+                // about the dispute state of another client's transaction
+                if transaction_record.client_id != current_transaction.client_id {
+                    error!("Unable to open dispute. Transaction id doesn't match with client.");
+                    return Err(RejectReason::ClientMismatch);
+                }
+
+                if transaction_record.reversed {
+                    info!("Withdrawal was reversed");
+                    return Err(RejectReason::AlreadyReversed);
+                }
+                match transaction_record.state {
+                    DisputeState::Undisputed => {}
+                    DisputeState::Resolved | DisputeState::Expired => {
+                        if self.config.redispute_policy != RedisputePolicy::AllowAlways {
+                            info!("Redispute policy denies reopening the dispute");
+                            return Err(RejectReason::RedisputeDenied);
+                        }
+                    }
+                    DisputeState::Disputed => {
+                        info!("Dispute already open for transaction");
+                        return Err(RejectReason::AlreadyDisputed);
+                    }
+                    DisputeState::ChargedBack => {
+                        info!("Transaction was already charged back");
+                        return Err(RejectReason::AlreadyChargedBack);
+                    }
+                }
+
+                // Make sure the client stays within its open disputes
+                let open_disputes = self
+                    .open_disputes
+                    .get(&current_transaction.client_id)
+                    .copied()
+                    .unwrap_or(0);
+                if self
+                    .config
+                    .max_open_disputes_per_client
+                    .is_some_and(|max| open_disputes >= max)
+                {
+                    warn!(
+                        "Client {} reached the maximum of open disputes",
+                        current_transaction.client_id
+                    );
+                    return Err(RejectReason::DisputeLimitReached);
+                }
+
+                let amount = match current_transaction.amount.filter(|_| partial_dispute) {
+                    Some(amount) if amount <= Decimal::ZERO => {
+                        return Err(RejectReason::NonPositiveAmount);
+                    }
+                    Some(amount) if amount > transaction_record.amount => {
+                        info!("Dispute amount {amount} exceeds the transaction");
+                        return Err(RejectReason::ExcessDisputeAmount);
+                    }
+                    Some(amount) => amount,
+                    None => transaction_record.amount,
+                };
+                match transaction_record.transaction_type {
+                    TransactionType::Deposit | TransactionType::Transfer => {
+                        // Make sure client has enough funds
+                        if !self.config.allow_negative_on_dispute && client.available() < amount {
+                            info!("Insufficient funds to open a dispute");
+                            return Err(RejectReason::InsufficientFunds);
+                        }
+
+                        // Update the funds
+                        client.hold(amount)?;
+                    }
+                    TransactionType::Withdrawal if self.config.dispute_withdrawals => {
+                        // Hold the withdrawn amount until the dispute is closed
+                        client.hold_withdrawn(amount)?;
+                    }
+                    _ => {
+                        error!("Unable to open dispute for withdrawal transactions");
+                        return Err(RejectReason::NotADeposit);
+                    }
+                }
+
+                self.set_dispute_state(key, DisputeState::Disputed);
+                if let Some(record) = Arc::make_mut(&mut self.transaction_records).get_mut(&key) {
+                    record.partial_amount = (amount != record.amount).then_some(amount);
+                    record.disputed_at = current_transaction.timestamp;
+                    if let Some(deadline) =
+                        aging_deadline(record, self.config.auto_chargeback_after)
+                    {
+                        self.aging.insert((deadline, key));
+                    }
+                }
+                *self
+                    .open_disputes
+                    .entry(current_transaction.client_id)
+                    .or_default() += 1;
+                self.budget.dispute_opened();
+                self.observer
+                    .on_dispute_opened(current_transaction.id, current_transaction.client_id);
+            }
+            TransactionType::Resolve => {
+                // Ignore if transaction not disputed
+                let Some(transaction_record) = self.transaction_records.get(&key) else {
+                    if self.defer(key, current_transaction) {
+                        return Err(RejectReason::Deferred);
+                    }
+                    info!("Transaction not disputed");
+                    return Err(RejectReason::NotDisputed);
+                };
+
+                if transaction_record.client_id != current_transaction.client_id {
+                    // Malicious actor
+                    error!("Unable to resolve. Transaction id doesn't match with client");
+                    return Err(RejectReason::ClientMismatch);
+                }
+                if transaction_record.state == DisputeState::Expired {
+                    info!("Dispute expired");
+                    return Err(RejectReason::DisputeExpired);
+                }
+                if !transaction_record.is_disputed() {
+                    info!("Transaction not disputed");
+                    return Err(RejectReason::NotDisputed);
+                }
+                let is_withdrawal =
+                    transaction_record.transaction_type == TransactionType::Withdrawal;
+                if !covers_dispute(client, transaction_record.disputed_amount(), is_withdrawal) {
+                    error!(
+                        "Unable to resolve. Client {} holds less than the disputed amount",
+                        current_transaction.client_id
+                    );
+                    return Err(RejectReason::InconsistentState);
+                }
+                // Update the funds
+                if is_withdrawal {
+                    // The withdrawal stands, drop the held amount again
+                    client.release_withdrawn(transaction_record.disputed_amount())?;
+                } else {
+                    client.release(transaction_record.disputed_amount())?;
+                }
+
+                close_dispute(&mut self.open_disputes, transaction_record.client_id);
+                self.set_dispute_state(key, DisputeState::Resolved);
+                self.budget.dispute_closed();
+                self.observer
+                    .on_dispute_resolved(current_transaction.id, current_transaction.client_id);
+            }
+            TransactionType::Chargeback => {
+                // Ignore if transaction not disputed
+                let Some(transaction_record) = self.transaction_records.get(&key) else {
+                    if self.defer(key, current_transaction) {
+                        return Err(RejectReason::Deferred);
+                    }
+                    info!("Transaction not disputed");
+                    return Err(RejectReason::NotDisputed);
+                };
+
+                if transaction_record.client_id != current_transaction.client_id {
+                    // Malicious actor
+                    error!("Unable to charge back. Transaction id doesn't match with client");
+                    return Err(RejectReason::ClientMismatch);
+                }
+                if transaction_record.state == DisputeState::Expired {
+                    info!("Dispute expired");
+                    return Err(RejectReason::DisputeExpired);
+                }
+                if !transaction_record.is_disputed() {
+                    info!("Transaction not disputed");
+                    return Err(RejectReason::NotDisputed);
+                }
+                let is_withdrawal =
+                    transaction_record.transaction_type == TransactionType::Withdrawal;
+                if !covers_dispute(client, transaction_record.disputed_amount(), !is_withdrawal) {
+                    error!(
+                        "Unable to charge back. Client {} holds less than the disputed amount",
+                        current_transaction.client_id
+                    );
+                    return Err(RejectReason::InconsistentState);
+                }
+
+                if is_withdrawal {
+                    // The withdrawal is reversed, the held amount goes back
+                    // to the client
+                    client.charge_back_withdrawn(
+                        transaction_record.disputed_amount(),
+                        self.config.lock_on_withdrawal_chargeback,
+                    )?;
+                } else {
+                    // Remove the held funds and lock the client
+                    client.charge_back(transaction_record.disputed_amount())?;
+                }
+                if client.is_locked() {
+                    info!("Client {} locked", current_transaction.client_id);
+                }
+
+                close_dispute(&mut self.open_disputes, transaction_record.client_id);
+                self.set_dispute_state(key, DisputeState::ChargedBack);
+                self.budget.dispute_closed();
+                self.observer
+                    .on_chargeback(current_transaction.id, current_transaction.client_id);
+                self.flag_if_suspicious(current_transaction.client_id);
+            }
+            TransactionType::Hold => {
+                let amount = checked_amount(current_transaction, &self.config)?;
+                if client.available() < amount {
+                    info!(
+                        "Unable to hold {amount}. Insufficient funds for transaction, {} available",
+                        client.available()
+                    );
+                    return Err(RejectReason::InsufficientFunds);
+                }
+                client.hold_manually(amount)?;
+            }
+            TransactionType::Release => {
+                let amount = checked_amount(current_transaction, &self.config)?;
+                client.release_manually(amount).inspect_err(|_| {
+                    info!(
+                        "Unable to release {amount}, {} manually held",
+                        client.manually_held()
+                    );
+                })?;
+            }
+            TransactionType::Lock => {
+                info!("Client {} locked", current_transaction.client_id);
+                client.lock();
+                self.observer.on_locked(current_transaction.client_id);
+            }
+            TransactionType::Unlock => {
+                if !self.config.force_unlock
+                    && self
+                        .open_disputes
+                        .contains_key(&current_transaction.client_id)
+                {
+                    warn!(
+                        "Client {} has open disputes, not unlocking",
+                        current_transaction.client_id
+                    );
+                    return Err(RejectReason::OpenDisputes);
+                }
+                info!("Client {} unlocked", current_transaction.client_id);
+                client.unlock();
+                self.observer.on_unlocked(current_transaction.client_id);
+            }
+            TransactionType::Adjustment => {
+                if let Some(record) = self.transaction_records.get(&key) {
+                    warn!("Duplicate transaction id");
+                    if record.replayed_by(current_transaction) {
+                        self.replayed += 1;
+                    }
+                    return Err(RejectReason::DuplicateTxId);
+                }
+                if self.retired.contains(&key) {
+                    warn!("Duplicate transaction id of a retired transaction");
+                    return Err(RejectReason::DuplicateTxId);
+                }
+                let amount = checked_amount(current_transaction, &self.config)?;
+                info!(
+                    "Adjusting client {} by {amount}",
+                    current_transaction.client_id
+                );
+                client.correct(amount)?;
+                // Stored for the audit trail only, adjustments can not be
+                // disputed
+                if self.budget.admit_record(&current_transaction.kind) {
+                    Arc::make_mut(&mut self.transaction_records).insert(
+                        key,
+                        TransactionRecord::new(
+                            current_transaction.client_id,
+                            amount,
+                            current_transaction.kind,
+                        )
+                        .in_currency(currency)
+                        .at(current_transaction.timestamp),
+                    );
+                    self.budget.record_stored();
+                }
+            }
+            TransactionType::Reversal => {
+                let Some(transaction_record) = self.transaction_records.get(&key) else {
+                    if self.budget.record_missing(&TransactionType::Withdrawal) {
+                        error!("Transaction record may have been evicted by the memory budget");
+                        return Err(RejectReason::RecordEvicted);
+                    }
+                    error!("No such transaction exists");
+                    return Err(RejectReason::UnknownTx);
+                };
+                if transaction_record.client_id != current_transaction.client_id {
+                    error!("Unable to reverse. Transaction id doesn't match with client");
+                    return Err(RejectReason::ClientMismatch);
+                }
+                if transaction_record.transaction_type != TransactionType::Withdrawal {
+                    error!(
+                        "Unable to reverse a {}",
+                        transaction_record.transaction_type
+                    );
+                    return Err(RejectReason::NotAWithdrawal);
+                }
+                if transaction_record.reversed {
+                    info!("Withdrawal was already reversed");
+                    return Err(RejectReason::AlreadyReversed);
+                }
+                // A chargeback already gave the withdrawn amount back
+                match transaction_record.state {
+                    DisputeState::Disputed => return Err(RejectReason::AlreadyDisputed),
+                    DisputeState::ChargedBack => return Err(RejectReason::AlreadyChargedBack),
+                    _ => {}
+                }
+
+                client.deposit(transaction_record.amount)?;
+                if let Some(record) = Arc::make_mut(&mut self.transaction_records).get_mut(&key) {
+                    record.reversed = true;
+                }
+            }
+            // Applied before the client is looked up
+            TransactionType::Settle | TransactionType::Accrue => {}
+            TransactionType::Refund => {
+                let Some(transaction_record) = self.transaction_records.get(&key) else {
+                    if self.budget.record_missing(&TransactionType::Purchase) {
+                        error!("Transaction record may have been evicted by the memory budget");
+                        return Err(RejectReason::RecordEvicted);
+                    }
+                    error!("No such transaction exists");
+                    return Err(RejectReason::UnknownTx);
+                };
+                if transaction_record.client_id != current_transaction.client_id {
+                    error!("Unable to refund. Transaction id doesn't match with client");
+                    return Err(RejectReason::ClientMismatch);
+                }
+                if transaction_record.transaction_type != TransactionType::Purchase {
+                    error!("Unable to refund a {}", transaction_record.transaction_type);
+                    return Err(RejectReason::NotAPurchase);
+                }
+                let amount = checked_amount(current_transaction, &self.config)?;
+                let refunded = transaction_record
+                    .refunded
+                    .checked_add(amount)
+                    .ok_or(RejectReason::Overflow)?;
+                if refunded > transaction_record.amount {
+                    info!(
+                        "Unable to refund {amount}, {} of {} refunded already",
+                        transaction_record.refunded, transaction_record.amount
+                    );
+                    return Err(RejectReason::ExcessRefund);
+                }
+
+                client.deposit(amount)?;
+                if let Some(record) = Arc::make_mut(&mut self.transaction_records).get_mut(&key) {
+                    record.refunded = refunded;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the records of closed disputes and reversed withdrawals, which
+    /// no transaction can reference anymore. Undisputed transactions and
+    /// open disputes are kept, as are resolved and expired disputes the
+    /// redispute policy allows to reopen. The keys of dropped records are
+    /// kept, so their transaction ids are still rejected as duplicates.
+    fn settle(&mut self) -> Settlement {
+        let mut settlement = Settlement::default();
+        let reopenable = self.config.redispute_policy == RedisputePolicy::AllowAlways;
+        let records = Arc::make_mut(&mut self.transaction_records);
+        let retired = &mut self.retired;
+        records.retain(|&key, record| {
+            if record.reversed {
+                settlement.reversed += 1;
+            } else {
+                match record.state {
+                    DisputeState::Resolved | DisputeState::Expired if !reopenable => {
+                        settlement.resolved += 1
+                    }
+                    DisputeState::ChargedBack => settlement.charged_back += 1,
+                    _ => return true,
+                }
+            }
+            retired.insert(key);
+            false
+        });
+        settlement.retained = records.len();
+        info!(
+            "Settled, dropped {} transaction records and kept {}",
+            settlement.purged(),
+            settlement.retained
+        );
+        self.budget.records_evicted(settlement.purged());
+        self.clear_journal();
+        settlement
+    }
+
+    /// Pay interest at the rate of an accrue row on the positive available
+    /// funds of every account of unlocked clients, in every currency. The
+    /// balance after accrual is rounded to four decimal places, ties to
+    /// even. The observer sees an adjustment for every account earning
+    /// interest.
+    fn accrue(&mut self, transaction: &Transaction) -> Result<(), RejectReason> {
+        let Some(rate) = transaction.amount else {
+            error!("Accrue without a rate");
+            return Err(RejectReason::MissingAmount);
+        };
+        if rate <= Decimal::ZERO {
+            error!("Rate {rate} to accrue is not positive");
+            return Err(RejectReason::NonPositiveAmount);
+        }
+        let factor = Decimal::ONE
+            .checked_add(rate)
+            .ok_or(RejectReason::Overflow)?;
+        // Computed up front, so an overflow leaves every account untouched
+        let mut accruals = Vec::new();
+        for (&client_id, client) in &self.clients {
+            // A chargeback in any currency locks every account of the client
+            if client.is_locked() {
+                continue;
+            }
+            let accounts = std::iter::once((None, client))
+                .chain(client.currencies().map(|(c, a)| (Some(c), a)));
+            for (currency, account) in accounts {
+                if account.is_locked() || account.available() <= Decimal::ZERO {
+                    continue;
+                }
+                let accrued = account
+                    .available()
+                    .checked_mul(factor)
+                    .ok_or(RejectReason::Overflow)?
+                    .round_dp_with_strategy(4, RoundingStrategy::MidpointNearestEven);
+                let interest = accrued - account.available();
+                if !interest.is_zero() {
+                    accruals.push((client_id, currency.map(str::to_string), interest));
+                }
+            }
+        }
+        accruals.sort_unstable();
+        info!(
+            "Accruing interest at {rate} for {} accounts",
+            accruals.len()
+        );
+        for (client_id, currency, interest) in accruals {
+            let Some(client) = self.clients.get_mut(&client_id) else {
+                continue;
+            };
+            let account = client.account_mut(currency.as_deref());
+            account.deposit(interest)?;
+            let adjustment = Transaction {
+                kind: TransactionType::Adjustment,
+                client_id,
+                id: transaction.id,
+                amount: Some(interest),
+                to_client: None,
+                fee: None,
+                currency,
+                timestamp: transaction.timestamp,
+            };
+            self.observer.on_applied(&adjustment, account);
+        }
+        self.clear_journal();
+        Ok(())
+    }
+
+    /// Currency of the account a transaction applies to, `None` for the
+    /// base currency. Dispute steps, reversals and refunds apply to the
+    /// currency of the transaction they reference.
+    fn account_currency(&self, transaction: &Transaction, key: u64) -> Option<String> {
+        if matches!(
+            transaction.kind,
+            TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::Reversal
+                | TransactionType::Refund
+        ) && let Some(record) = self.transaction_records.get(&key)
+        {
+            return record.currency.clone();
+        }
+        let currency = transaction.currency.as_deref()?.trim().to_ascii_uppercase();
+        (!currency.is_empty() && currency != self.config.base_currency).then_some(currency)
+    }
+
+    /// Park a dispute step until the transaction it references arrives,
+    /// as long as configured and there is room
+    fn defer(&mut self, key: u64, transaction: &Transaction) -> bool {
+        if !self.config.defer_unmatched_disputes {
+            return false;
+        }
+        if self.deferred.len() >= self.config.max_deferred_disputes {
+            warn!("Too many deferred dispute steps, rejecting");
+            return false;
+        }
+        info!(
+            "Deferring {} until its transaction arrives",
+            transaction.kind
+        );
+        self.deferred.push(key, transaction.clone());
+        true
+    }
+
+    /// Reject the dispute steps still waiting for their transaction, e.g.
+    /// at the end of the input. They count as unknown transactions, and
+    /// are returned in the order they were deferred.
+    pub fn reject_deferred(&mut self) -> Vec<Transaction> {
+        let transactions = self.deferred.drain();
+        for transaction in &transactions {
+            *self.rejections.entry(RejectReason::UnknownTx).or_default() += 1;
+            self.observer
+                .on_rejected(transaction, RejectReason::UnknownTx);
+        }
+        transactions
+    }
+
+    fn set_dispute_state(&mut self, key: u64, state: DisputeState) {
+        let ttl = self.config.dispute_ttl_events;
+        if let Some(record) = Arc::make_mut(&mut self.transaction_records).get_mut(&key) {
+            let counts = self.dispute_counts.entry(record.client_id).or_default();
+            match state {
+                DisputeState::Disputed if record.state == DisputeState::Undisputed => {
+                    counts.disputed += 1;
+                }
+                DisputeState::ChargedBack => counts.charged_back += 1,
+                _ => {}
+            }
+            record.state = state;
+            record.expires_in = ttl.filter(|_| state == DisputeState::Disputed);
+            if record.expires_in.is_some() {
+                let keys = self.expiring.entry(record.client_id).or_default();
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+    }
+
+    /// Flag a client with more chargebacks than configured
+    fn flag_if_suspicious(&mut self, client_id: ClientId) {
+        let Some(max) = self.config.flag_after_chargebacks else {
+            return;
+        };
+        if self.dispute_counts(client_id).charged_back <= max {
+            return;
+        }
+        if let Some(client) = self.clients.get_mut(&client_id)
+            && !client.is_flagged()
+        {
+            warn!("Client {client_id} flagged");
+            client.flag();
+            self.observer.on_flagged(client_id);
+        }
+    }
+
+    /// Count down the expiring disputes of a client after one of its
+    /// transactions was applied, leaving out the dispute of the applied
+    /// transaction itself, and expire the ones running out
+    fn expire_disputes(&mut self, client_id: ClientId, applied_key: u64) {
+        let Some(keys) = self.expiring.get_mut(&client_id) else {
+            return;
+        };
+        let records = Arc::make_mut(&mut self.transaction_records);
+        let mut expired = Vec::new();
+        keys.retain(|&key| {
+            let Some(record) = records.get_mut(&key).filter(|r| r.is_disputed()) else {
+                return false;
+            };
+            let Some(remaining) = record.expires_in.as_mut() else {
+                return false;
+            };
+            if key != applied_key {
+                *remaining = remaining.saturating_sub(1);
+                if *remaining == 0 {
+                    expired.push(key);
+                    return false;
+                }
+            }
+            true
+        });
+        if keys.is_empty() {
+            self.expiring.remove(&client_id);
+        }
+        for key in expired {
+            self.expire_dispute(client_id, key);
+        }
+    }
+
+    /// Release the funds of a dispute which stayed open too long
+    fn expire_dispute(&mut self, client_id: ClientId, key: u64) {
+        let record = &self.transaction_records[&key];
+        let Some(owner) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        let client = owner.account_mut(record.currency.as_deref());
+        let is_withdrawal = record.transaction_type == TransactionType::Withdrawal;
+        let released = if !covers_dispute(client, record.disputed_amount(), is_withdrawal) {
+            Err(RejectReason::InconsistentState)
+        } else if is_withdrawal {
+            client.release_withdrawn(record.disputed_amount())
+        } else {
+            client.release(record.disputed_amount())
+        };
+        if let Err(reason) = released {
+            error!("Unable to expire dispute of transaction {key}: {reason}");
+            return;
+        }
+        info!("Dispute of transaction {key} expired");
+
+        close_dispute(&mut self.open_disputes, client_id);
+        if self.config.auto_unlock {
+            unlock_if_settled(&self.open_disputes, client_id, owner);
+        }
+        self.set_dispute_state(key, DisputeState::Expired);
+        self.budget.dispute_closed();
+        self.observer.on_dispute_expired(key as u32, client_id);
+    }
+
+    /// Charge back the open disputes which aged out by the latest
+    /// timestamp of the stream, oldest first
+    fn charge_back_aged_disputes(&mut self) {
+        let (Some(now), Some(after)) = (self.last_timestamp, self.config.auto_chargeback_after)
+        else {
+            return;
+        };
+        while let Some(&(deadline, key)) = self.aging.first()
+            && deadline < now
+        {
+            self.aging.pop_first();
+            let current = self
+                .transaction_records
+                .get(&key)
+                .and_then(|record| aging_deadline(record, Some(after)));
+            if current == Some(deadline) {
+                self.charge_back_aged(deadline, key);
+            }
+        }
+    }
+
+    /// Charge back a dispute which stayed open past the configured age,
+    /// as a chargeback row would
+    fn charge_back_aged(&mut self, deadline: Timestamp, key: u64) {
+        let record = self.transaction_records[&key].clone();
+        let client_id = record.client_id;
+        let aged = self.undo_journal.is_some().then(|| AgedDispute {
+            deadline,
+            key,
+            client: self.clients.get(&client_id).cloned(),
+            open_disputes: self.open_disputes.get(&client_id).copied(),
+            dispute_counts: self.dispute_counts.get(&client_id).copied(),
+            record: record.clone(),
+        });
+        let Some(owner) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        let client = owner.account_mut(record.currency.as_deref());
+        let is_withdrawal = record.transaction_type == TransactionType::Withdrawal;
+        let charged_back = if !covers_dispute(client, record.disputed_amount(), !is_withdrawal) {
+            Err(RejectReason::InconsistentState)
+        } else if is_withdrawal {
+            client.charge_back_withdrawn(
+                record.disputed_amount(),
+                self.config.lock_on_withdrawal_chargeback,
+            )
+        } else {
+            client.charge_back(record.disputed_amount())
+        };
+        if let Err(reason) = charged_back {
+            error!("Unable to charge back aged dispute of transaction {key}: {reason}");
+            return;
+        }
+        info!("Dispute of transaction {key} aged out, charged back");
+        if client.is_locked() {
+            info!("Client {client_id} locked");
+        }
+
+        close_dispute(&mut self.open_disputes, client_id);
+        owner.lock_if_any_account_locked();
+        if self.config.auto_unlock {
+            unlock_if_settled(&self.open_disputes, client_id, owner);
+        }
+        if let (Some(aged), Some(entry)) = (
+            aged,
+            self.undo_journal
+                .as_mut()
+                .and_then(|journal| journal.last_mut()),
+        ) {
+            entry.aged.push(aged);
+        }
+        self.set_dispute_state(key, DisputeState::ChargedBack);
+        self.budget.dispute_closed();
+        self.observer.on_auto_chargeback(key as u32, client_id);
+        self.flag_if_suspicious(client_id);
+    }
+
+    /// Mark the following rows as read from `source`, e.g. the index of
+    /// an input file. Monotonic timestamps are enforced per source, so the
+    /// rows of a new source may start earlier than the ones before it.
+    pub fn enter_source(&mut self, source: u16) {
+        if self.source != Some(source) {
+            self.source = Some(source);
+            self.source_timestamp = None;
+        }
+    }
+
+    /// Apply all transactions in order, logging and skipping the ones
+    /// which are invalid. Returns the transactions which were rejected.
+    pub fn process<T>(&mut self, records: T) -> Vec<Rejection>
+    where
+        T: IntoIterator<Item = Result<Transaction>>,
+    {
+        self.process_numbered(records.into_iter().enumerate())
+    }
+
+    /// Like [`Engine::process`] for transactions numbered by their source,
+    /// e.g. with the lines of [`CsvSource::numbered_transactions`](crate::CsvSource::numbered_transactions)
+    ///
+    /// Deferred dispute steps are only returned if their transaction never
+    /// arrived, as unknown transactions.
+    pub fn process_numbered<T>(&mut self, records: T) -> Vec<Rejection>
+    where
+        T: IntoIterator<Item = (usize, Result<Transaction>)>,
+    {
+        let mut deferred = Vec::new();
+        let mut rejections: Vec<Rejection> = self
+            .process_numbered_stream(records)
+            .filter_map(|outcome| match (outcome.transaction, outcome.rejection) {
+                (Ok(transaction), Some(RejectReason::Deferred)) => {
+                    deferred.push((outcome.row, transaction));
+                    None
+                }
+                (Ok(transaction), Some(reason)) => Some(Rejection {
+                    row: outcome.row,
+                    available: (reason == RejectReason::InsufficientFunds
+                        && transaction.kind == TransactionType::Withdrawal)
+                        .then(|| outcome.client.as_ref().map(Client::available))
+                        .flatten(),
+                    transaction,
+                    reason,
+                }),
+                _ => None,
+            })
+            .collect();
+        for transaction in self.reject_deferred() {
+            let position = deferred.iter().position(|(_, t)| *t == transaction);
+            let row = position.map_or(0, |i| deferred.swap_remove(i).0);
+            rejections.push(Rejection {
+                row,
+                transaction,
+                reason: RejectReason::UnknownTx,
+                available: None,
+            });
+        }
+        rejections.sort_by_key(|r| r.row);
+        rejections
+    }
+
+    /// Lazily apply transactions in order, yielding the outcome of each
+    /// row as soon as it is applied. Nothing is buffered, rows are only
+    /// read as the outcomes are consumed.
+    pub fn process_stream<T>(&mut self, records: T) -> impl Iterator<Item = TransactionOutcome>
+    where
+        T: IntoIterator<Item = Result<Transaction>>,
+    {
+        self.process_numbered_stream(records.into_iter().enumerate())
+    }
+
+    /// Like [`Engine::process_stream`] for transactions numbered by their
+    /// source
+    pub fn process_numbered_stream<T>(
+        &mut self,
+        records: T,
+    ) -> impl Iterator<Item = TransactionOutcome>
+    where
+        T: IntoIterator<Item = (usize, Result<Transaction>)>,
+    {
+        records.into_iter().map(|(row, record)| {
+            info!("Processing row {row} {:?}", record);
+            match record {
+                Ok(transaction) => {
+                    let rejection = self.apply(transaction.clone()).err();
+                    if let Some(reason) = rejection {
+                        warn!("Rejected row {row}: {reason}");
+                    }
+                    let client = self.clients.get(&transaction.client_id).cloned();
+                    TransactionOutcome {
+                        row,
+                        transaction: Ok(transaction),
+                        rejection,
+                        client,
+                    }
+                }
+                Err(e) => {
+                    warn!("Invalid transaction at row {row}: {e}");
+                    TransactionOutcome {
+                        row,
+                        transaction: Err(e),
+                        rejection: None,
+                        client: None,
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Result of processing a single row
+#[derive(Debug)]
+pub struct TransactionOutcome {
+    pub row: usize,
+    /// Parsed transaction, or why the row could not be parsed
+    pub transaction: Result<Transaction>,
+    /// Why the transaction was not applied
+    pub rejection: Option<RejectReason>,
+    /// Balances of the affected client after the row was processed
+    pub client: Option<Client>,
+}
+
+impl TransactionOutcome {
+    pub fn is_applied(&self) -> bool {
+        self.transaction.is_ok() && self.rejection.is_none()
+    }
+}
+
+/// Dispute steps waiting for the transaction they reference, by its key
+#[derive(Debug, Default, Clone)]
+struct DeferredSteps {
+    /// Steps along with their sequence number, to restore arrival order
+    by_key: HashMap<u64, Vec<(u64, Transaction)>>,
+    len: usize,
+    next_seq: u64,
+}
+
+impl DeferredSteps {
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn push(&mut self, key: u64, transaction: Transaction) {
+        self.by_key
+            .entry(key)
+            .or_default()
+            .push((self.next_seq, transaction));
+        self.next_seq += 1;
+        self.len += 1;
+    }
+
+    /// Steps referencing `key` in the order they arrived
+    fn take(&mut self, key: u64) -> Vec<Transaction> {
+        let steps = self.by_key.remove(&key).unwrap_or_default();
+        self.len -= steps.len();
+        steps.into_iter().map(|(_, t)| t).collect()
+    }
+
+    /// All steps in the order they arrived
+    fn drain(&mut self) -> Vec<Transaction> {
+        let mut steps: Vec<_> = self.by_key.drain().flat_map(|(_, steps)| steps).collect();
+        steps.sort_by_key(|(seq, _)| *seq);
+        self.len = 0;
+        steps.into_iter().map(|(_, t)| t).collect()
+    }
+}
+
+/// State an applied transaction may have changed, as it was before
+#[derive(Debug, Clone)]
+struct UndoEntry {
+    client_id: ClientId,
+    /// None if the transaction created the client
+    client: Option<Client>,
+    /// Key of the transaction's own or referenced record
+    key: u64,
+    /// None if the transaction stored its own record
+    record: Option<TransactionRecord>,
+    /// Client whose open disputes the transaction may have changed
+    dispute_owner: ClientId,
+    open_disputes: Option<u32>,
+    dispute_counts: Option<DisputeCounts>,
+    /// Expiring disputes of the client, which the transaction counted down
+    expiring: Option<Vec<u64>>,
+    expiring_records: Vec<(u64, TransactionRecord)>,
+    /// Destination of a transfer, None if the transfer created it
+    destination: Option<(ClientId, Option<Client>)>,
+    last_timestamp: Option<Timestamp>,
+    source_timestamp: Option<Timestamp>,
+    /// Day a limited withdrawal counts on, and its sum before
+    daily_withdrawn: Option<(WithdrawalDay, Option<Decimal>)>,
+    /// Disputes charged back after the transaction, as they were before
+    aged: Vec<AgedDispute>,
+}
+
+/// Aged out dispute charged back after an applied transaction, with the
+/// state of its client before
+#[derive(Debug, Clone)]
+struct AgedDispute {
+    deadline: Timestamp,
+    key: u64,
+    record: TransactionRecord,
+    client: Option<Client>,
+    open_disputes: Option<u32>,
+    dispute_counts: Option<DisputeCounts>,
+}
+
+/// Client, currency and UTC day the withdrawals are summed up for
+type WithdrawalDay = (ClientId, Option<String>, NaiveDate);
+
+/// Reason applied transactions could not be rolled back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollbackError {
+    /// The engine was not created with an undo journal
+    NotJournaled,
+    NotEnoughEntries {
+        requested: usize,
+        available: usize,
+    },
+}
+
+impl fmt::Display for RollbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RollbackError::NotJournaled => f.write_str("engine has no undo journal"),
+            RollbackError::NotEnoughEntries {
+                requested,
+                available,
+            } => write!(
+                f,
+                "can not roll back {requested} transactions, only {available} are journaled"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RollbackError {}
+
+/// Amount of a deposit or withdrawal, as long as the configuration accepts
+/// it. Rounding happens first, so the stored record holds exactly what
+/// was credited or debited. Only adjustments may be negative.
+fn checked_amount(
+    transaction: &Transaction,
+    config: &EngineConfig,
+) -> Result<Decimal, RejectReason> {
+    let Some(amount) = transaction.amount else {
+        error!("Empty amount for {} transaction", transaction.kind);
+        return Err(RejectReason::MissingAmount);
+    };
+    let amount = with_precision(amount, config)?;
+    if amount.is_zero() && !config.allow_zero_amounts {
+        error!("Zero amount for {} transaction", transaction.kind);
+        return Err(RejectReason::ZeroAmount);
+    }
+    if amount < Decimal::ZERO && transaction.kind != TransactionType::Adjustment {
+        error!("Negative amount for {} transaction", transaction.kind);
+        return Err(RejectReason::NonPositiveAmount);
+    }
+    if matches!(
+        transaction.kind,
+        TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Purchase
+    ) && let Some(max) = config.max_transaction_amount
+        && amount > max
+    {
+        error!("Amount {amount} above the limit of {max}");
+        return Err(RejectReason::AmountAboveLimit);
+    }
+    Ok(amount)
+}
+
+/// Fee of a withdrawal, zero if there is none. It follows the precision
+/// policy like the amount, but may always be zero.
+fn checked_fee(transaction: &Transaction, config: &EngineConfig) -> Result<Decimal, RejectReason> {
+    let Some(fee) = transaction.fee else {
+        return Ok(Decimal::ZERO);
+    };
+    let fee = with_precision(fee, config)?;
+    if fee < Decimal::ZERO {
+        error!("Negative fee for {} transaction", transaction.kind);
+        return Err(RejectReason::NonPositiveAmount);
+    }
+    Ok(fee)
+}
+
+/// Refuse to take `amount` from a client if less than its minimum balance
+/// would be left
+fn keep_minimum_balance(
+    client: &Client,
+    amount: Decimal,
+    config: &EngineConfig,
+) -> Result<(), RejectReason> {
+    let Some(minimum) = client.min_balance().or(config.minimum_balance) else {
+        return Ok(());
+    };
+    if client.available() - amount < minimum {
+        info!("Unable to take {amount}, minimum balance of {minimum} required");
+        return Err(RejectReason::BelowMinimumBalance);
+    }
+    Ok(())
+}
+
+/// Day a withdrawal counts on for the daily withdrawal limit, if one is
+/// configured and the withdrawal has a timestamp
+fn withdrawal_day(
+    transaction: &Transaction,
+    currency: Option<String>,
+    config: &EngineConfig,
+) -> Option<WithdrawalDay> {
+    if !matches!(
+        transaction.kind,
+        TransactionType::Withdrawal | TransactionType::Purchase
+    ) || config.daily_withdrawal_limit.is_none()
+    {
+        return None;
+    }
+    let day = transaction.timestamp?.naive_utc().date();
+    Some((transaction.client_id, currency, day))
+}
+
+/// Apply the precision policy of the configuration to an amount
+fn with_precision(amount: Decimal, config: &EngineConfig) -> Result<Decimal, RejectReason> {
+    Ok(match config.precision_policy {
+        PrecisionPolicy::Passthrough => amount,
+        PrecisionPolicy::RejectExcess => {
+            if amount.normalize().scale() > 4 {
+                error!("Amount {amount} has more than four decimal places");
+                return Err(RejectReason::ExcessPrecision);
+            }
+            amount
+        }
+        PrecisionPolicy::RoundHalfEven(places) => {
+            amount.round_dp_with_strategy(places, RoundingStrategy::MidpointNearestEven)
+        }
+    })
+}
+
+/// Whether the client still holds the amount of a dispute being closed,
+/// and has the total too if closing it takes the amount off the total.
+/// Only corrupted state, e.g. a hand-edited snapshot, fails this.
+fn covers_dispute(client: &Client, amount: Decimal, reduces_total: bool) -> bool {
+    client.held() >= amount && (!reduces_total || client.total() >= amount)
+}
+
+/// Count down the open disputes of the client owning a closed dispute
+fn close_dispute(open_disputes: &mut HashMap<ClientId, u32>, client_id: ClientId) {
+    if let Some(count) = open_disputes.get_mut(&client_id) {
+        *count -= 1;
+        if *count == 0 {
+            open_disputes.remove(&client_id);
+        }
+    }
+}
+
+/// Unlock a locked client which has no open dispute left, along with its
+/// accounts in every currency
+fn unlock_if_settled(
+    open_disputes: &HashMap<ClientId, u32>,
+    client_id: ClientId,
+    client: &mut Client,
+) {
+    let locked = client.is_locked() || client.currencies().any(|(_, account)| account.is_locked());
+    if locked && !open_disputes.contains_key(&client_id) {
+        client.unlock_all();
+        info!("Client {client_id} unlocked");
+    }
+}
+
+/// Keys of the expiring open disputes per client
+fn expiring_disputes(
+    transaction_records: &HashMap<u64, TransactionRecord>,
+) -> HashMap<ClientId, Vec<u64>> {
+    let mut expiring: HashMap<ClientId, Vec<u64>> = HashMap::new();
+    for (&key, record) in transaction_records {
+        if record.is_disputed() && record.expires_in.is_some() {
+            expiring.entry(record.client_id).or_default().push(key);
+        }
+    }
+    for keys in expiring.values_mut() {
+        keys.sort_unstable();
+    }
+    expiring
+}
+
+/// Time an open dispute ages out, None unless it was opened by a row with
+/// a timestamp and disputes age at all
+fn aging_deadline(record: &TransactionRecord, after: Option<Duration>) -> Option<Timestamp> {
+    if !record.is_disputed() {
+        return None;
+    }
+    record.disputed_at?.checked_add_signed(after?)
+}
+
+/// Deadlines and keys of the open disputes which age out
+fn aging_disputes(
+    transaction_records: &HashMap<u64, TransactionRecord>,
+    after: Option<Duration>,
+) -> BTreeSet<(Timestamp, u64)> {
+    transaction_records
+        .iter()
+        .filter_map(|(&key, record)| Some((aging_deadline(record, after)?, key)))
+        .collect()
+}
+
+/// Number of open disputes per client, leaving out clients without any
+fn count_disputes(
+    transaction_records: &HashMap<u64, TransactionRecord>,
+) -> HashMap<ClientId, DisputeCounts> {
+    let mut dispute_counts: HashMap<ClientId, DisputeCounts> = HashMap::new();
+    for record in transaction_records.values() {
+        let counts = dispute_counts.entry(record.client_id).or_default();
+        match record.state {
+            DisputeState::Undisputed => {}
+            DisputeState::ChargedBack => {
+                counts.disputed += 1;
+                counts.charged_back += 1;
+            }
+            _ => counts.disputed += 1,
+        }
+    }
+    dispute_counts.retain(|_, counts| counts.disputed > 0);
+    dispute_counts
+}
+
+fn count_open_disputes(
+    transaction_records: &HashMap<u64, TransactionRecord>,
+) -> HashMap<ClientId, u32> {
+    let mut open_disputes = HashMap::new();
+    for record in transaction_records.values().filter(|r| r.is_disputed()) {
+        *open_disputes.entry(record.client_id).or_default() += 1;
+    }
+    open_disputes
+}
+
+/// Process all transactions in order and return the resulting accounts
+/// along with every transaction which was rejected.
+///
+/// Invalid transactions are logged and skipped.
+pub fn process_transactions<T>(
+    records: T,
+    config: EngineConfig,
+) -> (HashMap<ClientId, Client>, Vec<Rejection>)
+where
+    T: IntoIterator<Item = Result<Transaction>>,
+{
+    let mut engine = Engine::new(config);
+    let rejections = engine.process(records);
+    (engine.into_accounts(), rejections)
+}
+
+/// Evict records which can never be referenced again. Withdrawals can
+/// not be disputed unless configured, charged back transactions can not
+/// be disputed again, and all transactions from locked clients are
+/// ignored unless they still accept disputes. Open disputes are kept, as
+/// are purchases which are not fully refunded.
+fn evict_terminal_records<B: StorageBudget>(
+    transaction_records: &mut HashMap<u64, TransactionRecord>,
+    retired: &mut HashSet<u64>,
+    clients: &HashMap<ClientId, Client>,
+    config: &EngineConfig,
+    budget: &mut B,
+) {
+    let before = transaction_records.len();
+    transaction_records.retain(|&key, record| {
+        let disputable = match record.transaction_type {
+            TransactionType::Deposit | TransactionType::Transfer => true,
+            TransactionType::Withdrawal => config.dispute_withdrawals,
+            _ => false,
+        };
+        let ignored = !config.dispute_locked_accounts
+            && clients
+                .get(&record.client_id)
+                .is_some_and(|c| c.is_locked());
+        let charged_back = record.state == DisputeState::ChargedBack;
+        let refundable =
+            record.transaction_type == TransactionType::Purchase && record.refunded < record.amount;
+        let kept = record.is_disputed()
+            || (!ignored && ((disputable && !charged_back && !record.reversed) || refundable));
+        // Like settled records, the ids of evicted ones stay taken
+        if !kept {
+            retired.insert(key);
+        }
+        kept
+    });
+    let evicted = before - transaction_records.len();
+    info!("Evicted {evicted} terminal transaction records");
+    budget.records_evicted(evicted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::TxIdScope;
+    use crate::io::{CsvSource, TransactionSource};
+    use rust_decimal::dec;
+
+    fn reasons(rejections: &[Rejection]) -> Vec<(usize, RejectReason)> {
+        rejections.iter().map(|r| (r.row, r.reason)).collect()
+    }
+
+    #[test]
+    fn test_deposit_funds_multiple_clients() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 3,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 2,
+                id: 2,
+                amount: Some(dec!(0.1234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 2,
+                id: 4,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 5,
+                amount: Some(dec!(0.1234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(13.6974));
+        assert_eq!(client_1.total(), dec!(13.6974));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+
+        let client_2 = clients.get(&2).unwrap();
+
+        assert_eq!(client_2.available(), dec!(12.4634));
+        assert_eq!(client_2.total(), dec!(12.4634));
+        assert_eq!(client_2.held(), dec!(0));
+        assert!(!client_2.is_locked());
+    }
+
+    #[test]
+    fn test_withdraw_funds_multiple_clients() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(123.4)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 2,
+                id: 2,
+                amount: Some(dec!(12.56)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 2,
+                id: 3,
+                amount: Some(dec!(0.1234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 2,
+                id: 4,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 5,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(122.166));
+        assert_eq!(client_1.total(), dec!(122.166));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+
+        let client_2 = clients.get(&2).unwrap();
+
+        assert_eq!(client_2.available(), dec!(0.0966));
+        assert_eq!(client_2.total(), dec!(0.0966));
+        assert_eq!(client_2.held(), dec!(0));
+        assert!(!client_2.is_locked());
+    }
+
+    #[test]
+    fn test_withdraw_from_insufficient_balance() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.256)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 5,
+                amount: Some(dec!(123.4)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![(2, RejectReason::InsufficientFunds)]
+        );
+        assert_eq!(rejections[0].transaction.amount, Some(dec!(123.4)));
+        assert_eq!(rejections[0].available, Some(dec!(11.084)));
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(11.084));
+        assert_eq!(client_1.total(), dec!(11.084));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+        assert_eq!(client_1.failed_withdrawals(), 1);
+    }
+
+    #[test]
+    fn test_transaction_id_repeated_for_withdraw() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.256)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(0.1234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![(2, RejectReason::DuplicateTxId)]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(11.084));
+        assert_eq!(client_1.total(), dec!(11.084));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_transaction_id_repeated_for_deposit() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(1.256)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![(1, RejectReason::DuplicateTxId)]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(12.34));
+        assert_eq!(client_1.total(), dec!(12.34));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_open_dispute_for_transaction() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.256)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 1,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(1.256));
+        assert_eq!(client_1.total(), dec!(13.596));
+        assert_eq!(client_1.held(), dec!(12.34));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_open_dispute_with_insufficient_funds() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 1,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![(2, RejectReason::InsufficientFunds)]
+        );
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(11.106));
+        assert_eq!(client_1.total(), dec!(11.106));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_resolve_opened_dispute() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 1,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Resolve,
+                client_id: 1,
+                id: 1,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(12.34));
+        assert_eq!(client_1.total(), dec!(12.34));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_chargeback_opened_dispute() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 3,
+                amount: Some(dec!(0.1234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 1,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Chargeback,
+                client_id: 1,
+                id: 1,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(0.1234));
+        assert_eq!(client_1.total(), dec!(1.3574));
+        assert_eq!(client_1.held(), dec!(1.234));
+        assert!(client_1.is_locked());
+    }
+
+    #[test]
+    fn test_chargeback_outcome_stream() {
+        let transaction = |kind, id, amount| {
+            Ok(Transaction {
+                kind,
+                client_id: 1,
+                id,
+                amount,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            })
+        };
+        let records = vec![
+            transaction(TransactionType::Deposit, 1, Some(dec!(12.34))),
+            transaction(TransactionType::Deposit, 3, Some(dec!(0.1234))),
+            Err(anyhow::anyhow!("unparsable row")),
+            transaction(TransactionType::Deposit, 2, Some(dec!(1.234))),
+            transaction(TransactionType::Dispute, 1, None),
+            transaction(TransactionType::Dispute, 2, None),
+            transaction(TransactionType::Chargeback, 1, None),
+            transaction(TransactionType::Deposit, 4, Some(dec!(1.0))),
+        ];
+
+        let mut engine = Engine::new(EngineConfig::default());
+        let outcomes: Vec<TransactionOutcome> = engine.process_stream(records).collect();
+
+        let trajectory: Vec<_> = outcomes
+            .iter()
+            .map(|o| {
+                let client = o.client.as_ref()?;
+                Some((client.available(), client.held(), client.is_locked()))
+            })
+            .collect();
+        assert_eq!(
+            trajectory,
+            vec![
+                Some((dec!(12.34), dec!(0), false)),
+                Some((dec!(12.4634), dec!(0), false)),
+                None,
+                Some((dec!(13.6974), dec!(0), false)),
+                Some((dec!(1.3574), dec!(12.34), false)),
+                Some((dec!(0.1234), dec!(13.574), false)),
+                Some((dec!(0.1234), dec!(1.234), true)),
+                Some((dec!(0.1234), dec!(1.234), true)),
+            ]
+        );
+        assert!(outcomes[..2].iter().all(TransactionOutcome::is_applied));
+        assert!(outcomes[2].transaction.is_err());
+        assert_eq!(outcomes[7].rejection, Some(RejectReason::AccountLocked));
+        assert_eq!(outcomes[7].row, 7);
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Event {
+        Applied(u32, Decimal),
+        Rejected(u32, RejectReason),
+        DisputeOpened(u32, ClientId),
+        DisputeResolved(u32, ClientId),
+        Chargeback(u32, ClientId),
+        DisputeExpired(u32, ClientId),
+        Locked(ClientId),
+        Unlocked(ClientId),
+        Flagged(ClientId),
+        AutoChargeback(u32, ClientId),
+        Settled(Settlement),
+    }
+
+    /// Observer recording every event along with the available funds
+    #[derive(Debug, Default, Clone)]
+    struct Recorder(Vec<Event>);
+
+    impl EngineObserver for Recorder {
+        fn on_applied(&mut self, transaction: &Transaction, client: &Client) {
+            self.0
+                .push(Event::Applied(transaction.id, client.available()));
+        }
+
+        fn on_rejected(&mut self, transaction: &Transaction, reason: RejectReason) {
+            self.0.push(Event::Rejected(transaction.id, reason));
+        }
+
+        fn on_dispute_opened(&mut self, tx: u32, client_id: ClientId) {
+            self.0.push(Event::DisputeOpened(tx, client_id));
+        }
+
+        fn on_dispute_resolved(&mut self, tx: u32, client_id: ClientId) {
+            self.0.push(Event::DisputeResolved(tx, client_id));
+        }
+
+        fn on_chargeback(&mut self, tx: u32, client_id: ClientId) {
+            self.0.push(Event::Chargeback(tx, client_id));
+        }
+
+        fn on_dispute_expired(&mut self, tx: u32, client_id: ClientId) {
+            self.0.push(Event::DisputeExpired(tx, client_id));
+        }
+
+        fn on_locked(&mut self, client_id: ClientId) {
+            self.0.push(Event::Locked(client_id));
+        }
+
+        fn on_unlocked(&mut self, client_id: ClientId) {
+            self.0.push(Event::Unlocked(client_id));
+        }
+
+        fn on_flagged(&mut self, client_id: ClientId) {
+            self.0.push(Event::Flagged(client_id));
+        }
+
+        fn on_auto_chargeback(&mut self, tx: u32, client_id: ClientId) {
+            self.0.push(Event::AutoChargeback(tx, client_id));
+        }
+
+        fn on_settled(&mut self, settlement: &Settlement) {
+            self.0.push(Event::Settled(*settlement));
+        }
+    }
+
+    #[test]
+    fn test_observer_event_sequence() {
+        let transaction = |kind, id, amount| {
+            Ok(Transaction {
+                kind,
+                client_id: 1,
+                id,
+                amount,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            })
+        };
+        let records = vec![
+            transaction(TransactionType::Deposit, 1, Some(dec!(12.34))),
+            transaction(TransactionType::Deposit, 3, Some(dec!(0.1234))),
+            transaction(TransactionType::Deposit, 2, Some(dec!(1.234))),
+            transaction(TransactionType::Dispute, 1, None),
+            transaction(TransactionType::Dispute, 2, None),
+            transaction(TransactionType::Resolve, 2, None),
+            transaction(TransactionType::Chargeback, 1, None),
+            transaction(TransactionType::Deposit, 4, Some(dec!(1.0))),
+        ];
+
+        let mut engine = Engine::with_observer(EngineConfig::default(), Recorder::default());
+        engine.process(records);
+
+        assert_eq!(
+            engine.observer().0,
+            vec![
+                Event::Applied(1, dec!(12.34)),
+                Event::Applied(3, dec!(12.4634)),
+                Event::Applied(2, dec!(13.6974)),
+                Event::DisputeOpened(1, 1),
+                Event::Applied(1, dec!(1.3574)),
+                Event::DisputeOpened(2, 1),
+                Event::Applied(2, dec!(0.1234)),
+                Event::DisputeResolved(2, 1),
+                Event::Applied(2, dec!(1.3574)),
+                Event::Chargeback(1, 1),
+                Event::Applied(1, dec!(1.3574)),
+                Event::Rejected(4, RejectReason::AccountLocked),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_dispute_ttl_events() {
+        let transaction = |kind, id, amount| Transaction {
+            kind,
+            client_id: 1,
+            id,
+            amount,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+        let config = EngineConfig::builder().dispute_ttl_events(Some(2)).build();
+        let mut engine = Engine::with_observer(config, Recorder::default()).with_journal();
+        for (id, amount) in [(1, dec!(10.0)), (2, dec!(5.0))] {
+            engine
+                .apply(transaction(TransactionType::Deposit, id, Some(amount)))
+                .unwrap();
+        }
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, None))
+            .unwrap();
+
+        // Rejected transactions do not count
+        engine
+            .apply(transaction(TransactionType::Deposit, 3, Some(dec!(1.0))))
+            .unwrap();
+        assert!(
+            engine
+                .apply(transaction(TransactionType::Withdrawal, 4, Some(dec!(100))))
+                .is_err()
+        );
+        assert_eq!(engine.account(1).unwrap().held(), dec!(10.0));
+
+        engine
+            .apply(transaction(TransactionType::Withdrawal, 5, Some(dec!(1.0))))
+            .unwrap();
+        let client_1 = engine.account(1).unwrap();
+        assert_eq!(client_1.available(), dec!(15.0));
+        assert_eq!(client_1.held(), dec!(0));
+        assert_eq!(
+            engine.observer().0.last(),
+            Some(&Event::Applied(5, dec!(15.0)))
+        );
+        assert!(engine.observer().0.contains(&Event::DisputeExpired(1, 1)));
+        assert_eq!(
+            engine.apply(transaction(TransactionType::Resolve, 1, None)),
+            Err(RejectReason::DisputeExpired)
+        );
+        assert_eq!(
+            engine.apply(transaction(TransactionType::Chargeback, 1, None)),
+            Err(RejectReason::DisputeExpired)
+        );
+
+        // Rolling back the last transaction reopens the dispute
+        engine.rollback(1).unwrap();
+        assert_eq!(
+            engine.transaction(1, 1).unwrap().dispute_state(),
+            DisputeState::Disputed
+        );
+        assert_eq!(engine.account(1).unwrap().held(), dec!(10.0));
+        engine
+            .apply(transaction(TransactionType::Resolve, 1, None))
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(16.0));
+    }
+
+    #[test]
+    fn test_transactions_after_account_locked() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Chargeback,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 4,
+                amount: Some(dec!(65.78)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 3,
+                amount: Some(dec!(6.578)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 1,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (4, RejectReason::AccountLocked),
+                (5, RejectReason::AccountLocked),
+            ]
+        );
+        let client_1 = clients.get(&1).unwrap();
+
+        // The earlier deposit can still be disputed
+        assert_eq!(client_1.available(), dec!(0));
+        assert_eq!(client_1.total(), dec!(12.34));
+        assert_eq!(client_1.held(), dec!(12.34));
+        assert!(client_1.is_locked());
+    }
+
+    #[test]
+    fn test_second_chargeback_on_locked_account() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transaction = |kind, id, amount| Transaction {
+            kind,
+            client_id: 1,
+            id,
+            amount,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+        for id in 1..=2 {
+            engine
+                .apply(transaction(TransactionType::Deposit, id, Some(dec!(4.0))))
+                .unwrap();
+            engine
+                .apply(transaction(TransactionType::Dispute, id, None))
+                .unwrap();
+        }
+        engine
+            .apply(transaction(TransactionType::Chargeback, 1, None))
+            .unwrap();
+        assert!(engine.account(1).unwrap().is_locked());
+
+        let applied = engine
+            .apply(transaction(TransactionType::Chargeback, 2, None))
+            .unwrap();
+        assert_eq!(applied.held_delta(), dec!(-4.0));
+        assert_eq!(applied.total_delta(), dec!(-4.0));
+        assert!(!applied.locked_flipped());
+        let client_1 = engine.account(1).unwrap();
+        assert_eq!(client_1.held(), dec!(0));
+        assert_eq!(client_1.total(), dec!(0));
+        assert_eq!(
+            engine.apply(transaction(TransactionType::Deposit, 3, Some(dec!(1.0)))),
+            Err(RejectReason::AccountLocked)
+        );
+    }
+
+    #[test]
+    fn test_ignore_chargeback_if_not_disputed() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Chargeback,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![(2, RejectReason::NotDisputed)]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(13.574));
+        assert_eq!(client_1.total(), dec!(13.574));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_ignore_resolve_if_not_disputed() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Resolve,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![(2, RejectReason::NotDisputed)]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(13.574));
+        assert_eq!(client_1.total(), dec!(13.574));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_ignore_dispute_if_already_disputed() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![(3, RejectReason::AlreadyDisputed)]
+        );
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(12.34));
+        assert_eq!(client_1.total(), dec!(13.574));
+        assert_eq!(client_1.held(), dec!(1.234));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_ignore_dispute_if_tx_of_withdrawal() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![(2, RejectReason::NotADeposit)]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(11.106));
+        assert_eq!(client_1.total(), dec!(11.106));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_ignore_dispute_if_tx_and_client_dont_match() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 2,
+                id: 2,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![(2, RejectReason::ClientMismatch)]
+        );
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(12.34));
+        assert_eq!(client_1.total(), dec!(12.34));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+
+        let client_1 = clients.get(&2).unwrap();
+
+        assert_eq!(client_1.available(), dec!(1.234));
+        assert_eq!(client_1.total(), dec!(1.234));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_ignore_resolve_if_tx_and_client_dont_match() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 2,
+                id: 2,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 1,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Resolve,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![(3, RejectReason::ClientMismatch)]
+        );
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(0));
+        assert_eq!(client_1.total(), dec!(12.34));
+        assert_eq!(client_1.held(), dec!(12.34));
+        assert!(!client_1.is_locked());
+
+        let client_1 = clients.get(&2).unwrap();
+
+        assert_eq!(client_1.available(), dec!(1.234));
+        assert_eq!(client_1.total(), dec!(1.234));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_ignore_chargeback_if_tx_and_client_dont_match() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 7,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 2,
+                id: 2,
+                amount: Some(dec!(1.234)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 7,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Chargeback,
+                client_id: 2,
+                id: 7,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(records);
+        assert_eq!(
+            reasons(&rejections),
+            vec![(3, RejectReason::ClientMismatch)]
+        );
+        let client_1 = engine.account(1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(0));
+        assert_eq!(client_1.total(), dec!(12.34));
+        assert_eq!(client_1.held(), dec!(12.34));
+        assert!(!client_1.is_locked());
+        assert_eq!(engine.disputed_transactions().collect::<Vec<_>>(), vec![7]);
+
+        let client_2 = engine.account(2).unwrap();
+
+        assert_eq!(client_2.available(), dec!(1.234));
+        assert_eq!(client_2.total(), dec!(1.234));
+        assert_eq!(client_2.held(), dec!(0));
+        assert!(!client_2.is_locked());
+    }
+
+    #[test]
+    fn test_other_client_can_not_touch_dispute() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transaction = |kind, client_id, amount| Transaction {
+            kind,
+            client_id,
+            id: 5,
+            amount,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+        engine
+            .apply(transaction(TransactionType::Deposit, 1, Some(dec!(8.0))))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, None))
+            .unwrap();
+        let before = engine.snapshot();
+
+        for kind in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            assert_eq!(
+                engine.apply(transaction(kind, 2, None)),
+                Err(RejectReason::ClientMismatch)
+            );
+        }
+
+        let client_1 = engine.account(1).unwrap();
+        assert_eq!(client_1.available(), dec!(0));
+        assert_eq!(client_1.held(), dec!(8.0));
+        assert!(!client_1.is_locked());
+        assert_eq!(
+            engine.transaction(1, 5).unwrap().dispute_state(),
+            DisputeState::Disputed
+        );
+        // Rejected rows open no account for client 2
+        assert_eq!(engine.account(2), None);
+        assert_eq!(engine.snapshot(), before);
+        assert_eq!(engine.rejections(RejectReason::ClientMismatch), 3);
+    }
+
+    #[test]
+    fn test_ignore_resolve_if_invalid_tx_id() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 1,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Resolve,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![(2, RejectReason::NotDisputed)]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(0));
+        assert_eq!(client_1.total(), dec!(12.34));
+        assert_eq!(client_1.held(), dec!(12.34));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_ignore_dispute_if_invalid_tx_id() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 3,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![(1, RejectReason::UnknownTx)]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(12.34));
+        assert_eq!(client_1.total(), dec!(12.34));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_ignore_deposit_if_amount_is_none() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![(1, RejectReason::MissingAmount)]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(12.34));
+        assert_eq!(client_1.total(), dec!(12.34));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_ignore_withdrawal_if_amount_is_none() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(12.34)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![(1, RejectReason::MissingAmount)]);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(12.34));
+        assert_eq!(client_1.total(), dec!(12.34));
+        assert_eq!(client_1.held(), dec!(0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_reject_non_positive_amounts() {
+        let records = vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(10.0)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(-100.0)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 3,
+                amount: Some(dec!(-5.0)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            // The id of the rejected deposit was never used
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(1.0)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ];
+
+        let (clients, rejections) = process_transactions(records, EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (1, RejectReason::NonPositiveAmount),
+                (2, RejectReason::NonPositiveAmount),
+                (3, RejectReason::UnknownTx),
+            ]
+        );
+        let client_1 = clients.get(&1).unwrap();
+        assert_eq!(client_1.available(), dec!(11.0));
+        assert_eq!(client_1.held(), dec!(0));
+        assert_eq!(client_1.total(), dec!(11.0));
+    }
+
+    #[test]
+    fn test_config_allow_zero_amounts() {
+        let records = || {
+            vec![
+                Ok(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id: 1,
+                    amount: Some(dec!(0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Dispute,
+                    client_id: 1,
+                    id: 1,
+                    amount: None,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Chargeback,
+                    client_id: 1,
+                    id: 1,
+                    amount: None,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Withdrawal,
+                    client_id: 1,
+                    id: 2,
+                    amount: Some(dec!(0.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+            ]
+        };
+
+        let (clients, rejections) = process_transactions(records(), EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (0, RejectReason::ZeroAmount),
+                (1, RejectReason::UnknownTx),
+                (2, RejectReason::NotDisputed),
+                (3, RejectReason::ZeroAmount),
+            ]
+        );
+        assert!(!clients.contains_key(&1));
+
+        let config = EngineConfig::builder().allow_zero_amounts(true).build();
+        let (clients, rejections) = process_transactions(records(), config);
+        // The account is locked, so the zero withdrawal is refused
+        assert_eq!(reasons(&rejections), vec![(3, RejectReason::AccountLocked)]);
+        let client_1 = clients.get(&1).unwrap();
+        assert_eq!(client_1.total(), dec!(0));
+        assert!(client_1.is_locked());
+    }
+
+    #[test]
+    fn test_config_precision_policy() {
+        const FILE: &str = "type,client,tx,amount
+deposit,1,1,1.00006
+deposit,1,2,1.00006
+dispute,1,1,
+";
+        let run = |policy| {
+            let config = EngineConfig::builder().precision_policy(policy).build();
+            let mut engine = Engine::new(config);
+            let rejections = engine.process(CsvSource::new(FILE.as_bytes()).transactions());
+            (reasons(&rejections), engine)
+        };
+        // Printed available plus printed held equals printed total
+        let reconciles = |client: &Client| {
+            let columns: Vec<Decimal> = client
+                .to_string()
+                .split(',')
+                .take(3)
+                .map(|c| c.parse().unwrap())
+                .collect();
+            columns[0] + columns[1] == columns[2]
+        };
+
+        let (rejections, engine) = run(PrecisionPolicy::Passthrough);
+        assert!(rejections.is_empty());
+        assert!(!reconciles(engine.account(1).unwrap()));
+
+        let (rejections, engine) = run(PrecisionPolicy::RoundHalfEven(4));
+        assert!(rejections.is_empty());
+        let client_1 = engine.account(1).unwrap();
+        assert!(reconciles(client_1));
+        assert_eq!(client_1.held(), dec!(1.0001));
+        assert_eq!(engine.transaction(1, 1).unwrap().amount(), dec!(1.0001));
+
+        let (rejections, engine) = run(PrecisionPolicy::RejectExcess);
+        assert_eq!(
+            rejections,
+            vec![
+                (0, RejectReason::ExcessPrecision),
+                (1, RejectReason::ExcessPrecision),
+                (2, RejectReason::UnknownTx),
+            ]
+        );
+        assert!(engine.account(1).is_none());
+    }
+
+    #[test]
+    fn test_reject_overflowing_amounts() {
+        let config = EngineConfig::builder().dispute_withdrawals(true).build();
+        let mut engine = Engine::new(config);
+        let transaction = |kind, id, amount| Transaction {
+            kind,
+            client_id: 1,
+            id,
+            amount,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+
+        engine
+            .apply(transaction(TransactionType::Deposit, 1, Some(Decimal::MAX)))
+            .unwrap();
+        assert_eq!(
+            engine.apply(transaction(TransactionType::Deposit, 2, Some(dec!(1)))),
+            Err(RejectReason::Overflow)
+        );
+        assert!(engine.transaction(1, 2).is_none());
+        engine
+            .apply(transaction(TransactionType::Withdrawal, 3, Some(dec!(1))))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Deposit, 4, Some(dec!(1))))
+            .unwrap();
+        // Holding the withdrawal would push the total past the maximum
+        assert_eq!(
+            engine.apply(transaction(TransactionType::Dispute, 3, None)),
+            Err(RejectReason::Overflow)
+        );
+        assert_eq!(engine.disputed_transactions().count(), 0);
+
+        let client_1 = engine.account(1).unwrap();
+        assert_eq!(client_1.available(), Decimal::MAX);
+        assert_eq!(client_1.held(), dec!(0));
+        assert_eq!(client_1.total(), Decimal::MAX);
+        assert_eq!(engine.rejections(RejectReason::Overflow), 2);
+    }
+
+    #[test]
+    fn test_config_tx_id_scope() {
+        let records = || {
+            vec![
+                Ok(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id: 1,
+                    amount: Some(dec!(10.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 2,
+                    id: 1,
+                    amount: Some(dec!(3.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Dispute,
+                    client_id: 2,
+                    id: 1,
+                    amount: None,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+            ]
+        };
+
+        let (clients, rejections) = process_transactions(records(), EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (1, RejectReason::DuplicateTxId),
+                (2, RejectReason::ClientMismatch),
+            ]
+        );
+        assert_eq!(clients[&1].available(), dec!(10.0));
+        assert!(!clients.contains_key(&2));
+
+        let config = EngineConfig::builder()
+            .tx_id_scope(TxIdScope::PerClient)
+            .build();
+        let mut engine = Engine::new(config);
+        assert!(engine.process(records()).is_empty());
+        assert_eq!(engine.account(1).unwrap().available(), dec!(10.0));
+        let client_2 = engine.account(2).unwrap();
+        assert_eq!(client_2.available(), dec!(0));
+        assert_eq!(client_2.held(), dec!(3.0));
+        assert_eq!(
+            engine.transaction(1, 1).unwrap().dispute_state(),
+            DisputeState::Undisputed
+        );
+        assert_eq!(
+            engine.transaction(2, 1).unwrap().dispute_state(),
+            DisputeState::Disputed
+        );
+        // Duplicates are still caught within a client
+        assert_eq!(
+            engine.apply(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(1.0)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Err(RejectReason::DuplicateTxId)
+        );
+
+        // A snapshot only restores under the scope it was taken with
+        assert_eq!(
+            Engine::from_snapshot(engine.snapshot(), EngineConfig::default()).unwrap_err(),
+            SnapshotError::TxIdScopeMismatch(1)
+        );
+    }
+
+    #[test]
+    fn test_config_duplicate_tx_id_policy() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,20.0
+transfer,1,3,20.0
+withdrawal,1,2,5.0
+deposit,1,3,1.0
+deposit,1,2,1.0
+";
+        let (clients, rejections) = process_transactions(
+            CsvSource::new(data.as_bytes()).transactions(),
+            EngineConfig::default(),
+        );
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (1, RejectReason::InsufficientFunds),
+                (2, RejectReason::InvalidDestination),
+                (5, RejectReason::DuplicateTxId),
+            ]
+        );
+        // A transfer without a destination takes no id
+        assert_eq!(clients[&1].available(), dec!(6.0));
+
+        let config = EngineConfig::builder()
+            .duplicate_tx_id_policy(DuplicateTxIdPolicy::RejectAny)
+            .build();
+        let mut engine = Engine::new(config.clone());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (1, RejectReason::InsufficientFunds),
+                (2, RejectReason::InvalidDestination),
+                (3, RejectReason::DuplicateTxId),
+                (5, RejectReason::DuplicateTxId),
+            ]
+        );
+        assert_eq!(engine.account(1).unwrap().available(), dec!(11.0));
+
+        // The ids of rejected rows stay taken after a snapshot
+        let mut restored = Engine::from_snapshot(engine.snapshot(), config).unwrap();
+        let rejections = restored.process(
+            CsvSource::new(b"type,client,tx,amount\ndeposit,1,2,1.0\n".as_slice()).transactions(),
+        );
+        assert_eq!(reasons(&rejections), vec![(0, RejectReason::DuplicateTxId)]);
+    }
+
+    #[test]
+    fn test_config_strict_fields() {
+        let records = || {
+            vec![
+                Ok(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id: 1,
+                    amount: Some(dec!(2.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Dispute,
+                    client_id: 1,
+                    id: 1,
+                    amount: Some(dec!(9.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id: 2,
+                    amount: None,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+            ]
+        };
+
+        let (clients, rejections) = process_transactions(records(), EngineConfig::default());
+        assert_eq!(reasons(&rejections), vec![(2, RejectReason::MissingAmount)]);
+        // The stored amount is held, not the one on the row
+        assert_eq!(clients[&1].held(), dec!(2.0));
+
+        let config = EngineConfig::builder().strict_fields(true).build();
+        let (clients, rejections) = process_transactions(records(), config);
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (1, RejectReason::UnexpectedAmount),
+                (2, RejectReason::MissingAmount),
+            ]
+        );
+        assert_eq!(clients[&1].held(), dec!(0));
+    }
+
+    #[test]
+    fn test_rejections_report_line_numbers() {
+        let data = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,lots
+withdrawal,1,3,1.0
+withdrawal,1,4,9.0
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let not_applied: Vec<usize> = engine
+            .process_numbered_stream(CsvSource::new(data.as_bytes()).numbered_transactions())
+            .filter(|outcome| !outcome.is_applied())
+            .map(|outcome| outcome.row)
+            .collect();
+        assert_eq!(not_applied, vec![3, 5]);
+
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections =
+            engine.process_numbered(CsvSource::new(data.as_bytes()).numbered_transactions());
+        assert_eq!(
+            reasons(&rejections),
+            vec![(5, RejectReason::InsufficientFunds)]
+        );
+    }
+
+    #[test]
+    fn test_config_defer_unmatched_disputes() {
+        let records = || {
+            vec![
+                Ok(Transaction {
+                    kind: TransactionType::Dispute,
+                    client_id: 1,
+                    id: 1,
+                    amount: None,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id: 1,
+                    amount: Some(dec!(4.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Dispute,
+                    client_id: 1,
+                    id: 2,
+                    amount: None,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+            ]
+        };
+
+        let (clients, rejections) = process_transactions(records(), EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![(0, RejectReason::UnknownTx), (2, RejectReason::UnknownTx)]
+        );
+        assert_eq!(clients[&1].held(), dec!(0));
+
+        let config = EngineConfig::builder()
+            .defer_unmatched_disputes(true)
+            .build();
+        let mut engine = Engine::new(config);
+        let rejections = engine.process(records());
+        // Only the dispute whose deposit never arrived is left over
+        assert_eq!(reasons(&rejections), vec![(2, RejectReason::UnknownTx)]);
+        let client_1 = engine.account(1).unwrap();
+        assert_eq!(client_1.available(), dec!(0));
+        assert_eq!(client_1.held(), dec!(4.0));
+        assert_eq!(engine.rejections(RejectReason::Deferred), 2);
+
+        // A full queue rejects right away
+        let config = EngineConfig::builder()
+            .defer_unmatched_disputes(true)
+            .max_deferred_disputes(0)
+            .build();
+        let mut engine = Engine::new(config);
+        assert_eq!(
+            engine.apply(records()[0].as_ref().unwrap().clone()),
+            Err(RejectReason::UnknownTx)
+        );
+    }
+
+    #[test]
+    fn test_apply_returns_reject_reason() {
+        let mut engine = Engine::new(EngineConfig::default());
+
+        assert_eq!(
+            engine.apply(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(1.0)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Err(RejectReason::InsufficientFunds)
+        );
+        assert!(
+            engine
+                .apply(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id: 2,
+                    amount: Some(dec!(1.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                })
+                .is_ok()
+        );
+        assert_eq!(
+            engine.apply(Transaction {
+                kind: TransactionType::Resolve,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Err(RejectReason::NotDisputed)
+        );
+    }
+
+    #[test]
+    fn test_applied_deltas_for_dispute_and_chargeback() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transaction = |kind, id, amount| Transaction {
+            kind,
+            client_id: 1,
+            id,
+            amount,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+
+        let applied = engine
+            .apply(transaction(TransactionType::Deposit, 1, Some(dec!(5.0))))
+            .unwrap();
+        assert_eq!(applied.previous, Client::default());
+        assert_eq!(applied.total_delta(), dec!(5.0));
+        engine
+            .apply(transaction(TransactionType::Deposit, 2, Some(dec!(2.0))))
+            .unwrap();
+
+        let applied = engine
+            .apply(transaction(TransactionType::Dispute, 1, None))
+            .unwrap();
+        assert_eq!(applied.client_id, 1);
+        assert_eq!(applied.previous.available(), dec!(7.0));
+        assert_eq!(applied.current.available(), dec!(2.0));
+        assert_eq!(applied.available_delta(), dec!(-5.0));
+        assert_eq!(applied.held_delta(), dec!(5.0));
+        assert_eq!(applied.total_delta(), dec!(0));
+        assert!(!applied.locked_flipped());
+
+        let applied = engine
+            .apply(transaction(TransactionType::Chargeback, 1, None))
+            .unwrap();
+        assert_eq!(applied.available_delta(), dec!(0));
+        assert_eq!(applied.held_delta(), dec!(-5.0));
+        assert_eq!(applied.total_delta(), dec!(-5.0));
+        assert_eq!(applied.current.total(), dec!(2.0));
+        assert!(applied.locked_flipped());
+        assert!(applied.current.is_locked());
+    }
+
+    #[test]
+    fn test_simulate_leaves_engine_untouched() {
+        let mut engine = Engine::with_observer(EngineConfig::default(), Recorder::default());
+        engine.process(
+            CsvSource::new(
+                "type,client,tx,amount\ndeposit,42,1,10.0\ndeposit,42,2,3.0\ndispute,42,1,\n"
+                    .as_bytes(),
+            )
+            .transactions(),
+        );
+        let before = engine.snapshot();
+        let events = engine.observer().0.len();
+
+        let chargeback = Transaction {
+            kind: TransactionType::Chargeback,
+            client_id: 42,
+            id: 1,
+            amount: None,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+        let accounts = engine.simulate([chargeback]);
+
+        assert!(accounts[&42].is_locked());
+        assert_eq!(accounts[&42].total(), dec!(3.0));
+        assert_eq!(engine.snapshot(), before);
+        assert!(!engine.account(42).unwrap().is_locked());
+        assert_eq!(engine.observer().0.len(), events);
+
+        let copy = engine.clone();
+        assert!(Arc::ptr_eq(
+            &copy.transaction_records,
+            &engine.transaction_records
+        ));
+    }
+
+    #[test]
+    fn test_accounts_mid_stream() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine
+            .apply(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(5.0)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            })
+            .unwrap();
+
+        let client_1 = engine.account(1).unwrap();
+        assert_eq!(client_1.available(), dec!(5.0));
+        assert_eq!(client_1.total(), dec!(5.0));
+
+        engine
+            .apply(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: 1,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            })
+            .unwrap();
+
+        let client_1 = engine.account(1).unwrap();
+        assert_eq!(client_1.available(), dec!(0));
+        assert_eq!(client_1.held(), dec!(5.0));
+        assert_eq!(client_1.total(), dec!(5.0));
+    }
+
+    #[test]
+    fn test_query_why_client_is_locked() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transactions = [
+            (TransactionType::Deposit, 7, 1, Some(dec!(3.0))),
+            (TransactionType::Deposit, 7, 2, Some(dec!(4.0))),
+            (TransactionType::Deposit, 8, 3, Some(dec!(1.0))),
+            (TransactionType::Dispute, 7, 2, None),
+            (TransactionType::Dispute, 8, 3, None),
+        ];
+        for (kind, client_id, id, amount) in transactions {
+            engine
+                .apply(Transaction {
+                    kind,
+                    client_id,
+                    id,
+                    amount,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                })
+                .unwrap();
+        }
+
+        let mut disputed: Vec<u32> = engine.disputed_transactions().collect();
+        disputed.sort();
+        assert_eq!(disputed, vec![2, 3]);
+        assert_eq!(engine.accounts().count(), 2);
+        assert!(engine.account(9).is_none());
+
+        engine
+            .apply(Transaction {
+                kind: TransactionType::Chargeback,
+                client_id: 7,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            })
+            .unwrap();
+
+        assert!(engine.account(7).unwrap().is_locked());
+        assert_eq!(engine.disputed_transactions().collect::<Vec<_>>(), vec![3]);
+        let record = engine.transaction(7, 2).unwrap();
+        assert_eq!(record.client_id(), 7);
+        assert_eq!(record.amount(), dec!(4.0));
+        assert_eq!(record.transaction_type(), TransactionType::Deposit);
+        assert!(engine.transaction(8, 2).is_none());
+        assert!(engine.transaction(7, 4).is_none());
+    }
+
+    #[test]
+    fn test_seeded_accounts() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.seed_accounts([
+            (1, Client::new(dec!(5.0))),
+            (2, Client::with_balances(dec!(1.0), dec!(0), true)),
+        ]);
+
+        let withdrawal = Transaction {
+            kind: TransactionType::Withdrawal,
+            client_id: 1,
+            id: 1,
+            amount: Some(dec!(3.0)),
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+        assert!(engine.apply(withdrawal).is_ok());
+        assert_eq!(engine.account(1).unwrap().available(), dec!(2.0));
+
+        let deposit = Transaction {
+            kind: TransactionType::Deposit,
+            client_id: 2,
+            id: 2,
+            amount: Some(dec!(3.0)),
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+        assert_eq!(engine.apply(deposit), Err(RejectReason::AccountLocked));
+        assert_eq!(engine.account(2).unwrap().total(), dec!(1.0));
+    }
+
+    /// Journaled engine with two clients and an open dispute on tx 2
+    fn journaled_engine() -> Engine {
+        let mut engine = Engine::new(EngineConfig::default()).with_journal();
+        engine.process(
+            CsvSource::new(
+                "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.0
+deposit,1,7,4.0
+dispute,2,2,
+"
+                .as_bytes(),
+            )
+            .transactions(),
+        );
+        engine
+    }
+
+    #[test]
+    fn test_rollback_restores_identical_state() {
+        let transaction = |kind, client_id, id, amount| Transaction {
+            kind,
+            client_id,
+            id,
+            amount,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+        let cases = [
+            transaction(TransactionType::Deposit, 3, 4, Some(dec!(1.5))),
+            transaction(TransactionType::Withdrawal, 1, 5, Some(dec!(3.0))),
+            transaction(TransactionType::Dispute, 1, 1, None),
+            transaction(TransactionType::Resolve, 2, 2, None),
+            transaction(TransactionType::Chargeback, 2, 2, None),
+        ];
+        for case in cases {
+            let mut engine = journaled_engine();
+            let before = serde_json::to_vec(&engine.snapshot()).unwrap();
+            let open_disputes = engine.open_disputes.clone();
+
+            assert!(engine.apply(case.clone()).is_ok(), "{case:?}");
+            assert_ne!(serde_json::to_vec(&engine.snapshot()).unwrap(), before);
+            engine.rollback(1).unwrap();
+
+            assert_eq!(
+                serde_json::to_vec(&engine.snapshot()).unwrap(),
+                before,
+                "{case:?}"
+            );
+            assert_eq!(engine.open_disputes, open_disputes);
+        }
+    }
+
+    #[test]
+    fn test_rollback_skips_rejected_transactions() {
+        let mut engine = journaled_engine();
+        let empty = serde_json::to_vec(&Engine::new(EngineConfig::default()).snapshot()).unwrap();
+
+        // Insufficient funds, never journaled
+        let withdrawal = Transaction {
+            kind: TransactionType::Withdrawal,
+            client_id: 1,
+            id: 6,
+            amount: Some(dec!(100.0)),
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+        assert!(engine.apply(withdrawal).is_err());
+        assert_eq!(
+            engine.rollback(6),
+            Err(RollbackError::NotEnoughEntries {
+                requested: 6,
+                available: 5
+            })
+        );
+
+        engine.rollback(5).unwrap();
+        assert_eq!(serde_json::to_vec(&engine.snapshot()).unwrap(), empty);
+        assert!(engine.open_disputes.is_empty());
+        assert_eq!(
+            Engine::new(EngineConfig::default()).rollback(1),
+            Err(RollbackError::NotJournaled)
+        );
+    }
+
+    #[test]
+    fn test_verify_reports_violations() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.seed_accounts([
+            (1, Client::new(dec!(1.0))),
+            (2, Client::with_balances(dec!(-1.0), dec!(-0.5), false)),
+        ]);
+
+        let violations = engine.verify();
+        assert_eq!(
+            violations
+                .iter()
+                .map(|v| (v.client_id, v.invariant))
+                .collect::<Vec<_>>(),
+            vec![
+                (2, Invariant::NonNegativeHeld),
+                (2, Invariant::NonNegativeAvailable)
+            ]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            "client 2 violates held >= 0: available -1.0000, held -0.5000, total -1.5000"
+        );
+
+        let config = EngineConfig::builder()
+            .allow_negative_on_dispute(true)
+            .build();
+        let mut engine = Engine::new(config);
+        engine.seed_accounts([(2, Client::new(dec!(-1.0)))]);
+        assert!(engine.verify().is_empty());
+    }
+
+    /// Xorshift generator, so the random sequences are reproducible
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// Random mix of transactions over a few clients. Disputes, resolves
+    /// and chargebacks mostly reference earlier transactions.
+    fn random_transactions(rng: &mut Rng, len: u32) -> Vec<Transaction> {
+        let mut owners = Vec::new();
+        (0..len)
+            .map(|id| {
+                let amount = Decimal::new(rng.below(10_000) as i64, 2);
+                let mut client_id = rng.below(4) as ClientId;
+                let kind = match rng.below(10) {
+                    0..=3 => TransactionType::Deposit,
+                    4..=5 => TransactionType::Withdrawal,
+                    6..=7 => TransactionType::Dispute,
+                    8 => TransactionType::Resolve,
+                    _ => TransactionType::Chargeback,
+                };
+                if matches!(kind, TransactionType::Deposit | TransactionType::Withdrawal) {
+                    owners.push((id, client_id));
+                    return Transaction {
+                        kind,
+                        client_id,
+                        id,
+                        amount: Some(amount),
+                        to_client: None,
+                        fee: None,
+                        currency: None,
+                        timestamp: None,
+                    };
+                }
+                let mut id = rng.below(u64::from(len)) as u32;
+                if !owners.is_empty() && rng.below(4) != 0 {
+                    (id, client_id) = owners[rng.below(owners.len() as u64) as usize];
+                }
+                Transaction {
+                    kind,
+                    client_id,
+                    id,
+                    amount: None,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_after_random_sequences() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for round in 0..200 {
+            let config = EngineConfig::builder()
+                .allow_negative_on_dispute(round % 2 == 1)
+                .dispute_withdrawals(round % 3 == 1)
+                .dispute_locked_accounts(round % 5 == 1)
+                .build();
+            let transactions = random_transactions(&mut rng, 60);
+            let mut engine = Engine::new(config);
+            for transaction in transactions.iter().cloned() {
+                let _ = engine.apply(transaction);
+                assert_eq!(engine.verify(), vec![], "round {round}: {transactions:?}");
+            }
+        }
+    }
+
+    fn deposit_withdraw_dispute(dispute_tx: u32) -> Vec<Result<Transaction>> {
+        vec![
+            Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 1,
+                amount: Some(dec!(10.0)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Withdrawal,
+                client_id: 1,
+                id: 2,
+                amount: Some(dec!(4.0)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+            Ok(Transaction {
+                kind: TransactionType::Dispute,
+                client_id: 1,
+                id: dispute_tx,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_config_allow_negative_on_dispute() {
+        let (clients, _) =
+            process_transactions(deposit_withdraw_dispute(1), EngineConfig::default());
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(6.0));
+        assert_eq!(client_1.held(), dec!(0));
+
+        let config = EngineConfig::builder()
+            .allow_negative_on_dispute(true)
+            .build();
+        let mut records = deposit_withdraw_dispute(1);
+        // Withdrawals compare against the negative available funds
+        records.push(Ok(Transaction {
+            kind: TransactionType::Withdrawal,
+            client_id: 1,
+            id: 3,
+            amount: Some(dec!(1.0)),
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        }));
+        records.push(Ok(Transaction {
+            kind: TransactionType::Deposit,
+            client_id: 1,
+            id: 4,
+            amount: Some(dec!(5.0)),
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        }));
+        records.push(Ok(Transaction {
+            kind: TransactionType::Withdrawal,
+            client_id: 1,
+            id: 5,
+            amount: Some(dec!(1.0)),
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        }));
+        let (clients, rejections) = process_transactions(records, config);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![(3, RejectReason::InsufficientFunds)]
+        );
+        assert_eq!(client_1.available(), dec!(0.0));
+        assert_eq!(client_1.held(), dec!(10.0));
+        assert_eq!(client_1.total(), dec!(10.0));
+    }
+
+    #[test]
+    fn test_config_dispute_withdrawals() {
+        let (clients, _) =
+            process_transactions(deposit_withdraw_dispute(2), EngineConfig::default());
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.held(), dec!(0));
+        assert_eq!(client_1.total(), dec!(6.0));
+
+        let config = EngineConfig::builder().dispute_withdrawals(true).build();
+        let mut records = deposit_withdraw_dispute(2);
+        records.push(Ok(Transaction {
+            kind: TransactionType::Resolve,
+            client_id: 1,
+            id: 2,
+            amount: None,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        }));
+        let mut engine = Engine::new(config);
+        let mut records = records.into_iter();
+        engine.process(records.by_ref().take(3));
+        let client_1 = engine.account(1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(6.0));
+        assert_eq!(client_1.held(), dec!(4.0));
+        assert_eq!(client_1.total(), dec!(10.0));
+
+        // Resolving lets the withdrawal stand
+        engine.process(records);
+        let client_1 = engine.account(1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(6.0));
+        assert_eq!(client_1.held(), dec!(0));
+        assert_eq!(client_1.total(), dec!(6.0));
+    }
+
+    #[test]
+    fn test_withdrawal_chargeback_restores_balance() {
+        let run = |config| {
+            let mut records = deposit_withdraw_dispute(2);
+            records.push(Ok(Transaction {
+                kind: TransactionType::Chargeback,
+                client_id: 1,
+                id: 2,
+                amount: None,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }));
+            records.push(Ok(Transaction {
+                kind: TransactionType::Deposit,
+                client_id: 1,
+                id: 3,
+                amount: Some(dec!(1.0)),
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            }));
+            process_transactions(records, config)
+        };
+
+        let config = EngineConfig::builder().dispute_withdrawals(true).build();
+        let (clients, rejections) = run(config);
+        let client_1 = clients.get(&1).unwrap();
+        assert_eq!(reasons(&rejections), vec![(4, RejectReason::AccountLocked)]);
+        assert_eq!(client_1.available(), dec!(10.0));
+        assert_eq!(client_1.held(), dec!(0));
+        assert_eq!(client_1.total(), dec!(10.0));
+        assert!(client_1.is_locked());
+
+        let config = EngineConfig::builder()
+            .dispute_withdrawals(true)
+            .lock_on_withdrawal_chargeback(false)
+            .build();
+        let (clients, rejections) = run(config);
+        let client_1 = clients.get(&1).unwrap();
+        assert!(rejections.is_empty());
+        assert_eq!(client_1.available(), dec!(11.0));
+        assert_eq!(client_1.total(), dec!(11.0));
+        assert!(!client_1.is_locked());
+    }
+
+    #[test]
+    fn test_config_auto_unlock() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,1,
+dispute,1,2,
+chargeback,1,1,
+deposit,1,3,1.0
+resolve,1,2,
+deposit,1,4,2.0
+";
+        let run = |config| {
+            let mut engine = Engine::new(config);
+            let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+            (engine.into_accounts(), rejections)
+        };
+
+        let (clients, rejections) = run(EngineConfig::default());
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (5, RejectReason::AccountLocked),
+                (7, RejectReason::AccountLocked)
+            ]
+        );
+        assert!(clients[&1].is_locked());
+        assert_eq!(clients[&1].total(), dec!(5.0));
+
+        let (clients, rejections) = run(EngineConfig::builder().auto_unlock(true).build());
+        // Still locked while tx 2 is disputed
+        assert_eq!(reasons(&rejections), vec![(5, RejectReason::AccountLocked)]);
+        assert!(!clients[&1].is_locked());
+        assert_eq!(clients[&1].available(), dec!(7.0));
+        assert_eq!(clients[&1].total(), dec!(7.0));
+    }
+
+    #[test]
+    fn test_auto_unlock_every_currency() {
+        let data = "type,client,tx,amount,currency
+deposit,1,1,10.0,EUR
+deposit,1,2,5.0,EUR
+dispute,1,1,,EUR
+dispute,1,2,,EUR
+chargeback,1,1,,EUR
+deposit,1,3,1.0,
+resolve,1,2,,EUR
+deposit,1,4,2.0,
+deposit,1,5,3.0,EUR
+";
+        let mut engine = Engine::new(EngineConfig::builder().auto_unlock(true).build());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        // Locked in every currency while tx 2 is disputed
+        assert_eq!(reasons(&rejections), vec![(5, RejectReason::AccountLocked)]);
+        let client = engine.account(1).unwrap();
+        assert!(!client.is_locked());
+        assert_eq!(client.available(), dec!(2.0));
+        let eur = client.currency("EUR").unwrap();
+        assert!(!eur.is_locked());
+        assert_eq!(eur.available(), dec!(8.0));
+    }
+
+    #[test]
+    fn test_config_allow_partial_disputes() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,4.0
+dispute,1,1,12.0
+dispute,1,1,3.0
+dispute,2,2,1.5
+resolve,2,2,
+chargeback,1,1,
+";
+        let run = |config| {
+            let mut engine = Engine::new(config);
+            let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+            (reasons(&rejections), engine)
+        };
+
+        let config = EngineConfig::builder().allow_partial_disputes(true).build();
+        let (rejections, engine) = run(config);
+        assert_eq!(rejections, vec![(2, RejectReason::ExcessDisputeAmount)]);
+        assert_eq!(
+            engine.transaction(1, 1).unwrap().disputed_amount(),
+            dec!(3.0)
+        );
+        // Only the disputed part is charged back
+        assert_eq!(
+            *engine.account(1).unwrap(),
+            Client::with_balances(dec!(7.0), dec!(0), true)
+        );
+        assert_eq!(
+            *engine.account(2).unwrap(),
+            Client::with_balances(dec!(4.0), dec!(0), false)
+        );
+
+        // Without the option the amount is ignored
+        let (rejections, engine) = run(EngineConfig::default());
+        assert_eq!(rejections, vec![(3, RejectReason::AlreadyDisputed)]);
+        assert_eq!(
+            *engine.account(1).unwrap(),
+            Client::with_balances(dec!(0), dec!(0), true)
+        );
+    }
+
+    #[test]
+    fn test_transfer_between_clients() {
+        let data = "type,client,tx,amount,to_client
+deposit,1,1,10.0
+transfer,1,2,4.0,2
+transfer,1,3,7.0,2
+transfer,1,4,1.0,
+transfer,1,5,1.0,1
+deposit,3,6,1.0
+dispute,3,6,
+chargeback,3,6,
+transfer,1,7,1.0,3
+dispute,2,2,
+";
+        let mut engine = Engine::new(EngineConfig::default()).with_journal();
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (2, RejectReason::InsufficientFunds),
+                (3, RejectReason::InvalidDestination),
+                (4, RejectReason::InvalidDestination),
+                (8, RejectReason::AccountLocked),
+            ]
+        );
+        assert_eq!(
+            *engine.account(1).unwrap(),
+            Client::with_balances(dec!(6.0), dec!(0), false)
+        );
+        // The destination disputes the transfer like a deposit
+        assert_eq!(
+            *engine.account(2).unwrap(),
+            Client::with_balances(dec!(0), dec!(4.0), false)
+        );
+        assert_eq!(
+            engine.transaction(2, 2).unwrap().transaction_type(),
+            TransactionType::Transfer
+        );
+
+        // Rolling back the transfer restores both clients
+        engine.rollback(5).unwrap();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(10.0));
+        assert!(engine.account(2).is_none());
+        assert!(engine.transaction(2, 2).is_none());
+    }
+
+    #[test]
+    fn test_transfer_to_client_locked_in_another_currency() {
+        let data = "type,client,tx,amount,to_client,currency
+deposit,1,1,10.0,,EUR
+deposit,2,2,5.0,,USD
+dispute,2,2,,,USD
+chargeback,2,2,,,USD
+transfer,1,3,4.0,2,EUR
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(reasons(&rejections), vec![(4, RejectReason::AccountLocked)]);
+        let source = engine.account(1).unwrap().currency("EUR").unwrap();
+        assert_eq!(source.available(), dec!(10.0));
+        assert!(engine.account(2).unwrap().currency("EUR").is_none());
+    }
+
+    #[test]
+    fn test_reversal_of_withdrawal() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,4.0
+deposit,2,3,1.0
+reversal,1,2,
+reversal,1,2,
+reversal,1,1,
+reversal,1,9,
+reversal,2,2,
+dispute,1,2,
+";
+        let config = EngineConfig::builder().dispute_withdrawals(true).build();
+        let mut engine = Engine::new(config);
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (4, RejectReason::AlreadyReversed),
+                (5, RejectReason::NotAWithdrawal),
+                (6, RejectReason::UnknownTx),
+                (7, RejectReason::ClientMismatch),
+                (8, RejectReason::AlreadyReversed),
+            ]
+        );
+        assert_eq!(
+            *engine.account(1).unwrap(),
+            Client::with_balances(dec!(10.0), dec!(0), false)
+        );
+        assert!(engine.transaction(1, 2).unwrap().is_reversed());
+    }
+
+    #[test]
+    fn test_refunds_capped_at_purchase() {
+        let data = "type,client,tx,amount
+deposit,1,1,100.0
+purchase,1,2,30.0
+purchase,1,3,20.0
+refund,1,2,30.0
+refund,1,3,12.5
+refund,1,3,7.5
+refund,1,3,0.0001
+refund,1,2,1.0
+refund,1,1,1.0
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (6, RejectReason::ExcessRefund),
+                (7, RejectReason::ExcessRefund),
+                (8, RejectReason::NotAPurchase),
+            ]
+        );
+        assert_eq!(
+            *engine.account(1).unwrap(),
+            Client::with_balances(dec!(100.0), dec!(0), false)
+        );
+        let purchase = engine.transaction(1, 3).unwrap();
+        assert_eq!(purchase.transaction_type(), TransactionType::Purchase);
+        assert_eq!(purchase.refunded(), dec!(20.0));
+    }
+
+    #[test]
+    fn test_settle_drops_terminal_records() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+deposit,2,3,5.0
+withdrawal,1,4,1.0
+deposit,1,5,1.0
+dispute,1,1,
+resolve,1,1,
+dispute,2,3,
+chargeback,2,3,
+reversal,1,4,
+dispute,1,5,
+settle,0,0,
+dispute,1,1,
+dispute,1,2,
+resolve,1,5,
+";
+        let config = EngineConfig::builder()
+            .redispute_policy(RedisputePolicy::DenyAfterResolve)
+            .build();
+        let mut engine = Engine::with_observer(config, Recorder::default());
+        let rows: Vec<_> = CsvSource::new(data.as_bytes()).transactions().collect();
+        let (before, after) = rows.split_at(11);
+        engine.process(before.iter().map(|row| Ok(row.as_ref().unwrap().clone())));
+        assert_eq!(engine.transaction_records.len(), 5);
+
+        let rejections = engine.process(after.iter().map(|row| Ok(row.as_ref().unwrap().clone())));
+        assert_eq!(reasons(&rejections), vec![(1, RejectReason::UnknownTx)]);
+        assert_eq!(engine.transaction_records.len(), 2);
+        assert!(engine.transaction(1, 2).unwrap().is_disputed());
+        assert!(engine.account(0).is_none());
+        assert!(engine.observer().0.contains(&Event::Settled(Settlement {
+            resolved: 1,
+            charged_back: 1,
+            reversed: 1,
+            retained: 2,
+        })));
+        assert_eq!(engine.account(1).unwrap().held(), dec!(5.0));
+    }
+
+    #[test]
+    fn test_settle_keeps_redisputable_records() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+dispute,1,1,
+resolve,1,1,
+settle,0,0,
+dispute,1,1,
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert!(rejections.is_empty());
+        assert!(engine.transaction(1, 1).unwrap().is_disputed());
+        assert_eq!(engine.account(1).unwrap().held(), dec!(10.0));
+    }
+
+    #[test]
+    fn test_settled_tx_id_stays_taken() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+dispute,1,1,
+resolve,1,1,
+settle,0,0,
+deposit,1,1,10.0
+";
+        let config = EngineConfig::builder()
+            .redispute_policy(RedisputePolicy::DenyAfterResolve)
+            .build();
+        let mut engine = Engine::new(config.clone());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert_eq!(reasons(&rejections), vec![(4, RejectReason::DuplicateTxId)]);
+        assert!(engine.transaction(1, 1).is_none());
+        assert_eq!(engine.account(1).unwrap().total(), dec!(10.0));
+
+        // The settled ids are carried over by a snapshot
+        let mut restored = Engine::from_snapshot(engine.snapshot(), config).unwrap();
+        let replay = "type,client,tx,amount
+deposit,1,1,10.0
+";
+        let rejections = restored.process(CsvSource::new(replay.as_bytes()).transactions());
+        assert_eq!(reasons(&rejections), vec![(0, RejectReason::DuplicateTxId)]);
+    }
+
+    #[test]
+    fn test_evicted_tx_id_stays_taken() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+dispute,2,2,
+chargeback,2,2,
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert!(rejections.is_empty());
+        evict_terminal_records(
+            Arc::make_mut(&mut engine.transaction_records),
+            &mut engine.retired,
+            &engine.clients,
+            &engine.config,
+            &mut engine.budget,
+        );
+        assert!(engine.transaction(2, 2).is_none());
+        assert!(engine.transaction(1, 1).is_some());
+
+        let data = "type,client,tx,amount
+deposit,1,2,5.0
+";
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert_eq!(reasons(&rejections), vec![(0, RejectReason::DuplicateTxId)]);
+        assert_eq!(engine.account(1).unwrap().total(), dec!(10.0));
+    }
+
+    #[test]
+    fn test_eviction_keeps_refundable_purchases() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+purchase,1,2,4.0
+purchase,1,3,2.0
+refund,1,2,1.0
+refund,1,3,2.0
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert!(rejections.is_empty());
+        evict_terminal_records(
+            Arc::make_mut(&mut engine.transaction_records),
+            &mut engine.retired,
+            &engine.clients,
+            &engine.config,
+            &mut engine.budget,
+        );
+        assert!(engine.transaction(1, 3).is_none());
+
+        let data = "type,client,tx,amount
+refund,1,2,3.0
+";
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert!(rejections.is_empty());
+        assert_eq!(engine.account(1).unwrap().available(), dec!(10.0));
+    }
+
+    #[test]
+    fn test_accrue_interest_on_available_funds() {
+        let data = "type,client,tx,amount
+deposit,1,1,100.0
+deposit,2,2,0.00005
+deposit,3,3,50.0
+lock,3,3,
+deposit,4,4,20.0
+deposit,4,5,10.0
+dispute,4,5,
+accrue,0,9,0.0010
+";
+        let mut engine = Engine::with_observer(EngineConfig::default(), Recorder::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert!(rejections.is_empty());
+
+        assert_eq!(engine.account(1).unwrap().available(), dec!(100.1000));
+        // 0.00005005 rounds up to the fourth place
+        assert_eq!(engine.account(2).unwrap().available(), dec!(0.0001));
+        assert_eq!(engine.account(3).unwrap().available(), dec!(50.0));
+        let client = engine.account(4).unwrap();
+        assert_eq!(
+            (client.available(), client.held(), client.total()),
+            (dec!(20.0200), dec!(10.0), dec!(30.0200))
+        );
+        assert!(engine.account(0).is_none());
+        let accrued: Vec<_> = engine
+            .observer()
+            .0
+            .iter()
+            .filter(|event| matches!(event, Event::Applied(9, _)))
+            .collect();
+        assert_eq!(
+            accrued,
+            vec![
+                &Event::Applied(9, dec!(100.1000)),
+                &Event::Applied(9, dec!(0.0001)),
+                &Event::Applied(9, dec!(20.0200)),
+                &Event::Applied(9, dec!(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_accrue_skips_client_locked_in_another_currency() {
+        let data = "type,client,tx,amount,currency
+deposit,1,1,50.0,EUR
+deposit,1,2,5.0,USD
+dispute,1,2,,USD
+chargeback,1,2,,USD
+accrue,0,9,0.1,
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert!(rejections.is_empty());
+
+        let client = engine.account(1).unwrap();
+        assert!(client.is_locked());
+        assert_eq!(client.currency("EUR").unwrap().available(), dec!(50.0));
+    }
+
+    #[test]
+    fn test_config_enforce_monotonic_timestamps() {
+        let data = "type,client,tx,amount,ts
+deposit,1,1,10.0,2024-03-01T10:00:00Z
+deposit,1,2,1.0,
+deposit,1,3,2.0,2024-03-01T09:00:00Z
+withdrawal,1,4,1.0,2024-03-01T12:00:00+01:00
+withdrawal,1,5,1.0,2024-03-01T11:30:00+02:00
+";
+        let run = |enforce| {
+            let config = EngineConfig::builder()
+                .enforce_monotonic_timestamps(enforce)
+                .build();
+            let mut engine = Engine::new(config);
+            let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+            (reasons(&rejections), engine)
+        };
+
+        let (rejections, engine) = run(false);
+        assert!(rejections.is_empty());
+        assert_eq!(engine.account(1).unwrap().available(), dec!(11.0));
+        assert_eq!(
+            engine.transaction(1, 3).unwrap().timestamp(),
+            Some("2024-03-01T09:00:00Z".parse().unwrap())
+        );
+        assert_eq!(engine.transaction(1, 2).unwrap().timestamp(), None);
+
+        let (rejections, engine) = run(true);
+        assert_eq!(
+            rejections,
+            vec![(2, RejectReason::OutOfOrder), (4, RejectReason::OutOfOrder)]
+        );
+        assert_eq!(engine.account(1).unwrap().available(), dec!(10.0));
+    }
+
+    #[test]
+    fn test_config_daily_withdrawal_limit() {
+        let data = "type,client,tx,amount,ts
+deposit,1,1,100.0,2024-03-01T08:00:00Z
+withdrawal,1,2,20.0,2024-03-01T09:00:00Z
+withdrawal,1,3,25.0,2024-03-01T12:00:00Z
+withdrawal,1,4,10.0,2024-03-01T23:00:00Z
+withdrawal,1,5,10.0,2024-03-02T01:00:00+02:00
+withdrawal,1,6,30.0,2024-03-02T09:00:00Z
+withdrawal,1,7,1.0,
+";
+        let config = EngineConfig::builder()
+            .daily_withdrawal_limit(Some(dec!(50.0)))
+            .build();
+        let mut engine = Engine::new(config);
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        // 01:00 at +02:00 still is March 1st in UTC
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (3, RejectReason::DailyLimitExceeded),
+                (4, RejectReason::DailyLimitExceeded),
+                (6, RejectReason::MissingTimestamp),
+            ]
+        );
+        let client = engine.account(1).unwrap();
+        assert_eq!(client.available(), dec!(25.0));
+        assert_eq!(client.failed_withdrawals(), 0);
+    }
+
+    #[test]
+    fn test_config_max_transaction_amount() {
+        let data = "type,client,tx,amount
+deposit,1,1,100.0
+deposit,1,2,100.0001
+withdrawal,1,3,100.0001
+deposit,1,2,1.0
+deposit,1,1,1.0
+";
+        let config = EngineConfig::builder()
+            .max_transaction_amount(Some(dec!(100)))
+            .build();
+        let mut engine = Engine::new(config);
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        // The rejected tx 2 left its id unused
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (1, RejectReason::AmountAboveLimit),
+                (2, RejectReason::AmountAboveLimit),
+                (4, RejectReason::DuplicateTxId),
+            ]
+        );
+        assert_eq!(engine.account(1).unwrap().available(), dec!(101.0));
+    }
+
+    #[test]
+    fn test_config_flag_after_chargebacks() {
+        let data = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,5.0
+deposit,1,3,5.0
+deposit,2,4,5.0
+deposit,2,5,5.0
+dispute,1,1,
+chargeback,1,1,
+dispute,1,2,
+chargeback,1,2,
+dispute,2,4,
+chargeback,2,4,
+withdrawal,1,6,1.0
+withdrawal,2,7,1.0
+";
+        let run = |freeze| {
+            let config = EngineConfig::builder()
+                .flag_after_chargebacks(Some(1))
+                .freeze_flagged(freeze)
+                // Keep the clients unlocked to see the flag at work
+                .auto_unlock(true)
+                .build();
+            let mut engine = Engine::with_observer(config, Recorder::default());
+            let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+            (reasons(&rejections), engine)
+        };
+
+        let (rejections, engine) = run(false);
+        assert!(rejections.is_empty());
+        let client = engine.account(1).unwrap();
+        assert!(client.is_flagged());
+        assert!(!client.is_locked());
+        assert_eq!(
+            engine.dispute_counts(1),
+            DisputeCounts {
+                disputed: 2,
+                charged_back: 2
+            }
+        );
+        assert_eq!(client.available(), dec!(4.0));
+        assert!(!engine.account(2).unwrap().is_flagged());
+        assert!(engine.observer().0.contains(&Event::Flagged(1)));
+        assert!(!engine.observer().0.contains(&Event::Flagged(2)));
+
+        let (rejections, engine) = run(true);
+        assert_eq!(rejections, vec![(11, RejectReason::AccountFlagged)]);
+        assert_eq!(engine.account(2).unwrap().available(), dec!(4.0));
+    }
+
+    #[test]
+    fn test_config_auto_chargeback_after() {
+        let data = "type,client,tx,amount,ts
+deposit,1,1,10.0,2024-03-01T08:00:00Z
+deposit,2,2,20.0,2024-03-01T08:00:00Z
+deposit,3,3,5.0,2024-03-01T08:00:00Z
+dispute,1,1,,2024-03-01T09:00:00Z
+dispute,2,2,,2024-03-01T10:00:00Z
+dispute,3,3,,
+deposit,4,4,1.0,2024-03-02T09:30:00Z
+resolve,2,2,,2024-03-02T10:00:00Z
+deposit,4,5,1.0,2024-03-09T00:00:00Z
+";
+        let config = EngineConfig::builder()
+            .auto_chargeback_after(Some(Duration::hours(24)))
+            .build();
+        let mut engine = Engine::with_observer(config, Recorder::default()).with_journal();
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert!(rejections.is_empty());
+
+        // Tx 1 aged out with tx 4, tx 2 was resolved right at its deadline
+        // and tx 3 was disputed without a timestamp
+        let client = engine.account(1).unwrap();
+        assert_eq!((client.held(), client.total()), (dec!(0), dec!(0)));
+        assert!(client.is_locked());
+        assert_eq!(engine.dispute_counts(1).charged_back, 1);
+        assert_eq!(engine.account(2).unwrap().available(), dec!(20.0));
+        assert_eq!(engine.account(3).unwrap().held(), dec!(5.0));
+        let auto_chargebacks: Vec<_> = engine
+            .observer()
+            .0
+            .iter()
+            .filter(|event| matches!(event, Event::AutoChargeback(..)))
+            .collect();
+        assert_eq!(auto_chargebacks, vec![&Event::AutoChargeback(1, 1)]);
+
+        engine.rollback(3).unwrap();
+        let client = engine.account(1).unwrap();
+        assert_eq!(client.held(), dec!(10.0));
+        assert!(!client.is_locked());
+        assert_eq!(engine.dispute_counts(1).charged_back, 0);
+        assert!(engine.transaction_records[&1].is_disputed());
+        assert!(
+            engine
+                .aging
+                .contains(&("2024-03-02T09:00:00Z".parse().unwrap(), 1))
+        );
+    }
+
+    #[test]
+    fn test_config_minimum_balance() {
+        let accounts = "client,available,held,total,locked,min_balance
+1,100.0,0,100.0,false,
+2,100.0,0,100.0,false,50.0
+";
+        let data = "type,client,tx,amount,to_client
+withdrawal,1,1,90.0,
+withdrawal,1,2,0.0001,
+withdrawal,2,3,50.0001,
+withdrawal,2,4,50.0,
+transfer,1,5,1.0,2
+";
+        let config = EngineConfig::builder()
+            .minimum_balance(Some(dec!(10)))
+            .build();
+        let mut engine = Engine::new(config);
+        engine.seed_accounts(crate::io::read_accounts(accounts.as_bytes()).unwrap());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (1, RejectReason::BelowMinimumBalance),
+                (2, RejectReason::BelowMinimumBalance),
+                (4, RejectReason::BelowMinimumBalance),
+            ]
+        );
+        assert_eq!(engine.account(1).unwrap().available(), dec!(10.0));
+        assert_eq!(engine.account(2).unwrap().available(), dec!(50.0));
+        assert_eq!(engine.account(1).unwrap().failed_withdrawals(), 0);
+    }
+
+    #[test]
+    fn test_withdrawals_within_credit_limit() {
+        let accounts = "client,available,held,total,locked,credit_limit
+1,10.0,0,10.0,false,50.0
+2,10.0,0,10.0,false,
+";
+        let data = "type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,60.0
+withdrawal,1,3,5.0
+withdrawal,1,4,0.0001
+withdrawal,2,5,10.0001
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.seed_accounts(crate::io::read_accounts(accounts.as_bytes()).unwrap());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (3, RejectReason::InsufficientFunds),
+                (4, RejectReason::InsufficientFunds),
+            ]
+        );
+        let client = engine.account(1).unwrap();
+        assert_eq!(client.available(), dec!(-50.0));
+        assert!(client.is_overdrawn());
+        assert!(engine.verify().is_empty());
+
+        let data = "type,client,tx,amount
+deposit,1,6,70.0
+dispute,1,1,
+";
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        assert!(rejections.is_empty());
+        let client = engine.account(1).unwrap();
+        assert_eq!(
+            (client.available(), client.held(), client.total()),
+            (dec!(15.0), dec!(5.0), dec!(20.0))
+        );
+        assert!(!client.is_overdrawn());
+    }
+
+    #[test]
+    fn test_transfers_within_credit_limit() {
+        let accounts = "client,available,held,total,locked,credit_limit
+1,10.0,0,10.0,false,50.0
+2,10.0,0,10.0,false,
+";
+        let data = "type,client,tx,amount,to_client
+transfer,1,1,60.0,2
+transfer,1,2,0.0001,2
+transfer,2,3,70.0001,1
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.seed_accounts(crate::io::read_accounts(accounts.as_bytes()).unwrap());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (1, RejectReason::InsufficientFunds),
+                (2, RejectReason::InsufficientFunds),
+            ]
+        );
+        assert_eq!(engine.account(1).unwrap().available(), dec!(-50.0));
+        assert_eq!(engine.account(2).unwrap().available(), dec!(70.0));
+        assert!(engine.verify().is_empty());
+    }
+
+    #[test]
+    fn test_balances_per_currency() {
+        let data = "type,client,tx,amount,currency
+deposit,1,1,10.0,
+deposit,1,2,5.0,eur
+withdrawal,1,3,1.0,GBP
+withdrawal,1,4,2.0,USD
+dispute,1,2,,
+withdrawal,1,5,1.0,EUR
+resolve,1,2,,
+withdrawal,1,6,1.0,EUR
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (2, RejectReason::InsufficientFunds),
+                (5, RejectReason::InsufficientFunds),
+            ]
+        );
+        let client = engine.account(1).unwrap();
+        assert_eq!(client.available(), dec!(8.0));
+        assert_eq!(client.held(), dec!(0));
+        let eur = client.currency("EUR").unwrap();
+        assert_eq!(eur.available(), dec!(4.0));
+        assert_eq!(eur.total(), dec!(4.0));
+        assert_eq!(client.currency("GBP").unwrap().failed_withdrawals(), 1);
+    }
+
+    #[test]
+    fn test_rejected_rows_open_no_accounts() {
+        let data = "type,client,tx,amount,currency
+deposit,1,1,10.0,
+dispute,1,77,,GBP
+withdrawal,2,2,1.0,
+withdrawal,1,3,5.0,EUR
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (1, RejectReason::UnknownTx),
+                (2, RejectReason::InsufficientFunds),
+                (3, RejectReason::InsufficientFunds),
+            ]
+        );
+        let client = engine.account(1).unwrap();
+        assert!(client.currency("GBP").is_none());
+        // Failed withdrawals are still counted on the account
+        assert_eq!(client.currency("EUR").unwrap().failed_withdrawals(), 1);
+        assert_eq!(engine.account(2).unwrap().failed_withdrawals(), 1);
+    }
+
+    #[test]
+    fn test_chargeback_in_currency_locks_client() {
+        let data = "type,client,tx,amount,currency
+deposit,1,1,10.0,
+deposit,1,2,5.0,EUR
+dispute,1,2,,
+chargeback,1,2,,
+withdrawal,1,3,1.0,
+withdrawal,1,4,1.0,GBP
+unlock,1,0,,
+withdrawal,1,5,1.0,
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (4, RejectReason::AccountLocked),
+                (5, RejectReason::AccountLocked)
+            ]
+        );
+        let client = engine.account(1).unwrap();
+        assert!(!client.is_locked());
+        assert!(!client.currency("EUR").unwrap().is_locked());
+        assert_eq!(client.available(), dec!(9.0));
+
+        let (before, _) = data.split_at(data.find("withdrawal,1,3").unwrap());
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.process(CsvSource::new(before.as_bytes()).transactions());
+        assert!(engine.account(1).unwrap().is_locked());
+    }
+
+    #[test]
+    fn test_dispute_holds_in_currency_of_transaction() {
+        let data = "type,client,tx,amount,currency
+deposit,1,1,10.0,USD
+deposit,1,2,5.0,
+dispute,1,1,,
+";
+        let config = EngineConfig::builder().base_currency("eur").build();
+        let mut engine = Engine::new(config);
+        engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        let client = engine.account(1).unwrap();
+        assert_eq!(client.available(), dec!(5.0));
+        assert_eq!(client.held(), dec!(0));
+        assert_eq!(
+            *client.currency("USD").unwrap(),
+            Client::with_balances(dec!(0), dec!(10.0), false)
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_fees() {
+        let data = "type,client,tx,amount,to_client,fee
+deposit,1,1,10.0,,
+withdrawal,1,2,4.0,,0.5
+withdrawal,1,3,5.0,,0.6
+withdrawal,1,4,5.0,,-0.1
+dispute,1,2,,,
+chargeback,1,2,,,
+";
+        let config = EngineConfig::builder().dispute_withdrawals(true).build();
+        let mut engine = Engine::new(config);
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        // The fee pushes tx 3 over the available funds, nothing of it is
+        // withdrawn
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (2, RejectReason::InsufficientFunds),
+                (3, RejectReason::NonPositiveAmount),
+            ]
+        );
+        let client = engine.account(1).unwrap();
+        assert_eq!(client.fees_paid(), dec!(0.5));
+        assert_eq!(client.failed_withdrawals(), 1);
+        // Charging back the withdrawal credits the amount only
+        assert_eq!(client.available(), dec!(9.5));
+        assert_eq!(client.total(), dec!(9.5));
+        assert_eq!(engine.fees_paid(), dec!(0.5));
+    }
+
+    #[test]
+    fn test_manual_holds_apart_from_disputes() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+hold,1,3,4.0
+dispute,1,2,
+release,1,4,6.0
+hold,1,5,20.0
+resolve,1,2,
+release,1,6,4.0
+release,1,7,1.0
+deposit,2,8,10.0
+deposit,2,9,5.0
+hold,2,10,3.0
+dispute,2,8,
+hold,2,11,5.0
+chargeback,2,8,
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (4, RejectReason::ExcessRelease),
+                (5, RejectReason::InsufficientFunds),
+                (8, RejectReason::ExcessRelease),
+                (13, RejectReason::InsufficientFunds),
+            ]
+        );
+        let client = engine.account(1).unwrap();
+        assert_eq!(*client, Client::with_balances(dec!(15.0), dec!(0), false));
+        assert_eq!(client.manually_held(), dec!(0));
+
+        // The chargeback takes the disputed funds only, the manual hold
+        // stays in place
+        let client = engine.account(2).unwrap();
+        assert_eq!(client.held(), dec!(3.0));
+        assert_eq!(client.manually_held(), dec!(3.0));
+        assert_eq!(client.total(), dec!(5.0));
+        assert!(client.is_locked());
+    }
+
+    #[test]
+    fn test_lock_and_unlock_rows() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+lock,1,2,
+deposit,1,3,1.0
+unlock,1,4,
+deposit,1,5,2.0
+dispute,1,1,
+lock,1,6,
+unlock,1,7,
+deposit,1,8,3.0
+";
+        let run = |force| {
+            let config = EngineConfig::builder().force_unlock(force).build();
+            let mut engine = Engine::with_observer(config, Recorder::default());
+            let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+            (reasons(&rejections), engine)
+        };
+
+        let (rejections, engine) = run(false);
+        assert_eq!(
+            rejections,
+            vec![
+                (2, RejectReason::AccountLocked),
+                (7, RejectReason::OpenDisputes),
+                (8, RejectReason::AccountLocked),
+            ]
+        );
+        assert_eq!(
+            *engine.account(1).unwrap(),
+            Client::with_balances(dec!(2.0), dec!(10.0), true)
+        );
+        let locks: Vec<_> = engine
+            .observer()
+            .0
+            .iter()
+            .filter(|event| matches!(event, Event::Locked(_) | Event::Unlocked(_)))
+            .cloned()
+            .collect();
+        assert_eq!(
+            locks,
+            vec![Event::Locked(1), Event::Unlocked(1), Event::Locked(1)]
+        );
+
+        let (rejections, engine) = run(true);
+        assert_eq!(rejections, vec![(2, RejectReason::AccountLocked)]);
+        assert_eq!(
+            *engine.account(1).unwrap(),
+            Client::with_balances(dec!(5.0), dec!(10.0), false)
+        );
+    }
+
+    #[test]
+    fn test_lock_row_in_a_currency_locks_the_client() {
+        let data = "type,client,tx,amount,currency
+deposit,1,1,10.0,
+deposit,1,2,5.0,EUR
+lock,1,3,,EUR
+withdrawal,1,4,1.0,
+withdrawal,1,5,1.0,EUR
+unlock,1,6,,
+withdrawal,1,7,1.0,EUR
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (3, RejectReason::AccountLocked),
+                (4, RejectReason::AccountLocked),
+            ]
+        );
+        let client = engine.account(1).unwrap();
+        assert!(!client.is_locked());
+        assert_eq!(client.available(), dec!(10.0));
+        assert_eq!(client.currency("EUR").unwrap().available(), dec!(4.0));
+    }
+
+    #[test]
+    fn test_adjustment_corrects_balances() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+adjustment,1,2,2.5
+adjustment,1,3,-15.0
+adjustment,1,3,1.0
+dispute,1,2,
+deposit,2,4,1.0
+dispute,2,4,
+chargeback,2,4,
+adjustment,2,5,3.0
+";
+        let run = |adjust_locked| {
+            let config = EngineConfig::builder()
+                .adjust_locked_accounts(adjust_locked)
+                .build();
+            let mut engine = Engine::new(config);
+            let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+            (reasons(&rejections), engine)
+        };
+
+        let (rejections, engine) = run(true);
+        assert_eq!(
+            rejections,
+            vec![
+                (3, RejectReason::DuplicateTxId),
+                (4, RejectReason::NotADeposit)
+            ]
+        );
+        assert_eq!(
+            *engine.account(1).unwrap(),
+            Client::with_balances(dec!(-2.5), dec!(0), false)
+        );
+        assert_eq!(
+            *engine.account(2).unwrap(),
+            Client::with_balances(dec!(3.0), dec!(0), true)
+        );
+
+        let (rejections, engine) = run(false);
+        assert_eq!(rejections.last(), Some(&(8, RejectReason::AccountLocked)));
+        assert_eq!(
+            *engine.account(2).unwrap(),
+            Client::with_balances(dec!(0), dec!(0), true)
+        );
+    }
+
+    #[test]
+    fn test_config_locked_deposit_policy() {
+        let data = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,1,
+dispute,1,2,
+chargeback,1,1,
+deposit,1,3,4.0
+resolve,1,2,
+deposit,1,4,1.0
+";
+        let run = |policy, auto_unlock| {
+            let config = EngineConfig::builder()
+                .locked_deposit_policy(policy)
+                .auto_unlock(auto_unlock)
+                .build();
+            let mut engine = Engine::new(config);
+            let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+            let client = engine.account(1).unwrap().clone();
+            (
+                reasons(&rejections),
+                client,
+                engine.transaction(1, 3).is_some(),
+            )
+        };
+
+        let (rejections, client, stored) = run(LockedDepositPolicy::Reject, false);
+        assert_eq!(
+            rejections,
+            vec![
+                (5, RejectReason::AccountLocked),
+                (7, RejectReason::AccountLocked)
+            ]
+        );
+        assert_eq!(client, Client::with_balances(dec!(5.0), dec!(0), true));
+        assert!(!stored);
+
+        // Held for good while the client stays locked
+        let (rejections, client, stored) = run(LockedDepositPolicy::Hold, false);
+        assert!(rejections.is_empty());
+        assert_eq!(client.available(), dec!(5.0));
+        assert_eq!(client.held(), dec!(5.0));
+        assert_eq!(client.total(), dec!(10.0));
+        assert!(client.is_locked());
+        assert!(stored);
+
+        // Released once resolving tx 2 unlocks the client
+        let (rejections, client, stored) = run(LockedDepositPolicy::Hold, true);
+        assert!(rejections.is_empty());
+        assert_eq!(client, Client::with_balances(dec!(10.0), dec!(0), false));
+        assert!(stored);
+
+        // Ignored without a trace
+        let (rejections, client, stored) = run(LockedDepositPolicy::Drop, false);
+        assert!(rejections.is_empty());
+        assert_eq!(client, Client::with_balances(dec!(5.0), dec!(0), true));
+        assert!(!stored);
+
+        assert_eq!("hold".parse(), Ok(LockedDepositPolicy::Hold));
+        assert_eq!("drop".parse(), Ok(LockedDepositPolicy::Drop));
+        assert!("keep".parse::<LockedDepositPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_config_dispute_locked_accounts() {
+        let records = || {
+            vec![
+                Ok(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id: 1,
+                    amount: Some(dec!(10.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id: 2,
+                    amount: Some(dec!(5.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Dispute,
+                    client_id: 1,
+                    id: 1,
+                    amount: None,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Chargeback,
+                    client_id: 1,
+                    id: 1,
+                    amount: None,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Dispute,
+                    client_id: 1,
+                    id: 2,
+                    amount: None,
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+                Ok(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id: 3,
+                    amount: Some(dec!(1.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                }),
+            ]
+        };
+
+        let config = EngineConfig::builder()
+            .dispute_locked_accounts(false)
+            .build();
+        let (clients, _) = process_transactions(records(), config);
+        let client_1 = clients.get(&1).unwrap();
+
+        assert_eq!(client_1.available(), dec!(5.0));
+        assert_eq!(client_1.held(), dec!(0));
+        assert_eq!(client_1.total(), dec!(5.0));
+
+        let (clients, _) = process_transactions(records(), EngineConfig::default());
+        let client_1 = clients.get(&1).unwrap();
+
+        // Dispute went through, deposit was still refused
+        assert_eq!(client_1.available(), dec!(0));
+        assert_eq!(client_1.held(), dec!(5.0));
+        assert_eq!(client_1.total(), dec!(5.0));
+        assert!(client_1.is_locked());
+    }
+
+    #[test]
+    fn test_max_open_disputes_per_client() {
+        let config = EngineConfig::builder()
+            .max_open_disputes_per_client(Some(2))
+            .build();
+        let mut engine = Engine::new(config);
+        for id in 1..=4 {
+            engine
+                .apply(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id,
+                    amount: Some(dec!(1.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                })
+                .unwrap();
+        }
+        let dispute = |kind, id| Transaction {
+            kind,
+            client_id: 1,
+            id,
+            amount: None,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+
+        assert!(engine.apply(dispute(TransactionType::Dispute, 1)).is_ok());
+        assert!(engine.apply(dispute(TransactionType::Dispute, 2)).is_ok());
+        assert_eq!(
+            engine.apply(dispute(TransactionType::Dispute, 3)),
+            Err(RejectReason::DisputeLimitReached)
+        );
+        assert_eq!(engine.rejections(RejectReason::DisputeLimitReached), 1);
+
+        // Resolving one dispute allows the next
+        assert!(engine.apply(dispute(TransactionType::Resolve, 1)).is_ok());
+        assert!(engine.apply(dispute(TransactionType::Dispute, 3)).is_ok());
+        assert_eq!(
+            engine.apply(dispute(TransactionType::Dispute, 4)),
+            Err(RejectReason::DisputeLimitReached)
+        );
+        assert_eq!(engine.rejections(RejectReason::DisputeLimitReached), 2);
+
+        let client_1 = engine.account(1).unwrap();
+        assert_eq!(client_1.available(), dec!(2.0));
+        assert_eq!(client_1.held(), dec!(2.0));
+    }
+
+    #[test]
+    fn test_dispute_cap_and_auto_unlock_share_counts() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,1.0
+deposit,1,3,1.0
+dispute,1,1,
+dispute,1,2,
+dispute,1,3,
+chargeback,1,1,
+resolve,1,2,
+deposit,1,4,1.0
+";
+        let config = EngineConfig::builder()
+            .max_open_disputes_per_client(Some(2))
+            .auto_unlock(true)
+            .build();
+        let mut engine = Engine::new(config);
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+
+        // The capped dispute of tx 3 never counts as open, so the client is
+        // unlocked once tx 2 is resolved
+        assert_eq!(
+            reasons(&rejections),
+            vec![(5, RejectReason::DisputeLimitReached)]
+        );
+        assert!(engine.open_disputes.is_empty());
+        let client_1 = engine.account(1).unwrap();
+        assert!(!client_1.is_locked());
+        assert_eq!(client_1.available(), dec!(3.0));
+    }
+
+    #[test]
+    fn test_chargeback_closes_open_dispute() {
+        let config = EngineConfig::builder()
+            .max_open_disputes_per_client(Some(1))
+            .build();
+        let mut engine = Engine::new(config);
+        for id in 1..=2 {
+            engine
+                .apply(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id,
+                    amount: Some(dec!(1.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                })
+                .unwrap();
+        }
+        let dispute = |kind, id| Transaction {
+            kind,
+            client_id: 1,
+            id,
+            amount: None,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+
+        assert!(engine.apply(dispute(TransactionType::Dispute, 1)).is_ok());
+        assert_eq!(
+            engine.apply(dispute(TransactionType::Dispute, 2)),
+            Err(RejectReason::DisputeLimitReached)
+        );
+        assert!(
+            engine
+                .apply(dispute(TransactionType::Chargeback, 1))
+                .is_ok()
+        );
+        assert!(engine.apply(dispute(TransactionType::Dispute, 2)).is_ok());
+    }
+
+    #[test]
+    fn test_dispute_lifecycle_transitions() {
+        let mut engine = Engine::new(EngineConfig::default());
+        for id in 1..=2 {
+            engine
+                .apply(Transaction {
+                    kind: TransactionType::Deposit,
+                    client_id: 1,
+                    id,
+                    amount: Some(dec!(5.0)),
+                    to_client: None,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                })
+                .unwrap();
+        }
+        let dispute = |kind, id| Transaction {
+            kind,
+            client_id: 1,
+            id,
+            amount: None,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+
+        // A resolved dispute may be reopened, but not charged back
+        assert!(engine.apply(dispute(TransactionType::Dispute, 1)).is_ok());
+        assert!(engine.apply(dispute(TransactionType::Resolve, 1)).is_ok());
+        assert_eq!(
+            engine.transaction(1, 1).unwrap().dispute_state(),
+            DisputeState::Resolved
+        );
+        assert_eq!(
+            engine.apply(dispute(TransactionType::Chargeback, 1)),
+            Err(RejectReason::NotDisputed)
+        );
+        assert!(engine.apply(dispute(TransactionType::Dispute, 1)).is_ok());
+        assert_eq!(
+            engine.transaction(1, 1).unwrap().dispute_state(),
+            DisputeState::Disputed
+        );
+
+        // A charged back transaction can not be disputed again
+        assert!(
+            engine
+                .apply(dispute(TransactionType::Chargeback, 1))
+                .is_ok()
+        );
+        assert_eq!(
+            engine.apply(dispute(TransactionType::Dispute, 1)),
+            Err(RejectReason::AlreadyChargedBack)
+        );
+        assert_eq!(
+            engine.apply(dispute(TransactionType::Chargeback, 1)),
+            Err(RejectReason::NotDisputed)
+        );
+
+        let client_1 = engine.account(1).unwrap();
+        assert_eq!(client_1.available(), dec!(5.0));
+        assert_eq!(client_1.held(), dec!(0));
+        assert_eq!(client_1.total(), dec!(5.0));
+        assert!(client_1.is_locked());
+        assert_eq!(engine.disputed_transactions().count(), 0);
+    }
+
+    #[test]
+    fn test_redispute_policy() {
+        let held_after_redispute = |policy| {
+            let config = EngineConfig::builder().redispute_policy(policy).build();
+            let mut engine = Engine::new(config);
+            let transaction = |kind, amount| Transaction {
+                kind,
+                client_id: 1,
+                id: 1,
+                amount,
+                to_client: None,
+                fee: None,
+                currency: None,
+                timestamp: None,
+            };
+            engine
+                .apply(transaction(TransactionType::Deposit, Some(dec!(3.0))))
+                .unwrap();
+            engine
+                .apply(transaction(TransactionType::Dispute, None))
+                .unwrap();
+            engine
+                .apply(transaction(TransactionType::Resolve, None))
+                .unwrap();
+            let redispute = engine.apply(transaction(TransactionType::Dispute, None));
+            (redispute.err(), engine.account(1).unwrap().held())
+        };
+
+        assert_eq!(
+            held_after_redispute(RedisputePolicy::AllowAlways),
+            (None, dec!(3.0))
+        );
+        assert_eq!(
+            held_after_redispute(RedisputePolicy::DenyAfterResolve),
+            (Some(RejectReason::RedisputeDenied), dec!(0))
+        );
+        assert_eq!(
+            held_after_redispute(RedisputePolicy::DenyAfterAnyClosure),
+            (Some(RejectReason::RedisputeDenied), dec!(0))
+        );
+        assert_eq!(
+            "deny-after-resolve".parse(),
+            Ok(RedisputePolicy::DenyAfterResolve)
+        );
+        assert!("never".parse::<RedisputePolicy>().is_err());
+    }
+
+    /// Source yielding a scripted mix of transactions and parse errors
+    struct MockSource(Vec<Option<Transaction>>);
+
+    impl TransactionSource for MockSource {
+        fn transactions(self) -> impl Iterator<Item = Result<Transaction>> {
+            self.0
+                .into_iter()
+                .map(|t| t.ok_or_else(|| anyhow::anyhow!("unparsable row")))
+        }
+    }
+
+    #[test]
+    fn test_source_with_parse_errors_mid_stream() {
+        let deposit = |id, amount| Transaction {
+            kind: TransactionType::Deposit,
+            client_id: 1,
+            id,
+            amount: Some(amount),
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        };
+        let source = MockSource(vec![
+            Some(deposit(1, dec!(1.0))),
+            None,
+            Some(deposit(2, dec!(2.0))),
+            None,
+            Some(deposit(2, dec!(5.0))),
+        ]);
+
+        let mut engine = Engine::new(EngineConfig::default());
+        let rejections = engine.process(source.transactions());
+
+        // Parse errors are skipped but still count as rows
+        assert_eq!(reasons(&rejections), vec![(4, RejectReason::DuplicateTxId)]);
+        assert_eq!(engine.account(1).unwrap().available(), dec!(3.0));
+    }
+
+    fn shard_engine(data: &str) -> Engine {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.process(CsvSource::new(data.as_bytes()).transactions());
+        engine
+    }
+
+    #[test]
+    fn test_merge_client_shards() {
+        let shard_1 = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,3,4.0
+deposit,1,5,2.0
+dispute,1,5,
+deposit,1,6,1.0
+dispute,1,6,
+chargeback,1,6,
+";
+        let shard_2 = "type,client,tx,amount
+deposit,2,2,5.0
+dispute,2,2,
+withdrawal,2,4,1.0
+";
+        let combined = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,4.0
+dispute,2,2,
+withdrawal,2,4,1.0
+deposit,1,5,2.0
+dispute,1,5,
+deposit,1,6,1.0
+dispute,1,6,
+chargeback,1,6,
+";
+
+        let merged = shard_engine(shard_1).merge(shard_engine(shard_2)).unwrap();
+        let single = shard_engine(combined);
+
+        assert_eq!(merged.snapshot(), single.snapshot());
+        assert_eq!(merged.open_disputes, single.open_disputes);
+        assert_eq!(
+            merged.rejections(RejectReason::InsufficientFunds),
+            single.rejections(RejectReason::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn test_merge_conflicting_shards() {
+        let shard_1 = shard_engine("type,client,tx,amount\ndeposit,1,1,10.0\n");
+        let shard_2 = shard_engine("type,client,tx,amount\ndeposit,1,2,3.0\n");
+        assert_eq!(
+            shard_1.merge(shard_2).unwrap_err(),
+            MergeError::ClientConflict(1)
+        );
+
+        let shard_1 = shard_engine("type,client,tx,amount\ndeposit,1,1,10.0\n");
+        let shard_2 = shard_engine("type,client,tx,amount\ndeposit,2,1,3.0\n");
+        assert_eq!(
+            shard_1.merge(shard_2).unwrap_err(),
+            MergeError::TransactionConflict { client: 2, tx: 1 }
+        );
+    }
+
+    #[test]
+    fn test_merge_keeps_daily_withdrawals_and_timestamps() {
+        let config = EngineConfig::builder()
+            .daily_withdrawal_limit(Some(dec!(50.0)))
+            .enforce_monotonic_timestamps(true)
+            .build();
+        let shard = |data: &str| {
+            let mut engine = Engine::new(config.clone());
+            engine.process(CsvSource::new(data.as_bytes()).transactions());
+            engine
+        };
+        let shard_1 = shard(
+            "type,client,tx,amount,ts
+deposit,1,1,100.0,2024-03-01T08:00:00Z
+withdrawal,1,2,40.0,2024-03-01T09:00:00Z
+",
+        );
+        let shard_2 = shard(
+            "type,client,tx,amount,ts
+deposit,2,3,10.0,2024-03-01T12:00:00Z
+",
+        );
+
+        let mut merged = shard_1.merge(shard_2).unwrap();
+        let data = "type,client,tx,amount,ts
+withdrawal,1,4,20.0,2024-03-01T10:00:00Z
+withdrawal,1,5,20.0,2024-03-01T13:00:00Z
+withdrawal,1,6,5.0,2024-03-01T14:00:00Z
+";
+        let rejections = merged.process(CsvSource::new(data.as_bytes()).transactions());
+        assert_eq!(
+            reasons(&rejections),
+            vec![
+                (0, RejectReason::OutOfOrder),
+                (1, RejectReason::DailyLimitExceeded),
+            ]
+        );
+        assert_eq!(merged.account(1).unwrap().available(), dec!(55.0));
+    }
+}
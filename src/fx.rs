@@ -0,0 +1,168 @@
+//! Conversion of the balances in other currencies into the base currency,
+//! for reports consolidating every client into a single account.
+
+use crate::io::csv_reader;
+use crate::model::{Client, ClientId};
+use ahash::{HashMap, HashMapExt};
+use anyhow::{Result, bail};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Read;
+
+/// Exchange rates of currencies to the base currency
+#[derive(Debug, Default, Clone)]
+pub struct Rates(HashMap<String, Decimal>);
+
+/// Single row of a rates file
+#[derive(Debug, Deserialize)]
+struct RateRecord {
+    currency: String,
+    rate: Decimal,
+}
+
+impl Rates {
+    /// Read `currency,rate` rows, the rate being the value of one unit of
+    /// the currency in the base currency. Every currency may only appear
+    /// once, with a positive rate.
+    pub fn read<R: Read>(rdr: R) -> Result<Self> {
+        let mut rates = HashMap::new();
+        for record in csv_reader(rdr).deserialize::<RateRecord>() {
+            let record = record?;
+            let currency = record.currency.trim().to_ascii_uppercase();
+            if record.rate <= Decimal::ZERO {
+                bail!("rate {} of {currency} is not positive", record.rate);
+            }
+            if rates.insert(currency.clone(), record.rate).is_some() {
+                bail!("currency {currency} appears more than once");
+            }
+        }
+        Ok(Self(rates))
+    }
+
+    pub fn rate(&self, currency: &str) -> Option<Decimal> {
+        self.0.get(currency).copied()
+    }
+
+    /// Amount in the base currency, rounded to four decimal places with
+    /// ties to even. None without a rate or on overflow.
+    pub fn convert(&self, currency: &str, amount: Decimal) -> Option<Decimal> {
+        let converted = amount.checked_mul(self.rate(currency)?)?;
+        Some(converted.round_dp_with_strategy(4, RoundingStrategy::MidpointNearestEven))
+    }
+}
+
+/// Reason the accounts could not be consolidated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsolidationError {
+    /// Currencies held by some client without a rate, in order
+    MissingRates(Vec<String>),
+    /// Converted balances of the client leave the representable range
+    Overflow(ClientId),
+}
+
+impl fmt::Display for ConsolidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsolidationError::MissingRates(currencies) => {
+                write!(f, "no rate for {}", currencies.join(", "))
+            }
+            ConsolidationError::Overflow(client) => {
+                write!(f, "balances of client {client} overflow when consolidated")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConsolidationError {}
+
+/// Fold the accounts of every client in other currencies into its
+/// balances in the base currency. Nothing is converted unless there is a
+/// rate for every currency.
+pub fn consolidate(
+    mut clients: HashMap<ClientId, Client>,
+    rates: &Rates,
+) -> Result<HashMap<ClientId, Client>, ConsolidationError> {
+    let missing: BTreeSet<&str> = clients
+        .values()
+        .flat_map(Client::currencies)
+        .map(|(currency, _)| currency)
+        .filter(|currency| rates.rate(currency).is_none())
+        .collect();
+    if !missing.is_empty() {
+        return Err(ConsolidationError::MissingRates(
+            missing.into_iter().map(str::to_string).collect(),
+        ));
+    }
+    for (&client_id, client) in clients.iter_mut() {
+        client
+            .consolidate(|currency, amount| rates.convert(currency, amount))
+            .map_err(|_| ConsolidationError::Overflow(client_id))?;
+    }
+    Ok(clients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::CsvSource;
+    use crate::{Engine, EngineConfig, TransactionSource};
+    use rust_decimal::dec;
+
+    fn accounts(data: &str) -> HashMap<ClientId, Client> {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.process(CsvSource::new(data.as_bytes()).transactions());
+        engine.into_accounts()
+    }
+
+    #[test]
+    fn test_consolidate_converts_and_rounds() {
+        let clients = accounts(
+            "type,client,tx,amount,currency
+deposit,1,1,10.0,
+deposit,1,2,3.00005,EUR
+deposit,1,3,1000,JPY
+dispute,1,3,,
+deposit,2,4,2.5,eur
+",
+        );
+        let rates =
+            Rates::read("currency,rate\nEUR,1.0\njpy,0.00675\nGBP,1.27\n".as_bytes()).unwrap();
+
+        let clients = consolidate(clients, &rates).unwrap();
+        // 3.00005 rounds to even, 1000 JPY are 6.75 held
+        let client = &clients[&1];
+        assert_eq!(
+            (client.available(), client.held(), client.total()),
+            (dec!(13.0000), dec!(6.75), dec!(19.75))
+        );
+        assert!(client.currencies().next().is_none());
+        assert_eq!(clients[&2].available(), dec!(2.5));
+    }
+
+    #[test]
+    fn test_consolidate_requires_every_rate() {
+        let clients = accounts(
+            "type,client,tx,amount,currency
+deposit,1,1,1.0,JPY
+deposit,2,2,1.0,EUR
+deposit,2,3,1.0,CHF
+",
+        );
+        let rates = Rates::read("currency,rate\nEUR,1.08\n".as_bytes()).unwrap();
+
+        let err = consolidate(clients, &rates).unwrap_err();
+        assert_eq!(
+            err,
+            ConsolidationError::MissingRates(vec!["CHF".to_string(), "JPY".to_string()])
+        );
+        assert_eq!(err.to_string(), "no rate for CHF, JPY");
+    }
+
+    #[test]
+    fn test_read_rates_rejects_invalid_rows() {
+        assert!(Rates::read("currency,rate\nEUR,0\n".as_bytes()).is_err());
+        assert!(Rates::read("currency,rate\nEUR,1.1\neur,1.2\n".as_bytes()).is_err());
+    }
+}
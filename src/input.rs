@@ -0,0 +1,706 @@
+//! Opening of the transaction inputs, decompressing them on the way when
+//! they are compressed and unpacking the CSV members of zip archives.
+
+use encoding_rs::WINDOWS_1252;
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use flate2::bufread::MultiGzDecoder;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use zip::ZipArchive;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// Name standing for stdin in place of a file name
+pub const STDIN: &str = "-";
+
+/// Format of the transactions in an input
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Rows with a `type,client,tx,amount` header
+    #[default]
+    Csv,
+    /// A JSON object per line, see [`crate::io::JsonLinesSource`]
+    JsonLines,
+    /// Parquet files, see [`crate::columnar::ParquetSource`]
+    Parquet,
+    /// Arrow IPC streams or files, see [`crate::columnar::ArrowSource`]
+    Arrow,
+    /// Avro object container files, see [`crate::avro::AvroSource`]
+    Avro,
+    /// A stream of MessagePack maps, see [`crate::io::MsgpackSource`]
+    Msgpack,
+    /// A length delimited stream of protobuf messages, see
+    /// [`crate::proto::ProtoSource`]
+    Proto,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" => Ok(InputFormat::JsonLines),
+            "parquet" => Ok(InputFormat::Parquet),
+            "arrow" => Ok(InputFormat::Arrow),
+            "avro" => Ok(InputFormat::Avro),
+            "msgpack" => Ok(InputFormat::Msgpack),
+            "proto" => Ok(InputFormat::Proto),
+            other => Err(format!(
+                "unknown input format {other}, expected csv, jsonl, parquet, arrow, avro, msgpack or proto"
+            )),
+        }
+    }
+}
+
+/// Character encoding of text input
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    /// Read as is, the bytes are expected to be UTF-8
+    #[default]
+    Utf8,
+    /// Windows-1252, the superset of ISO 8859-1 written by Windows systems
+    Latin1,
+    /// UTF-8, UTF-16LE or UTF-16BE as told by a byte order mark, UTF-8
+    /// without one
+    Auto,
+}
+
+impl TextEncoding {
+    /// Reader transcoding the input to UTF-8
+    pub fn decode<'a>(self, rdr: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+        match self {
+            TextEncoding::Utf8 => rdr,
+            TextEncoding::Latin1 => Box::new(
+                DecodeReaderBytesBuilder::new()
+                    .encoding(Some(WINDOWS_1252))
+                    .build(rdr),
+            ),
+            TextEncoding::Auto => {
+                Box::new(DecodeReaderBytesBuilder::new().strip_bom(true).build(rdr))
+            }
+        }
+    }
+}
+
+impl FromStr for TextEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(TextEncoding::Utf8),
+            "latin1" => Ok(TextEncoding::Latin1),
+            "auto" => Ok(TextEncoding::Auto),
+            other => Err(format!(
+                "unknown encoding {other}, expected utf8, latin1 or auto"
+            )),
+        }
+    }
+}
+
+/// Compression of an input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Compression implied by the extension of a file name, if any
+    pub fn from_extension(name: &str) -> Option<Self> {
+        match Path::new(name).extension()?.to_str()? {
+            "gz" => Some(Self::Gzip),
+            "zst" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compression recognised from the first bytes of an input
+    pub fn from_magic(head: &[u8]) -> Self {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Wrap a reader into the decoder of its compression. Decoders stream,
+/// only holding a window of the input in memory.
+pub fn decoder<'a, R: BufRead + 'a>(
+    compression: Compression,
+    rdr: R,
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(rdr),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(rdr)),
+        Compression::Zstd => Box::new(ZstdDecoder::with_buffer(rdr)?),
+    })
+}
+
+/// UTF-8 byte order mark, which spreadsheet programs put in front of text
+/// exports
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Reader dropping a UTF-8 byte order mark at the start of its input, so
+/// it does not end up in the first column name or value
+pub struct SkipBom<R> {
+    inner: R,
+    /// Bytes read while looking for the mark which are not part of one
+    head: Vec<u8>,
+    pos: usize,
+    checked: bool,
+}
+
+impl<R: Read> SkipBom<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            head: Vec::new(),
+            pos: 0,
+            checked: false,
+        }
+    }
+}
+
+impl<R: Read> Read for SkipBom<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.checked {
+            let mut head = [0; BOM.len()];
+            let mut len = 0;
+            while len < head.len() {
+                match self.inner.read(&mut head[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            if &head[..len] != BOM {
+                self.head = head[..len].to_vec();
+            }
+            self.checked = true;
+        }
+        if self.pos < self.head.len() {
+            let n = buf.len().min(self.head.len() - self.pos);
+            buf[..n].copy_from_slice(&self.head[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Reader following a growing file like `tail -f`. At the end of the file
+/// it polls for more bytes instead of ending, and it only hands out
+/// complete lines, so a line still being written is held back until its
+/// newline arrives. Once `stop` is set it ends at the next line boundary.
+pub struct Follow<R> {
+    inner: R,
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+    pending: Vec<u8>,
+    pos: usize,
+    /// End of the complete lines in `pending`
+    complete: usize,
+}
+
+impl<R: Read> Follow<R> {
+    pub fn new(inner: R, interval: Duration, stop: Arc<AtomicBool>) -> Self {
+        Self {
+            inner,
+            interval,
+            stop,
+            pending: Vec::new(),
+            pos: 0,
+            complete: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Follow<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.complete {
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(0);
+            }
+            self.pending.drain(..self.complete);
+            self.pos = 0;
+            self.complete = 0;
+            let mut chunk = [0; 8 * 1024];
+            match self.inner.read(&mut chunk)? {
+                0 => thread::sleep(self.interval),
+                n => {
+                    self.pending.extend_from_slice(&chunk[..n]);
+                    if let Some(end) = self.pending.iter().rposition(|&b| b == b'\n') {
+                        self.complete = end + 1;
+                    }
+                }
+            }
+        }
+        let n = buf.len().min(self.complete - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Single input of transactions, a file, stdin or a member of a zip archive
+pub struct Input {
+    name: String,
+    source: Source,
+}
+
+enum Source {
+    Stream(Box<dyn Read>),
+    Mapped(Mmap),
+    /// Every member has its own handle on the archive, so the readers of
+    /// all members can be held at once
+    ZipMember(ZipArchive<File>, usize),
+}
+
+impl Input {
+    /// Name of the input, `archive.zip/member.csv` for archive members
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Reader of the contents of the input. Errors, including those of a
+    /// corrupt compressed stream, name the input.
+    pub fn reader(&mut self) -> io::Result<Box<dyn Read + '_>> {
+        let inner: Box<dyn Read + '_> = match &mut self.source {
+            Source::Stream(rdr) => Box::new(rdr),
+            Source::Mapped(map) => Box::new(&map[..]),
+            Source::ZipMember(archive, index) => Box::new(
+                archive
+                    .by_index(*index)
+                    .map_err(|e| named(&self.name, e.into()))?,
+            ),
+        };
+        Ok(Box::new(Named {
+            name: self.name.clone(),
+            inner,
+        }))
+    }
+}
+
+/// Open the inputs of a file of transactions, or of stdin for [`STDIN`].
+///
+/// A `.zip` archive opens to an input per CSV member, in the order of
+/// their names, other members are skipped. Anything else is a single
+/// input, decompressed if its extension says so. Inputs without a known
+/// extension are recognised from their first bytes instead. An http(s)
+/// URL is fetched and its body streamed, with the `http` feature, as is
+/// an `s3://bucket/key` object with the `s3` feature.
+pub fn open(name: &str) -> io::Result<Vec<Input>> {
+    let is_zip = Path::new(name)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+    if is_zip && name != STDIN && !is_url(name) {
+        return open_zip(name).map_err(|e| named(name, e));
+    }
+    Ok(vec![Input {
+        name: name.to_string(),
+        source: Source::Stream(open_stream(name)?),
+    }])
+}
+
+/// Open the inputs of a file like [`open`], mapping the file into memory
+/// rather than reading it. Stdin, URLs, archives, compressed and empty
+/// files are opened by [`open`] instead, as mapping them gains nothing.
+///
+/// The map is only sound as long as no other process truncates or
+/// changes the file, which is then undefined behavior, typically ending
+/// the run with SIGBUS.
+pub fn open_mapped(name: &str) -> io::Result<Vec<Input>> {
+    let by_name = name == STDIN
+        || is_url(name)
+        || Compression::from_extension(name).is_some()
+        || Path::new(name)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+    if by_name {
+        return open(name);
+    }
+    let file = File::open(name).map_err(|e| named(name, e))?;
+    if file.metadata().map_err(|e| named(name, e))?.len() == 0 {
+        return open(name);
+    }
+    // SAFETY: the file is only read through the map, the caller is told
+    // the file must not change while it is mapped
+    let map = unsafe { Mmap::map(&file) }.map_err(|e| named(name, e))?;
+    if Compression::from_magic(&map) != Compression::None {
+        return open(name);
+    }
+    Ok(vec![Input {
+        name: name.to_string(),
+        source: Source::Mapped(map),
+    }])
+}
+
+/// Open a plain local file to be followed as it grows, see [`Follow`].
+/// Stdin, URLs, archives and compressed files can not be followed.
+pub fn open_followed(
+    name: &str,
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+) -> io::Result<Vec<Input>> {
+    let by_name = name == STDIN
+        || is_url(name)
+        || Compression::from_extension(name).is_some()
+        || Path::new(name)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+    if by_name {
+        return Err(named(
+            name,
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only plain local files can be followed",
+            ),
+        ));
+    }
+    let file = File::open(name).map_err(|e| named(name, e))?;
+    Ok(vec![Input {
+        name: name.to_string(),
+        source: Source::Stream(Box::new(Follow::new(file, interval, stop))),
+    }])
+}
+
+/// Inputs of the CSV members of a zip archive, sorted by name
+fn open_zip(name: &str) -> io::Result<Vec<Input>> {
+    let mut archive = ZipArchive::new(File::open(name)?)?;
+    let mut members = Vec::new();
+    for index in 0..archive.len() {
+        let member = archive.by_index_raw(index)?;
+        let is_csv = Path::new(member.name())
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        if !member.is_file() || !is_csv {
+            continue;
+        }
+        if member.encrypted() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} is password protected, which is not supported",
+                    member.name()
+                ),
+            ));
+        }
+        members.push((member.name().to_string(), index));
+    }
+    members.sort();
+    members
+        .into_iter()
+        .map(|(member, index)| {
+            Ok(Input {
+                name: format!("{name}/{member}"),
+                source: Source::ZipMember(ZipArchive::new(File::open(name)?)?, index),
+            })
+        })
+        .collect()
+}
+
+/// Whether an input name is an http(s) or S3 URL rather than a file name
+pub fn is_url(name: &str) -> bool {
+    name.starts_with("http://") || name.starts_with("https://") || name.starts_with("s3://")
+}
+
+/// Open a file, URL or stdin, decompressing it as needed
+fn open_stream(name: &str) -> io::Result<Box<dyn Read>> {
+    let mut rdr: Box<dyn BufRead> = if name == STDIN {
+        Box::new(BufReader::new(io::stdin().lock()))
+    } else if is_url(name) {
+        Box::new(BufReader::new(get(name).map_err(|e| named(name, e))?))
+    } else {
+        let file = File::open(name).map_err(|e| named(name, e))?;
+        Box::new(BufReader::new(file))
+    };
+    let compression = match Compression::from_extension(name) {
+        Some(compression) => compression,
+        None => Compression::from_magic(rdr.fill_buf().map_err(|e| named(name, e))?),
+    };
+    decoder(compression, rdr).map_err(|e| named(name, e))
+}
+
+/// Stream the body of a successful response to a GET request, or the
+/// contents of an S3 object
+#[cfg(feature = "http")]
+fn get(url: &str) -> io::Result<Box<dyn Read>> {
+    if url.starts_with("s3://") {
+        return get_s3(url);
+    }
+    match ureq::get(url).call() {
+        Ok(response) => Ok(Box::new(response.into_body().into_reader())),
+        Err(ureq::Error::StatusCode(status)) => Err(io::Error::other(format!(
+            "server responded with status {status}"
+        ))),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+#[cfg(not(feature = "http"))]
+fn get(url: &str) -> io::Result<Box<dyn Read>> {
+    if url.starts_with("s3://") {
+        return get_s3(url);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading from URLs needs the http feature",
+    ))
+}
+
+#[cfg(feature = "s3")]
+fn get_s3(url: &str) -> io::Result<Box<dyn Read>> {
+    let object = crate::s3::S3Object::parse(url)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "expected s3://bucket/key"))?;
+    crate::s3::get(&object)
+}
+
+#[cfg(not(feature = "s3"))]
+fn get_s3(_url: &str) -> io::Result<Box<dyn Read>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading from S3 needs the s3 feature",
+    ))
+}
+
+/// Prefix an error with the name of the input it occurred in
+fn named(name: &str, e: io::Error) -> io::Error {
+    let name = if name == STDIN { "stdin" } else { name };
+    io::Error::new(e.kind(), format!("{name}: {e}"))
+}
+
+/// Reader naming its input in the errors it returns
+struct Named<R> {
+    name: String,
+    inner: R,
+}
+
+impl<R: Read> Read for Named<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| named(&self.name, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    const DATA: &[u8] = b"type,client,tx,amount\ndeposit,1,1,1.0\n";
+
+    #[test]
+    fn test_text_encodings_decoded_to_utf8() {
+        let decode = |encoding: TextEncoding, data: Vec<u8>| {
+            let mut out = String::new();
+            encoding
+                .decode(Box::new(io::Cursor::new(data)))
+                .read_to_string(&mut out)
+                .unwrap();
+            out
+        };
+        assert_eq!(
+            decode(TextEncoding::Latin1, b"Jos\xe9 M\xfcller \x80".to_vec()),
+            "José Müller €"
+        );
+
+        let utf16 = |bom: &[u8], to_bytes: fn(u16) -> [u8; 2]| {
+            let units = "type,client\ndéposit".encode_utf16();
+            [bom.to_vec(), units.flat_map(to_bytes).collect()].concat()
+        };
+        for data in [
+            utf16(b"\xff\xfe", u16::to_le_bytes),
+            utf16(b"\xfe\xff", u16::to_be_bytes),
+            "\u{feff}type,client\ndéposit".as_bytes().to_vec(),
+        ] {
+            assert_eq!(decode(TextEncoding::Auto, data), "type,client\ndéposit");
+        }
+        // Without a byte order mark input is taken for UTF-8, as is
+        assert_eq!(decode(TextEncoding::Auto, b"caf\xc3\xa9".to_vec()), "café");
+        assert_eq!(decode(TextEncoding::Utf8, DATA.to_vec()).as_bytes(), DATA);
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        zstd::encode_all(data, 0).unwrap()
+    }
+
+    fn decode(compression: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        decoder(compression, data)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_compression_detection() {
+        assert_eq!(
+            Compression::from_extension("tx.csv.gz"),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            Compression::from_extension("tx.csv.zst"),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::from_extension("tx.csv"), None);
+        assert_eq!(Compression::from_extension("tx"), None);
+        assert_eq!(Compression::from_magic(&gzip(b"type")), Compression::Gzip);
+        assert_eq!(Compression::from_magic(&zstd(b"type")), Compression::Zstd);
+        assert_eq!(Compression::from_magic(b"type,client"), Compression::None);
+        assert_eq!(Compression::from_magic(b""), Compression::None);
+    }
+
+    #[test]
+    fn test_plain_decoder() {
+        assert_eq!(decode(Compression::None, DATA).unwrap(), DATA);
+    }
+
+    #[test]
+    fn test_gzip_decoder() {
+        assert_eq!(decode(Compression::Gzip, &gzip(DATA)).unwrap(), DATA);
+    }
+
+    #[test]
+    fn test_zstd_decoder() {
+        assert_eq!(decode(Compression::Zstd, &zstd(DATA)).unwrap(), DATA);
+
+        // Concatenated frames decode as one stream
+        let mut frames = zstd(&DATA[..10]);
+        frames.extend(zstd(&DATA[10..]));
+        assert_eq!(decode(Compression::Zstd, &frames).unwrap(), DATA);
+
+        let compressed = zstd(DATA);
+        assert!(decode(Compression::Zstd, &compressed[..compressed.len() / 2]).is_err());
+    }
+
+    fn skip_bom(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        SkipBom::new(data).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_bom_skipped() {
+        assert_eq!(skip_bom(&[BOM, DATA].concat()), DATA);
+        assert_eq!(skip_bom(DATA), DATA);
+        assert_eq!(skip_bom(b"ty"), b"ty");
+        assert_eq!(skip_bom(b""), b"");
+        // Only a mark at the very start is dropped
+        assert_eq!(skip_bom(&[b"a", BOM].concat()), [b"a", BOM].concat());
+
+        // A mark split across reads is still recognised
+        let split = io::Cursor::new(&BOM[..1]).chain(io::Cursor::new([&BOM[1..], DATA].concat()));
+        let mut out = Vec::new();
+        SkipBom::new(split).read_to_end(&mut out).unwrap();
+        assert_eq!(out, DATA);
+    }
+
+    #[test]
+    fn test_truncated_gzip_names_the_input() {
+        let compressed = gzip(DATA);
+        let mut rdr = Named {
+            name: "tx.csv.gz".to_string(),
+            inner: decoder(Compression::Gzip, &compressed[..compressed.len() / 2]).unwrap(),
+        };
+        let e = rdr.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(e.to_string().starts_with("tx.csv.gz: "), "{e}");
+    }
+
+    #[test]
+    fn test_zip_members_in_name_order() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/daily.zip");
+        let mut inputs = open(path).unwrap();
+        let names: Vec<_> = inputs.iter().map(Input::name).collect();
+        assert_eq!(
+            names,
+            [
+                format!("{path}/1_deposits.csv"),
+                format!("{path}/2_disputes.csv")
+            ]
+        );
+
+        let mut out = String::new();
+        inputs[0]
+            .reader()
+            .unwrap()
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, "type,client,tx,amount\ndeposit,1,1,5.0\n");
+    }
+
+    fn contents(mut inputs: Vec<Input>) -> Vec<u8> {
+        let mut out = Vec::new();
+        for input in &mut inputs {
+            input.reader().unwrap().read_to_end(&mut out).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn test_mapped_inputs() {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+        for name in ["single_client.csv", "single_client.csv.gz", "daily.zip"] {
+            let path = format!("{fixtures}/{name}");
+            let mapped = open_mapped(&path).unwrap();
+            assert_eq!(
+                matches!(mapped[0].source, Source::Mapped(_)),
+                name.ends_with(".csv")
+            );
+            assert_eq!(contents(mapped), contents(open(&path).unwrap()));
+        }
+
+        let empty = std::env::temp_dir().join("transaction_engine_empty.csv");
+        File::create(&empty).unwrap();
+        let inputs = open_mapped(empty.to_str().unwrap()).unwrap();
+        assert!(matches!(inputs[0].source, Source::Stream(_)));
+        assert!(contents(inputs).is_empty());
+    }
+
+    #[test]
+    fn test_follow_holds_back_partial_lines() {
+        let path = std::env::temp_dir().join("transaction_engine_follow.csv");
+        std::fs::write(&path, "type\n1\n2").unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let appender = {
+            let (path, stop) = (path.clone(), stop.clone());
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+                std::io::Write::write_all(&mut file, b"0\n3").unwrap();
+                thread::sleep(Duration::from_millis(50));
+                stop.store(true, Ordering::Relaxed);
+            })
+        };
+        let inputs = open_followed(path.to_str().unwrap(), Duration::from_millis(5), stop).unwrap();
+        // The line without a newline when following stopped is not read
+        assert_eq!(contents(inputs), b"type\n1\n20\n");
+        appender.join().unwrap();
+
+        let e = open_followed("tx.csv.gz", Duration::ZERO, Arc::default())
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("only plain local files"), "{e}");
+    }
+
+    #[test]
+    fn test_password_protected_zip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/encrypted.zip");
+        let e = open(path).err().unwrap();
+        assert!(e.to_string().contains("password protected"), "{e}");
+        assert!(e.to_string().starts_with(path), "{e}");
+    }
+}
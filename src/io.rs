@@ -0,0 +1,1637 @@
+use crate::config::{DEFAULT_BASE_CURRENCY, ScientificAmounts};
+use crate::model::{Client, ClientId, FourPlaces, Transaction};
+use ahash::{HashMap, HashMapExt};
+use anyhow::{Result, anyhow, bail};
+use csv::{ByteRecord, Reader, ReaderBuilder, StringRecord, Trim, Writer, WriterBuilder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, Serializer};
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+
+/// Layout of the accounts output.
+///
+/// Downstream parsers can pin a version, so columns added by newer
+/// versions never change the output they see.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OutputVersion {
+    /// `client,available,held,total,locked`
+    #[default]
+    V1,
+    /// `client,available,held,total,locked,failed_withdrawals`
+    V2,
+    /// `client,available,held,total,locked,failed_withdrawals,fees_paid`
+    V3,
+    /// `client,available,held,total,locked,failed_withdrawals,fees_paid,currency`
+    /// with a row per client and currency, ordered by client and currency.
+    /// Earlier versions only write the base currency.
+    V4,
+    /// Version 4 with a `flagged` column added
+    V5,
+    /// Version 5 with `credit_limit` and `overdrawn` columns added, the
+    /// credit limit is empty for clients without a credit line
+    V6,
+}
+
+impl FromStr for OutputVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(OutputVersion::V1),
+            "2" => Ok(OutputVersion::V2),
+            "3" => Ok(OutputVersion::V3),
+            "4" => Ok(OutputVersion::V4),
+            "5" => Ok(OutputVersion::V5),
+            "6" => Ok(OutputVersion::V6),
+            other => Err(format!(
+                "unsupported output version {other}, supported versions: 1 to 6"
+            )),
+        }
+    }
+}
+
+/// Format of the accounts output
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// CSV in the layout of an [`OutputVersion`], see [`CsvAccountsWriter`]
+    #[default]
+    Csv,
+    /// A JSON array of accounts, see [`JsonAccountsWriter`]
+    Json,
+    /// A JSON account per line
+    JsonLines,
+    /// An aligned table for reading in a terminal, see
+    /// [`TableAccountsWriter`]
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "table" => Ok(OutputFormat::Table),
+            other => Err(format!(
+                "unknown output format {other}, expected csv, json, jsonl or table"
+            )),
+        }
+    }
+}
+
+/// Column the accounts output is sorted by
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccountSort {
+    #[default]
+    Client,
+    /// Total funds of the client in the base currency
+    Total,
+}
+
+impl FromStr for AccountSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(AccountSort::Client),
+            "total" => Ok(AccountSort::Total),
+            other => Err(format!(
+                "unknown sort order {other}, expected client or total"
+            )),
+        }
+    }
+}
+
+/// Order the accounts are written in, so the output of the same input is
+/// always the same
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AccountOrder {
+    pub by: AccountSort,
+    pub descending: bool,
+}
+
+impl AccountOrder {
+    /// Clients in this order, those of equal totals by ascending client id
+    pub fn sorted<'a>(
+        &self,
+        clients: &'a HashMap<ClientId, Client>,
+    ) -> Vec<(ClientId, &'a Client)> {
+        let mut sorted: Vec<(ClientId, &Client)> = clients
+            .iter()
+            .map(|(&client_id, client)| (client_id, client))
+            .collect();
+        sorted.sort_unstable_by(|(a_id, a), (b_id, b)| {
+            let order = match self.by {
+                AccountSort::Client => a_id.cmp(b_id),
+                AccountSort::Total => a.total().cmp(&b.total()),
+            };
+            let order = if self.descending {
+                order.reverse()
+            } else {
+                order
+            };
+            order.then(a_id.cmp(b_id))
+        });
+        sorted
+    }
+}
+
+/// Build a CSV reader tolerating missing amount columns and padding spaces,
+/// skipping lines which start with `#`
+pub fn csv_reader<R: Read>(rdr: R) -> Reader<R> {
+    CsvDialect::default().reader(rdr)
+}
+
+/// Field delimiter, quote character and header of CSV input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvDialect {
+    pub delimiter: u8,
+    pub quote: u8,
+    /// Whether the first row names the columns. Transactions without a
+    /// header have the columns `type,client,tx,amount` in this order.
+    pub has_headers: bool,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: b'"',
+            has_headers: true,
+        }
+    }
+}
+
+impl CsvDialect {
+    /// Build a reader of the dialect, like [`csv_reader`] otherwise
+    pub fn reader<R: Read>(&self, rdr: R) -> Reader<R> {
+        ReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .has_headers(self.has_headers)
+            .comment(Some(b'#'))
+            .from_reader(rdr)
+    }
+}
+
+/// Parse a delimiter or quote character given on the command line. It
+/// must be a single byte, with `\t` standing for a tab.
+pub fn parse_csv_byte(s: &str) -> Result<u8, String> {
+    match s.as_bytes() {
+        b"\\t" => Ok(b'\t'),
+        [byte] if byte.is_ascii() => Ok(*byte),
+        _ => Err(format!(
+            "{s:?} is not a single byte character, e.g. ';' or '\\t'"
+        )),
+    }
+}
+
+/// Parser of amounts as people write them, like `$1,234.56` or
+/// `1 234,56`, rather than the plain decimals of the input format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LenientAmounts {
+    /// Currency symbol stripped in front of or behind the number
+    pub symbol: String,
+    /// The comma is the decimal point and the dot separates thousands
+    pub decimal_comma: bool,
+}
+
+impl Default for LenientAmounts {
+    fn default() -> Self {
+        Self {
+            symbol: "$".to_string(),
+            decimal_comma: false,
+        }
+    }
+}
+
+impl LenientAmounts {
+    /// Whether the amount is negative, and its number without the sign and
+    /// the currency symbol
+    pub fn strip_symbol<'a>(&self, s: &'a str) -> (bool, &'a str) {
+        let value = s.trim();
+        let (negative, value) = match value.strip_prefix('-') {
+            Some(value) => (true, value.trim_start()),
+            None => (false, value),
+        };
+        if self.symbol.is_empty() {
+            return (negative, value);
+        }
+        let value = value
+            .strip_prefix(self.symbol.as_str())
+            .or_else(|| value.strip_suffix(self.symbol.as_str()))
+            .unwrap_or(value)
+            .trim();
+        (negative, value)
+    }
+
+    /// Parse an amount. Thousands separators must group three digits and
+    /// may not follow the decimal point, otherwise the amount is ambiguous
+    /// and rejected.
+    pub fn parse(&self, s: &str) -> Result<Decimal, String> {
+        let (point, thousands) = if self.decimal_comma {
+            (',', '.')
+        } else {
+            ('.', ',')
+        };
+        let (negative, value) = self.strip_symbol(s);
+        let (integer, fraction) = match value.split_once(point) {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (value, None),
+        };
+        if fraction.is_some_and(|f| f.contains(thousands)) {
+            return Err(format!(
+                "ambiguous amount {s}, {thousands:?} follows the decimal point {point:?}"
+            ));
+        }
+        let groups: Vec<&str> = integer
+            .split([thousands, ' ', '\'', '\u{a0}', '\u{202f}'])
+            .collect();
+        if groups.len() > 1
+            && (groups[0].is_empty()
+                || groups[0].len() > 3
+                || groups[1..].iter().any(|group| group.len() != 3))
+        {
+            return Err(format!(
+                "ambiguous amount {s}, thousands separators must group three digits"
+            ));
+        }
+        let mut normalized = String::with_capacity(value.len() + 1);
+        if negative {
+            normalized.push('-');
+        }
+        normalized.extend(groups);
+        if let Some(fraction) = fraction {
+            normalized.push('.');
+            normalized.push_str(fraction);
+        }
+        Decimal::from_str(&normalized).map_err(|e| format!("invalid amount {s}: {e}"))
+    }
+}
+
+/// Rename of a CSV column, given as `from=to` on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnMapping {
+    pub from: String,
+    pub to: String,
+}
+
+impl ColumnMapping {
+    /// Columns every transaction needs, `amount` may be left out
+    pub const REQUIRED: [&str; 3] = ["type", "client", "tx"];
+
+    /// Header with the mapped columns renamed. Two columns ending up with
+    /// the same name, or a required column missing, is an error.
+    pub fn apply(mappings: &[ColumnMapping], headers: &ByteRecord) -> Result<ByteRecord, String> {
+        let renamed: Vec<&[u8]> = headers
+            .iter()
+            .map(|column| {
+                mappings
+                    .iter()
+                    .find(|m| m.from.as_bytes() == column)
+                    .map_or(column, |m| m.to.as_bytes())
+            })
+            .collect();
+        for (i, column) in renamed.iter().enumerate() {
+            if renamed[..i].contains(column) {
+                return Err(format!(
+                    "column {} appears twice after mapping",
+                    String::from_utf8_lossy(column)
+                ));
+            }
+        }
+        let renamed = ByteRecord::from(renamed);
+        let missing = Self::missing(&renamed);
+        if !missing.is_empty() {
+            return Err(format!(
+                "missing columns {} after mapping, found {}",
+                missing.join(", "),
+                String::from_utf8_lossy(&renamed.iter().collect::<Vec<_>>().join(&b","[..]))
+            ));
+        }
+        Ok(renamed)
+    }
+
+    /// Required columns the header does not have
+    pub fn missing(headers: &ByteRecord) -> Vec<&'static str> {
+        Self::REQUIRED
+            .into_iter()
+            .filter(|required| !headers.iter().any(|column| column == required.as_bytes()))
+            .collect()
+    }
+}
+
+impl FromStr for ColumnMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => Ok(Self {
+                from: from.trim().to_string(),
+                to: to.trim().to_string(),
+            }),
+            _ => Err(format!("expected FROM=TO, found {s:?}")),
+        }
+    }
+}
+
+/// Input the engine can be fed from.
+///
+/// A source yields transactions in order. Rows which can not be turned
+/// into a transaction are yielded as errors, the engine skips them and
+/// keeps going.
+pub trait TransactionSource {
+    fn transactions(self) -> impl Iterator<Item = Result<Transaction>>;
+}
+
+/// Transactions read from CSV with a `type,client,tx,amount` header.
+///
+/// Rows are read into one record reused for the whole input, only the
+/// `currency` of a transaction allocates.
+pub struct CsvSource<R> {
+    reader: Reader<R>,
+    strict_types: bool,
+}
+
+impl<R: Read> CsvSource<R> {
+    pub fn new(rdr: R) -> Self {
+        Self {
+            reader: csv_reader(rdr),
+            strict_types: false,
+        }
+    }
+
+    /// Only accept the lowercase transaction types, rows using another
+    /// casing or an alias are errors. See [`crate::EngineConfig::strict_types`].
+    pub fn strict_types(mut self, strict: bool) -> Self {
+        self.strict_types = strict;
+        self
+    }
+
+    /// Transactions along with the line each row starts at, the header
+    /// being line 1
+    pub fn numbered_transactions(mut self) -> impl Iterator<Item = (usize, Result<Transaction>)> {
+        let mut headers = None;
+        let mut type_column = None;
+        let mut record = StringRecord::new();
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            if headers.is_none() {
+                match self.reader.headers() {
+                    Ok(h) => {
+                        if self.strict_types {
+                            type_column = h.iter().position(|column| column == "type");
+                        }
+                        headers = Some(h.clone());
+                    }
+                    Err(e) => {
+                        done = true;
+                        return Some((1, Err(e.into())));
+                    }
+                }
+            }
+            loop {
+                match self.reader.read_record(&mut record) {
+                    // Rows of nothing but spaces or delimiters are blank lines
+                    Ok(true) if record.iter().all(str::is_empty) => {}
+                    Ok(true) => break,
+                    Ok(false) => return None,
+                    Err(e) => {
+                        // The reader can not recover from I/O errors
+                        done = e.is_io_error();
+                        let line = e.position().map_or(0, |p| p.line());
+                        return Some((line as usize, Err(e.into())));
+                    }
+                }
+            }
+            let line = record.position().map_or(0, |p| p.line());
+            let transaction = record
+                .deserialize::<Transaction>(headers.as_ref())
+                .map_err(Into::into)
+                .and_then(
+                    |transaction| match type_column.and_then(|i| record.get(i)) {
+                        Some(name) if name != transaction.kind.as_str() => {
+                            bail!("unknown transaction type {name}")
+                        }
+                        _ => Ok(transaction),
+                    },
+                );
+            Some((line as usize, transaction))
+        })
+    }
+}
+
+impl<R: Read> TransactionSource for CsvSource<R> {
+    fn transactions(self) -> impl Iterator<Item = Result<Transaction>> {
+        // Strict types need the type as written, only one of the two
+        // yields anything
+        let (strict, lenient) = if self.strict_types {
+            (Some(self.numbered_transactions()), None)
+        } else {
+            (None, Some(self.reader.into_deserialize::<Transaction>()))
+        };
+        let strict = strict
+            .into_iter()
+            .flatten()
+            .map(|(_, transaction)| transaction);
+        let lenient = lenient.into_iter().flatten().map(|r| r.map_err(Into::into));
+        strict.chain(lenient)
+    }
+}
+
+/// Transactions read from JSON Lines, an object per line with the fields
+/// named like the CSV columns. The amount may be a string or a number,
+/// other fields are ignored and blank lines skipped.
+pub struct JsonLinesSource<R> {
+    reader: R,
+}
+
+impl<R: BufRead> JsonLinesSource<R> {
+    pub fn new(rdr: R) -> Self {
+        Self { reader: rdr }
+    }
+
+    /// Transactions along with the line each was read from, the first
+    /// line being line 1
+    pub fn numbered_transactions(self) -> impl Iterator<Item = (usize, Result<Transaction>)> {
+        let mut lines = self.reader.split(b'\n').enumerate();
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            for (i, line) in lines.by_ref() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        // The reader can not recover from I/O errors
+                        done = true;
+                        return Some((i + 1, Err(e.into())));
+                    }
+                };
+                let line = line.trim_ascii();
+                if !line.is_empty() {
+                    let parsed = parse_json_line(line, ScientificAmounts::default());
+                    return Some((i + 1, parsed.map_err(|e| anyhow!(e))));
+                }
+            }
+            None
+        })
+    }
+}
+
+impl<R: BufRead> TransactionSource for JsonLinesSource<R> {
+    fn transactions(self) -> impl Iterator<Item = Result<Transaction>> {
+        self.numbered_transactions()
+            .map(|(_, transaction)| transaction)
+    }
+}
+
+/// Amount in scientific notation, like `1.2E3`, expanded as the policy
+/// allows. `None` for amounts in plain notation.
+pub(crate) fn scientific_amount(
+    field: &str,
+    amount: &str,
+    policy: ScientificAmounts,
+) -> Result<Option<Decimal>, String> {
+    if !amount.contains(['e', 'E']) {
+        return Ok(None);
+    }
+    match policy {
+        ScientificAmounts::Reject => Err(format!(
+            "malformed {field} {amount}, scientific notation is not accepted"
+        )),
+        ScientificAmounts::Accept => Decimal::from_scientific(amount)
+            .map(Some)
+            .map_err(|e| format!("malformed {field} {amount}: {e}")),
+    }
+}
+
+/// Parse a single JSON Lines object into a transaction. Errors only give
+/// the column, the line being known to the caller.
+pub(crate) fn parse_json_line(
+    line: &[u8],
+    scientific: ScientificAmounts,
+) -> Result<Transaction, String> {
+    let mut value: serde_json::Value = serde_json::from_slice(line).map_err(|e| {
+        let position = format!(" at line {} column {}", e.line(), e.column());
+        let message = e.to_string();
+        match message.strip_suffix(&position) {
+            Some(message) => format!("{message} at column {}", e.column()),
+            None => message,
+        }
+    })?;
+    // Amounts are parsed from their text, numbers keep the exact text of
+    // the line rather than going through a float
+    if let Some(object) = value.as_object_mut() {
+        for field in ["amount", "fee"] {
+            let mut text = match object.get(field) {
+                Some(serde_json::Value::Number(number)) => number.to_string(),
+                Some(serde_json::Value::String(text)) => text.clone(),
+                _ => continue,
+            };
+            if let Some(amount) = scientific_amount(field, &text, scientific)? {
+                text = amount.to_string();
+            }
+            object.insert(field.to_string(), serde_json::Value::String(text));
+        }
+    }
+    Transaction::deserialize(value).map_err(|e| e.to_string())
+}
+
+/// Transactions read from a stream of MessagePack maps, one after the
+/// other. Maps are keyed by the names of the CSV columns or by their
+/// index in `type,client,tx,amount,to_client,fee,currency,ts`.
+///
+/// A value which is not a valid transaction is skipped. A stream which
+/// can not be decoded any further ends the transactions with an error,
+/// telling a value cut short at the end of the stream from a corrupt one.
+pub struct MsgpackSource<R> {
+    reader: R,
+}
+
+impl<R: BufRead> MsgpackSource<R> {
+    pub fn new(rdr: R) -> Self {
+        Self { reader: rdr }
+    }
+
+    /// Transactions along with the position of their value in the
+    /// stream, the first being 1
+    pub fn numbered_transactions(mut self) -> impl Iterator<Item = (usize, Result<Transaction>)> {
+        let mut index = 0;
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            index += 1;
+            match next_msgpack_value(&mut self.reader) {
+                Ok(Some(value)) => {
+                    let parsed = msgpack_transaction(value, ScientificAmounts::default());
+                    Some((index, parsed.map_err(|e| anyhow!(e))))
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    done = true;
+                    Some((index, Err(e.into())))
+                }
+            }
+        })
+    }
+}
+
+impl<R: BufRead> TransactionSource for MsgpackSource<R> {
+    fn transactions(self) -> impl Iterator<Item = Result<Transaction>> {
+        self.numbered_transactions()
+            .map(|(_, transaction)| transaction)
+    }
+}
+
+/// Fields of a transaction in the order of their integer MessagePack keys
+const MSGPACK_FIELDS: [&str; 8] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "to_client",
+    "fee",
+    "currency",
+    "ts",
+];
+
+/// Next value of a MessagePack stream, `None` if the stream ended cleanly
+/// before it
+pub(crate) fn next_msgpack_value<R: BufRead>(rdr: &mut R) -> io::Result<Option<rmpv::Value>> {
+    if rdr.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut deserializer = rmp_serde::Deserializer::new(rdr);
+    match rmpv::Value::deserialize(&mut deserializer) {
+        Ok(value) => Ok(Some(value)),
+        Err(
+            rmp_serde::decode::Error::InvalidMarkerRead(e)
+            | rmp_serde::decode::Error::InvalidDataRead(e),
+        ) if e.kind() == io::ErrorKind::UnexpectedEof => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "stream ends within a value",
+        )),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+/// Turn a decoded MessagePack map into a transaction
+pub(crate) fn msgpack_transaction(
+    value: rmpv::Value,
+    scientific: ScientificAmounts,
+) -> Result<Transaction, String> {
+    let rmpv::Value::Map(entries) = value else {
+        return Err(format!("expected a map, found {value}"));
+    };
+    let entries = entries
+        .into_iter()
+        .map(|(key, value)| {
+            let name = match &key {
+                rmpv::Value::Integer(i) => i
+                    .as_u64()
+                    .and_then(|i| MSGPACK_FIELDS.get(usize::try_from(i).ok()?))
+                    .map(|name| rmpv::Value::from(*name)),
+                rmpv::Value::String(_) => Some(key.clone()),
+                _ => None,
+            };
+            let name = name.ok_or_else(|| format!("unknown field {key}"))?;
+            // Numbers are typed, only amounts written as text may be in
+            // scientific notation
+            let value = match (name.as_str(), value) {
+                (Some(field @ ("amount" | "fee")), rmpv::Value::String(text)) => {
+                    match text
+                        .as_str()
+                        .map(|s| scientific_amount(field, s, scientific))
+                    {
+                        Some(Ok(Some(amount))) => rmpv::Value::from(amount.to_string()),
+                        Some(Err(e)) => return Err(e),
+                        _ => rmpv::Value::String(text),
+                    }
+                }
+                (_, value) => value,
+            };
+            Ok((name, value))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    rmpv::ext::from_value(rmpv::Value::Map(entries)).map_err(|e| e.to_string())
+}
+
+/// Destination of the final account balances
+pub trait OutputSink {
+    fn write_accounts(&mut self, clients: &HashMap<ClientId, Client>) -> io::Result<()>;
+}
+
+/// Single row of the accounts output
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountRecord {
+    client: ClientId,
+    #[serde(serialize_with = "four_places")]
+    available: Decimal,
+    #[serde(serialize_with = "four_places")]
+    held: Decimal,
+    #[serde(serialize_with = "four_places")]
+    total: Decimal,
+    locked: bool,
+    /// Only read, from initial accounts setting a reserve per client
+    #[serde(default, skip_serializing)]
+    min_balance: Option<Decimal>,
+    /// Only read, from initial accounts granting a credit line
+    #[serde(default, skip_serializing)]
+    credit_limit: Option<Decimal>,
+}
+
+/// Row of the accounts output from version 2 on, `fees_paid` is only
+/// written from version 3 on
+#[derive(Debug, Serialize)]
+struct ExtendedAccountRecord {
+    client: ClientId,
+    #[serde(serialize_with = "four_places")]
+    available: Decimal,
+    #[serde(serialize_with = "four_places")]
+    held: Decimal,
+    #[serde(serialize_with = "four_places")]
+    total: Decimal,
+    locked: bool,
+    failed_withdrawals: u64,
+    #[serde(
+        serialize_with = "four_places_if_present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    fees_paid: Option<Decimal>,
+}
+
+/// Row of the accounts output from version 4 on, `flagged` is only
+/// written from version 5 on and the credit line from version 6 on
+#[derive(Debug, Serialize)]
+struct CurrencyAccountRecord<'a> {
+    client: ClientId,
+    #[serde(serialize_with = "four_places")]
+    available: Decimal,
+    #[serde(serialize_with = "four_places")]
+    held: Decimal,
+    #[serde(serialize_with = "four_places")]
+    total: Decimal,
+    locked: bool,
+    failed_withdrawals: u64,
+    #[serde(serialize_with = "four_places")]
+    fees_paid: Decimal,
+    currency: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flagged: Option<bool>,
+    /// Only written if set, an empty column for clients without a limit
+    #[serde(
+        serialize_with = "four_places_if_set",
+        skip_serializing_if = "Option::is_none"
+    )]
+    credit_limit: Option<Option<Decimal>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    overdrawn: Option<bool>,
+}
+
+fn four_places<S: Serializer>(amount: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&FourPlaces(*amount))
+}
+
+fn four_places_if_present<S: Serializer>(
+    amount: &Option<Decimal>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => four_places(amount, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn four_places_if_set<S: Serializer>(
+    amount: &Option<Option<Decimal>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    four_places_if_present(&amount.flatten(), serializer)
+}
+
+/// Writes the accounts as CSV with amounts at four decimal places, by
+/// ascending client id unless ordered otherwise
+pub struct CsvAccountsWriter<W: Write> {
+    writer: Writer<W>,
+    version: OutputVersion,
+    base_currency: String,
+    order: AccountOrder,
+}
+
+impl<W: Write> CsvAccountsWriter<W> {
+    pub fn new(writer: W, version: OutputVersion) -> Self {
+        Self::with_delimiter(writer, version, b',')
+    }
+
+    /// Writer separating the fields with `delimiter` instead of a comma
+    pub fn with_delimiter(writer: W, version: OutputVersion, delimiter: u8) -> Self {
+        Self {
+            // The header is written explicitly, so it is there even
+            // without any accounts
+            writer: WriterBuilder::new()
+                .has_headers(false)
+                .delimiter(delimiter)
+                .from_writer(writer),
+            version,
+            base_currency: DEFAULT_BASE_CURRENCY.to_string(),
+            order: AccountOrder::default(),
+        }
+    }
+
+    /// Currency written for the balances of the clients themselves
+    pub fn base_currency(mut self, currency: &str) -> Self {
+        self.base_currency = currency.to_ascii_uppercase();
+        self
+    }
+
+    /// Order of the clients. From version 4 on the rows of a client are
+    /// ordered by currency.
+    pub fn order(mut self, order: AccountOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn into_inner(self) -> io::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl<W: Write> OutputSink for CsvAccountsWriter<W> {
+    fn write_accounts(&mut self, clients: &HashMap<ClientId, Client>) -> io::Result<()> {
+        match self.version {
+            OutputVersion::V1 => {
+                self.writer
+                    .write_record(["client", "available", "held", "total", "locked"])?;
+                for (client_id, client) in self.order.sorted(clients) {
+                    self.writer.serialize(AccountRecord {
+                        client: client_id,
+                        available: client.available(),
+                        held: client.held(),
+                        total: client.total(),
+                        locked: client.is_locked(),
+                        min_balance: None,
+                        credit_limit: None,
+                    })?;
+                }
+            }
+            OutputVersion::V2 | OutputVersion::V3 => {
+                let with_fees = self.version == OutputVersion::V3;
+                let header = [
+                    "client",
+                    "available",
+                    "held",
+                    "total",
+                    "locked",
+                    "failed_withdrawals",
+                    "fees_paid",
+                ];
+                self.writer
+                    .write_record(&header[..if with_fees { 7 } else { 6 }])?;
+                for (client_id, client) in self.order.sorted(clients) {
+                    self.writer.serialize(ExtendedAccountRecord {
+                        client: client_id,
+                        available: client.available(),
+                        held: client.held(),
+                        total: client.total(),
+                        locked: client.is_locked(),
+                        failed_withdrawals: client.failed_withdrawals(),
+                        fees_paid: with_fees.then(|| client.fees_paid()),
+                    })?;
+                }
+            }
+            OutputVersion::V4 | OutputVersion::V5 | OutputVersion::V6 => {
+                let with_flag = self.version >= OutputVersion::V5;
+                let with_credit = self.version >= OutputVersion::V6;
+                let header = [
+                    "client",
+                    "available",
+                    "held",
+                    "total",
+                    "locked",
+                    "failed_withdrawals",
+                    "fees_paid",
+                    "currency",
+                    "flagged",
+                    "credit_limit",
+                    "overdrawn",
+                ];
+                let columns = match self.version {
+                    OutputVersion::V4 => 8,
+                    OutputVersion::V5 => 9,
+                    _ => 11,
+                };
+                self.writer.write_record(&header[..columns])?;
+                // The flag of a client covers all of its currencies
+                let mut accounts: Vec<(ClientId, &str, &Client, bool)> = Vec::new();
+                for (client_id, client) in self.order.sorted(clients) {
+                    let flagged = client.is_flagged();
+                    let start = accounts.len();
+                    accounts.push((client_id, &self.base_currency, client, flagged));
+                    accounts.extend(
+                        client
+                            .currencies()
+                            .map(|(currency, account)| (client_id, currency, account, flagged)),
+                    );
+                    accounts[start..].sort_unstable_by_key(|&(_, currency, _, _)| currency);
+                }
+                for (client_id, currency, client, flagged) in accounts {
+                    self.writer.serialize(CurrencyAccountRecord {
+                        client: client_id,
+                        available: client.available(),
+                        held: client.held(),
+                        total: client.total(),
+                        locked: client.is_locked(),
+                        failed_withdrawals: client.failed_withdrawals(),
+                        fees_paid: client.fees_paid(),
+                        currency,
+                        flagged: with_flag.then_some(flagged),
+                        credit_limit: with_credit.then(|| client.credit_limit()),
+                        overdrawn: with_credit.then(|| client.is_overdrawn()),
+                    })?;
+                }
+            }
+        }
+        self.writer.flush()
+    }
+}
+
+/// Account of the JSON output. Amounts are strings holding every decimal
+/// place of the balance, so no precision is lost to floating point.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonAccountRecord {
+    pub client: ClientId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl JsonAccountRecord {
+    pub fn new(client_id: ClientId, client: &Client) -> Self {
+        Self {
+            client: client_id,
+            available: client.available().normalize(),
+            held: client.held().normalize(),
+            total: client.total().normalize(),
+            locked: client.is_locked(),
+        }
+    }
+}
+
+/// Writes the balances of the clients as a JSON array of
+/// [`JsonAccountRecord`], or as an object per line, by ascending client id
+/// unless ordered otherwise
+pub struct JsonAccountsWriter<W: Write> {
+    writer: W,
+    one_per_line: bool,
+    order: AccountOrder,
+}
+
+impl<W: Write> JsonAccountsWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            one_per_line: false,
+            order: AccountOrder::default(),
+        }
+    }
+
+    /// Order of the clients
+    pub fn order(mut self, order: AccountOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Write an object per line instead of an array, for consumers
+    /// streaming the accounts
+    pub fn one_per_line(mut self, one_per_line: bool) -> Self {
+        self.one_per_line = one_per_line;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> OutputSink for JsonAccountsWriter<W> {
+    fn write_accounts(&mut self, clients: &HashMap<ClientId, Client>) -> io::Result<()> {
+        let (start, separator, end) = if self.one_per_line {
+            ("", "\n", "\n")
+        } else {
+            ("[", ",\n", "]\n")
+        };
+        self.writer.write_all(start.as_bytes())?;
+        for (i, (client_id, client)) in self.order.sorted(clients).into_iter().enumerate() {
+            if i > 0 {
+                self.writer.write_all(separator.as_bytes())?;
+            }
+            serde_json::to_writer(&mut self.writer, &JsonAccountRecord::new(client_id, client))?;
+        }
+        if !(self.one_per_line && clients.is_empty()) {
+            self.writer.write_all(end.as_bytes())?;
+        }
+        self.writer.flush()
+    }
+}
+
+/// Writes the accounts as a table of aligned columns for reading in a
+/// terminal, with a footer of the totals across all clients. Numbers are
+/// right aligned, amounts at four decimal places. A total which overflows
+/// is shown as `overflow`.
+pub struct TableAccountsWriter<W: Write> {
+    writer: W,
+    human: bool,
+    order: AccountOrder,
+}
+
+impl<W: Write> TableAccountsWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            human: false,
+            order: AccountOrder::default(),
+        }
+    }
+
+    /// Group the digits of amounts in thousands, like `1,234.5000`
+    pub fn human(mut self, human: bool) -> Self {
+        self.human = human;
+        self
+    }
+
+    /// Order of the clients
+    pub fn order(mut self, order: AccountOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn amount(&self, amount: Decimal) -> String {
+        let amount = FourPlaces(amount).to_string();
+        if !self.human {
+            return amount;
+        }
+        let (sign, digits) = match amount.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", amount.as_str()),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let mut grouped = String::from(sign);
+        for (i, digit) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i) % 3 == 0 {
+                grouped.push(',');
+            }
+            grouped.push(digit);
+        }
+        format!("{grouped}.{fraction}")
+    }
+
+    fn sum(&self, sum: Option<Decimal>) -> String {
+        sum.map_or_else(|| "overflow".to_string(), |sum| self.amount(sum))
+    }
+}
+
+impl<W: Write> OutputSink for TableAccountsWriter<W> {
+    fn write_accounts(&mut self, clients: &HashMap<ClientId, Client>) -> io::Result<()> {
+        const HEADER: [&str; 5] = ["client", "available", "held", "total", "locked"];
+        // Every column but locked holds numbers
+        const RIGHT_ALIGNED: [bool; 5] = [true, true, true, true, false];
+
+        // A sum is gone once it overflows, the footer marks it instead
+        let (mut available, mut held, mut total) = (
+            Some(Decimal::ZERO),
+            Some(Decimal::ZERO),
+            Some(Decimal::ZERO),
+        );
+        let mut rows: Vec<[String; 5]> = Vec::with_capacity(clients.len());
+        for (client_id, client) in self.order.sorted(clients) {
+            available = available.and_then(|sum| sum.checked_add(client.available()));
+            held = held.and_then(|sum| sum.checked_add(client.held()));
+            total = total.and_then(|sum| sum.checked_add(client.total()));
+            rows.push([
+                client_id.to_string(),
+                self.amount(client.available()),
+                self.amount(client.held()),
+                self.amount(client.total()),
+                if client.is_locked() { "yes" } else { "no" }.to_string(),
+            ]);
+        }
+        let footer = [
+            "total".to_string(),
+            self.sum(available),
+            self.sum(held),
+            self.sum(total),
+            String::new(),
+        ];
+        let header = HEADER.map(str::to_string);
+
+        let mut widths = HEADER.map(str::len);
+        for row in rows.iter().chain([&footer]) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        let rule: String = widths
+            .iter()
+            .map(|&width| format!("+{}", "-".repeat(width + 2)))
+            .collect::<String>()
+            + "+\n";
+        let line = |row: &[String; 5]| {
+            let mut line = String::new();
+            for ((cell, &width), right) in row.iter().zip(&widths).zip(RIGHT_ALIGNED) {
+                if right {
+                    line.push_str(&format!("| {cell:>width$} "));
+                } else {
+                    line.push_str(&format!("| {cell:<width$} "));
+                }
+            }
+            line + "|\n"
+        };
+
+        self.writer.write_all(rule.as_bytes())?;
+        self.writer.write_all(line(&header).as_bytes())?;
+        self.writer.write_all(rule.as_bytes())?;
+        for row in &rows {
+            self.writer.write_all(line(row).as_bytes())?;
+        }
+        if !rows.is_empty() {
+            self.writer.write_all(rule.as_bytes())?;
+        }
+        self.writer.write_all(line(&footer).as_bytes())?;
+        self.writer.write_all(rule.as_bytes())?;
+        self.writer.flush()
+    }
+}
+
+/// Read accounts in the layout written by [`CsvAccountsWriter`], e.g. the
+/// closing balances of an earlier run.
+///
+/// Every row must have a total of exactly available plus held funds, and
+/// every client may only appear once. Optional `min_balance` and
+/// `credit_limit` columns set the minimum balance and credit line of the
+/// client.
+pub fn read_accounts<R: Read>(rdr: R) -> Result<HashMap<ClientId, Client>> {
+    let mut accounts = HashMap::new();
+    let mut reader = csv_reader(rdr);
+    for record in reader.deserialize::<AccountRecord>() {
+        let record = record?;
+        let mut client = Client::with_balances(record.available, record.held, record.locked);
+        client.set_min_balance(record.min_balance);
+        client.set_credit_limit(record.credit_limit);
+        if client.total() != record.total {
+            bail!(
+                "client {} total {} is not available plus held funds",
+                record.client,
+                record.total
+            );
+        }
+        if accounts.insert(record.client, client).is_some() {
+            bail!("client {} appears more than once", record.client);
+        }
+    }
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TransactionType;
+    use crate::{Engine, EngineConfig};
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_read_transactions_with_padding_and_missing_amount() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 1.5\ndispute, 1, 1\n";
+        let records: Vec<Transaction> = CsvSource::new(data.as_bytes())
+            .transactions()
+            .collect::<Result<_>>()
+            .unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].kind, TransactionType::Deposit);
+        assert_eq!(records[0].amount, Some(dec!(1.5)));
+        assert_eq!(records[1].kind, TransactionType::Dispute);
+        assert_eq!(records[1].amount, None);
+    }
+
+    #[test]
+    fn test_csv_strict_types() {
+        let data = "type,client,tx,amount\nDeposit,1,1,5.0\nwithdraw,1,2,1.0\ndeposit,1,3,2.0\n";
+        let process = |config: EngineConfig| {
+            let rows = CsvSource::new(data.as_bytes())
+                .strict_types(config.strict_types())
+                .transactions();
+            crate::process_transactions(rows, config)
+        };
+
+        let (clients, rejections) = process(EngineConfig::default());
+        assert!(rejections.is_empty());
+        assert_eq!(clients[&1].available(), dec!(6.0));
+
+        let (clients, _) = process(EngineConfig::builder().strict_types(true).build());
+        assert_eq!(clients[&1].available(), dec!(2.0));
+        let errors: Vec<_> = CsvSource::new(data.as_bytes())
+            .strict_types(true)
+            .numbered_transactions()
+            .filter_map(|(line, row)| row.err().map(|e| (line, e.to_string())))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (2, "unknown transaction type Deposit".to_string()),
+                (3, "unknown transaction type withdraw".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_json_lines() {
+        let data = concat!(
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.5\",\"source\":\"web\"}\n",
+            "\n",
+            "{\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":0.25}\r\n",
+            "{\"type\":\"dispute\",\"client\":1,\"tx\":1}\n",
+            "{\"type\":\"deposit\",\"client\":1,\n",
+        );
+        let records: Vec<_> = JsonLinesSource::new(data.as_bytes())
+            .numbered_transactions()
+            .collect();
+
+        assert_eq!(records.len(), 4);
+        let lines: Vec<_> = records.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![1, 3, 4, 5]);
+        let first = records[0].1.as_ref().unwrap();
+        assert_eq!(first.kind, TransactionType::Deposit);
+        assert_eq!(first.amount, Some(dec!(1.5)));
+        assert_eq!(records[1].1.as_ref().unwrap().amount, Some(dec!(0.25)));
+        assert_eq!(records[2].1.as_ref().unwrap().amount, None);
+        let e = records[3].1.as_ref().unwrap_err().to_string();
+        assert_eq!(e, "EOF while parsing a value at column 29");
+    }
+
+    #[test]
+    fn test_json_numbers_keep_every_digit() {
+        let line =
+            br#"{"type":"withdrawal","client":1,"tx":1,"amount":1234567890123.12345,"fee":0.1}"#;
+        let transaction = parse_json_line(line, ScientificAmounts::Reject).unwrap();
+        assert_eq!(transaction.amount, Some(dec!(1234567890123.12345)));
+        assert_eq!(transaction.fee, Some(dec!(0.1)));
+    }
+
+    #[test]
+    fn test_json_scientific_amounts() {
+        for line in [
+            &br#"{"type":"deposit","client":1,"tx":1,"amount":1.2E3}"#[..],
+            &br#"{"type":"deposit","client":1,"tx":1,"amount":"1.2E3"}"#[..],
+        ] {
+            assert_eq!(
+                parse_json_line(line, ScientificAmounts::Reject),
+                Err("malformed amount 1.2E3, scientific notation is not accepted".to_string())
+            );
+            let accepted = parse_json_line(line, ScientificAmounts::Accept).unwrap();
+            assert_eq!(accepted.amount, Some(dec!(1200)));
+        }
+    }
+
+    #[test]
+    fn test_read_msgpack_with_string_and_integer_keys() {
+        #[derive(Serialize)]
+        struct Row<'a> {
+            #[serde(rename = "type")]
+            kind: &'a str,
+            client: u32,
+            tx: u32,
+            amount: Option<&'a str>,
+        }
+        let mut data = rmp_serde::to_vec_named(&Row {
+            kind: "deposit",
+            client: 1,
+            tx: 1,
+            amount: Some("1.5"),
+        })
+        .unwrap();
+        let keyed_by_index = rmpv::Value::Map(vec![
+            (0.into(), "withdrawal".into()),
+            (1.into(), 1.into()),
+            (2.into(), 2.into()),
+            (3.into(), 0.25.into()),
+        ]);
+        rmpv::encode::write_value(&mut data, &keyed_by_index).unwrap();
+        rmpv::encode::write_value(&mut data, &rmpv::Value::from("deposit")).unwrap();
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.5\nwithdrawal,1,2,0.25\n";
+
+        let records: Vec<_> = MsgpackSource::new(&data[..])
+            .numbered_transactions()
+            .collect();
+        assert_eq!(records.len(), 3);
+        let from_csv: Vec<_> = CsvSource::new(csv.as_bytes())
+            .transactions()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(records[0].1.as_ref().unwrap(), &from_csv[0]);
+        assert_eq!(records[1].1.as_ref().unwrap(), &from_csv[1]);
+        assert_eq!(
+            records[2].1.as_ref().unwrap_err().to_string(),
+            "expected a map, found \"deposit\""
+        );
+    }
+
+    #[test]
+    fn test_msgpack_scientific_amounts() {
+        let value = rmpv::Value::Map(vec![
+            (0.into(), "deposit".into()),
+            (1.into(), 1.into()),
+            (2.into(), 1.into()),
+            (3.into(), "1.5E1".into()),
+        ]);
+        assert_eq!(
+            msgpack_transaction(value.clone(), ScientificAmounts::Reject),
+            Err("malformed amount 1.5E1, scientific notation is not accepted".to_string())
+        );
+        let accepted = msgpack_transaction(value, ScientificAmounts::Accept).unwrap();
+        assert_eq!(accepted.amount, Some(dec!(15)));
+    }
+
+    #[test]
+    fn test_msgpack_end_of_stream() {
+        let value = rmpv::Value::Map(vec![("type".into(), "deposit".into())]);
+        let mut data = Vec::new();
+        rmpv::encode::write_value(&mut data, &value).unwrap();
+
+        let mut clean = &data[..];
+        assert_eq!(next_msgpack_value(&mut clean).unwrap(), Some(value));
+        assert_eq!(next_msgpack_value(&mut clean).unwrap(), None);
+
+        let mut truncated = &data[..data.len() - 2];
+        let e = next_msgpack_value(&mut truncated).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_semicolon_dialect() {
+        let dialect = CsvDialect {
+            delimiter: parse_csv_byte(";").unwrap(),
+            quote: parse_csv_byte("'").unwrap(),
+            ..CsvDialect::default()
+        };
+        let data = "type;client;tx;amount\ndeposit;1;1;'1.5'\n";
+        let mut reader = dialect.reader(data.as_bytes());
+        let records: Vec<Transaction> = reader.deserialize().collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(records[0].amount, Some(dec!(1.5)));
+        assert_eq!(parse_csv_byte("\\t"), Ok(b'\t'));
+        assert!(parse_csv_byte(";;").is_err());
+        assert!(parse_csv_byte("§").is_err());
+        assert!(parse_csv_byte("").is_err());
+    }
+
+    #[test]
+    fn test_lenient_amounts() {
+        let dot = LenientAmounts::default();
+        assert_eq!(dot.parse("$1,234.56"), Ok(dec!(1234.56)));
+        assert_eq!(dot.parse(" 1,234,567.5 $ "), Ok(dec!(1234567.5)));
+        assert_eq!(dot.parse("-$12"), Ok(dec!(-12)));
+        assert_eq!(dot.parse("1 234.56"), Ok(dec!(1234.56)));
+        assert_eq!(dot.parse("0.1234"), Ok(dec!(0.1234)));
+        assert_eq!(
+            dot.parse("1.234,56"),
+            Err("ambiguous amount 1.234,56, ',' follows the decimal point '.'".to_string())
+        );
+        assert_eq!(
+            dot.parse("1,5"),
+            Err("ambiguous amount 1,5, thousands separators must group three digits".to_string())
+        );
+        assert!(dot.parse("€5").is_err());
+
+        let comma = LenientAmounts {
+            symbol: "€".to_string(),
+            decimal_comma: true,
+        };
+        assert_eq!(comma.parse("1 234,56"), Ok(dec!(1234.56)));
+        assert_eq!(comma.parse("1.234,56 €"), Ok(dec!(1234.56)));
+        assert_eq!(comma.parse("€1,5"), Ok(dec!(1.5)));
+        assert_eq!(comma.parse("1\u{a0}234"), Ok(dec!(1234)));
+        assert!(comma.parse("1,234.56").is_err());
+        assert!(comma.parse("12.34").is_err());
+    }
+
+    #[test]
+    fn test_column_mapping() {
+        let mappings: Vec<ColumnMapping> = ["txn_type=type", "customer=client", " txn_id = tx"]
+            .iter()
+            .map(|m| m.parse().unwrap())
+            .collect();
+        let headers = ByteRecord::from(vec!["txn_type", "customer", "txn_id", "amount", "note"]);
+        assert_eq!(
+            ColumnMapping::apply(&mappings, &headers).unwrap(),
+            ByteRecord::from(vec!["type", "client", "tx", "amount", "note"])
+        );
+
+        let headers = ByteRecord::from(vec!["txn_type", "cust", "txn", "amount"]);
+        assert_eq!(
+            ColumnMapping::apply(&mappings, &headers).unwrap_err(),
+            "missing columns client, tx after mapping, found type,cust,txn,amount"
+        );
+
+        let headers = ByteRecord::from(vec!["txn_type", "customer", "client", "txn_id"]);
+        assert_eq!(
+            ColumnMapping::apply(&mappings, &headers).unwrap_err(),
+            "column client appears twice after mapping"
+        );
+
+        assert!("type".parse::<ColumnMapping>().is_err());
+        assert!("=type".parse::<ColumnMapping>().is_err());
+    }
+
+    #[test]
+    fn test_write_accounts_format() {
+        let mut clients = HashMap::new();
+        clients.insert(7, Client::with_balances(dec!(1.5), dec!(0.25), true));
+        let mut writer = CsvAccountsWriter::new(Vec::new(), OutputVersion::V1);
+
+        writer.write_accounts(&clients).unwrap();
+
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "client,available,held,total,locked\n7,1.5000,0.2500,1.7500,true\n"
+        );
+    }
+
+    #[test]
+    fn test_write_accounts_beyond_1e27() {
+        let mut clients = HashMap::new();
+        clients.insert(
+            1,
+            Client::with_balances(dec!(1234567890123456789012345678), dec!(0), false),
+        );
+        let mut writer = CsvAccountsWriter::new(Vec::new(), OutputVersion::V1);
+
+        writer.write_accounts(&clients).unwrap();
+
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "client,available,held,total,locked\n\
+             1,1234567890123456789012345678.0000,0.0000,1234567890123456789012345678.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_write_accounts_round_trip() {
+        let mut clients = HashMap::new();
+        clients.insert(1, Client::new(dec!(13.6974)));
+        clients.insert(2, Client::with_balances(dec!(0.0966), dec!(4), false));
+        clients.insert(3, Client::with_balances(dec!(0), dec!(0), true));
+        let mut writer = CsvAccountsWriter::new(Vec::new(), OutputVersion::V1);
+        writer.write_accounts(&clients).unwrap();
+        let output = writer.into_inner().unwrap();
+
+        let mut reader = csv_reader(output.as_slice());
+        let rows: Vec<(ClientId, Decimal, Decimal, Decimal, bool)> =
+            reader.deserialize().collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(rows.len(), clients.len());
+        for (client_id, available, held, total, locked) in rows {
+            let client = &clients[&client_id];
+            assert_eq!(available, client.available());
+            assert_eq!(held, client.held());
+            assert_eq!(total, client.total());
+            assert_eq!(locked, client.is_locked());
+        }
+    }
+
+    #[test]
+    fn test_accounts_sorted() {
+        let mut clients = HashMap::new();
+        clients.insert(3, Client::new(dec!(5)));
+        clients.insert(1, Client::new(dec!(2)));
+        clients.insert(2, Client::with_balances(dec!(1), dec!(4), false));
+        clients.insert(10, Client::new(dec!(0)));
+        let ids = |by, descending| -> Vec<ClientId> {
+            AccountOrder { by, descending }
+                .sorted(&clients)
+                .into_iter()
+                .map(|(client_id, _)| client_id)
+                .collect()
+        };
+
+        assert_eq!(ids(AccountSort::Client, false), [1, 2, 3, 10]);
+        assert_eq!(ids(AccountSort::Client, true), [10, 3, 2, 1]);
+        assert_eq!(ids(AccountSort::Total, false), [10, 1, 2, 3]);
+        // Equal totals stay in ascending client order
+        assert_eq!(ids(AccountSort::Total, true), [2, 3, 1, 10]);
+
+        let mut writer = CsvAccountsWriter::new(Vec::new(), OutputVersion::V1);
+        writer.write_accounts(&clients).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let written: Vec<&str> = output
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(written, ["1", "2", "3", "10"]);
+    }
+
+    #[test]
+    fn test_table_amounts_grouped_in_thousands() {
+        let table = TableAccountsWriter::new(Vec::new()).human(true);
+        assert_eq!(table.amount(dec!(1234567.5)), "1,234,567.5000");
+        assert_eq!(table.amount(dec!(-1000)), "-1,000.0000");
+        assert_eq!(table.amount(dec!(999.9999)), "999.9999");
+        assert_eq!(table.amount(dec!(0.1234)), "0.1234");
+        assert_eq!(
+            TableAccountsWriter::new(Vec::new()).amount(dec!(1234567.5)),
+            "1234567.5000"
+        );
+    }
+
+    #[test]
+    fn test_table_footer_marks_overflowing_sums() {
+        let mut clients = HashMap::new();
+        clients.insert(1, Client::new(Decimal::MAX));
+        clients.insert(2, Client::with_balances(dec!(1), dec!(1), false));
+        let mut table = TableAccountsWriter::new(Vec::new());
+
+        table.write_accounts(&clients).unwrap();
+
+        let output = String::from_utf8(table.into_inner()).unwrap();
+        let footer = output.lines().rev().nth(1).unwrap();
+        let cells: Vec<&str> = footer.split('|').map(str::trim).collect();
+        assert_eq!(cells[1..5], ["total", "overflow", "1.0000", "overflow"]);
+    }
+
+    #[test]
+    fn test_json_accounts_round_trip() {
+        let mut clients = HashMap::new();
+        clients.insert(1, Client::new(dec!(0.1234)));
+        clients.insert(2, Client::with_balances(dec!(1.5000), dec!(0.00005), false));
+        clients.insert(3, Client::with_balances(dec!(0), dec!(0), true));
+        let check = |records: Vec<JsonAccountRecord>| {
+            assert_eq!(records.len(), clients.len());
+            for record in records {
+                let client = &clients[&record.client];
+                assert_eq!(record.available, client.available());
+                assert_eq!(record.held, client.held());
+                assert_eq!(record.total, client.total());
+                assert_eq!(record.locked, client.is_locked());
+            }
+        };
+
+        let mut writer = JsonAccountsWriter::new(Vec::new());
+        writer.write_accounts(&clients).unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        // Amounts are strings, with the places of the balance
+        assert!(output.contains(r#""available":"0.1234""#), "{output}");
+        assert!(output.contains(r#""held":"0.00005""#), "{output}");
+        assert!(output.contains(r#""total":"0""#), "{output}");
+        check(serde_json::from_str(&output).unwrap());
+
+        let mut writer = JsonAccountsWriter::new(Vec::new()).one_per_line(true);
+        writer.write_accounts(&clients).unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(output.lines().count(), clients.len());
+        check(
+            output
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect(),
+        );
+
+        let mut writer = JsonAccountsWriter::new(Vec::new());
+        writer.write_accounts(&HashMap::new()).unwrap();
+        assert_eq!(writer.into_inner(), b"[]\n");
+    }
+
+    #[test]
+    fn test_read_accounts_validates_totals() {
+        let data = "client,available,held,total,locked\n1,1.5000,0.2500,1.7500,true\n";
+        let accounts = read_accounts(data.as_bytes()).unwrap();
+        assert_eq!(
+            accounts[&1],
+            Client::with_balances(dec!(1.5), dec!(0.25), true)
+        );
+
+        let data = "client,available,held,total,locked\n1,1.5000,0.2500,1.5000,false\n";
+        let err = read_accounts(data.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "client 1 total 1.5 is not available plus held funds"
+        );
+
+        let data = "client,available,held,total,locked\n1,1,0,1,false\n1,2,0,2,false\n";
+        assert!(read_accounts(data.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_output_version_4_has_a_row_per_currency() {
+        let data = "type,client,tx,amount,currency
+deposit,2,1,1.0,
+deposit,1,2,2.0,JPY
+deposit,1,3,3.0,
+deposit,1,4,4.0,EUR
+";
+        let mut engine = Engine::new(EngineConfig::default());
+        engine.process(CsvSource::new(data.as_bytes()).transactions());
+        let mut writer = CsvAccountsWriter::new(Vec::new(), OutputVersion::V4);
+        writer.write_accounts(&engine.into_accounts()).unwrap();
+
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,failed_withdrawals,fees_paid,currency
+1,4.0000,0.0000,4.0000,false,0,0.0000,EUR
+1,2.0000,0.0000,2.0000,false,0,0.0000,JPY
+1,3.0000,0.0000,3.0000,false,0,0.0000,USD
+2,1.0000,0.0000,1.0000,false,0,0.0000,USD
+"
+        );
+    }
+
+    #[test]
+    fn test_output_version_6_has_the_credit_line() {
+        let accounts = "client,available,held,total,locked,credit_limit
+1,-5.0,0,-5.0,false,10.0
+2,1.0,0,1.0,false,
+";
+        let clients = read_accounts(accounts.as_bytes()).unwrap();
+        let mut writer = CsvAccountsWriter::new(Vec::new(), OutputVersion::V6);
+        writer.write_accounts(&clients).unwrap();
+
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let mut lines: Vec<&str> = output.lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "1,-5.0000,0.0000,-5.0000,false,0,0.0000,USD,false,10.0000,true",
+                "2,1.0000,0.0000,1.0000,false,0,0.0000,USD,false,,false",
+                "client,available,held,total,locked,failed_withdrawals,fees_paid,currency,flagged,credit_limit,overdrawn",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_output_version() {
+        assert_eq!("1".parse(), Ok(OutputVersion::V1));
+        assert_eq!("2".parse(), Ok(OutputVersion::V2));
+        assert_eq!("3".parse(), Ok(OutputVersion::V3));
+        assert_eq!("4".parse(), Ok(OutputVersion::V4));
+        assert_eq!("5".parse(), Ok(OutputVersion::V5));
+        assert_eq!("6".parse(), Ok(OutputVersion::V6));
+        assert!("7".parse::<OutputVersion>().is_err());
+    }
+}
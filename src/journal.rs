@@ -0,0 +1,591 @@
+//! Tamper-evident journal of applied transactions.
+//!
+//! Every applied transaction is written as one journal entry, and each
+//! entry carries a hash covering the previous entry's hash plus the
+//! canonical serialization of the entry. Entries of format version 2 are
+//! serialized as:
+//!
+//! ```text
+//! entry  = index   u64, big endian
+//!          kind    u8, 0 deposit, 1 withdrawal, 2 dispute, 3 resolve, 4 chargeback,
+//!                  5 transfer, 6 reversal, 7 adjustment, 8 lock,
+//!                  9 unlock, 10 hold, 11 release, 12 purchase, 13 refund,
+//!                  14 settle, 15 accrue
+//!          client  u32, big endian
+//!          tx      u32, big endian
+//!          amount  0x00 if absent, otherwise 0x01 followed by the 16 byte
+//!                  `Decimal::serialize` of the normalized amount
+//!          to      0x00 if absent, otherwise 0x01 followed by the u32
+//!                  destination, big endian
+//!          fee     0x00 if absent, otherwise 0x01 followed by the 16 byte
+//!                  `Decimal::serialize` of the normalized fee
+//!          ccy     0x00 if absent, otherwise 0x01 followed by the u64 byte
+//!                  length, big endian, and the UTF-8 currency
+//! hash_0 = SHA-256([0; 32] || entry_0)
+//! hash_i = SHA-256(hash_{i-1} || entry_i)
+//! ```
+//!
+//! Version 1 entries, written before the journal had a version column, had
+//! no presence byte before the destination, the fee and the currency, and
+//! a single byte currency length. They are still verified as written.
+//!
+//! Only the structured fields are hashed, so how the journal file quotes
+//! or pads them does not matter. The hash of the last entry is the chain
+//! head, any edited, reordered or removed entry changes it.
+
+use crate::engine::RejectReason;
+use crate::model::{ClientId, Transaction, TransactionType};
+use crate::pipeline::{ParseFailure, RawInterest, RawSink, RawTx};
+use csv::{ReaderBuilder, Trim, Writer};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+pub type ChainHash = [u8; 32];
+
+/// Hash preceding the first entry
+pub const GENESIS: ChainHash = [0; 32];
+
+/// Format version of the entries written
+pub const JOURNAL_VERSION: u8 = 2;
+
+/// A single line of the journal file
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    /// Missing in journals written before entries had a version
+    #[serde(default = "first_version")]
+    version: u8,
+    index: u64,
+    #[serde(rename = "type")]
+    kind: TransactionType,
+    client: ClientId,
+    tx: u32,
+    amount: Option<Decimal>,
+    #[serde(default)]
+    to_client: Option<ClientId>,
+    #[serde(default)]
+    fee: Option<Decimal>,
+    #[serde(default)]
+    currency: Option<String>,
+    hash: String,
+}
+
+fn first_version() -> u8 {
+    1
+}
+
+fn kind_tag(kind: TransactionType) -> u8 {
+    match kind {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Transfer => 5,
+        TransactionType::Reversal => 6,
+        TransactionType::Adjustment => 7,
+        TransactionType::Lock => 8,
+        TransactionType::Unlock => 9,
+        TransactionType::Hold => 10,
+        TransactionType::Release => 11,
+        TransactionType::Purchase => 12,
+        TransactionType::Refund => 13,
+        TransactionType::Settle => 14,
+        TransactionType::Accrue => 15,
+    }
+}
+
+/// Hash of entry `index` following `previous` in the chain
+pub fn chain_hash(previous: &ChainHash, index: u64, transaction: &Transaction) -> ChainHash {
+    versioned_hash(JOURNAL_VERSION, previous, index, transaction)
+}
+
+/// Hash of an entry serialized as of format `version`
+fn versioned_hash(
+    version: u8,
+    previous: &ChainHash,
+    index: u64,
+    transaction: &Transaction,
+) -> ChainHash {
+    let mut hasher = Sha256::new();
+    hasher.update(previous);
+    hasher.update(index.to_be_bytes());
+    hasher.update([kind_tag(transaction.kind)]);
+    hasher.update(transaction.client_id.to_be_bytes());
+    hasher.update(transaction.id.to_be_bytes());
+    match transaction.amount {
+        Some(amount) => {
+            hasher.update([1]);
+            hasher.update(amount.normalize().serialize());
+        }
+        None => hasher.update([0]),
+    }
+    match (version, transaction.to_client) {
+        (1, Some(to_client)) => hasher.update(to_client.to_be_bytes()),
+        (1, None) => {}
+        (_, Some(to_client)) => {
+            hasher.update([1]);
+            hasher.update(to_client.to_be_bytes());
+        }
+        (_, None) => hasher.update([0]),
+    }
+    match (version, transaction.fee) {
+        (1, Some(fee)) => hasher.update(fee.normalize().serialize()),
+        (1, None) => {}
+        (_, Some(fee)) => {
+            hasher.update([1]);
+            hasher.update(fee.normalize().serialize());
+        }
+        (_, None) => hasher.update([0]),
+    }
+    match (version, &transaction.currency) {
+        // Longer currencies were cut off in the length, as written then
+        (1, Some(currency)) => {
+            hasher.update([currency.len() as u8]);
+            hasher.update(currency.as_bytes());
+        }
+        (1, None) => {}
+        (_, Some(currency)) => {
+            hasher.update([1]);
+            hasher.update((currency.len() as u64).to_be_bytes());
+            hasher.update(currency.as_bytes());
+        }
+        (_, None) => hasher.update([0]),
+    }
+    hasher.finalize().into()
+}
+
+pub fn to_hex(hash: &ChainHash) -> String {
+    hash.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Writes applied transactions as journal entries
+pub struct JournalWriter<W: Write> {
+    writer: Writer<W>,
+    index: u64,
+    head: ChainHash,
+}
+
+impl<W: Write> JournalWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Writer::from_writer(writer),
+            index: 0,
+            head: GENESIS,
+        }
+    }
+
+    /// Hash of the last entry written
+    pub fn head(&self) -> ChainHash {
+        self.head
+    }
+
+    pub fn append(&mut self, transaction: &Transaction) -> io::Result<()> {
+        self.head = chain_hash(&self.head, self.index, transaction);
+        self.writer.serialize(JournalEntry {
+            version: JOURNAL_VERSION,
+            index: self.index,
+            kind: transaction.kind,
+            client: transaction.client_id,
+            tx: transaction.id,
+            amount: transaction.amount,
+            to_client: transaction.to_client,
+            fee: transaction.fee,
+            currency: transaction.currency.clone(),
+            hash: to_hex(&self.head),
+        })?;
+        self.index += 1;
+        Ok(())
+    }
+
+    /// Flush the journal and return the chain head
+    pub fn finish(mut self) -> io::Result<ChainHash> {
+        self.writer.flush()?;
+        Ok(self.head)
+    }
+}
+
+impl<W: Write> RawSink for JournalWriter<W> {
+    fn interest(&self) -> RawInterest {
+        RawInterest::NONE
+    }
+
+    fn applied(&mut self, _raw: &RawTx, transaction: &Transaction) -> io::Result<()> {
+        self.append(transaction)
+    }
+
+    fn parse_failed(&mut self, _raw: &RawTx, _failure: &ParseFailure) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rejected(&mut self, _raw: &RawTx, _reason: RejectReason) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// First problem found while verifying a journal
+#[derive(Debug, PartialEq, Eq)]
+pub enum JournalError {
+    /// Entry could not be read at all
+    Malformed { index: u64, message: String },
+    /// Entry of a format version this build does not know
+    UnsupportedVersion { index: u64, version: u8 },
+    /// Entry content does not match its hash
+    Edited { index: u64 },
+    /// Entry with this index is missing
+    Missing { index: u64 },
+    /// Entry with this index is not where it belongs
+    OutOfOrder { index: u64 },
+    /// All entries are consistent but the chain ends at a different head,
+    /// e.g. because trailing entries were removed
+    HeadMismatch { expected: String, found: String },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Malformed { index, message } => {
+                write!(f, "journal entry {index} is malformed: {message}")
+            }
+            JournalError::UnsupportedVersion { index, version } => write!(
+                f,
+                "journal entry {index} has unsupported version {version}, supported \
+                 versions: 1 to {JOURNAL_VERSION}"
+            ),
+            JournalError::Edited { index } => {
+                write!(f, "journal entry {index} does not match its hash")
+            }
+            JournalError::Missing { index } => write!(f, "journal entry {index} is missing"),
+            JournalError::OutOfOrder { index } => {
+                write!(f, "journal entry {index} is out of order")
+            }
+            JournalError::HeadMismatch { expected, found } => {
+                write!(f, "journal head is {found}, expected {expected}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+/// Recompute the hash chain over a journal and return its head.
+///
+/// If `expected_head` is given the recomputed head must match it, which
+/// also detects entries removed from the end of the journal.
+pub fn verify_journal<R: Read>(
+    rdr: R,
+    expected_head: Option<&str>,
+) -> Result<ChainHash, JournalError> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(rdr);
+    let mut entries = reader.deserialize::<JournalEntry>().peekable();
+    let mut head = GENESIS;
+    let mut index = 0;
+
+    while let Some(entry) = entries.next() {
+        let entry = entry.map_err(|e| JournalError::Malformed {
+            index,
+            message: e.to_string(),
+        })?;
+        if !(1..=JOURNAL_VERSION).contains(&entry.version) {
+            return Err(JournalError::UnsupportedVersion {
+                index,
+                version: entry.version,
+            });
+        }
+        if entry.index != index {
+            // A swapped pair has the expected entry right after this one
+            let swapped = matches!(entries.peek(), Some(Ok(next)) if next.index == index);
+            return Err(if swapped || entry.index < index {
+                JournalError::OutOfOrder { index }
+            } else {
+                JournalError::Missing { index }
+            });
+        }
+
+        let transaction = Transaction {
+            kind: entry.kind,
+            client_id: entry.client,
+            id: entry.tx,
+            amount: entry.amount,
+            to_client: entry.to_client,
+            fee: entry.fee,
+            currency: entry.currency,
+            // Timestamps do not change balances and are not journaled
+            timestamp: None,
+        };
+        head = versioned_hash(entry.version, &head, index, &transaction);
+        if to_hex(&head) != entry.hash {
+            return Err(JournalError::Edited { index });
+        }
+        index += 1;
+    }
+
+    if let Some(expected) = expected_head {
+        let found = to_hex(&head);
+        if !found.eq_ignore_ascii_case(expected) {
+            return Err(JournalError::HeadMismatch {
+                expected: expected.to_string(),
+                found,
+            });
+        }
+    }
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    fn journal() -> (String, String) {
+        let transactions = [
+            (TransactionType::Deposit, 1, 1, Some(dec!(10.0)), None),
+            (TransactionType::Deposit, 2, 2, Some(dec!(2.5)), None),
+            (TransactionType::Withdrawal, 1, 3, Some(dec!(1.25)), None),
+            (TransactionType::Dispute, 2, 2, None, None),
+            (TransactionType::Transfer, 1, 4, Some(dec!(3.0)), Some(7)),
+        ];
+        let mut writer = JournalWriter::new(Vec::new());
+        for (kind, client_id, id, amount, to_client) in transactions {
+            writer
+                .append(&Transaction {
+                    kind,
+                    client_id,
+                    id,
+                    amount,
+                    to_client,
+                    fee: None,
+                    currency: None,
+                    timestamp: None,
+                })
+                .unwrap();
+        }
+        let head = to_hex(&writer.head());
+        let data = String::from_utf8(writer.writer.into_inner().unwrap()).unwrap();
+        (data, head)
+    }
+
+    /// Journal of version 1 entries, as written before entries had a version
+    fn journal_v1(transactions: &[Transaction]) -> String {
+        let mut head = GENESIS;
+        let mut data = String::from("index,type,client,tx,amount,to_client,fee,currency,hash\n");
+        for (index, transaction) in (0..).zip(transactions) {
+            head = versioned_hash(1, &head, index, transaction);
+            let optional = |value: Option<String>| value.unwrap_or_default();
+            data += &format!(
+                "{index},{},{},{},{},{},{},{},{}\n",
+                transaction.kind,
+                transaction.client_id,
+                transaction.id,
+                optional(transaction.amount.map(|amount| amount.to_string())),
+                optional(transaction.to_client.map(|to| to.to_string())),
+                optional(transaction.fee.map(|fee| fee.to_string())),
+                optional(transaction.currency.clone()),
+                to_hex(&head)
+            );
+        }
+        data
+    }
+
+    fn withdrawal(fee: Option<Decimal>) -> Transaction {
+        Transaction {
+            kind: TransactionType::Withdrawal,
+            client_id: 1,
+            id: 3,
+            amount: Some(dec!(1.25)),
+            to_client: None,
+            fee,
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    fn swap_lines(data: &str, a: usize, b: usize) -> String {
+        let mut lines: Vec<&str> = data.lines().collect();
+        lines.swap(a, b);
+        lines.join("\n") + "\n"
+    }
+
+    #[test]
+    fn test_verify_untouched_journal() {
+        let (data, head) = journal();
+
+        let verified = verify_journal(data.as_bytes(), Some(&head)).unwrap();
+        assert_eq!(to_hex(&verified), head);
+    }
+
+    #[test]
+    fn test_hash_independent_of_formatting() {
+        let (data, head) = journal();
+        // Same values, padded and with a different decimal scale
+        let data = data.replace(",10.0,", ", 10.000 ,");
+
+        assert!(verify_journal(data.as_bytes(), Some(&head)).is_ok());
+    }
+
+    #[test]
+    fn test_verify_edited_transfer_destination() {
+        let (data, head) = journal();
+        let data = data.replace(",3.0,7,", ",3.0,8,");
+
+        assert_eq!(
+            verify_journal(data.as_bytes(), Some(&head)),
+            Err(JournalError::Edited { index: 4 })
+        );
+    }
+
+    #[test]
+    fn test_verify_single_character_edit() {
+        let (data, head) = journal();
+        let data = data.replace(",1.25,", ",1.26,");
+
+        assert_eq!(
+            verify_journal(data.as_bytes(), Some(&head)),
+            Err(JournalError::Edited { index: 2 })
+        );
+    }
+
+    #[test]
+    fn test_verify_deleted_line() {
+        let (data, head) = journal();
+        let mut lines: Vec<&str> = data.lines().collect();
+        lines.remove(2);
+        let data = lines.join("\n") + "\n";
+
+        let err = verify_journal(data.as_bytes(), Some(&head)).unwrap_err();
+        assert_eq!(err, JournalError::Missing { index: 1 });
+        assert_eq!(err.to_string(), "journal entry 1 is missing");
+    }
+
+    #[test]
+    fn test_verify_deleted_last_line() {
+        let (data, head) = journal();
+        let mut lines: Vec<&str> = data.lines().collect();
+        lines.pop();
+        let data = lines.join("\n") + "\n";
+
+        assert!(matches!(
+            verify_journal(data.as_bytes(), Some(&head)),
+            Err(JournalError::HeadMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_version_1_journal() {
+        let data = journal_v1(&[withdrawal(Some(dec!(0.5))), withdrawal(None)]);
+        assert!(verify_journal(data.as_bytes(), None).is_ok());
+
+        let data = data.replacen(",0.5,", ",0.6,", 1);
+        assert_eq!(
+            verify_journal(data.as_bytes(), None),
+            Err(JournalError::Edited { index: 0 })
+        );
+    }
+
+    #[test]
+    fn test_fee_presence_is_hashed() {
+        let with_fee = chain_hash(&GENESIS, 0, &withdrawal(Some(dec!(0.5))));
+        let without_fee = chain_hash(&GENESIS, 0, &withdrawal(None));
+        assert_ne!(with_fee, without_fee);
+        // Version 1 wrote nothing for an absent fee
+        assert_ne!(
+            without_fee,
+            versioned_hash(1, &GENESIS, 0, &withdrawal(None))
+        );
+
+        let mut writer = JournalWriter::new(Vec::new());
+        writer.append(&withdrawal(Some(dec!(0.5)))).unwrap();
+        let data = String::from_utf8(writer.writer.into_inner().unwrap()).unwrap();
+        assert!(data.starts_with("version,index,"));
+        let data = data.replace(",0.5,", ",,");
+        assert_eq!(
+            verify_journal(data.as_bytes(), None),
+            Err(JournalError::Edited { index: 0 })
+        );
+    }
+
+    #[test]
+    fn test_destination_presence_is_hashed() {
+        // Destination 0 and an 11 byte currency are the same bytes as a fee
+        let mut transfer = withdrawal(None);
+        transfer.kind = TransactionType::Transfer;
+        transfer.to_client = Some(0);
+        transfer.currency = Some("ABCDEFGHIJK".to_string());
+        let mut fee = [0; 16];
+        fee[4] = 11;
+        fee[5..].copy_from_slice(b"ABCDEFGHIJK");
+        let mut moved = withdrawal(Some(Decimal::deserialize(fee)));
+        moved.kind = TransactionType::Transfer;
+
+        assert_eq!(
+            versioned_hash(1, &GENESIS, 0, &transfer),
+            versioned_hash(1, &GENESIS, 0, &moved)
+        );
+        assert_ne!(
+            chain_hash(&GENESIS, 0, &transfer),
+            chain_hash(&GENESIS, 0, &moved)
+        );
+    }
+
+    #[test]
+    fn test_currency_of_any_length_is_hashed() {
+        let in_currency = |currency: &str| {
+            let mut transaction = withdrawal(None);
+            transaction.currency = Some(currency.to_string());
+            transaction
+        };
+        // Version 1 cut the length of this one off to 0
+        let long = "X".repeat(256);
+        assert_ne!(
+            chain_hash(&GENESIS, 0, &in_currency(&long)),
+            chain_hash(&GENESIS, 0, &in_currency(""))
+        );
+        assert_ne!(
+            chain_hash(&GENESIS, 0, &in_currency("")),
+            chain_hash(&GENESIS, 0, &withdrawal(None))
+        );
+
+        let mut writer = JournalWriter::new(Vec::new());
+        writer.append(&in_currency(&long)).unwrap();
+        let head = to_hex(&writer.head());
+        let data = String::from_utf8(writer.writer.into_inner().unwrap()).unwrap();
+        assert!(verify_journal(data.as_bytes(), Some(&head)).is_ok());
+        let data = data.replace(&long, &"X".repeat(255));
+        assert_eq!(
+            verify_journal(data.as_bytes(), Some(&head)),
+            Err(JournalError::Edited { index: 0 })
+        );
+    }
+
+    #[test]
+    fn test_verify_unsupported_version() {
+        let (data, _) = journal();
+        let data = data.replacen("\n2,0,", "\n3,0,", 1);
+
+        let err = verify_journal(data.as_bytes(), None).unwrap_err();
+        assert_eq!(
+            err,
+            JournalError::UnsupportedVersion {
+                index: 0,
+                version: 3
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "journal entry 0 has unsupported version 3, supported versions: 1 to 2"
+        );
+    }
+
+    #[test]
+    fn test_verify_swapped_lines() {
+        let (data, head) = journal();
+        // Line 0 is the header
+        let data = swap_lines(&data, 2, 3);
+
+        let err = verify_journal(data.as_bytes(), Some(&head)).unwrap_err();
+        assert_eq!(err, JournalError::OutOfOrder { index: 1 });
+        assert_eq!(err.to_string(), "journal entry 1 is out of order");
+    }
+}
@@ -0,0 +1,47 @@
+//! Transaction engine simulating the accounts of a small bank.
+//!
+//! Transactions are applied in order by [`process_transactions`], which
+//! returns the resulting [`Client`] accounts keyed by client id along with
+//! the transactions which were rejected. For
+//! incremental processing an [`Engine`] can be driven one transaction at
+//! a time with [`Engine::apply`].
+//!
+//! Inputs in other formats can be fed to the engine by implementing
+//! [`TransactionSource`], as [`CsvSource`], [`JsonLinesSource`],
+//! [`MsgpackSource`], [`columnar::ParquetSource`],
+//! [`columnar::ArrowSource`] and [`proto::ProtoSource`] do.
+
+pub mod avro;
+pub mod budget;
+pub mod columnar;
+pub mod config;
+pub mod engine;
+pub mod fx;
+pub mod input;
+pub mod io;
+pub mod journal;
+pub mod model;
+pub mod observer;
+pub mod pipeline;
+pub mod proto;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod snapshot;
+pub mod verify;
+pub mod watch;
+
+pub use config::{
+    DuplicateTxIdPolicy, EngineConfig, LockedDepositPolicy, PrecisionPolicy, RedisputePolicy,
+    ScientificAmounts, TxIdScope,
+};
+pub use engine::{
+    Applied, DisputeCounts, Engine, MergeError, RejectReason, Rejection, RollbackError, Settlement,
+    TransactionOutcome, process_transactions,
+};
+pub use io::{CsvSource, JsonLinesSource, MsgpackSource, TransactionSource};
+pub use model::{
+    Client, ClientId, DisputeState, Timestamp, Transaction, TransactionRecord, TransactionType,
+};
+pub use observer::{EngineObserver, NoObserver};
+pub use snapshot::{EngineSnapshot, SnapshotError, SnapshotVersion};
+pub use verify::{Invariant, InvariantViolation};
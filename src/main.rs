@@ -1,56 +1,424 @@
-use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
-use anyhow::Result;
-use clap::Parser;
-use csv::{ReaderBuilder, Trim};
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
 use rust_decimal::Decimal;
-use serde::Deserialize;
+use std::cell::Cell;
 use std::fs::File;
-use tracing::{debug, error, info, warn};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use transaction_engine::avro::AvroSource;
+use transaction_engine::budget::StorageBudget;
+use transaction_engine::columnar::{ArrowSource, ParquetSource};
+use transaction_engine::fx::{self, Rates};
+use transaction_engine::input::{Input, InputFormat, SkipBom, TextEncoding};
+use transaction_engine::io::{
+    AccountOrder, AccountSort, ColumnMapping, CsvAccountsWriter, CsvDialect, JsonAccountsWriter,
+    LenientAmounts, OutputFormat, OutputSink, TableAccountsWriter,
+};
+use transaction_engine::journal::{self, JournalWriter};
+use transaction_engine::pipeline::{
+    self, MalformedRows, ParseFailure, Quarantine, RawInterest, RawJsonLines, RawMsgpack, RawProto,
+    RawSink, RawSource, RawTx, VerboseRejects,
+};
+use transaction_engine::watch::{self, DirWatcher};
+use transaction_engine::{
+    DuplicateTxIdPolicy, Engine, EngineConfig, EngineSnapshot, LockedDepositPolicy,
+    PrecisionPolicy, RedisputePolicy, ScientificAmounts, SnapshotVersion, TxIdScope, input, io,
+};
 
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Opts {
-    filename: String,
+    /// Files of transactions to process in order, read from stdin if
+    /// omitted or `-`. Gzip and zstd files are decompressed as they are read,
+    /// zip archives are read as the CSV files they contain
+    filenames: Vec<String>,
+    /// Process the files matching this pattern, ordered by name
+    #[arg(long, value_name = "PATTERN", conflicts_with = "filenames")]
+    glob: Option<String>,
+    /// List the files being processed on stderr
+    #[arg(long)]
+    verbose: bool,
+    /// Format of the input files: csv, jsonl, parquet, arrow, avro, msgpack
+    /// or proto
+    #[arg(long, value_name = "FORMAT", default_value = "csv")]
+    format: InputFormat,
+    /// Map plain local files into memory instead of reading them. The
+    /// files must not change while the run reads them
+    #[arg(long)]
+    mmap: bool,
+    /// Keep reading the input file as it grows, like `tail -f`, until
+    /// interrupted with Ctrl-C
+    #[arg(long, conflicts_with_all = ["glob", "mmap", "order_by_timestamp"])]
+    follow: bool,
+    /// Milliseconds to wait for more bytes at the end of a followed file
+    #[arg(long, value_name = "MS", default_value = "500", requires = "follow")]
+    follow_interval: u64,
+    /// Process the files of this directory in name order, then every file
+    /// dropped into it, until interrupted with Ctrl-C
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["filenames", "glob", "follow", "mmap", "order_by_timestamp", "checkpoint_every"]
+    )]
+    watch: Option<String>,
+    /// Milliseconds a watched file without a `.done` marker must stay
+    /// unchanged before it is processed
+    #[arg(long, value_name = "MS", default_value = "1000", requires = "watch")]
+    watch_settle: u64,
+    /// Parse CSV rows without serde when they only have the type, client,
+    /// tx and amount columns
+    #[arg(long)]
+    fast_parse: bool,
+    /// Character encoding of CSV and JSON Lines input: utf8, latin1 for
+    /// Windows-1252, or auto to tell UTF-8 and UTF-16 apart by their byte
+    /// order mark
+    #[arg(long, value_name = "ENCODING", default_value = "utf8")]
+    encoding: TextEncoding,
+    /// Field delimiter of CSV input, a single byte or `\t` for a tab
+    #[arg(long, value_name = "CHAR", default_value = ",", value_parser = io::parse_csv_byte)]
+    delimiter: u8,
+    /// Quote character of CSV input
+    #[arg(long, value_name = "CHAR", default_value = "\"", value_parser = io::parse_csv_byte)]
+    quote_char: u8,
+    /// CSV input has no header, its columns are type, client, tx and
+    /// amount in this order
+    #[arg(long)]
+    no_header: bool,
+    /// Rename a CSV column before reading rows, e.g. `txn_type=type`.
+    /// May be repeated
+    #[arg(
+        long = "map-column",
+        value_name = "FROM=TO",
+        conflicts_with = "no_header"
+    )]
+    map_columns: Vec<ColumnMapping>,
+    /// Accept amounts with a currency symbol and thousands separators,
+    /// like `$1,234.56`
+    #[arg(long)]
+    lenient_amounts: bool,
+    /// Currency symbol stripped from lenient amounts
+    #[arg(
+        long,
+        value_name = "SYMBOL",
+        default_value = "$",
+        requires = "lenient_amounts"
+    )]
+    currency_symbol: String,
+    /// Lenient amounts have a decimal comma, like `1.234,56`
+    #[arg(long, requires = "lenient_amounts")]
+    decimal_comma: bool,
+    /// Format of the accounts output: csv, json for an array of accounts,
+    /// jsonl for an account per line, amounts being strings in JSON, or
+    /// table for an aligned table to read in a terminal
+    #[arg(long, value_name = "FORMAT", default_value = "csv")]
+    output_format: OutputFormat,
+    /// Group the digits of amounts in thousands in table output
+    #[arg(long)]
+    human: bool,
+    /// Column the accounts are sorted by: client or total
+    #[arg(long, value_name = "COLUMN", default_value = "client")]
+    sort: AccountSort,
+    /// Sort the accounts in descending order, e.g. the biggest totals first
+    #[arg(long)]
+    desc: bool,
+    /// Write the accounts to this file instead of stdout. The file only
+    /// appears once complete
+    #[arg(long, value_name = "PATH")]
+    output: Option<String>,
+    /// Field delimiter of the accounts output
+    #[arg(long, value_name = "CHAR", default_value = ",", value_parser = io::parse_csv_byte)]
+    output_delimiter: u8,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Write rows which failed to parse or were rejected to this file
+    #[arg(long)]
+    quarantine: Option<String>,
+    /// Report every row which was not applied on stderr
+    #[arg(long)]
+    verbose_rejects: bool,
+    /// Fail at the first row which can not be parsed instead of skipping it
+    #[arg(long)]
+    strict: bool,
+    /// Reject disputes, resolves and chargebacks which carry an amount
+    #[arg(long)]
+    strict_fields: bool,
+    /// Only accept lowercase transaction types, without aliases
+    #[arg(long)]
+    strict_types: bool,
+    /// Write every applied transaction to this hash chained journal
+    #[arg(long)]
+    journal: Option<String>,
+    /// Open disputes even if they drive available funds negative
+    #[arg(long, alias = "allow-negative-available")]
+    allow_negative_on_dispute: bool,
+    /// Allow disputes against withdrawals
+    #[arg(long)]
+    dispute_withdrawals: bool,
+    /// Leave the client unlocked when a disputed withdrawal is charged back
+    #[arg(long)]
+    no_lock_on_withdrawal_chargeback: bool,
+    /// Ignore disputes, resolves and chargebacks for locked accounts
+    #[arg(long, conflicts_with = "dispute_locked_accounts")]
+    ignore_locked_account_disputes: bool,
+    /// Disputes on locked accounts are processed by default now, the flag
+    /// is kept for existing invocations
+    #[arg(long, hide = true)]
+    dispute_locked_accounts: bool,
+    /// Hold only the amount of dispute rows carrying one
+    #[arg(long)]
+    allow_partial_disputes: bool,
+    /// Reject adjustments for locked accounts
+    #[arg(long)]
+    no_adjust_locked_accounts: bool,
+    /// Let unlock rows unlock clients with open disputes
+    #[arg(long)]
+    force_unlock: bool,
+    /// Flag clients once they had more than this many chargebacks
+    #[arg(long, value_name = "N")]
+    flag_after_chargebacks: Option<u32>,
+    /// Reject withdrawals and transfers of flagged clients
+    #[arg(long, requires = "flag_after_chargebacks")]
+    freeze_flagged: bool,
+    /// Reject withdrawals and transfers leaving less available funds
+    #[arg(long, value_name = "AMOUNT")]
+    minimum_balance: Option<Decimal>,
+    /// Reject deposits and withdrawals above this amount
+    #[arg(long, value_name = "AMOUNT")]
+    max_amount: Option<Decimal>,
+    /// Cap the withdrawals of a client per UTC day
+    #[arg(long, value_name = "AMOUNT")]
+    daily_withdrawal_limit: Option<Decimal>,
+    /// Reject rows with a timestamp earlier than the previous row's of the
+    /// same file
+    #[arg(long)]
+    enforce_monotonic_timestamps: bool,
+    /// Currency of rows without a currency column
+    #[arg(long, value_name = "CURRENCY", default_value = "USD")]
+    base_currency: String,
+    /// Reject disputes once a client has this many open disputes
+    #[arg(long, value_name = "N")]
+    max_open_disputes_per_client: Option<u32>,
+    /// Whether closed disputes may be reopened: allow-always,
+    /// deny-after-resolve or deny-after-any-closure
+    #[arg(long, value_name = "POLICY", default_value = "allow-always")]
+    redispute_policy: RedisputePolicy,
+    /// Accept deposits and withdrawals of zero
+    #[arg(long)]
+    allow_zero_amounts: bool,
+    /// Handling of amounts with more than four decimal places:
+    /// passthrough, reject-excess or round-half-even
+    #[arg(long, value_name = "POLICY", default_value = "passthrough")]
+    precision_policy: PrecisionPolicy,
+    /// Fail Arrow input whose amount column holds floats rather than
+    /// converting them
+    #[arg(long)]
+    reject_float_amounts: bool,
+    /// Handling of amounts in scientific notation like `1.2E3`: reject or
+    /// accept
+    #[arg(long, value_name = "POLICY", default_value = "reject")]
+    scientific_amounts: ScientificAmounts,
+    /// Whether transaction ids are unique across all clients (global) or
+    /// only within each client (per-client)
+    #[arg(long, value_name = "SCOPE", default_value = "global")]
+    tx_id_scope: TxIdScope,
+    /// Whether the ids of rejected deposits, withdrawals and the like are
+    /// taken as well: reject-applied or reject-any
+    #[arg(long, value_name = "POLICY", default_value = "reject-applied")]
+    duplicate_tx_ids: DuplicateTxIdPolicy,
+    /// Park disputes, resolves and chargebacks arriving before the
+    /// transaction they reference, until it arrives
+    #[arg(long)]
+    defer_unmatched_disputes: bool,
+    /// Maximum of parked dispute steps
+    #[arg(long, value_name = "N", default_value_t = 10_000)]
+    max_deferred_disputes: usize,
+    /// Unlock a client once none of its disputes is open anymore
+    #[arg(long)]
+    auto_unlock: bool,
+    /// Deposits for locked clients: reject, hold until the client is
+    /// unlocked, or drop without reporting them
+    #[arg(long, value_name = "POLICY", default_value = "reject")]
+    locked_deposit_policy: LockedDepositPolicy,
+    /// Resolve a dispute by itself once its client had this many further
+    /// transactions applied
+    #[arg(long, value_name = "N")]
+    dispute_ttl_events: Option<u32>,
+    /// Charge back a dispute once the row timestamps pass the one of its
+    /// dispute row by this many hours
+    #[arg(long, value_name = "HOURS")]
+    auto_chargeback_after_hours: Option<u32>,
+    /// Pin the accounts output to the layout of this version
+    #[arg(long, value_name = "VERSION", default_value = "1")]
+    output_compat: io::OutputVersion,
+    /// Add the newest columns to the accounts output, e.g. the number of
+    /// withdrawals rejected for insufficient funds
+    #[arg(long, conflicts_with = "output_compat")]
+    extended_output: bool,
+    /// Open accounts with the balances of this file, in the output layout
+    #[arg(long, value_name = "PATH", conflicts_with = "load_state")]
+    initial_accounts: Option<String>,
+    /// Check the account invariants after processing and fail if any is
+    /// violated
+    #[arg(long)]
+    verify: bool,
+    /// Continue from the engine state saved by an earlier run
+    #[arg(long, value_name = "PATH")]
+    load_state: Option<String>,
+    /// Save the engine state after processing, for a later --load-state
+    #[arg(long, value_name = "PATH")]
+    save_state: Option<String>,
+    /// Save the state in the format of this snapshot version, leaving out
+    /// what it can not hold
+    #[arg(
+        long,
+        value_name = "VERSION",
+        default_value = "2",
+        requires = "save_state"
+    )]
+    snapshot_compat: SnapshotVersion,
+    /// Also save the state to --save-state after every N rows
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "save_state",
+        conflicts_with = "order_by_timestamp"
+    )]
+    checkpoint_every: Option<NonZeroUsize>,
+    /// Approximate budget in bytes for the stored engine state
+    #[cfg(feature = "mem-budget")]
+    #[arg(long)]
+    memory_budget: Option<usize>,
+    /// Rates of other currencies to the base currency, as `currency,rate`
+    #[arg(long, value_name = "PATH", requires = "consolidate")]
+    rates: Option<String>,
+    /// Write a single row per client with every currency converted to the
+    /// base currency
+    #[arg(long, requires = "rates")]
+    consolidate: bool,
+    /// Apply the rows in the order of their timestamps instead of the
+    /// order of the file
+    #[arg(long)]
+    order_by_timestamp: bool,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
-enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
-}
-
-#[derive(Debug, Deserialize)]
-struct Transaction {
-    #[serde(rename = "type")]
-    kind: TransactionType,
-    #[serde(rename = "client")]
-    client_id: u16,
-    #[serde(rename = "tx")]
-    id: u32,
-    amount: Option<Decimal>,
-}
-
-#[derive(Debug, Default)]
-struct Client {
-    available_funds: Decimal,
-    held_funds: Decimal,
-    total_funds: Decimal,
-    locked: bool,
-}
-
-#[derive(Debug)]
-struct TransactionRecord {
-    client_id: u16,
-    amount: Decimal,
-    transaction_type: TransactionType,
+#[derive(Subcommand)]
+enum Command {
+    /// Recompute the hash chain of a journal written with --journal
+    VerifyJournal {
+        path: String,
+        /// Chain head the journal must end at
+        #[arg(long)]
+        head: Option<String>,
+    },
 }
 
 fn main() -> Result<()> {
     let opts = Opts::parse();
-    let file = File::open(&opts.filename)?;
+    if let Some(Command::VerifyJournal { path, head }) = &opts.command {
+        let head = journal::verify_journal(File::open(path)?, head.as_deref())?;
+        println!("Journal verified, head {}", journal::to_hex(&head));
+        return Ok(());
+    }
+    if opts.output_format != OutputFormat::Csv {
+        let csv_only = if opts.extended_output {
+            Some("--extended-output")
+        } else if opts.output_compat != io::OutputVersion::V1 {
+            Some("--output-compat")
+        } else if opts.output_delimiter != b',' {
+            Some("--output-delimiter")
+        } else {
+            None
+        };
+        if let Some(flag) = csv_only {
+            bail!(
+                "{flag} only applies to CSV output, JSON and table output have the fields of \
+                 output version 1 and no delimiter"
+            );
+        }
+    }
+    if opts.output_format == OutputFormat::Table {
+        let csv_path = opts.output.as_deref().filter(|path| {
+            std::path::Path::new(path)
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"))
+        });
+        if let Some(path) = csv_path {
+            bail!("table output is not CSV, refusing to write it to {path}");
+        }
+    } else if opts.human {
+        bail!("--human only applies to table output");
+    }
+    // Opened before any row is read, so an unwritable path fails right away
+    let output = opts
+        .output
+        .as_deref()
+        .map(PendingOutput::create)
+        .transpose()?;
+    let filenames = match (&opts.glob, opts.filenames.as_slice()) {
+        (None, []) if opts.watch.is_some() => Vec::new(),
+        (Some(pattern), _) => expand_glob(pattern)?,
+        (None, []) => vec![input::STDIN.to_string()],
+        (None, filenames) => filenames.to_vec(),
+    };
+    // Parquet is read out of order, its files are opened by ParquetSource
+    let mut inputs = Vec::new();
+    let stop = Arc::new(AtomicBool::new(false));
+    if opts.follow || opts.watch.is_some() {
+        // The run ends like at the end of its input, writing the accounts
+        let stop = stop.clone();
+        ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))?;
+    }
+    if opts.watch.is_some() && opts.format == InputFormat::Parquet {
+        bail!("parquet input can not be watched");
+    }
+    if opts.encoding != TextEncoding::Utf8
+        && !matches!(opts.format, InputFormat::Csv | InputFormat::JsonLines)
+    {
+        bail!("--encoding only applies to csv and jsonl input");
+    }
+    if opts.follow {
+        if !matches!(opts.format, InputFormat::Csv | InputFormat::JsonLines) {
+            bail!("only csv and jsonl input can be followed");
+        }
+        let [filename] = filenames.as_slice() else {
+            bail!("only a single file can be followed");
+        };
+        let interval = Duration::from_millis(opts.follow_interval);
+        inputs.extend(input::open_followed(filename, interval, stop.clone())?);
+    } else if opts.format != InputFormat::Parquet {
+        for filename in &filenames {
+            if opts.mmap {
+                inputs.extend(input::open_mapped(filename)?);
+            } else {
+                inputs.extend(input::open(filename)?);
+            }
+        }
+    }
+    let names: Vec<String> = match opts.format {
+        InputFormat::Parquet => filenames.clone(),
+        _ => inputs
+            .iter()
+            .map(|input| input.name().to_string())
+            .collect(),
+    };
+    if opts.verbose {
+        for name in &names {
+            eprintln!("Processing {name}");
+        }
+    }
+    if names.len() > usize::from(u16::MAX) {
+        bail!("at most {} input files are supported", u16::MAX);
+    }
+    // Reports only name the file a row came from with several of them
+    let sources = if names.len() > 1 { names } else { Vec::new() };
+    let readers = inputs
+        .iter_mut()
+        .map(Input::reader)
+        .collect::<Result<Vec<_>, _>>()?;
 
     let (non_blocking_writer, _tracing_worker_guard) =
         tracing_appender::non_blocking(File::create("transaction_engine.log")?);
@@ -61,934 +429,495 @@ fn main() -> Result<()> {
         .with_level(true)
         .init();
 
-    let mut reader = ReaderBuilder::new()
-        .flexible(true)
-        .trim(Trim::All)
-        .from_reader(file);
-    let records = reader
-        .deserialize::<Transaction>()
-        .map(|r| r.map_err(Into::into));
-
-    let clients = process_transactions(records);
-
-    //Output client data
-    println!("client,available,held,total,locked");
-    for (client_id, client) in clients {
-        println!(
-            "{},{:.4},{:.4},{:.4},{}",
-            client_id, client.available_funds, client.held_funds, client.total_funds, client.locked
-        );
+    let mut quarantine = match &opts.quarantine {
+        Some(path) => Some(Quarantine::new(BufWriter::new(File::create(path)?))),
+        None => None,
+    };
+    let mut verbose_rejects = opts
+        .verbose_rejects
+        .then(|| VerboseRejects::new(std::io::stderr()).sources(sources.clone()));
+    let mut journal = match &opts.journal {
+        Some(path) => Some(JournalWriter::new(BufWriter::new(File::create(path)?))),
+        None => None,
+    };
+    let mut malformed = MalformedRows::new(opts.strict).sources(sources);
+    let mut sinks: Vec<&mut dyn RawSink> = vec![&mut malformed];
+    if let Some(sink) = quarantine.as_mut() {
+        sinks.push(sink);
     }
-
-    Ok(())
-}
-
-fn process_transactions<T>(records: T) -> HashMap<u16, Client>
-where
-    T: IntoIterator<Item = Result<Transaction>>,
-{
-    let mut clients: HashMap<u16, Client> = HashMap::new();
-    let mut transaction_records: HashMap<u32, TransactionRecord> = HashMap::new();
-    let mut disputed_transaction: HashSet<u32> = HashSet::new();
-
-    for record in records {
-        info!("Processing {:?}", record);
-        let current_transaction = match record {
-            Ok(r) => r,
-            Err(e) => {
-                warn!("Invalid transaction {e}");
-                continue;
-            }
-        };
-        let client = clients.entry(current_transaction.client_id).or_default();
-
-        // Ignore all transactions from locked client
-        if client.locked {
-            debug!("Client {} is locked", current_transaction.client_id);
-            continue;
+    if let Some(sink) = verbose_rejects.as_mut() {
+        sinks.push(sink);
+    }
+    if let Some(sink) = journal.as_mut() {
+        sinks.push(sink);
+    }
+    let config = EngineConfig::builder()
+        .allow_negative_on_dispute(opts.allow_negative_on_dispute)
+        .dispute_withdrawals(opts.dispute_withdrawals)
+        .lock_on_withdrawal_chargeback(!opts.no_lock_on_withdrawal_chargeback)
+        .dispute_locked_accounts(
+            opts.dispute_locked_accounts || !opts.ignore_locked_account_disputes,
+        )
+        .max_open_disputes_per_client(opts.max_open_disputes_per_client)
+        .redispute_policy(opts.redispute_policy)
+        .allow_zero_amounts(opts.allow_zero_amounts)
+        .precision_policy(opts.precision_policy)
+        .tx_id_scope(opts.tx_id_scope)
+        .duplicate_tx_id_policy(opts.duplicate_tx_ids)
+        .strict_fields(opts.strict_fields)
+        .strict_types(opts.strict_types)
+        .defer_unmatched_disputes(opts.defer_unmatched_disputes)
+        .max_deferred_disputes(opts.max_deferred_disputes)
+        .auto_unlock(opts.auto_unlock)
+        .dispute_ttl_events(opts.dispute_ttl_events)
+        .auto_chargeback_after(
+            opts.auto_chargeback_after_hours
+                .map(|hours| chrono::Duration::hours(hours.into())),
+        )
+        .locked_deposit_policy(opts.locked_deposit_policy)
+        .allow_partial_disputes(opts.allow_partial_disputes)
+        .adjust_locked_accounts(!opts.no_adjust_locked_accounts)
+        .force_unlock(opts.force_unlock)
+        .base_currency(&opts.base_currency)
+        .enforce_monotonic_timestamps(opts.enforce_monotonic_timestamps)
+        .daily_withdrawal_limit(opts.daily_withdrawal_limit)
+        .max_transaction_amount(opts.max_amount)
+        .minimum_balance(opts.minimum_balance)
+        .flag_after_chargebacks(opts.flag_after_chargebacks)
+        .freeze_flagged(opts.freeze_flagged)
+        .build();
+    let rates = match &opts.rates {
+        Some(path) => Some(Rates::read(File::open(path)?)?),
+        None => None,
+    };
+    // Every file has its own header, all share the state of one engine
+    let interest = pipeline::interest_of(&sinks);
+    let dialect = CsvDialect {
+        delimiter: opts.delimiter,
+        quote: opts.quote_char,
+        has_headers: !opts.no_header,
+    };
+    let lenient_amounts = opts.lenient_amounts.then(|| LenientAmounts {
+        symbol: opts.currency_symbol.clone(),
+        decimal_comma: opts.decimal_comma,
+    });
+    let format = RowFormat {
+        format: opts.format,
+        dialect,
+        interest,
+        strict_types: config.strict_types(),
+        fast_parse: opts.fast_parse,
+        map_columns: opts.map_columns.clone(),
+        lenient_amounts,
+        scientific_amounts: opts.scientific_amounts,
+        reject_float_amounts: opts.reject_float_amounts,
+        encoding: opts.encoding,
+    };
+    let feed = if let Some(dir) = &opts.watch {
+        let settle = Duration::from_millis(opts.watch_settle);
+        Feed::Watch(DirWatcher::new(dir, settle, stop)?, &format)
+    } else if opts.format == InputFormat::Parquet {
+        Feed::Rows(Box::new(
+            open_parquet(&filenames, interest)?.into_iter().flatten(),
+        ))
+    } else {
+        Feed::Rows(Box::new(
+            readers
+                .into_iter()
+                .enumerate()
+                .flat_map(|(source, input)| format.rows(source as u16, input)),
+        ))
+    };
+
+    #[cfg(feature = "mem-budget")]
+    let clients = match opts.memory_budget {
+        Some(limit) => {
+            let budget = transaction_engine::budget::MemoryBudget::new(limit);
+            let engine = run(Engine::with_budget(config, budget), &opts, feed, &mut sinks)?;
+            let budget = engine.budget();
+            tracing::info!(
+                "Memory budget ended at {} bytes, transitions {:?}, {:?}",
+                budget.used(),
+                budget.transitions(),
+                budget.stats()
+            );
+            engine.into_accounts()
         }
-        // Convert all if conditions above to improve
-        // readability
-        match current_transaction.kind {
-            TransactionType::Deposit => {
-                if transaction_records.contains_key(&current_transaction.id) {
-                    // This transaction ID has been used before
-                    // There is some error
-                    warn!("Duplicate transaction id");
-                    continue;
-                }
-
-                let amount = if let Some(a) = current_transaction.amount {
-                    a
-                } else {
-                    error!("Empty amount for deposit transaction");
-                    continue;
-                };
+        None => run(Engine::new(config), &opts, feed, &mut sinks)?.into_accounts(),
+    };
+    #[cfg(not(feature = "mem-budget"))]
+    let clients = run(Engine::new(config), &opts, feed, &mut sinks)?.into_accounts();
 
-                client.available_funds += amount;
-                client.total_funds += amount;
-                transaction_records.insert(
-                    current_transaction.id,
-                    TransactionRecord {
-                        client_id: current_transaction.client_id,
-                        amount,
-                        transaction_type: current_transaction.kind,
-                    },
-                );
-            }
-            TransactionType::Withdrawal => {
-                if transaction_records.contains_key(&current_transaction.id) {
-                    // This transaction ID has been used before
-                    // There is some error
-                    continue;
-                }
-
-                let amount = if let Some(a) = current_transaction.amount {
-                    a
-                } else {
-                    error!("Empty amount for deposit transaction");
-                    continue;
-                };
-                // Sufficient funds available
-                if client.available_funds < amount {
-                    info!("Unable to withdraw. Insufficient funds for transaction");
-                    continue;
-                }
-                client.available_funds -= amount;
-                client.total_funds -= amount;
-
-                transaction_records.insert(
-                    current_transaction.id,
-                    TransactionRecord {
-                        client_id: current_transaction.client_id,
-                        amount,
-                        transaction_type: current_transaction.kind,
-                    },
-                );
-            }
-            TransactionType::Dispute => {
-                // Make sure if there is no double disputes open
-                if disputed_transaction.contains(&current_transaction.id) {
-                    info!("Dispute already open for transaction");
-                    continue;
-                }
-
-                // Check if transaction to be disputed exists
-                let transaction_record =
-                    if let Some(tr) = transaction_records.get(&current_transaction.id) {
-                        tr
-                    } else {
-                        error!("No such transaction exists");
-                        continue;
-                    };
-
-                // Check for malicious client
-                if transaction_record.client_id != current_transaction.client_id {
-                    error!("Unable to open dispute. Transaction id doesn't match with client.");
-                    continue;
-                }
-
-                if transaction_record.transaction_type != TransactionType::Deposit {
-                    error!("Unable to open dispute for withdrawal transactions");
-                    continue;
-                }
-
-                // Make sure client has enough funds
-                if client.available_funds < transaction_record.amount {
-                    info!("Insufficient funds to open a dispute");
-                    continue;
-                }
-
-                // Update the funds
-                client.available_funds -= transaction_record.amount;
-                client.held_funds += transaction_record.amount;
-
-                // Record the transaction id under dispute
-                disputed_transaction.insert(current_transaction.id);
-            }
-            TransactionType::Resolve => {
-                // Ignore if transaction not disputed
-                if !disputed_transaction.contains(&current_transaction.id) {
-                    info!("Transaction not disputed");
-                    continue;
-                }
-
-                let transaction_record =
-                    if let Some(tr) = transaction_records.get(&current_transaction.id) {
-                        tr
-                    } else {
-                        error!("No such transaction exists");
-                        continue;
-                    };
-
-                if transaction_record.client_id != current_transaction.client_id {
-                    // Malicious actor
-                    error!("Unable to open dispute. Transaction id doesn't match with client");
-                    continue;
-                }
-                // Update the funds
-                client.available_funds += transaction_record.amount;
-                client.held_funds -= transaction_record.amount;
-
-                // Remove the disputed transaction
-                disputed_transaction.remove(&current_transaction.id);
-            }
-            TransactionType::Chargeback => {
-                // Ignore if transaction not disputed
-                if !disputed_transaction.contains(&current_transaction.id) {
-                    info!("Transaction not disputed");
-                    continue;
-                }
-
-                let transaction_record =
-                    if let Some(tr) = transaction_records.get(&current_transaction.id) {
-                        tr
-                    } else {
-                        error!("No such transaction exists");
-                        continue;
-                    };
-
-                // Update the funds
-                client.held_funds -= transaction_record.amount;
-                client.total_funds -= transaction_record.amount;
-
-                info!("Client {} locked", current_transaction.id);
-                // Lock the client
-                client.locked = true;
-
-                // Remove the disputed transaction
-                disputed_transaction.remove(&current_transaction.id);
-            }
-        }
+    if malformed.count() > 0 {
+        eprintln!("Skipped {} malformed rows", malformed.count());
     }
-    clients
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    use rust_decimal::dec;
-
-    #[test]
-    fn test_deposit_funds_multiple_clients() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(1.234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 3,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 2,
-                id: 2,
-                amount: Some(dec!(0.1234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 2,
-                id: 4,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 5,
-                amount: Some(dec!(0.1234)),
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(13.6974));
-        assert_eq!(client_1.total_funds, dec!(13.6974));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
-
-        let client_2 = clients.get(&2).unwrap();
-
-        assert_eq!(client_2.available_funds, dec!(12.4634));
-        assert_eq!(client_2.total_funds, dec!(12.4634));
-        assert_eq!(client_2.held_funds, dec!(0));
-        assert!(!client_2.locked);
+    if let Some(quarantine) = quarantine {
+        quarantine.into_inner().flush()?;
     }
-
-    #[test]
-    fn test_withdraw_funds_multiple_clients() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(123.4)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 2,
-                id: 2,
-                amount: Some(dec!(12.56)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 2,
-                id: 3,
-                amount: Some(dec!(0.1234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 2,
-                id: 4,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 5,
-                amount: Some(dec!(1.234)),
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(122.166));
-        assert_eq!(client_1.total_funds, dec!(122.166));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
-
-        let client_2 = clients.get(&2).unwrap();
-
-        assert_eq!(client_2.available_funds, dec!(0.0966));
-        assert_eq!(client_2.total_funds, dec!(0.0966));
-        assert_eq!(client_2.held_funds, dec!(0));
-        assert!(!client_2.locked);
+    if let Some(journal) = journal {
+        let head = journal.finish()?;
+        eprintln!("Journal head {}", journal::to_hex(&head));
     }
 
-    #[test]
-    fn test_withdraw_from_insufficient_balance() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(1.256)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 5,
-                amount: Some(dec!(123.4)),
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(11.084));
-        assert_eq!(client_1.total_funds, dec!(11.084));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+    let clients = match &rates {
+        Some(rates) => fx::consolidate(clients, rates)?,
+        None => clients,
+    };
+    let version = if opts.extended_output {
+        io::OutputVersion::V6
+    } else {
+        opts.output_compat
+    };
+    if version < io::OutputVersion::V4 && clients.values().any(|c| c.currencies().next().is_some())
+    {
+        bail!(
+            "balances in other currencies than {} can only be written by CSV output version 4 \
+             or later, pass --output-compat 4 or --extended-output",
+            opts.base_currency
+        );
     }
-
-    #[test]
-    fn test_transaction_id_repeated_for_withdraw() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(1.256)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(0.1234)),
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(11.084));
-        assert_eq!(client_1.total_funds, dec!(11.084));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+    let target: Box<dyn Write> = match &output {
+        Some(output) => Box::new(&output.file),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let order = AccountOrder {
+        by: opts.sort,
+        descending: opts.desc,
+    };
+    let mut buffered = BufWriter::with_capacity(OUTPUT_BUFFER, target);
+    let mut accounts: Box<dyn OutputSink + '_> = match opts.output_format {
+        OutputFormat::Csv => Box::new(
+            CsvAccountsWriter::with_delimiter(&mut buffered, version, opts.output_delimiter)
+                .base_currency(&opts.base_currency)
+                .order(order),
+        ),
+        OutputFormat::Json | OutputFormat::JsonLines => Box::new(
+            JsonAccountsWriter::new(&mut buffered)
+                .one_per_line(opts.output_format == OutputFormat::JsonLines)
+                .order(order),
+        ),
+        OutputFormat::Table => Box::new(
+            TableAccountsWriter::new(&mut buffered)
+                .human(opts.human)
+                .order(order),
+        ),
+    };
+    accounts.write_accounts(&clients)?;
+    drop(accounts);
+    buffered.flush()?;
+    drop(buffered);
+    if let Some(output) = output {
+        output.commit()?;
     }
 
-    #[test]
-    fn test_transaction_id_repeated_for_deposit() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(1.256)),
-            }),
-        ];
+    Ok(())
+}
 
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
+/// Bytes of accounts output gathered before they are written, many lines
+/// at a time rather than the line at a time stdout would write
+const OUTPUT_BUFFER: usize = 256 * 1024;
+
+/// Accounts output written next to its path first and moved in place once
+/// complete. Dropped before, the file is removed again, so a failed run
+/// leaves no partial output behind.
+struct PendingOutput {
+    path: String,
+    partial: String,
+    file: File,
+}
 
-        assert_eq!(client_1.available_funds, dec!(12.34));
-        assert_eq!(client_1.total_funds, dec!(12.34));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+impl PendingOutput {
+    fn create(path: &str) -> Result<Self> {
+        let partial = format!("{path}.partial");
+        let file =
+            File::create(&partial).with_context(|| format!("can not write output {path}"))?;
+        Ok(Self {
+            path: path.to_string(),
+            partial,
+            file,
+        })
     }
 
-    #[test]
-    fn test_open_dispute_for_transaction() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(1.256)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 1,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(1.256));
-        assert_eq!(client_1.total_funds, dec!(13.596));
-        assert_eq!(client_1.held_funds, dec!(12.34));
-        assert!(!client_1.locked);
+    fn commit(self) -> Result<()> {
+        std::fs::rename(&self.partial, &self.path)
+            .with_context(|| format!("can not write output {}", self.path))?;
+        Ok(())
     }
+}
 
-    #[test]
-    fn test_open_dispute_with_insufficient_funds() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(1.234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 1,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(11.106));
-        assert_eq!(client_1.total_funds, dec!(11.106));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+impl Drop for PendingOutput {
+    fn drop(&mut self) {
+        // Gone already once committed
+        let _ = std::fs::remove_file(&self.partial);
     }
+}
 
-    #[test]
-    fn test_resolve_opened_dispute() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 1,
-                amount: None,
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Resolve,
-                client_id: 1,
-                id: 1,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
+/// How the rows of an input are read
+struct RowFormat {
+    format: InputFormat,
+    dialect: CsvDialect,
+    interest: RawInterest,
+    strict_types: bool,
+    fast_parse: bool,
+    map_columns: Vec<ColumnMapping>,
+    lenient_amounts: Option<LenientAmounts>,
+    scientific_amounts: ScientificAmounts,
+    reject_float_amounts: bool,
+    encoding: TextEncoding,
+}
 
-        assert_eq!(client_1.available_funds, dec!(12.34));
-        assert_eq!(client_1.total_funds, dec!(12.34));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+impl RowFormat {
+    /// Rows of a single input, other than a Parquet one
+    fn rows<'r>(
+        &self,
+        source: u16,
+        input: Box<dyn Read + 'r>,
+    ) -> Box<dyn Iterator<Item = RawTx> + 'r> {
+        let interest = self.interest;
+        // Inputs failing before their first row fail the run
+        let unreadable = |e: anyhow::Error| -> Box<dyn Iterator<Item = RawTx> + 'r> {
+            Box::new(std::iter::once(RawTx {
+                record: None,
+                row: 1,
+                source,
+                parsed: Err(ParseFailure {
+                    message: e.to_string(),
+                    fatal: true,
+                }),
+            }))
+        };
+        match self.format {
+            InputFormat::JsonLines => Box::new(
+                RawJsonLines::new(
+                    BufReader::new(SkipBom::new(self.encoding.decode(input))),
+                    source,
+                    interest,
+                )
+                .scientific_amounts(self.scientific_amounts),
+            ),
+            InputFormat::Msgpack => Box::new(
+                RawMsgpack::new(BufReader::new(input), source, interest)
+                    .scientific_amounts(self.scientific_amounts),
+            ),
+            InputFormat::Proto => Box::new(RawProto::new(BufReader::new(input), source, interest)),
+            InputFormat::Arrow => {
+                match ArrowSource::stream(input, source, interest, self.reject_float_amounts) {
+                    Ok(rows) => Box::new(rows),
+                    Err(e) => unreadable(e),
+                }
+            }
+            InputFormat::Avro => match AvroSource::new(input, source, interest) {
+                Ok(rows) => Box::new(rows),
+                Err(e) => unreadable(e),
+            },
+            _ => Box::new(
+                RawSource::new(
+                    SkipBom::new(self.encoding.decode(input)),
+                    self.dialect,
+                    source,
+                    interest,
+                )
+                .strict_types(self.strict_types)
+                .fast_parse(self.fast_parse)
+                .map_columns(self.map_columns.clone())
+                .lenient_amounts(self.lenient_amounts.clone())
+                .scientific_amounts(self.scientific_amounts),
+            ),
+        }
     }
+}
 
-    #[test]
-    fn test_chargeback_opened_dispute() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 3,
-                amount: Some(dec!(0.1234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(1.234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 1,
-                amount: None,
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Chargeback,
-                client_id: 1,
-                id: 1,
-                amount: None,
-            }),
-        ];
+/// Where the rows of a run come from
+enum Feed<'a> {
+    /// Rows of the inputs given on the command line
+    Rows(Box<dyn Iterator<Item = RawTx> + 'a>),
+    /// Rows of the files dropped into a watched directory
+    Watch(DirWatcher, &'a RowFormat),
+}
 
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
+/// Parquet sources of the files. Parquet needs random access, stdin is
+/// read into memory first.
+fn open_parquet(filenames: &[String], interest: RawInterest) -> Result<Vec<ParquetSource>> {
+    filenames
+        .iter()
+        .enumerate()
+        .map(|(source, name)| {
+            let source = source as u16;
+            let parquet = if name == input::STDIN {
+                let mut data = Vec::new();
+                std::io::stdin().read_to_end(&mut data)?;
+                ParquetSource::new(Bytes::from(data), source, interest)
+            } else {
+                File::open(name)
+                    .map_err(Into::into)
+                    .and_then(|file| ParquetSource::new(file, source, interest))
+            };
+            parquet.with_context(|| format!("can not read {name}"))
+        })
+        .collect()
+}
 
-        assert_eq!(client_1.available_funds, dec!(0.1234));
-        assert_eq!(client_1.total_funds, dec!(1.3574));
-        assert_eq!(client_1.held_funds, dec!(1.234));
-        assert!(client_1.locked);
+/// Paths matching a glob pattern, sorted by name so the order does not
+/// depend on the file system
+fn expand_glob(pattern: &str) -> Result<Vec<String>> {
+    let mut paths = glob::glob(pattern)?.collect::<Result<Vec<_>, _>>()?;
+    if paths.is_empty() {
+        bail!("no files match {pattern}");
     }
+    paths.sort();
+    Ok(paths
+        .into_iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect())
+}
 
-    #[test]
-    fn test_transactions_after_account_locked() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(1.234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Chargeback,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 4,
-                amount: Some(dec!(65.78)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 3,
-                amount: Some(dec!(6.578)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 1,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(12.34));
-        assert_eq!(client_1.total_funds, dec!(12.34));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(client_1.locked);
+/// Run all rows through the engine, loading and saving its state as asked
+fn run<B: StorageBudget>(
+    mut engine: Engine<B>,
+    opts: &Opts,
+    feed: Feed,
+    sinks: &mut [&mut dyn RawSink],
+) -> Result<Engine<B>> {
+    // Line of a followed input up to which the loaded state has its rows
+    let mut resume = None;
+    if let Some(path) = &opts.load_state {
+        let snapshot: EngineSnapshot = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        if opts.follow {
+            resume = snapshot.input_line();
+        }
+        engine.restore(snapshot)?;
     }
-
-    #[test]
-    fn test_ignore_chargeback_if_not_disputed() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(1.234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Chargeback,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(13.574));
-        assert_eq!(client_1.total_funds, dec!(13.574));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+    if let Some(path) = &opts.initial_accounts {
+        engine.seed_accounts(io::read_accounts(File::open(path)?)?);
     }
-
-    #[test]
-    fn test_ignore_resolve_if_not_disputed() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(1.234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Resolve,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(13.574));
-        assert_eq!(client_1.total_funds, dec!(13.574));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+    let rows = match feed {
+        Feed::Rows(rows) => rows,
+        Feed::Watch(mut watcher, format) => {
+            // Each file is saved in the state before it is moved away, and
+            // reports name the file of a row
+            let mut source = 0u16;
+            while let Some(path) = watcher.next_file()? {
+                let name = path.to_string_lossy().into_owned();
+                if opts.verbose {
+                    eprintln!("Processing {name}");
+                }
+                for sink in sinks.iter_mut() {
+                    sink.source_named(source, &name);
+                }
+                match watched_rows(&name, source, format, opts.strict) {
+                    Ok(rows) => {
+                        pipeline::run(&mut engine, rows, sinks)?;
+                        if let Some(path) = &opts.save_state {
+                            save_state(path, &engine.snapshot(), opts.snapshot_compat)?;
+                        }
+                        watcher.complete(&path)?;
+                    }
+                    Err(e) => {
+                        eprintln!("Moved {name} to {}: {e:#}", watch::FAILED_DIR);
+                        watcher.fail(&path)?;
+                    }
+                }
+                source = source.wrapping_add(1);
+            }
+            Box::new(std::iter::empty())
+        }
+    };
+    let line = Cell::new(resume.unwrap_or(0));
+    let rows = rows
+        .filter(|raw| {
+            resume.is_none_or(|resume| raw.row > resume)
+                || raw.parsed.as_ref().is_err_and(|failure| failure.fatal)
+        })
+        .inspect(|raw| line.set(line.get().max(raw.row)));
+    let snapshot = |engine: &Engine<B>| {
+        let snapshot = engine.snapshot();
+        if opts.follow {
+            snapshot.with_input_line(line.get())
+        } else {
+            snapshot
+        }
+    };
+    if opts.order_by_timestamp {
+        let untimed = pipeline::run_by_timestamp(&mut engine, rows, sinks)?;
+        if untimed > 0 {
+            eprintln!("Rejected {untimed} rows without a timestamp");
+        }
+    } else if let (Some(every), Some(path)) = (opts.checkpoint_every, &opts.save_state) {
+        let mut rows = rows.peekable();
+        while rows.peek().is_some() {
+            pipeline::run(&mut engine, rows.by_ref().take(every.get()), sinks)?;
+            save_state(path, &snapshot(&engine), opts.snapshot_compat)?;
+        }
+    } else {
+        pipeline::run(&mut engine, rows, sinks)?;
     }
-
-    #[test]
-    fn test_ignore_dispute_if_already_disputed() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(1.234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(12.34));
-        assert_eq!(client_1.total_funds, dec!(13.574));
-        assert_eq!(client_1.held_funds, dec!(1.234));
-        assert!(!client_1.locked);
+    if engine.replayed() > 0 {
+        eprintln!("Skipped {} replayed rows", engine.replayed());
     }
-
-    #[test]
-    fn test_ignore_dispute_if_tx_of_withdrawal() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 2,
-                amount: Some(dec!(1.234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(11.106));
-        assert_eq!(client_1.total_funds, dec!(11.106));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+    if !engine.fees_paid().is_zero() {
+        eprintln!("Charged {} in withdrawal fees", engine.fees_paid());
     }
-
-    #[test]
-    fn test_ignore_dispute_if_tx_and_client_dont_match() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 2,
-                id: 2,
-                amount: Some(dec!(1.234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(12.34));
-        assert_eq!(client_1.total_funds, dec!(12.34));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
-
-        let client_1 = clients.get(&2).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(1.234));
-        assert_eq!(client_1.total_funds, dec!(1.234));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+    let unmatched = engine.reject_deferred();
+    if !unmatched.is_empty() {
+        eprintln!(
+            "{} deferred dispute steps never matched a transaction",
+            unmatched.len()
+        );
     }
-
-    #[test]
-    fn test_ignore_resolve_if_tx_and_client_dont_match() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 2,
-                id: 2,
-                amount: Some(dec!(1.234)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 1,
-                amount: None,
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Resolve,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(0));
-        assert_eq!(client_1.total_funds, dec!(12.34));
-        assert_eq!(client_1.held_funds, dec!(12.34));
-        assert!(!client_1.locked);
-
-        let client_1 = clients.get(&2).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(1.234));
-        assert_eq!(client_1.total_funds, dec!(1.234));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+    if opts.verify {
+        let violations = engine.verify();
+        for violation in &violations {
+            eprintln!("{violation}");
+        }
+        if !violations.is_empty() {
+            bail!("{} invariant violations found", violations.len());
+        }
     }
-
-    #[test]
-    fn test_ignore_resolve_if_invalid_tx_id() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 1,
-                amount: None,
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Resolve,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(0));
-        assert_eq!(client_1.total_funds, dec!(12.34));
-        assert_eq!(client_1.held_funds, dec!(12.34));
-        assert!(!client_1.locked);
+    if let Some(path) = &opts.save_state {
+        save_state(path, &snapshot(&engine), opts.snapshot_compat)?;
     }
+    Ok(engine)
+}
 
-    #[test]
-    fn test_ignore_dispute_if_invalid_tx_id() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Dispute,
-                client_id: 1,
-                id: 3,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(12.34));
-        assert_eq!(client_1.total_funds, dec!(12.34));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+/// Rows of a watched file, read in full before any is applied. A file
+/// which can not be read, or with a malformed row in strict mode, fails
+/// as a whole and leaves the accounts untouched.
+fn watched_rows(name: &str, source: u16, format: &RowFormat, strict: bool) -> Result<Vec<RawTx>> {
+    let mut rows = Vec::new();
+    for mut input in input::open(name)? {
+        rows.extend(format.rows(source, input.reader()?));
     }
-
-    #[test]
-    fn test_ignore_deposit_if_amount_is_none() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(12.34));
-        assert_eq!(client_1.total_funds, dec!(12.34));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+    let failure = rows.iter().find_map(|raw| {
+        let failure = raw.parsed.as_ref().err()?;
+        if failure.fatal {
+            Some(failure.to_string())
+        } else {
+            strict.then(|| format!("malformed row {}: {failure}", raw.row))
+        }
+    });
+    match failure {
+        Some(failure) => bail!(failure),
+        None => Ok(rows),
     }
+}
 
-    #[test]
-    fn test_ignore_withdrawal_if_amount_is_none() {
-        let records = vec![
-            Ok(Transaction {
-                kind: TransactionType::Deposit,
-                client_id: 1,
-                id: 1,
-                amount: Some(dec!(12.34)),
-            }),
-            Ok(Transaction {
-                kind: TransactionType::Withdrawal,
-                client_id: 1,
-                id: 2,
-                amount: None,
-            }),
-        ];
-
-        let clients = process_transactions(records);
-        let client_1 = clients.get(&1).unwrap();
-
-        assert_eq!(client_1.available_funds, dec!(12.34));
-        assert_eq!(client_1.total_funds, dec!(12.34));
-        assert_eq!(client_1.held_funds, dec!(0));
-        assert!(!client_1.locked);
+/// Write the state next to `path` first and move it in place, so a run
+/// stopped mid-write leaves the previous state intact. What the snapshot
+/// version can not hold is left out and named on stderr
+fn save_state(path: &str, snapshot: &EngineSnapshot, version: SnapshotVersion) -> Result<()> {
+    let (snapshot, dropped) = snapshot.to_version(version)?;
+    if !dropped.is_empty() {
+        let dropped: Vec<_> = dropped.into_iter().collect();
+        eprintln!(
+            "The snapshot version can not hold {}, left out of {path}",
+            dropped.join(", ")
+        );
     }
+    let partial = format!("{path}.partial");
+    let mut writer = BufWriter::new(File::create(&partial)?);
+    serde_json::to_writer_pretty(&mut writer, &snapshot)?;
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(&partial, path)?;
+    Ok(())
 }
@@ -0,0 +1,892 @@
+use crate::engine::RejectReason;
+use chrono::{DateTime, FixedOffset};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Deserializer, Serialize, de};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Identifier of a client account
+pub type ClientId = u32;
+
+/// Point in time of a transaction, with the offset it was given in
+pub type Timestamp = DateTime<FixedOffset>;
+
+/// Kind of a transaction row
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// Moves funds from `client` to `to_client`
+    Transfer,
+    /// Cancels the withdrawal `tx` of the client
+    Reversal,
+    /// Manual correction by a signed amount
+    Adjustment,
+    /// Locks the client right away
+    Lock,
+    /// Lifts the lock of the client
+    Unlock,
+    /// Moves an amount of available funds to held funds
+    Hold,
+    /// Moves manually held funds back to available funds
+    Release,
+    /// Card purchase, debited like a withdrawal
+    Purchase,
+    /// Credits back part or all of the purchase `tx` of the client
+    Refund,
+    /// Drops the records no transaction can reference anymore, `client`
+    /// and `tx` are ignored
+    Settle,
+    /// Pays interest at the rate in `amount` on the available funds of
+    /// every unlocked client, `client` is ignored
+    Accrue,
+}
+
+impl TransactionType {
+    /// Name of the kind as used in the `type` column
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Reversal => "reversal",
+            TransactionType::Adjustment => "adjustment",
+            TransactionType::Lock => "lock",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Hold => "hold",
+            TransactionType::Release => "release",
+            TransactionType::Purchase => "purchase",
+            TransactionType::Refund => "refund",
+            TransactionType::Settle => "settle",
+            TransactionType::Accrue => "accrue",
+        }
+    }
+
+    /// Kind for a `type` column in any casing, also accepting `withdraw`,
+    /// `charge_back` and `charge-back`
+    pub fn from_alias(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "deposit" => Some(TransactionType::Deposit),
+            "withdrawal" | "withdraw" => Some(TransactionType::Withdrawal),
+            "dispute" => Some(TransactionType::Dispute),
+            "resolve" => Some(TransactionType::Resolve),
+            "chargeback" | "charge_back" | "charge-back" => Some(TransactionType::Chargeback),
+            "transfer" => Some(TransactionType::Transfer),
+            "reversal" => Some(TransactionType::Reversal),
+            "adjustment" => Some(TransactionType::Adjustment),
+            "lock" => Some(TransactionType::Lock),
+            "unlock" => Some(TransactionType::Unlock),
+            "hold" => Some(TransactionType::Hold),
+            "release" => Some(TransactionType::Release),
+            "purchase" => Some(TransactionType::Purchase),
+            "refund" => Some(TransactionType::Refund),
+            "settle" => Some(TransactionType::Settle),
+            "accrue" => Some(TransactionType::Accrue),
+            _ => None,
+        }
+    }
+}
+
+/// Accepts the names of [`TransactionType::from_alias`], sources can
+/// insist on the lowercase names
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        TransactionType::from_alias(&name)
+            .ok_or_else(|| de::Error::custom(format!("unknown transaction type {name}")))
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(TransactionType::Deposit),
+            "withdrawal" => Ok(TransactionType::Withdrawal),
+            "dispute" => Ok(TransactionType::Dispute),
+            "resolve" => Ok(TransactionType::Resolve),
+            "chargeback" => Ok(TransactionType::Chargeback),
+            "transfer" => Ok(TransactionType::Transfer),
+            "reversal" => Ok(TransactionType::Reversal),
+            "adjustment" => Ok(TransactionType::Adjustment),
+            "lock" => Ok(TransactionType::Lock),
+            "unlock" => Ok(TransactionType::Unlock),
+            "hold" => Ok(TransactionType::Hold),
+            "release" => Ok(TransactionType::Release),
+            "purchase" => Ok(TransactionType::Purchase),
+            "refund" => Ok(TransactionType::Refund),
+            "settle" => Ok(TransactionType::Settle),
+            "accrue" => Ok(TransactionType::Accrue),
+            other => Err(format!("unknown transaction type {other}")),
+        }
+    }
+}
+
+/// A single transaction as read from the input.
+///
+/// `amount` is present for deposits, withdrawals, transfers, holds,
+/// releases, adjustments, purchases and refunds, and holds the interest
+/// rate of accrue rows. Disputes, resolves and chargebacks reference an
+/// earlier transaction by `id`. Only transfers have a `to_client`, and
+/// only withdrawals and purchases a `fee`.
+/// Rows without a `currency` are in the base currency of the engine. The
+/// optional `ts` column holds an RFC 3339 timestamp.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Transaction {
+    #[serde(rename = "type")]
+    pub kind: TransactionType,
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    #[serde(rename = "tx")]
+    pub id: u32,
+    #[serde(default, deserialize_with = "exact_amount")]
+    pub amount: Option<Decimal>,
+    #[serde(default)]
+    pub to_client: Option<ClientId>,
+    /// Charged on top of the amount of a withdrawal
+    #[serde(default, deserialize_with = "exact_amount")]
+    pub fee: Option<Decimal>,
+    #[serde(default)]
+    pub currency: Option<String>,
+    #[serde(default, rename = "ts")]
+    pub timestamp: Option<Timestamp>,
+}
+
+/// Parse an amount from its text rather than a float, which CSV fields
+/// that look like numbers are otherwise read as, so every digit is kept.
+/// Formats giving numbers a type, like MessagePack, may still pass them
+/// as such.
+fn exact_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Decimal>, D::Error> {
+    struct Amount;
+
+    impl<'de> de::Visitor<'de> for Amount {
+        type Value = Option<Decimal>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a decimal amount")
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+            d.deserialize_str(self)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            if v.is_empty() {
+                return Ok(None);
+            }
+            Decimal::from_str(v)
+                .map(Some)
+                .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+            Ok(Some(Decimal::from(v)))
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+            Ok(Some(Decimal::from(v)))
+        }
+
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+            Decimal::from_str(&v.to_string())
+                .map(Some)
+                .map_err(|_| E::invalid_value(de::Unexpected::Float(v), &self))
+        }
+    }
+
+    deserializer.deserialize_option(Amount)
+}
+
+impl Transaction {
+    /// Client owning the record the transaction stores or refers to, the
+    /// destination of a transfer
+    pub(crate) fn record_owner(&self) -> ClientId {
+        match (self.kind, self.to_client) {
+            (TransactionType::Transfer, Some(to_client)) => to_client,
+            _ => self.client_id,
+        }
+    }
+}
+
+/// Account state of a single client.
+///
+/// The engine keeps `total == available + held` at all times. Held funds
+/// never become negative. Available funds only do through a withdrawal or
+/// transfer within the credit line, a dispute allowed to overdraw them, or
+/// a negative adjustment. Transfers take funds from one client and credit
+/// them to another.
+///
+/// A chargeback or lock row, in the base currency or any other, locks
+/// the client.
+/// Its further rows are rejected, apart from dispute steps, adjustments
+/// and deposits held by the configured policies, until an unlock row or
+/// auto unlock lifts the lock again.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Client {
+    #[serde(rename = "available")]
+    available_funds: Decimal,
+    #[serde(rename = "held")]
+    held_funds: Decimal,
+    #[serde(rename = "total")]
+    total_funds: Decimal,
+    locked: bool,
+    /// Withdrawals rejected for insufficient funds
+    #[serde(default, skip_serializing_if = "is_zero")]
+    failed_withdrawals: u64,
+    /// Part of the held funds deposited while locked
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    locked_deposits: Decimal,
+    /// Part of the held funds placed by hold rows, apart from disputes
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    manually_held: Decimal,
+    /// Fees charged on withdrawals
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    fees_paid: Decimal,
+    /// Reserve the available funds may not drop below by withdrawals,
+    /// overriding the configured one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_balance: Option<Decimal>,
+    /// How far withdrawals may take the available funds below zero
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    credit_limit: Option<Decimal>,
+    /// Marked as suspicious for too many chargebacks, apart from locked
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    flagged: bool,
+    /// Accounts of the client in currencies other than the base one
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    currencies: BTreeMap<String, Client>,
+}
+
+fn is_zero(count: &u64) -> bool {
+    *count == 0
+}
+
+impl Client {
+    /// Client with an opening balance and nothing held
+    pub fn new(available: Decimal) -> Self {
+        Self::with_balances(available, Decimal::ZERO, false)
+    }
+
+    pub fn with_balances(available: Decimal, held: Decimal, locked: bool) -> Self {
+        Self {
+            available_funds: available,
+            held_funds: held,
+            total_funds: available + held,
+            locked,
+            failed_withdrawals: 0,
+            locked_deposits: Decimal::ZERO,
+            manually_held: Decimal::ZERO,
+            fees_paid: Decimal::ZERO,
+            min_balance: None,
+            credit_limit: None,
+            flagged: false,
+            currencies: BTreeMap::new(),
+        }
+    }
+
+    pub fn available(&self) -> Decimal {
+        self.available_funds
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held_funds
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.total_funds
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Withdrawals rejected because they exceeded the available funds
+    pub fn failed_withdrawals(&self) -> u64 {
+        self.failed_withdrawals
+    }
+
+    /// Part of the held funds placed by hold rows
+    pub fn manually_held(&self) -> Decimal {
+        self.manually_held
+    }
+
+    /// Fees charged on withdrawals
+    pub fn fees_paid(&self) -> Decimal {
+        self.fees_paid
+    }
+
+    pub(crate) fn record_failed_withdrawal(&mut self) {
+        self.failed_withdrawals += 1;
+    }
+
+    /// Reserve of the client overriding the configured minimum balance
+    pub fn min_balance(&self) -> Option<Decimal> {
+        self.min_balance
+    }
+
+    pub(crate) fn set_min_balance(&mut self, min_balance: Option<Decimal>) {
+        self.min_balance = min_balance;
+    }
+
+    /// Agreed overdraft of the client, if it has a credit line
+    pub fn credit_limit(&self) -> Option<Decimal> {
+        self.credit_limit
+    }
+
+    pub(crate) fn set_credit_limit(&mut self, credit_limit: Option<Decimal>) {
+        self.credit_limit = credit_limit;
+    }
+
+    /// Funds a withdrawal may take, the available funds plus the credit
+    /// line
+    pub(crate) fn spendable(&self) -> Decimal {
+        self.available_funds + self.credit_limit.unwrap_or_default()
+    }
+
+    /// Whether the available funds are below zero
+    pub fn is_overdrawn(&self) -> bool {
+        self.available_funds < Decimal::ZERO
+    }
+
+    /// Whether the client was flagged for too many chargebacks
+    pub fn is_flagged(&self) -> bool {
+        self.flagged
+    }
+
+    pub(crate) fn flag(&mut self) {
+        self.flagged = true;
+    }
+
+    /// Account of the client in a currency other than the base one
+    pub fn currency(&self, currency: &str) -> Option<&Client> {
+        self.currencies.get(currency)
+    }
+
+    /// Accounts of the client in currencies other than the base one,
+    /// ordered by currency
+    pub fn currencies(&self) -> impl Iterator<Item = (&str, &Client)> {
+        self.currencies
+            .iter()
+            .map(|(currency, client)| (currency.as_str(), client))
+    }
+
+    /// Account in `currency`, the client itself for the base currency
+    pub(crate) fn account_mut(&mut self, currency: Option<&str>) -> &mut Client {
+        match currency {
+            Some(currency) => self.currencies.entry(currency.to_string()).or_default(),
+            None => self,
+        }
+    }
+
+    /// Drop the account in `currency`, opened for a transaction which was
+    /// rejected
+    pub(crate) fn close_account(&mut self, currency: &str) {
+        self.currencies.remove(currency);
+    }
+
+    /// Fold the accounts in other currencies into the client, every amount
+    /// converted to the base currency by `convert`. The client ends up
+    /// locked if any of its accounts was. On overflow the client is left
+    /// partly consolidated.
+    pub(crate) fn consolidate(
+        &mut self,
+        convert: impl Fn(&str, Decimal) -> Option<Decimal>,
+    ) -> Result<(), RejectReason> {
+        for (currency, account) in std::mem::take(&mut self.currencies) {
+            let converted = |amount| convert(&currency, amount).ok_or(RejectReason::Overflow);
+            let available = converted(account.available_funds)?;
+            let held = converted(account.held_funds)?;
+            let total = available.checked_add(held).ok_or(RejectReason::Overflow)?;
+            let add = |sum: Decimal, amount| {
+                converted(amount).and_then(|a| sum.checked_add(a).ok_or(RejectReason::Overflow))
+            };
+            let locked_deposits = add(self.locked_deposits, account.locked_deposits)?;
+            let manually_held = add(self.manually_held, account.manually_held)?;
+            let fees_paid = add(self.fees_paid, account.fees_paid)?;
+            self.adjust(available, held, total)?;
+            self.locked_deposits = locked_deposits;
+            self.manually_held = manually_held;
+            self.fees_paid = fees_paid;
+            self.failed_withdrawals += account.failed_withdrawals;
+            self.locked |= account.locked;
+        }
+        Ok(())
+    }
+
+    /// Whether total funds are exactly available plus held funds, in
+    /// every currency
+    pub(crate) fn is_balanced(&self) -> bool {
+        self.total_funds == self.available_funds + self.held_funds
+            && self.currencies.values().all(Client::is_balanced)
+    }
+
+    /// Add the deltas to the balances. On overflow none of the balances
+    /// change.
+    fn adjust(
+        &mut self,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+    ) -> Result<(), RejectReason> {
+        let (Some(available), Some(held), Some(total)) = (
+            self.available_funds.checked_add(available),
+            self.held_funds.checked_add(held),
+            self.total_funds.checked_add(total),
+        ) else {
+            return Err(RejectReason::Overflow);
+        };
+        self.available_funds = available;
+        self.held_funds = held;
+        self.total_funds = total;
+        Ok(())
+    }
+
+    pub(crate) fn deposit(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        self.adjust(amount, Decimal::ZERO, amount)
+    }
+
+    /// Correct available and total funds by a signed amount, which may
+    /// leave the available funds negative
+    pub(crate) fn correct(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        self.adjust(amount, Decimal::ZERO, amount)
+    }
+
+    /// Hold a deposit made while locked until the client is unlocked
+    pub(crate) fn deposit_locked(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        let locked_deposits = self
+            .locked_deposits
+            .checked_add(amount)
+            .ok_or(RejectReason::Overflow)?;
+        self.adjust(Decimal::ZERO, amount, amount)?;
+        self.locked_deposits = locked_deposits;
+        Ok(())
+    }
+
+    pub(crate) fn withdraw(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        self.adjust(-amount, Decimal::ZERO, -amount)
+    }
+
+    /// Withdraw the amount and the fee together, either both or neither
+    pub(crate) fn withdraw_with_fee(
+        &mut self,
+        amount: Decimal,
+        fee: Decimal,
+    ) -> Result<(), RejectReason> {
+        let (Some(charged), Some(fees_paid)) =
+            (amount.checked_add(fee), self.fees_paid.checked_add(fee))
+        else {
+            return Err(RejectReason::Overflow);
+        };
+        self.withdraw(charged)?;
+        self.fees_paid = fees_paid;
+        Ok(())
+    }
+
+    /// Move a disputed deposit from available to held funds
+    pub(crate) fn hold(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        self.adjust(-amount, amount, Decimal::ZERO)
+    }
+
+    /// Place a manual hold on available funds
+    pub(crate) fn hold_manually(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        let manually_held = self
+            .manually_held
+            .checked_add(amount)
+            .ok_or(RejectReason::Overflow)?;
+        self.hold(amount)?;
+        self.manually_held = manually_held;
+        Ok(())
+    }
+
+    /// Release part of the manual holds, never funds held by disputes
+    pub(crate) fn release_manually(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        if amount > self.manually_held {
+            return Err(RejectReason::ExcessRelease);
+        }
+        self.release(amount)?;
+        self.manually_held -= amount;
+        Ok(())
+    }
+
+    /// Give a held deposit back once its dispute is resolved
+    pub(crate) fn release(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        self.adjust(amount, -amount, Decimal::ZERO)
+    }
+
+    /// Hold a disputed withdrawal on top of the total, the funds already
+    /// left the account
+    pub(crate) fn hold_withdrawn(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        self.adjust(Decimal::ZERO, amount, amount)
+    }
+
+    /// Drop the hold of a disputed withdrawal which stands
+    pub(crate) fn release_withdrawn(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        self.adjust(Decimal::ZERO, -amount, -amount)
+    }
+
+    /// Remove held funds for good and lock the client
+    pub(crate) fn charge_back(&mut self, amount: Decimal) -> Result<(), RejectReason> {
+        self.adjust(Decimal::ZERO, -amount, -amount)?;
+        self.locked = true;
+        Ok(())
+    }
+
+    pub(crate) fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    /// Lock the client as a whole once its account in any currency is
+    /// locked by a chargeback
+    pub(crate) fn lock_if_any_account_locked(&mut self) {
+        self.locked |= self.currencies.values().any(Client::is_locked);
+    }
+
+    /// Lift the lock of the client and of its accounts in every currency
+    pub(crate) fn unlock_all(&mut self) {
+        self.unlock();
+        for account in self.currencies.values_mut() {
+            account.unlock();
+        }
+    }
+
+    /// Lift the lock of a client, releasing the deposits held while it
+    /// was locked
+    pub(crate) fn unlock(&mut self) {
+        self.locked = false;
+        // Releasing nothing would leave the held funds at minus zero
+        if self.locked_deposits.is_zero() {
+            return;
+        }
+        let pending = self.locked_deposits;
+        if self.adjust(pending, -pending, Decimal::ZERO).is_ok() {
+            self.locked_deposits = Decimal::ZERO;
+        }
+    }
+
+    /// Reverse a disputed withdrawal, the held amount becomes available
+    /// to the client again
+    pub(crate) fn charge_back_withdrawn(
+        &mut self,
+        amount: Decimal,
+        lock: bool,
+    ) -> Result<(), RejectReason> {
+        self.adjust(amount, -amount, Decimal::ZERO)?;
+        self.locked |= lock;
+        Ok(())
+    }
+}
+
+/// Renders `available,held,total,locked` with amounts at four decimal
+/// places, as in the accounts output
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            FourPlaces(self.available_funds),
+            FourPlaces(self.held_funds),
+            FourPlaces(self.total_funds),
+            self.locked
+        )
+    }
+}
+
+/// Renders an amount with exactly four decimal places, cut off like the
+/// `{:.4}` format of [`Decimal`], which panics from 1e27 on
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FourPlaces(pub(crate) Decimal);
+
+impl fmt::Display for FourPlaces {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let amount = self.0.round_dp_with_strategy(4, RoundingStrategy::ToZero);
+        let rendered = amount.to_string();
+        let places = rendered
+            .split_once('.')
+            .map_or(0, |(_, fraction)| fraction.len());
+        f.write_str(&rendered)?;
+        if places == 0 {
+            f.write_str(".")?;
+        }
+        for _ in places..4 {
+            f.write_str("0")?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a stored transaction is in its dispute lifecycle.
+///
+/// Disputes move a transaction from `Undisputed` (or `Resolved`) to
+/// `Disputed`, which a resolve or chargeback closes again. `ChargedBack`
+/// is final. An `Expired` dispute was resolved by the engine because it
+/// stayed open too long.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisputeState {
+    #[default]
+    Undisputed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Expired,
+}
+
+/// Deposit or withdrawal kept around so it can be disputed later
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransactionRecord {
+    pub(crate) client_id: ClientId,
+    pub(crate) amount: Decimal,
+    pub(crate) transaction_type: TransactionType,
+    #[serde(default)]
+    pub(crate) state: DisputeState,
+    /// Further transactions of the client until an open dispute expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_in: Option<u32>,
+    /// Amount of a partial dispute, below the amount of the transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) partial_amount: Option<Decimal>,
+    /// Withdrawal cancelled by a reversal
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) reversed: bool,
+    /// Sum of the refunds of a purchase so far
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub(crate) refunded: Decimal,
+    /// Currency of the transaction, unless it is the base currency
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) currency: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timestamp: Option<Timestamp>,
+    /// Timestamp of the row which opened the current or last dispute
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) disputed_at: Option<Timestamp>,
+}
+
+impl TransactionRecord {
+    /// Record of a freshly applied transaction, not disputed yet
+    pub(crate) fn new(client_id: ClientId, amount: Decimal, kind: TransactionType) -> Self {
+        Self {
+            client_id,
+            amount,
+            transaction_type: kind,
+            state: DisputeState::Undisputed,
+            expires_in: None,
+            partial_amount: None,
+            reversed: false,
+            refunded: Decimal::ZERO,
+            currency: None,
+            timestamp: None,
+            disputed_at: None,
+        }
+    }
+
+    /// Same record in a currency other than the base one
+    pub(crate) fn in_currency(mut self, currency: Option<String>) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    /// Same record with the timestamp of its row
+    pub(crate) fn at(mut self, timestamp: Option<Timestamp>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    /// Timestamp of the row which stored the record, if it had one
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    pub fn transaction_type(&self) -> TransactionType {
+        self.transaction_type
+    }
+
+    pub fn dispute_state(&self) -> DisputeState {
+        self.state
+    }
+
+    pub fn is_reversed(&self) -> bool {
+        self.reversed
+    }
+
+    /// Sum of the refunds of a purchase so far
+    pub fn refunded(&self) -> Decimal {
+        self.refunded
+    }
+
+    /// Amount held by the latest dispute, all of it unless only a part
+    /// was disputed
+    pub fn disputed_amount(&self) -> Decimal {
+        self.partial_amount.unwrap_or(self.amount)
+    }
+
+    /// Whether `transaction` repeats the one this record was stored for
+    pub(crate) fn replayed_by(&self, transaction: &Transaction) -> bool {
+        self.client_id == transaction.client_id
+            && self.transaction_type == transaction.kind
+            && transaction.amount == Some(self.amount)
+    }
+
+    /// Counted against the open disputes of its client
+    pub(crate) fn is_disputed(&self) -> bool {
+        self.state == DisputeState::Disputed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    fn assert_balances(client: &Client, available: Decimal, held: Decimal) {
+        assert_eq!(client.available(), available);
+        assert_eq!(client.held(), held);
+        assert_eq!(client.total(), available + held);
+    }
+
+    #[test]
+    fn test_deposit_dispute_round_trip() {
+        let mut client = Client::new(dec!(10));
+        client.deposit(dec!(2.5)).unwrap();
+        client.withdraw(dec!(1)).unwrap();
+        assert_balances(&client, dec!(11.5), dec!(0));
+
+        client.hold(dec!(2.5)).unwrap();
+        assert_balances(&client, dec!(9), dec!(2.5));
+        client.release(dec!(2.5)).unwrap();
+        assert_balances(&client, dec!(11.5), dec!(0));
+
+        client.hold(dec!(2.5)).unwrap();
+        client.charge_back(dec!(2.5)).unwrap();
+        assert_balances(&client, dec!(9), dec!(0));
+        assert!(client.is_locked());
+    }
+
+    #[test]
+    fn test_withdrawal_hold() {
+        let mut client = Client::with_balances(dec!(4), dec!(1), false);
+        assert_eq!(client.total(), dec!(5));
+
+        client.hold_withdrawn(dec!(3)).unwrap();
+        assert_balances(&client, dec!(4), dec!(4));
+        client.release_withdrawn(dec!(3)).unwrap();
+        assert_balances(&client, dec!(4), dec!(1));
+        assert!(!client.is_locked());
+
+        client.hold_withdrawn(dec!(3)).unwrap();
+        client.charge_back_withdrawn(dec!(3), false).unwrap();
+        assert_balances(&client, dec!(7), dec!(1));
+        assert!(!client.is_locked());
+    }
+
+    #[test]
+    fn test_overflow_leaves_balances_untouched() {
+        let mut client = Client::new(Decimal::MAX);
+        assert_eq!(client.deposit(dec!(1)), Err(RejectReason::Overflow));
+        assert_balances(&client, Decimal::MAX, dec!(0));
+
+        // Held funds would fit, the total does not
+        client.withdraw(dec!(1)).unwrap();
+        client.deposit(dec!(1)).unwrap();
+        assert_eq!(client.hold_withdrawn(dec!(1)), Err(RejectReason::Overflow));
+        assert_balances(&client, Decimal::MAX, dec!(0));
+        assert_eq!(
+            client.charge_back(Decimal::MIN),
+            Err(RejectReason::Overflow)
+        );
+        assert!(!client.is_locked());
+    }
+
+    #[test]
+    fn test_client_serde_round_trip_and_display() {
+        let client = Client::with_balances(dec!(1.5), dec!(0.25), true);
+
+        let json = serde_json::to_string(&client).unwrap();
+        assert_eq!(
+            json,
+            r#"{"available":"1.5","held":"0.25","total":"1.75","locked":true}"#
+        );
+        assert_eq!(serde_json::from_str::<Client>(&json).unwrap(), client);
+        assert_eq!(client.to_string(), "1.5000,0.2500,1.7500,true");
+        assert_eq!(Client::default().to_string(), "0.0000,0.0000,0.0000,false");
+    }
+
+    #[test]
+    fn test_transaction_type_round_trip() {
+        let kinds = [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+            TransactionType::Transfer,
+            TransactionType::Reversal,
+            TransactionType::Adjustment,
+            TransactionType::Lock,
+            TransactionType::Unlock,
+            TransactionType::Hold,
+            TransactionType::Release,
+            TransactionType::Purchase,
+            TransactionType::Refund,
+            TransactionType::Settle,
+            TransactionType::Accrue,
+        ];
+        for kind in kinds {
+            let json = serde_json::to_string(&kind).unwrap();
+            assert_eq!(json, format!("\"{kind}\""));
+            assert_eq!(
+                serde_json::from_str::<TransactionType>(&json).unwrap(),
+                kind
+            );
+            assert_eq!(kind.to_string().parse(), Ok(kind));
+        }
+        assert_eq!(TransactionType::Chargeback.to_string(), "chargeback");
+        assert!("payout".parse::<TransactionType>().is_err());
+        assert!("Deposit".parse::<TransactionType>().is_err());
+    }
+
+    #[test]
+    fn test_transaction_type_aliases() {
+        for (name, kind) in [
+            ("\"Deposit\"", TransactionType::Deposit),
+            ("\"WITHDRAWAL\"", TransactionType::Withdrawal),
+            ("\"withdraw\"", TransactionType::Withdrawal),
+            ("\"Charge_Back\"", TransactionType::Chargeback),
+            ("\"charge-back\"", TransactionType::Chargeback),
+        ] {
+            assert_eq!(serde_json::from_str::<TransactionType>(name).unwrap(), kind);
+        }
+        let err = serde_json::from_str::<TransactionType>("\"Payout\"").unwrap_err();
+        assert!(err.to_string().contains("unknown transaction type Payout"));
+    }
+}
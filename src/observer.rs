@@ -0,0 +1,45 @@
+use crate::engine::{RejectReason, Settlement};
+use crate::model::{Client, ClientId, Transaction};
+
+/// Notifications about what the engine does.
+///
+/// The default implementations do nothing, so the `NoObserver` engine
+/// compiles down to the plain processing loop.
+pub trait EngineObserver {
+    /// Transaction applied, `client` holds the balances afterwards
+    fn on_applied(&mut self, _transaction: &Transaction, _client: &Client) {}
+
+    fn on_rejected(&mut self, _transaction: &Transaction, _reason: RejectReason) {}
+
+    fn on_dispute_opened(&mut self, _tx: u32, _client_id: ClientId) {}
+
+    fn on_dispute_resolved(&mut self, _tx: u32, _client_id: ClientId) {}
+
+    /// Disputed funds were charged back, which usually locks the client
+    fn on_chargeback(&mut self, _tx: u32, _client_id: ClientId) {}
+
+    /// Dispute stayed open too long and was resolved by the engine
+    fn on_dispute_expired(&mut self, _tx: u32, _client_id: ClientId) {}
+
+    /// Client locked by a lock row
+    fn on_locked(&mut self, _client_id: ClientId) {}
+
+    /// Client unlocked by an unlock row
+    fn on_unlocked(&mut self, _client_id: ClientId) {}
+
+    /// Client flagged for too many chargebacks
+    fn on_flagged(&mut self, _client_id: ClientId) {}
+
+    /// Dispute stayed open past the configured age and was charged back
+    /// by the engine
+    fn on_auto_chargeback(&mut self, _tx: u32, _client_id: ClientId) {}
+
+    /// Settle row dropped the records no transaction can reference anymore
+    fn on_settled(&mut self, _settlement: &Settlement) {}
+}
+
+/// Observer ignoring every event
+#[derive(Debug, Default, Clone)]
+pub struct NoObserver;
+
+impl EngineObserver for NoObserver {}
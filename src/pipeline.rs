@@ -0,0 +1,1533 @@
+use crate::budget::StorageBudget;
+use crate::config::ScientificAmounts;
+use crate::engine::{Engine, RejectReason};
+use crate::io::{
+    ColumnMapping, CsvDialect, LenientAmounts, msgpack_transaction, next_msgpack_value,
+    parse_json_line,
+};
+use crate::model::{Transaction, TransactionType};
+use crate::observer::EngineObserver;
+use crate::proto;
+use csv::{ByteRecord, Reader, WriterBuilder};
+use rust_decimal::Decimal;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::ops::BitOr;
+use std::rc::Rc;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Reason a row could not be parsed into a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseFailure {
+    pub message: String,
+    /// The input can not be read any further, rather than the row being
+    /// malformed
+    pub fatal: bool,
+}
+
+impl fmt::Display for ParseFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<csv::Error> for ParseFailure {
+    fn from(e: csv::Error) -> Self {
+        Self {
+            fatal: e.is_io_error(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Bitmask of sinks which need the raw record of each row
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RawInterest(u8);
+
+impl RawInterest {
+    pub const NONE: Self = Self(0);
+    pub const QUARANTINE: Self = Self(1);
+    pub const VERBOSE_REJECTS: Self = Self(1 << 1);
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for RawInterest {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Row as it was read, before parsing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawRecord {
+    /// Fields of a row of another format, rendered as CSV
+    Csv(ByteRecord),
+    /// Row of CSV input as it was written, without its line terminator,
+    /// and the header line of its input if it has one
+    CsvLine {
+        header: Option<Arc<[u8]>>,
+        line: Vec<u8>,
+    },
+    /// Line of a line based format, without its line terminator
+    Line(Vec<u8>),
+}
+
+/// A single input row, parsed once and shared by reference with
+/// everything that needs it.
+#[derive(Debug)]
+pub struct RawTx {
+    /// Raw record as read, only kept if some sink registered interest
+    pub record: Option<RawRecord>,
+    /// Line of the row in its source
+    pub row: u64,
+    /// Index of the source the row was read from
+    pub source: u16,
+    pub parsed: Result<Transaction, ParseFailure>,
+}
+
+/// Columns of CSV input without a header, in order
+const POSITIONAL_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Optional columns only the serde path reads, a header with any of them
+/// is parsed by it even with the fast path
+const SERDE_ONLY_COLUMNS: [&[u8]; 4] = [b"to_client", b"fee", b"currency", b"ts"];
+
+/// Columns of the fast path, resolved from the header once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FastColumns {
+    kind: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    /// Fields of the header, serde fails every shorter record
+    width: usize,
+}
+
+impl FastColumns {
+    /// Columns of a header, `None` if the header needs the serde path
+    fn resolve(headers: &ByteRecord) -> Option<Self> {
+        if headers.iter().any(|h| SERDE_ONLY_COLUMNS.contains(&h)) {
+            return None;
+        }
+        let column = |name: &[u8]| {
+            let mut columns = headers.iter().enumerate().filter(|(_, h)| *h == name);
+            match (columns.next(), columns.next()) {
+                (Some((i, _)), None) => Ok(Some(i)),
+                (None, _) => Ok(None),
+                // serde fails such rows as a duplicate field
+                (Some(_), Some(_)) => Err(()),
+            }
+        };
+        Some(Self {
+            kind: column(b"type").ok()??,
+            client: column(b"client").ok()??,
+            tx: column(b"tx").ok()??,
+            amount: column(b"amount").ok()?,
+            width: headers.len(),
+        })
+    }
+
+    /// Transaction of a record, failing the same rows as deserializing it
+    fn parse(&self, record: &ByteRecord) -> Result<Transaction, ParseFailure> {
+        let malformed = |message| ParseFailure {
+            message,
+            fatal: false,
+        };
+        if record.len() < self.width {
+            return Err(malformed("expected field, but got end of row".to_string()));
+        }
+        let number = |column: usize, name: &str| {
+            let value = &record[column];
+            std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse::<u32>().ok())
+                .ok_or_else(|| {
+                    malformed(format!(
+                        "malformed {name} {}",
+                        String::from_utf8_lossy(value)
+                    ))
+                })
+        };
+
+        let name = &record[self.kind];
+        let kind = match name {
+            b"deposit" => Some(TransactionType::Deposit),
+            b"withdrawal" => Some(TransactionType::Withdrawal),
+            b"dispute" => Some(TransactionType::Dispute),
+            b"resolve" => Some(TransactionType::Resolve),
+            b"chargeback" => Some(TransactionType::Chargeback),
+            _ => std::str::from_utf8(name)
+                .ok()
+                .and_then(TransactionType::from_alias),
+        }
+        .ok_or_else(|| {
+            malformed(format!(
+                "unknown transaction type {}",
+                String::from_utf8_lossy(name)
+            ))
+        })?;
+        let amount = match self.amount.map(|column| &record[column]) {
+            None | Some(b"") => None,
+            Some(value) => Some(
+                std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.parse::<Decimal>().ok())
+                    .ok_or_else(|| {
+                        malformed(format!(
+                            "malformed amount {}",
+                            String::from_utf8_lossy(value)
+                        ))
+                    })?,
+            ),
+        };
+        Ok(Transaction {
+            kind,
+            client_id: number(self.client, "client")?,
+            id: number(self.tx, "tx")?,
+            amount,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        })
+    }
+}
+
+/// Input of a [`RawSource`], keeping a copy of the bytes read if rows are
+/// wanted as they were written
+struct KeptInput<R> {
+    inner: R,
+    kept: Option<Rc<RefCell<Vec<u8>>>>,
+}
+
+impl<R: Read> Read for KeptInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(kept) = &self.kept {
+            kept.borrow_mut().extend_from_slice(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Reads CSV rows into `RawTx`, reusing one record buffer across rows.
+/// Without a header, see [`CsvDialect::has_headers`], the columns are
+/// `type,client,tx,amount` in order. Lines starting with `#` and blank
+/// lines are skipped.
+///
+/// [`CsvDialect::has_headers`]: crate::io::CsvDialect
+pub struct RawSource<R> {
+    reader: Reader<KeptInput<R>>,
+    /// Bytes read but not yet cut into rows, only kept if some sink
+    /// registered interest
+    kept: Option<Rc<RefCell<Vec<u8>>>>,
+    /// Offset of the first kept byte in the input
+    kept_from: u64,
+    header_line: Option<Arc<[u8]>>,
+    headers: Option<ByteRecord>,
+    record: ByteRecord,
+    source: u16,
+    /// Column of the transaction type, if only lowercase names are accepted
+    strict_type_column: Option<usize>,
+    strict_types: bool,
+    mappings: Vec<ColumnMapping>,
+    lenient_amounts: Option<LenientAmounts>,
+    scientific_amounts: ScientificAmounts,
+    amount_column: Option<usize>,
+    /// Record with the amount normalized, deserialized in place of `record`
+    normalized: ByteRecord,
+    fast_parse: bool,
+    /// Columns of the fast path, if it is used for this header
+    fast_columns: Option<FastColumns>,
+    done: bool,
+}
+
+impl<R: Read> RawSource<R> {
+    /// Rows of `input` in the given dialect, see [`CsvDialect::reader`]
+    pub fn new(input: R, dialect: CsvDialect, source: u16, interest: RawInterest) -> Self {
+        let kept = (!interest.is_empty()).then(Rc::default);
+        let input = KeptInput {
+            inner: input,
+            kept: kept.clone(),
+        };
+        Self {
+            reader: dialect.reader(input),
+            kept,
+            kept_from: 0,
+            header_line: None,
+            headers: None,
+            record: ByteRecord::new(),
+            source,
+            strict_type_column: None,
+            strict_types: false,
+            mappings: Vec::new(),
+            lenient_amounts: None,
+            scientific_amounts: ScientificAmounts::default(),
+            amount_column: None,
+            normalized: ByteRecord::new(),
+            fast_parse: false,
+            fast_columns: None,
+            done: false,
+        }
+    }
+
+    /// Parse amounts with currency symbols and thousands separators, see
+    /// [`LenientAmounts`]
+    pub fn lenient_amounts(mut self, lenient: Option<LenientAmounts>) -> Self {
+        self.lenient_amounts = lenient;
+        self
+    }
+
+    /// Whether amounts in scientific notation are rejected as malformed or
+    /// expanded, see [`ScientificAmounts`]
+    pub fn scientific_amounts(mut self, policy: ScientificAmounts) -> Self {
+        self.scientific_amounts = policy;
+        self
+    }
+
+    /// Rename columns of the header before rows are read by it. The input
+    /// fails as a whole if a required column is missing after renaming.
+    pub fn map_columns(mut self, mappings: Vec<ColumnMapping>) -> Self {
+        self.mappings = mappings;
+        self
+    }
+
+    /// Only accept the lowercase transaction types, rows using another
+    /// casing or an alias fail to parse
+    pub fn strict_types(mut self, strict: bool) -> Self {
+        self.strict_types = strict;
+        self
+    }
+
+    /// Parse `type`, `client`, `tx` and `amount` straight from the bytes of
+    /// each record instead of deserializing it. Rows fail exactly where
+    /// they would otherwise, only with other messages, and amounts are read
+    /// from their text without a detour through floating point. Headers
+    /// with `to_client`, `fee`, `currency` or `ts` still use serde.
+    pub fn fast_parse(mut self, fast: bool) -> Self {
+        self.fast_parse = fast;
+        self
+    }
+}
+
+impl<R: Read> Iterator for RawSource<R> {
+    type Item = RawTx;
+
+    fn next(&mut self) -> Option<RawTx> {
+        if self.done {
+            return None;
+        }
+        if self.headers.is_none() {
+            let headers = if self.reader.has_headers() {
+                match self.reader.byte_headers() {
+                    Ok(headers) => {
+                        let headers = headers.clone();
+                        self.header_line = self.take_line().map(Arc::from);
+                        headers
+                    }
+                    Err(e) => {
+                        self.done = true;
+                        return Some(self.failure(1, e));
+                    }
+                }
+            } else {
+                ByteRecord::from(POSITIONAL_COLUMNS.to_vec())
+            };
+            let headers = if self.mappings.is_empty() {
+                check_header(headers)
+            } else {
+                ColumnMapping::apply(&self.mappings, &headers).and_then(check_header)
+            };
+            let headers = match headers {
+                Ok(headers) => headers,
+                Err(message) => {
+                    self.done = true;
+                    return Some(RawTx {
+                        record: None,
+                        row: 1,
+                        source: self.source,
+                        parsed: Err(ParseFailure {
+                            message,
+                            fatal: true,
+                        }),
+                    });
+                }
+            };
+            if self.strict_types {
+                self.strict_type_column = headers.iter().position(|h| h == b"type");
+            }
+            self.amount_column = headers.iter().position(|h| h == b"amount");
+            if self.fast_parse {
+                self.fast_columns = FastColumns::resolve(&headers);
+            }
+            self.headers = Some(headers);
+        }
+
+        let line = loop {
+            match self.reader.read_byte_record(&mut self.record) {
+                // Rows of nothing but spaces or delimiters are blank lines
+                Ok(true) if self.record.iter().all(<[u8]>::is_empty) => {
+                    self.take_line();
+                }
+                Ok(true) => break self.take_line(),
+                Ok(false) => return None,
+                Err(e) => {
+                    // The reader can not recover from I/O errors
+                    self.done = e.is_io_error();
+                    let row = e.position().map_or(0, |p| p.line());
+                    return Some(self.failure(row, e));
+                }
+            }
+        };
+
+        let row = self.record.position().map_or(0, |p| p.line());
+        let parsed = self
+            .normalize_amount()
+            .and_then(|normalized| {
+                let record = if normalized {
+                    &self.normalized
+                } else {
+                    &self.record
+                };
+                match &self.fast_columns {
+                    Some(columns) => columns.parse(record),
+                    None => record
+                        .deserialize::<Transaction>(self.headers.as_ref())
+                        .map_err(ParseFailure::from),
+                }
+            })
+            .and_then(|transaction| self.check_type(transaction));
+        let record = line.map(|line| RawRecord::CsvLine {
+            header: self.header_line.clone(),
+            line,
+        });
+        Some(RawTx {
+            record,
+            row,
+            source: self.source,
+            parsed,
+        })
+    }
+}
+
+impl<R: Read> RawSource<R> {
+    /// Cut the bytes read up to the end of the last record from the kept
+    /// input, without the blank and comment lines before the record and
+    /// without its line terminator. `None` if no input is kept.
+    fn take_line(&mut self) -> Option<Vec<u8>> {
+        let mut kept = self.kept.as_ref()?.borrow_mut();
+        let end = self.reader.position().byte();
+        let len =
+            usize::try_from(end - self.kept_from).map_or(kept.len(), |len| len.min(kept.len()));
+        let bytes: Vec<u8> = kept.drain(..len).collect();
+        self.kept_from = end;
+
+        let mut line = bytes.as_slice();
+        loop {
+            match line.first() {
+                Some(b'\r' | b'\n') => line = &line[1..],
+                Some(b'#') => {
+                    let next = line
+                        .iter()
+                        .position(|&b| b == b'\n')
+                        .map_or(line.len(), |i| i + 1);
+                    line = &line[next..];
+                }
+                _ => break,
+            }
+        }
+        while let [rest @ .., b'\r' | b'\n'] = line {
+            line = rest;
+        }
+        Some(line.to_vec())
+    }
+
+    /// Copy the record into `normalized` with its amount rewritten to a
+    /// plain decimal, if it is in scientific notation or amounts are parsed
+    /// leniently. Whether it did so.
+    fn normalize_amount(&mut self) -> Result<bool, ParseFailure> {
+        let Some(column) = self.amount_column else {
+            return Ok(false);
+        };
+        let amount = match self.record.get(column) {
+            Some(amount) if !amount.is_empty() => amount,
+            _ => return Ok(false),
+        };
+        let malformed = |message| ParseFailure {
+            message,
+            fatal: false,
+        };
+        let amount = String::from_utf8_lossy(amount);
+        // The currency symbol, like `EUR`, is no exponent
+        let (negative, number) = match &self.lenient_amounts {
+            Some(lenient) => lenient.strip_symbol(&amount),
+            None => (false, amount.as_ref()),
+        };
+        let amount = if number.contains(['e', 'E']) {
+            match self.scientific_amounts {
+                ScientificAmounts::Reject => {
+                    return Err(malformed(format!(
+                        "malformed amount {amount}, scientific notation is not accepted"
+                    )));
+                }
+                ScientificAmounts::Accept => Decimal::from_scientific(number)
+                    .map(|number| if negative { -number } else { number })
+                    .map_err(|e| malformed(format!("malformed amount {amount}: {e}")))?,
+            }
+        } else if let Some(lenient) = &self.lenient_amounts {
+            lenient.parse(&amount).map_err(malformed)?
+        } else {
+            return Ok(false);
+        };
+        self.normalized.clear();
+        for (i, field) in self.record.iter().enumerate() {
+            if i == column {
+                self.normalized.push_field(amount.to_string().as_bytes());
+            } else {
+                self.normalized.push_field(field);
+            }
+        }
+        self.normalized
+            .set_position(self.record.position().cloned());
+        Ok(true)
+    }
+
+    /// Fail transactions whose type is not written in lowercase, if asked
+    fn check_type(&self, transaction: Transaction) -> Result<Transaction, ParseFailure> {
+        let Some(name) = self.strict_type_column.and_then(|i| self.record.get(i)) else {
+            return Ok(transaction);
+        };
+        if name == transaction.kind.as_str().as_bytes() {
+            Ok(transaction)
+        } else {
+            Err(ParseFailure {
+                message: format!("unknown transaction type {}", String::from_utf8_lossy(name)),
+                fatal: false,
+            })
+        }
+    }
+
+    fn failure(&self, row: u64, e: csv::Error) -> RawTx {
+        RawTx {
+            record: None,
+            row,
+            source: self.source,
+            parsed: Err(e.into()),
+        }
+    }
+}
+
+/// Header with its columns lowercased, so they are named regardless of
+/// case. A required column missing is an error, as every row would fail
+/// without it.
+fn check_header(headers: ByteRecord) -> Result<ByteRecord, String> {
+    let lowercased: ByteRecord = headers.iter().map(<[u8]>::to_ascii_lowercase).collect();
+    let missing = ColumnMapping::missing(&lowercased);
+    if missing.is_empty() {
+        return Ok(lowercased);
+    }
+    let found = if headers.is_empty() {
+        "no header".to_string()
+    } else {
+        let columns: Vec<&[u8]> = headers.iter().collect();
+        format!(
+            "header {}",
+            String::from_utf8_lossy(&columns.join(&b","[..]))
+        )
+    };
+    Err(format!(
+        "missing columns {}, expected type, client, tx and optionally amount, found {found}",
+        missing.join(", ")
+    ))
+}
+
+/// Reads JSON Lines rows into `RawTx`, skipping blank lines
+pub struct RawJsonLines<R> {
+    reader: R,
+    line: Vec<u8>,
+    row: u64,
+    source: u16,
+    interest: RawInterest,
+    scientific_amounts: ScientificAmounts,
+    done: bool,
+}
+
+impl<R: BufRead> RawJsonLines<R> {
+    pub fn new(reader: R, source: u16, interest: RawInterest) -> Self {
+        Self {
+            reader,
+            line: Vec::new(),
+            row: 0,
+            source,
+            interest,
+            scientific_amounts: ScientificAmounts::default(),
+            done: false,
+        }
+    }
+
+    /// Whether amounts in scientific notation are rejected as malformed or
+    /// expanded, see [`ScientificAmounts`]
+    pub fn scientific_amounts(mut self, policy: ScientificAmounts) -> Self {
+        self.scientific_amounts = policy;
+        self
+    }
+}
+
+impl<R: BufRead> Iterator for RawJsonLines<R> {
+    type Item = RawTx;
+
+    fn next(&mut self) -> Option<RawTx> {
+        while !self.done {
+            self.line.clear();
+            self.row += 1;
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) => self.done = true,
+                Ok(_) => {
+                    let line = self.line.trim_ascii();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    let parsed =
+                        parse_json_line(line, self.scientific_amounts).map_err(|message| {
+                            ParseFailure {
+                                message,
+                                fatal: false,
+                            }
+                        });
+                    let record =
+                        (!self.interest.is_empty()).then(|| RawRecord::Line(line.to_vec()));
+                    return Some(RawTx {
+                        record,
+                        row: self.row,
+                        source: self.source,
+                        parsed,
+                    });
+                }
+                Err(e) => {
+                    // The reader can not recover from I/O errors
+                    self.done = true;
+                    return Some(RawTx {
+                        record: None,
+                        row: self.row,
+                        source: self.source,
+                        parsed: Err(ParseFailure {
+                            message: e.to_string(),
+                            fatal: true,
+                        }),
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Reads a MessagePack stream into `RawTx`, numbering the values from 1.
+///
+/// The raw record of a value is its rendering as text. A value which can
+/// not be decoded ends the stream, as the next one can not be found.
+pub struct RawMsgpack<R> {
+    reader: R,
+    row: u64,
+    source: u16,
+    interest: RawInterest,
+    scientific_amounts: ScientificAmounts,
+    done: bool,
+}
+
+impl<R: BufRead> RawMsgpack<R> {
+    pub fn new(reader: R, source: u16, interest: RawInterest) -> Self {
+        Self {
+            reader,
+            row: 0,
+            source,
+            interest,
+            scientific_amounts: ScientificAmounts::default(),
+            done: false,
+        }
+    }
+
+    /// Whether amounts written as text in scientific notation are
+    /// rejected as malformed or expanded, see [`ScientificAmounts`]
+    pub fn scientific_amounts(mut self, policy: ScientificAmounts) -> Self {
+        self.scientific_amounts = policy;
+        self
+    }
+}
+
+impl<R: BufRead> Iterator for RawMsgpack<R> {
+    type Item = RawTx;
+
+    fn next(&mut self) -> Option<RawTx> {
+        if self.done {
+            return None;
+        }
+        self.row += 1;
+        let (record, parsed) = match next_msgpack_value(&mut self.reader) {
+            Ok(Some(value)) => {
+                let record = (!self.interest.is_empty())
+                    .then(|| RawRecord::Line(value.to_string().into_bytes()));
+                let parsed =
+                    msgpack_transaction(value, self.scientific_amounts).map_err(|message| {
+                        ParseFailure {
+                            message,
+                            fatal: false,
+                        }
+                    });
+                (record, parsed)
+            }
+            Ok(None) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                self.done = true;
+                let failure = ParseFailure {
+                    message: e.to_string(),
+                    fatal: true,
+                };
+                (None, Err(failure))
+            }
+        };
+        Some(RawTx {
+            record,
+            row: self.row,
+            source: self.source,
+            parsed,
+        })
+    }
+}
+
+/// Reads a length delimited stream of protobuf messages into `RawTx`,
+/// numbering the messages from 1.
+///
+/// The raw record of a message holds its fields as they would appear in
+/// CSV. A stream ending within a message ends the rows with an I/O
+/// failure.
+pub struct RawProto<R> {
+    reader: R,
+    row: u64,
+    source: u16,
+    interest: RawInterest,
+    done: bool,
+}
+
+impl<R: BufRead> RawProto<R> {
+    pub fn new(reader: R, source: u16, interest: RawInterest) -> Self {
+        Self {
+            reader,
+            row: 0,
+            source,
+            interest,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for RawProto<R> {
+    type Item = RawTx;
+
+    fn next(&mut self) -> Option<RawTx> {
+        if self.done {
+            return None;
+        }
+        self.row += 1;
+        let (record, parsed) = match proto::next_message(&mut self.reader) {
+            Ok(Some(Ok(message))) => {
+                let record = (!self.interest.is_empty()).then(|| RawRecord::Csv(message.raw()));
+                (record, message.to_transaction())
+            }
+            Ok(Some(Err(message))) => (None, Err(message)),
+            Ok(None) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                self.done = true;
+                let failure = ParseFailure {
+                    message: e.to_string(),
+                    fatal: true,
+                };
+                return Some(RawTx {
+                    record: None,
+                    row: self.row,
+                    source: self.source,
+                    parsed: Err(failure),
+                });
+            }
+        };
+        Some(RawTx {
+            record,
+            row: self.row,
+            source: self.source,
+            parsed: parsed.map_err(|message| ParseFailure {
+                message,
+                fatal: false,
+            }),
+        })
+    }
+}
+
+/// Consumer of processed rows
+pub trait RawSink {
+    fn interest(&self) -> RawInterest;
+
+    fn applied(&mut self, _raw: &RawTx, _transaction: &Transaction) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn parse_failed(&mut self, raw: &RawTx, failure: &ParseFailure) -> io::Result<()>;
+
+    fn rejected(&mut self, raw: &RawTx, reason: RejectReason) -> io::Result<()>;
+
+    /// Rows of `source` come from the input `name` from now on, for sinks
+    /// which name the input of a row
+    fn source_named(&mut self, _source: u16, _name: &str) {}
+}
+
+/// Combined interest of all sinks, computed once before reading
+pub fn interest_of(sinks: &[&mut dyn RawSink]) -> RawInterest {
+    sinks
+        .iter()
+        .fold(RawInterest::NONE, |acc, sink| acc | sink.interest())
+}
+
+/// Apply every row to the engine, handing the outcome of each row to the
+/// sinks. Failing to read the input ends the run with the error, as
+/// nothing after it can be trusted.
+pub fn run<I, B, O>(
+    engine: &mut Engine<B, O>,
+    rows: I,
+    sinks: &mut [&mut dyn RawSink],
+) -> io::Result<()>
+where
+    I: IntoIterator<Item = RawTx>,
+    B: StorageBudget,
+    O: EngineObserver,
+{
+    for raw in rows {
+        info!("Processing row {} {:?}", raw.row, raw.parsed);
+        engine.enter_source(raw.source);
+        match &raw.parsed {
+            Ok(transaction) => match engine.apply(transaction.clone()) {
+                Ok(_) => {
+                    for sink in sinks.iter_mut() {
+                        sink.applied(&raw, transaction)?;
+                    }
+                }
+                Err(reason) => {
+                    warn!("Rejected row {}: {reason}", raw.row);
+                    for sink in sinks.iter_mut() {
+                        sink.rejected(&raw, reason)?;
+                    }
+                }
+            },
+            Err(failure) if failure.fatal => return Err(io::Error::other(failure.message.clone())),
+            Err(failure) => {
+                warn!("Invalid transaction at row {}: {failure}", raw.row);
+                for sink in sinks.iter_mut() {
+                    sink.parse_failed(&raw, failure)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Like [`run`], but applies the rows in the order of their timestamps,
+/// rows with equal timestamps in the order they were read. All rows are
+/// read into memory first.
+///
+/// Rows without a timestamp can not be placed, they are rejected up front
+/// along with the rows which failed to parse. Returns how many rows were
+/// rejected for lack of a timestamp.
+pub fn run_by_timestamp<I, B, O>(
+    engine: &mut Engine<B, O>,
+    rows: I,
+    sinks: &mut [&mut dyn RawSink],
+) -> io::Result<u64>
+where
+    I: IntoIterator<Item = RawTx>,
+    B: StorageBudget,
+    O: EngineObserver,
+{
+    let mut timed = Vec::new();
+    let mut untimed = 0;
+    for raw in rows {
+        match &raw.parsed {
+            Ok(transaction) => match transaction.timestamp {
+                Some(timestamp) => timed.push((timestamp, raw)),
+                None => {
+                    warn!("Rejected row {}: missing timestamp", raw.row);
+                    untimed += 1;
+                    for sink in sinks.iter_mut() {
+                        sink.rejected(&raw, RejectReason::MissingTimestamp)?;
+                    }
+                }
+            },
+            Err(failure) if failure.fatal => return Err(io::Error::other(failure.message.clone())),
+            Err(failure) => {
+                warn!("Invalid transaction at row {}: {failure}", raw.row);
+                for sink in sinks.iter_mut() {
+                    sink.parse_failed(&raw, failure)?;
+                }
+            }
+        }
+    }
+    // The sort is stable, ties keep the order of the input
+    timed.sort_by_key(|&(timestamp, _)| timestamp);
+    run(engine, timed.into_iter().map(|(_, raw)| raw), sinks)?;
+    Ok(untimed)
+}
+
+/// Name `source` in `sources`, indexed like [`RawTx::source`]
+fn name_source(sources: &mut Vec<String>, source: u16, name: &str) {
+    let source = usize::from(source);
+    if sources.len() <= source {
+        sources.resize(source + 1, String::new());
+    }
+    sources[source] = name.to_string();
+}
+
+/// Row number of a raw row, after the name of its source if known
+fn location(raw: &RawTx, sources: &[String]) -> String {
+    match sources.get(usize::from(raw.source)) {
+        Some(name) => format!("{name} row {}", raw.row),
+        None => format!("row {}", raw.row),
+    }
+}
+
+/// Render a raw record back into a single line of its format
+fn raw_line(record: Option<&RawRecord>) -> io::Result<Vec<u8>> {
+    match record {
+        Some(RawRecord::CsvLine { line, .. }) => Ok([line.as_slice(), b"\n"].concat()),
+        Some(RawRecord::Csv(record)) => {
+            let mut writer = WriterBuilder::new().flexible(true).from_writer(Vec::new());
+            writer.write_byte_record(record)?;
+            writer.into_inner().map_err(|e| e.into_error())
+        }
+        Some(RawRecord::Line(line)) => Ok([line.as_slice(), b"\n"].concat()),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Writes the raw rows which failed to parse or were rejected, so they
+/// can be inspected and fed again after fixing. Rows of CSV input are
+/// written as they were read, after the header line of their input.
+pub struct Quarantine<W> {
+    writer: W,
+    /// Header line written last, rows of an input with another header are
+    /// preceded by theirs
+    header: Option<Arc<[u8]>>,
+}
+
+impl<W: Write> Quarantine<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            header: None,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, raw: &RawTx) -> io::Result<()> {
+        if let Some(RawRecord::CsvLine {
+            header: Some(header),
+            ..
+        }) = &raw.record
+            && self.header.as_ref() != Some(header)
+        {
+            self.writer.write_all(header)?;
+            self.writer.write_all(b"\n")?;
+            self.header = Some(Arc::clone(header));
+        }
+        self.writer.write_all(&raw_line(raw.record.as_ref())?)
+    }
+}
+
+impl<W: Write> RawSink for Quarantine<W> {
+    fn interest(&self) -> RawInterest {
+        RawInterest::QUARANTINE
+    }
+
+    fn parse_failed(&mut self, raw: &RawTx, _failure: &ParseFailure) -> io::Result<()> {
+        self.write(raw)
+    }
+
+    fn rejected(&mut self, raw: &RawTx, reason: RejectReason) -> io::Result<()> {
+        // Deferred rows are applied later, feeding them again would
+        // apply them twice
+        if reason == RejectReason::Deferred {
+            return Ok(());
+        }
+        self.write(raw)
+    }
+}
+
+/// Reports every row which was not applied along with its raw line
+pub struct VerboseRejects<W> {
+    writer: W,
+    sources: Vec<String>,
+}
+
+impl<W: Write> VerboseRejects<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            sources: Vec::new(),
+        }
+    }
+
+    /// Name the sources in reports, indexed like [`RawTx::source`]
+    pub fn sources(mut self, names: Vec<String>) -> Self {
+        self.sources = names;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn report(&mut self, raw: &RawTx, reason: &dyn fmt::Display) -> io::Result<()> {
+        write!(self.writer, "{}: {reason}: ", location(raw, &self.sources))?;
+        self.writer.write_all(&raw_line(raw.record.as_ref())?)
+    }
+}
+
+impl<W: Write> RawSink for VerboseRejects<W> {
+    fn interest(&self) -> RawInterest {
+        RawInterest::VERBOSE_REJECTS
+    }
+
+    fn parse_failed(&mut self, raw: &RawTx, failure: &ParseFailure) -> io::Result<()> {
+        self.report(raw, failure)
+    }
+
+    fn rejected(&mut self, raw: &RawTx, reason: RejectReason) -> io::Result<()> {
+        self.report(raw, &reason)
+    }
+
+    fn source_named(&mut self, source: u16, name: &str) {
+        name_source(&mut self.sources, source, name);
+    }
+}
+
+/// Counts rows which failed to parse. In strict mode the first one fails
+/// the run instead, with its row number and parse error.
+pub struct MalformedRows {
+    strict: bool,
+    count: u64,
+    sources: Vec<String>,
+}
+
+impl MalformedRows {
+    pub fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            count: 0,
+            sources: Vec::new(),
+        }
+    }
+
+    /// Name the sources in errors, indexed like [`RawTx::source`]
+    pub fn sources(mut self, names: Vec<String>) -> Self {
+        self.sources = names;
+        self
+    }
+
+    /// Rows which failed to parse and were skipped
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl RawSink for MalformedRows {
+    fn interest(&self) -> RawInterest {
+        RawInterest::NONE
+    }
+
+    fn parse_failed(&mut self, raw: &RawTx, failure: &ParseFailure) -> io::Result<()> {
+        if self.strict {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed {}: {failure}", location(raw, &self.sources)),
+            ));
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    fn rejected(&mut self, _raw: &RawTx, _reason: RejectReason) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn source_named(&mut self, source: u16, name: &str) {
+        name_source(&mut self.sources, source, name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use rust_decimal::dec;
+
+    const DATA: &str = "type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,9.0
+deposit,1,three,1.0
+\"dispute\",1,7,
+";
+
+    #[test]
+    fn test_raw_record_dropped_without_interest() {
+        let rows: Vec<RawTx> =
+            RawSource::new(DATA.as_bytes(), CsvDialect::default(), 0, RawInterest::NONE).collect();
+
+        assert_eq!(rows.len(), 4);
+        assert!(rows.iter().all(|r| r.record.is_none()));
+        assert_eq!(
+            rows.iter().map(|r| r.row).collect::<Vec<_>>(),
+            vec![2, 3, 4, 5]
+        );
+        assert!(rows[2].parsed.is_err());
+    }
+
+    #[test]
+    fn test_comments_and_blank_lines_skipped() {
+        let data = "# accounts of client 1\ntype,client,tx,amount\n\n  \ndeposit,1,1,5.0\n\
+                    # a comment\n,,,\nwithdrawal,1,2,1.0\n";
+        let rows: Vec<RawTx> =
+            RawSource::new(data.as_bytes(), CsvDialect::default(), 0, RawInterest::NONE).collect();
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|raw| (raw.row, raw.parsed.is_ok()))
+            .collect();
+        assert_eq!(rows, [(5, true), (8, true)]);
+    }
+
+    #[test]
+    fn test_scientific_amounts() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1.2E3\ndeposit,1,2,5e-5\n\
+                    deposit,1,3,1e40\ndeposit,1,4,2.5\n";
+        let read = |policy| -> Vec<_> {
+            RawSource::new(data.as_bytes(), CsvDialect::default(), 0, RawInterest::NONE)
+                .scientific_amounts(policy)
+                .map(|raw| raw.parsed.map(|t| t.amount.unwrap()).map_err(|f| f.message))
+                .collect()
+        };
+
+        let rejected = read(ScientificAmounts::Reject);
+        assert_eq!(
+            rejected[0],
+            Err("malformed amount 1.2E3, scientific notation is not accepted".to_string())
+        );
+        assert!(rejected[1].is_err());
+        assert!(rejected[2].is_err());
+        assert_eq!(rejected[3], Ok(dec!(2.5)));
+
+        let accepted = read(ScientificAmounts::Accept);
+        assert_eq!(accepted[0], Ok(dec!(1200)));
+        assert_eq!(accepted[1], Ok(dec!(0.00005)));
+        assert!(
+            accepted[2]
+                .as_ref()
+                .unwrap_err()
+                .starts_with("malformed amount 1e40: ")
+        );
+        assert_eq!(accepted[3], Ok(dec!(2.5)));
+
+        let data = "type,client,tx,amount\ndeposit,1,1,12.50 EUR\ndeposit,1,2,-1.5E2EUR\n";
+        let read = |policy| -> Vec<_> {
+            let lenient = LenientAmounts {
+                symbol: "EUR".to_string(),
+                decimal_comma: false,
+            };
+            RawSource::new(data.as_bytes(), CsvDialect::default(), 0, RawInterest::NONE)
+                .lenient_amounts(Some(lenient))
+                .scientific_amounts(policy)
+                .map(|raw| raw.parsed.map(|t| t.amount.unwrap()).map_err(|f| f.message))
+                .collect()
+        };
+        let rejected = read(ScientificAmounts::Reject);
+        assert_eq!(rejected[0], Ok(dec!(12.50)));
+        assert_eq!(
+            rejected[1],
+            Err("malformed amount -1.5E2EUR, scientific notation is not accepted".to_string())
+        );
+        assert_eq!(read(ScientificAmounts::Accept)[1], Ok(dec!(-150)));
+    }
+
+    #[test]
+    fn test_headerless_rows_read_by_position() {
+        let dialect = CsvDialect {
+            has_headers: false,
+            ..CsvDialect::default()
+        };
+        let data = DATA.split_once('\n').unwrap().1;
+        let headerless: Vec<RawTx> =
+            RawSource::new(data.as_bytes(), dialect, 0, RawInterest::NONE).collect();
+        let with_header: Vec<RawTx> =
+            RawSource::new(DATA.as_bytes(), CsvDialect::default(), 0, RawInterest::NONE).collect();
+
+        assert_eq!(headerless.len(), 4);
+        for (headerless, with_header) in headerless.iter().zip(&with_header) {
+            assert_eq!(headerless.parsed.is_ok(), with_header.parsed.is_ok());
+            assert_eq!(
+                headerless.parsed.as_ref().ok(),
+                with_header.parsed.as_ref().ok()
+            );
+            assert_eq!(headerless.row + 1, with_header.row);
+        }
+    }
+
+    #[test]
+    fn test_header_missing_required_columns() {
+        let rows = |data: &str| -> Vec<RawTx> {
+            RawSource::new(data.as_bytes(), CsvDialect::default(), 0, RawInterest::NONE).collect()
+        };
+
+        let misspelled = rows("type,cleint,tx,amount\ndeposit,1,1,1.0\n");
+        assert_eq!(misspelled.len(), 1);
+        let failure = misspelled[0].parsed.as_ref().unwrap_err();
+        assert!(failure.fatal);
+        assert_eq!(
+            failure.message,
+            "missing columns client, expected type, client, tx and optionally amount, \
+             found header type,cleint,tx,amount"
+        );
+
+        let empty = rows("");
+        assert_eq!(
+            empty[0].parsed.as_ref().unwrap_err().message,
+            "missing columns type, client, tx, expected type, client, tx and optionally \
+             amount, found no header"
+        );
+
+        // Columns in another order or case, with padding and extra columns
+        let reordered =
+            rows(" Amount ,TX,note, Client,TYPE\n1.0,1,x,1,deposit\n2.5,2,,1,withdrawal\n");
+        let with_header = rows("type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,2.5\n");
+        for (reordered, with_header) in reordered.iter().zip(&with_header) {
+            assert_eq!(reordered.parsed, with_header.parsed);
+        }
+        assert_eq!(reordered.len(), 2);
+    }
+
+    #[test]
+    fn test_sinks_receive_identical_raw_lines() {
+        let mut quarantine = Quarantine::new(Vec::new());
+        let mut verbose = VerboseRejects::new(Vec::new());
+        let mut engine = Engine::new(EngineConfig::default());
+        {
+            let mut sinks: [&mut dyn RawSink; 2] = [&mut quarantine, &mut verbose];
+            let interest = interest_of(&sinks);
+            assert!(interest.contains(RawInterest::QUARANTINE));
+            assert!(interest.contains(RawInterest::VERBOSE_REJECTS));
+            let rows = RawSource::new(DATA.as_bytes(), CsvDialect::default(), 0, interest);
+            run(&mut engine, rows, &mut sinks).unwrap();
+        }
+
+        let quarantined = String::from_utf8(quarantine.into_inner()).unwrap();
+        let verbose = String::from_utf8(verbose.into_inner()).unwrap();
+        assert_eq!(
+            quarantined,
+            "type,client,tx,amount\nwithdrawal,1,2,9.0\ndeposit,1,three,1.0\n\"dispute\",1,7,\n"
+        );
+        let stripped: String = verbose
+            .lines()
+            .map(|line| format!("{}\n", line.rsplit(": ").next().unwrap()))
+            .collect();
+        assert_eq!(
+            Some(stripped.as_str()),
+            quarantined.strip_prefix("type,client,tx,amount\n")
+        );
+        assert!(verbose.starts_with("row 3: insufficient funds: "));
+        assert_eq!(engine.account(1).unwrap().available(), dec!(5.0));
+    }
+
+    #[test]
+    fn test_quarantine_can_be_fed_again() {
+        let data = "# exported 2024-03-01\r
+ Amount ,TX,client,type\r
+5.0,1,1,deposit\r
+\r
+# disputes\r
+\"9.0\", 2 ,1,withdrawal\r
+,7,1,dispute\r
+1.0,three,1,deposit\r
+";
+        let quarantine = |data: &[u8]| {
+            let mut quarantine = Quarantine::new(Vec::new());
+            let mut engine = Engine::new(EngineConfig::default());
+            let rows = {
+                let mut sinks: [&mut dyn RawSink; 1] = [&mut quarantine];
+                let rows: Vec<_> =
+                    RawSource::new(data, CsvDialect::default(), 0, interest_of(&sinks)).collect();
+                // Messages name the position of the row, which moves
+                let parsed: Vec<_> = rows
+                    .iter()
+                    .map(|raw| raw.parsed.clone().map_err(|_| ()))
+                    .collect();
+                run(&mut engine, rows, &mut sinks).unwrap();
+                parsed
+            };
+            (quarantine.into_inner(), rows)
+        };
+
+        let (quarantined, rows) = quarantine(data.as_bytes());
+        assert_eq!(
+            String::from_utf8(quarantined.clone()).unwrap(),
+            " Amount ,TX,client,type\n\"9.0\", 2 ,1,withdrawal\n,7,1,dispute\n\
+             1.0,three,1,deposit\n"
+        );
+        // Fed again, the same rows fail the same way
+        let (again, fed) = quarantine(&quarantined);
+        assert_eq!(again, quarantined);
+        assert_eq!(fed, rows[1..]);
+    }
+
+    #[test]
+    fn test_run_by_timestamp_reorders_rows() {
+        let data = "type,client,tx,amount,ts
+dispute,1,1,,2024-03-01T12:00:00Z
+deposit,1,1,5.0,2024-03-01T10:00:00Z
+deposit,1,2,1.0,
+deposit,1,3,2.0,2024-03-01T13:00:00+02:00
+withdrawal,1,4,2.0,2024-03-01T11:00:00Z
+";
+        let process = |by_timestamp| {
+            let mut verbose = VerboseRejects::new(Vec::new());
+            let mut engine = Engine::new(EngineConfig::default());
+            let untimed = {
+                let mut sinks: [&mut dyn RawSink; 1] = [&mut verbose];
+                let rows = RawSource::new(
+                    data.as_bytes(),
+                    CsvDialect::default(),
+                    0,
+                    interest_of(&sinks),
+                );
+                if by_timestamp {
+                    run_by_timestamp(&mut engine, rows, &mut sinks).unwrap()
+                } else {
+                    run(&mut engine, rows, &mut sinks).map(|()| 0).unwrap()
+                }
+            };
+            let verbose = String::from_utf8(verbose.into_inner()).unwrap();
+            (engine, untimed, verbose)
+        };
+
+        // In file order the dispute comes before its deposit
+        let (engine, _, verbose) = process(false);
+        assert!(verbose.starts_with("row 2: unknown transaction: "));
+        let client = engine.account(1).unwrap();
+        assert_eq!((client.available(), client.held()), (dec!(6.0), dec!(0)));
+
+        // 13:00+02:00 ties with the withdrawal at 11:00 UTC and stays
+        // before it, the dispute then holds the whole deposit
+        let (engine, untimed, verbose) = process(true);
+        assert_eq!(untimed, 1);
+        assert_eq!(verbose.lines().count(), 1);
+        assert!(verbose.starts_with("row 4: missing timestamp: "));
+        let client = engine.account(1).unwrap();
+        assert_eq!((client.available(), client.held()), (dec!(0.0), dec!(5.0)));
+    }
+
+    #[test]
+    fn test_malformed_rows_strict_and_lenient() {
+        let data = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,five
+deposit,1,3,1.0
+";
+        let process = |strict| {
+            let mut malformed = MalformedRows::new(strict);
+            let mut engine = Engine::new(EngineConfig::default());
+            let result = {
+                let mut sinks: [&mut dyn RawSink; 1] = [&mut malformed];
+                let rows = RawSource::new(
+                    data.as_bytes(),
+                    CsvDialect::default(),
+                    0,
+                    interest_of(&sinks),
+                );
+                run(&mut engine, rows, &mut sinks)
+            };
+            (result, malformed.count(), engine)
+        };
+
+        let (result, count, engine) = process(false);
+        assert!(result.is_ok());
+        assert_eq!(count, 1);
+        assert_eq!(engine.account(1).unwrap().available(), dec!(6.0));
+
+        // Nothing after the malformed row is applied
+        let (result, _, engine) = process(true);
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("malformed row 3: "));
+        assert_eq!(engine.account(1).unwrap().available(), dec!(5.0));
+    }
+
+    #[test]
+    fn test_mixed_case_transaction_types() {
+        let data = "type,client,tx,amount
+Deposit,1,1,5.0
+DEPOSIT,2,2,3.0
+withdraw,1,3,1.5
+Dispute,2,2,
+CHARGE_BACK,2,2,
+";
+        let process = |data: &str, strict| {
+            let mut malformed = MalformedRows::new(false);
+            let mut engine = Engine::new(EngineConfig::default());
+            {
+                let mut sinks: [&mut dyn RawSink; 1] = [&mut malformed];
+                let rows = RawSource::new(
+                    data.as_bytes(),
+                    CsvDialect::default(),
+                    0,
+                    interest_of(&sinks),
+                )
+                .strict_types(strict);
+                run(&mut engine, rows, &mut sinks).unwrap();
+            }
+            (malformed.count(), engine.snapshot())
+        };
+
+        let lowercase = data
+            .replace("withdraw,", "withdrawal,")
+            .replace("CHARGE_BACK", "chargeback")
+            .to_lowercase();
+        let (count, snapshot) = process(data, false);
+        assert_eq!(count, 0);
+        assert_eq!(process(&lowercase, false), (0, snapshot.clone()));
+        assert_eq!(process(&lowercase, true), (0, snapshot));
+
+        let (count, snapshot) = process(data, true);
+        assert_eq!(count, 5);
+        assert_eq!(snapshot, Engine::new(EngineConfig::default()).snapshot());
+    }
+
+    #[test]
+    fn test_wide_client_ids() {
+        let data = "type,client,tx,amount
+deposit,100000,1,2.0
+deposit,5000000000,2,1.0
+withdrawal,100000,3,0.5
+";
+        let mut verbose = VerboseRejects::new(Vec::new());
+        let mut engine = Engine::new(EngineConfig::default());
+        {
+            let mut sinks: [&mut dyn RawSink; 1] = [&mut verbose];
+            let rows = RawSource::new(
+                data.as_bytes(),
+                CsvDialect::default(),
+                0,
+                interest_of(&sinks),
+            );
+            run(&mut engine, rows, &mut sinks).unwrap();
+        }
+
+        // A client id out of range only fails its own row
+        let verbose = String::from_utf8(verbose.into_inner()).unwrap();
+        assert_eq!(verbose.lines().count(), 1);
+        assert!(verbose.starts_with("row 3: "));
+        assert!(verbose.ends_with("deposit,5000000000,2,1.0\n"));
+        assert_eq!(engine.account(100_000).unwrap().available(), dec!(1.5));
+        assert_eq!(engine.accounts().count(), 1);
+    }
+
+    #[test]
+    fn test_fast_parse_matches_serde() {
+        let process = |data: &[u8], fast| {
+            let mut malformed = MalformedRows::new(false);
+            let mut engine = Engine::new(EngineConfig::default());
+            {
+                let mut sinks: [&mut dyn RawSink; 1] = [&mut malformed];
+                let rows = RawSource::new(data, CsvDialect::default(), 0, interest_of(&sinks))
+                    .fast_parse(fast);
+                run(&mut engine, rows, &mut sinks).unwrap();
+            }
+            (malformed.count(), engine.snapshot())
+        };
+
+        let edge_cases = "type,client,tx,amount,note
+deposit,1,1,+2.5,
+Deposit,1,2,.5,x
+withdraw,1,3,1_0,
+deposit,1,4,abc,
+deposit,-1,5,1.0,
+deposit,1,x,1.0,
+deposit,1,7,inf,
+deposit,1,8,NaN,
+transfer,1,9,1.0,
+refund,1,10,
+teleport,1,11,1.0,
+dispute,1,1,,
+deposit,4294967296,12,1.0,
+resolve,1,1
+deposit,1,13,1.0,,extra
+";
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let mut inputs = vec![edge_cases.as_bytes().to_vec()];
+        // Fixtures of other dialects or columns, and output, fail before
+        // their rows
+        let other_dialects = ["headerless", "formatted", "partner", "semicolon", "v1"];
+        for entry in std::fs::read_dir(fixtures).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            if path.extension().is_some_and(|e| e == "csv")
+                && !other_dialects.iter().any(|dialect| name.ends_with(dialect))
+            {
+                inputs.push(std::fs::read(path).unwrap());
+            }
+        }
+        for input in inputs {
+            assert_eq!(process(&input, true), process(&input, false));
+        }
+        assert_eq!(process(edge_cases.as_bytes(), true).0, 9);
+    }
+
+    #[test]
+    fn test_json_lines_quarantined_as_read() {
+        let data = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":5.0}\n\
+            \n\
+            {\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":\"9.0\"}\n\
+            {\"type\":\"deposit\",\"client\":1,\"tx\":\"three\"}\n";
+        let mut quarantine = Quarantine::new(Vec::new());
+        let mut verbose = VerboseRejects::new(Vec::new());
+        let mut engine = Engine::new(EngineConfig::default());
+        {
+            let mut sinks: [&mut dyn RawSink; 2] = [&mut quarantine, &mut verbose];
+            let interest = interest_of(&sinks);
+            let rows = RawJsonLines::new(data.as_bytes(), 0, interest);
+            run(&mut engine, rows, &mut sinks).unwrap();
+        }
+
+        let quarantined = String::from_utf8(quarantine.into_inner()).unwrap();
+        let verbose = String::from_utf8(verbose.into_inner()).unwrap();
+        assert_eq!(
+            quarantined,
+            "{\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":\"9.0\"}\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":\"three\"}\n"
+        );
+        assert!(verbose.starts_with("row 3: insufficient funds: {"));
+        assert!(verbose.contains("\nrow 4: invalid type: string \"three\""));
+        assert_eq!(engine.account(1).unwrap().available(), dec!(5.0));
+    }
+}
@@ -0,0 +1,252 @@
+//! Protobuf interchange format of `proto/transaction.proto`.
+//!
+//! A stream holds [`Transaction`] messages, each prefixed by its length as
+//! a varint, as written by `prost::Message::encode_length_delimited`. The
+//! code generated by prost is checked in, see `build.rs`.
+
+include!("proto/transaction_engine.v1.rs");
+
+use crate::io::TransactionSource;
+use crate::model::{self, TransactionType};
+use anyhow::{Result, anyhow};
+use csv::ByteRecord;
+use prost::Message;
+use rust_decimal::Decimal;
+use std::io::{self, BufRead, Read};
+use std::str::FromStr;
+use transaction::Type;
+
+/// Transactions read from a length delimited stream of messages.
+///
+/// A message which does not decode, or holds a type or amount which is
+/// not valid, is skipped. A stream ending within a message ends the
+/// transactions with an error.
+pub struct ProtoSource<R> {
+    reader: R,
+}
+
+impl<R: BufRead> ProtoSource<R> {
+    pub fn new(rdr: R) -> Self {
+        Self { reader: rdr }
+    }
+
+    /// Transactions along with the position of their message in the
+    /// stream, the first being 1
+    pub fn numbered_transactions(
+        mut self,
+    ) -> impl Iterator<Item = (usize, Result<model::Transaction>)> {
+        let mut index = 0;
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            index += 1;
+            match next_message(&mut self.reader) {
+                Ok(Some(message)) => Some((
+                    index,
+                    message
+                        .and_then(|m| m.to_transaction())
+                        .map_err(|e| anyhow!(e)),
+                )),
+                Ok(None) => None,
+                Err(e) => {
+                    done = true;
+                    Some((index, Err(e.into())))
+                }
+            }
+        })
+    }
+}
+
+impl<R: BufRead> TransactionSource for ProtoSource<R> {
+    fn transactions(self) -> impl Iterator<Item = Result<model::Transaction>> {
+        self.numbered_transactions()
+            .map(|(_, transaction)| transaction)
+    }
+}
+
+/// Next message of a length delimited stream, `None` if the stream ended
+/// cleanly before it. A message which does not decode is an error of its
+/// own, the stream stays readable past it.
+pub(crate) fn next_message<R: BufRead>(
+    rdr: &mut R,
+) -> io::Result<Option<Result<Transaction, String>>> {
+    if rdr.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "stream ends within a message");
+    let mut len = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        if rdr.read(&mut byte)? == 0 {
+            return Err(truncated());
+        }
+        len |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut buf = Vec::new();
+            rdr.take(len).read_to_end(&mut buf)?;
+            if buf.len() as u64 != len {
+                return Err(truncated());
+            }
+            return Ok(Some(
+                Transaction::decode(&buf[..]).map_err(|e| format!("invalid message: {e}")),
+            ));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "message length prefix is too long",
+    ))
+}
+
+impl Transaction {
+    /// The transaction of the message, rejecting types outside of the
+    /// known set and amounts which are not decimals
+    pub fn to_transaction(&self) -> Result<model::Transaction, String> {
+        let kind = match Type::try_from(self.r#type) {
+            Ok(Type::Unspecified) => return Err("missing transaction type".to_string()),
+            Ok(Type::Deposit) => TransactionType::Deposit,
+            Ok(Type::Withdrawal) => TransactionType::Withdrawal,
+            Ok(Type::Dispute) => TransactionType::Dispute,
+            Ok(Type::Resolve) => TransactionType::Resolve,
+            Ok(Type::Chargeback) => TransactionType::Chargeback,
+            Ok(Type::Transfer) => TransactionType::Transfer,
+            Ok(Type::Reversal) => TransactionType::Reversal,
+            Ok(Type::Adjustment) => TransactionType::Adjustment,
+            Ok(Type::Lock) => TransactionType::Lock,
+            Ok(Type::Unlock) => TransactionType::Unlock,
+            Ok(Type::Hold) => TransactionType::Hold,
+            Ok(Type::Release) => TransactionType::Release,
+            Ok(Type::Purchase) => TransactionType::Purchase,
+            Ok(Type::Refund) => TransactionType::Refund,
+            Ok(Type::Settle) => TransactionType::Settle,
+            Ok(Type::Accrue) => TransactionType::Accrue,
+            Err(_) => return Err(format!("unknown transaction type {}", self.r#type)),
+        };
+        let id = u32::try_from(self.tx).map_err(|_| format!("tx {} out of range", self.tx))?;
+        let amount = match self.amount.as_deref().map(str::trim) {
+            None | Some("") => None,
+            Some(amount) => Some(
+                Decimal::from_str(amount).map_err(|e| format!("invalid amount {amount}: {e}"))?,
+            ),
+        };
+        Ok(model::Transaction {
+            kind,
+            client_id: self.client,
+            id,
+            amount,
+            to_client: None,
+            fee: None,
+            currency: None,
+            timestamp: None,
+        })
+    }
+
+    /// Fields of the message as they would appear in CSV
+    pub(crate) fn raw(&self) -> ByteRecord {
+        let kind = match Type::try_from(self.r#type) {
+            Ok(kind) => kind.as_str_name()["TYPE_".len()..].to_ascii_lowercase(),
+            Err(_) => self.r#type.to_string(),
+        };
+        ByteRecord::from(vec![
+            kind,
+            self.client.to_string(),
+            self.tx.to_string(),
+            self.amount.clone().unwrap_or_default(),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::CsvSource;
+    use crate::{EngineConfig, process_transactions};
+
+    fn message(kind: Type, tx: u64, amount: Option<&str>) -> Transaction {
+        Transaction {
+            r#type: kind as i32,
+            client: 1,
+            tx,
+            amount: amount.map(String::from),
+        }
+    }
+
+    fn encode(messages: &[Transaction]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for message in messages {
+            message.encode_length_delimited(&mut data).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn test_proto_matches_csv() {
+        let data = encode(&[
+            message(Type::Deposit, 1, Some("1.0")),
+            message(Type::Deposit, 2, Some("2.5")),
+            message(Type::Withdrawal, 3, Some("0.1234")),
+            message(Type::Dispute, 2, None),
+        ]);
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.5\n\
+                   withdrawal,1,3,0.1234\ndispute,1,2,\n";
+
+        let (from_proto, rejected) = process_transactions(
+            ProtoSource::new(&data[..]).transactions(),
+            EngineConfig::default(),
+        );
+        let (from_csv, _) = process_transactions(
+            CsvSource::new(csv.as_bytes()).transactions(),
+            EngineConfig::default(),
+        );
+        assert!(rejected.is_empty());
+        assert_eq!(from_proto, from_csv);
+    }
+
+    #[test]
+    fn test_invalid_messages_rejected_with_reasons() {
+        let mut unknown = message(Type::Deposit, 1, Some("1.0"));
+        unknown.r#type = 42;
+        let mut data = encode(&[
+            unknown,
+            message(Type::Deposit, 2, Some("1.0.0")),
+            message(Type::Unspecified, 3, None),
+            message(Type::Deposit, 4, Some("2")),
+        ]);
+        // A message which does not decode at all
+        data.extend([2, 0xff, 0xff]);
+
+        let records: Vec<_> = ProtoSource::new(&data[..])
+            .numbered_transactions()
+            .collect();
+        let errors: Vec<_> = records
+            .iter()
+            .map(|(_, r)| r.as_ref().map_err(ToString::to_string).err())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                Some("unknown transaction type 42".to_string()),
+                Some("invalid amount 1.0.0: Invalid decimal: two decimal points".to_string()),
+                Some("missing transaction type".to_string()),
+                None,
+                Some(errors[4].clone().unwrap()),
+            ]
+        );
+        assert!(errors[4].as_ref().unwrap().starts_with("invalid message: "));
+    }
+
+    #[test]
+    fn test_truncated_stream() {
+        let data = encode(&[message(Type::Deposit, 1, Some("1.0"))]);
+
+        let mut clean = &data[..];
+        assert!(next_message(&mut clean).unwrap().is_some());
+        assert!(next_message(&mut clean).unwrap().is_none());
+
+        let mut truncated = &data[..data.len() - 1];
+        let e = next_message(&mut truncated).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
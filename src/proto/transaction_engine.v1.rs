@@ -0,0 +1,90 @@
+// This file is @generated by prost-build.
+/// Transaction as exchanged with partners, a stream of them is written with
+/// each message prefixed by its length as a varint.
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct Transaction {
+    #[prost(enumeration = "transaction::Type", tag = "1")]
+    pub r#type: i32,
+    #[prost(uint32, tag = "2")]
+    pub client: u32,
+    #[prost(uint64, tag = "3")]
+    pub tx: u64,
+    /// Decimal amount, as a string to keep its precision
+    #[prost(string, optional, tag = "4")]
+    pub amount: ::core::option::Option<::prost::alloc::string::String>,
+}
+/// Nested message and enum types in `Transaction`.
+pub mod transaction {
+    /// Kind of a transaction, named like the CSV types
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Type {
+        Unspecified = 0,
+        Deposit = 1,
+        Withdrawal = 2,
+        Dispute = 3,
+        Resolve = 4,
+        Chargeback = 5,
+        Transfer = 6,
+        Reversal = 7,
+        Adjustment = 8,
+        Lock = 9,
+        Unlock = 10,
+        Hold = 11,
+        Release = 12,
+        Purchase = 13,
+        Refund = 14,
+        Settle = 15,
+        Accrue = 16,
+    }
+    impl Type {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Unspecified => "TYPE_UNSPECIFIED",
+                Self::Deposit => "TYPE_DEPOSIT",
+                Self::Withdrawal => "TYPE_WITHDRAWAL",
+                Self::Dispute => "TYPE_DISPUTE",
+                Self::Resolve => "TYPE_RESOLVE",
+                Self::Chargeback => "TYPE_CHARGEBACK",
+                Self::Transfer => "TYPE_TRANSFER",
+                Self::Reversal => "TYPE_REVERSAL",
+                Self::Adjustment => "TYPE_ADJUSTMENT",
+                Self::Lock => "TYPE_LOCK",
+                Self::Unlock => "TYPE_UNLOCK",
+                Self::Hold => "TYPE_HOLD",
+                Self::Release => "TYPE_RELEASE",
+                Self::Purchase => "TYPE_PURCHASE",
+                Self::Refund => "TYPE_REFUND",
+                Self::Settle => "TYPE_SETTLE",
+                Self::Accrue => "TYPE_ACCRUE",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "TYPE_UNSPECIFIED" => Some(Self::Unspecified),
+                "TYPE_DEPOSIT" => Some(Self::Deposit),
+                "TYPE_WITHDRAWAL" => Some(Self::Withdrawal),
+                "TYPE_DISPUTE" => Some(Self::Dispute),
+                "TYPE_RESOLVE" => Some(Self::Resolve),
+                "TYPE_CHARGEBACK" => Some(Self::Chargeback),
+                "TYPE_TRANSFER" => Some(Self::Transfer),
+                "TYPE_REVERSAL" => Some(Self::Reversal),
+                "TYPE_ADJUSTMENT" => Some(Self::Adjustment),
+                "TYPE_LOCK" => Some(Self::Lock),
+                "TYPE_UNLOCK" => Some(Self::Unlock),
+                "TYPE_HOLD" => Some(Self::Hold),
+                "TYPE_RELEASE" => Some(Self::Release),
+                "TYPE_PURCHASE" => Some(Self::Purchase),
+                "TYPE_REFUND" => Some(Self::Refund),
+                "TYPE_SETTLE" => Some(Self::Settle),
+                "TYPE_ACCRUE" => Some(Self::Accrue),
+                _ => None,
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Reading `s3://bucket/key` objects through the AWS SDK.
+//!
+//! Credentials and the region are resolved the standard way of the SDK:
+//! the environment, the shared config and credentials files, SSO, web
+//! identity tokens and instance metadata. The region defaults to
+//! `us-east-1`. `AWS_ENDPOINT_URL_S3` or `AWS_ENDPOINT_URL` point requests
+//! at another endpoint, like a local S3 mock, using path style addressing.
+
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::Client;
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::primitives::ByteStream;
+use bytes::Bytes;
+use std::io::{self, Read};
+use tokio::runtime::Runtime;
+
+/// Region of requests when none is configured
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Bucket and key of an object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Object {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Object {
+    /// Object of an `s3://bucket/key` name, `None` for other names
+    pub fn parse(name: &str) -> Option<Self> {
+        let (bucket, key) = name.strip_prefix("s3://")?.split_once('/')?;
+        if bucket.is_empty() || key.is_empty() {
+            return None;
+        }
+        Some(Self {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+/// Stream the contents of an object. A missing object or denied access
+/// is an error of its own.
+pub fn get(object: &S3Object) -> io::Result<Box<dyn Read>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let body = runtime.block_on(async {
+        let region = RegionProviderChain::default_provider().or_else(Region::new(DEFAULT_REGION));
+        let config = aws_config::defaults(BehaviorVersion::latest())
+            .region(region)
+            .load()
+            .await;
+        let s3_config = aws_sdk_s3::config::Builder::from(&config)
+            .force_path_style(config.endpoint_url().is_some())
+            .build();
+        let response = Client::from_conf(s3_config)
+            .get_object()
+            .bucket(&object.bucket)
+            .key(&object.key)
+            .send()
+            .await;
+        match response {
+            Ok(output) => Ok(output.body),
+            Err(SdkError::ServiceError(e)) => Err(match e.raw().status().as_u16() {
+                404 => io::Error::new(io::ErrorKind::NotFound, "no such object"),
+                403 => io::Error::new(io::ErrorKind::PermissionDenied, "access denied"),
+                status => io::Error::other(format!("server responded with status {status}")),
+            }),
+            Err(e) => Err(io::Error::other(DisplayErrorContext(e).to_string())),
+        }
+    })?;
+    Ok(Box::new(ObjectReader {
+        runtime,
+        body,
+        chunk: Bytes::new(),
+    }))
+}
+
+/// Blocking reader of the body of an object, which drives the runtime of
+/// the SDK as the chunks are read
+struct ObjectReader {
+    runtime: Runtime,
+    body: ByteStream,
+    /// Part of the last chunk not read yet
+    chunk: Bytes,
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.runtime.block_on(self.body.try_next()) {
+                Ok(Some(chunk)) => self.chunk = chunk,
+                Ok(None) => return Ok(0),
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+        let read = self.chunk.split_to(buf.len().min(self.chunk.len()));
+        buf[..read.len()].copy_from_slice(&read);
+        Ok(read.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_object() {
+        assert_eq!(
+            S3Object::parse("s3://dumps/2024/06-01.csv.gz"),
+            Some(S3Object {
+                bucket: "dumps".to_string(),
+                key: "2024/06-01.csv.gz".to_string()
+            })
+        );
+        assert_eq!(S3Object::parse("s3://dumps"), None);
+        assert_eq!(S3Object::parse("s3://dumps/"), None);
+        assert_eq!(S3Object::parse("dumps/tx.csv"), None);
+    }
+}
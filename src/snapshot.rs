@@ -0,0 +1,514 @@
+//! Serializable engine state, so balances and seen transactions can be
+//! carried from one run to the next.
+
+use crate::model::{Client, ClientId, DisputeState, TransactionRecord, TransactionType};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// Format version written into every snapshot
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// Format version a snapshot is written in, so an engine which only reads
+/// an older version can still load it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SnapshotVersion {
+    /// Balances, the stored deposits and withdrawals and the list of open
+    /// disputes
+    V1,
+    /// Dispute state on every record, and the fields of newer features
+    #[default]
+    V2,
+}
+
+impl FromStr for SnapshotVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(SnapshotVersion::V1),
+            "2" => Ok(SnapshotVersion::V2),
+            other => Err(format!(
+                "unsupported snapshot version {other}, supported versions: 1 to {SNAPSHOT_VERSION}"
+            )),
+        }
+    }
+}
+
+/// Fields of the clients in version 1 snapshots
+const V1_CLIENT_FIELDS: [&str; 4] = ["available", "held", "total", "locked"];
+/// Fields of the records in version 1 snapshots, the state is turned into
+/// the list of open disputes
+const V1_RECORD_FIELDS: [&str; 4] = ["client_id", "amount", "transaction_type", "state"];
+
+/// State of an [`Engine`](crate::Engine) at one point in time.
+///
+/// Ordered maps keep the serialized form identical for identical state.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    pub(crate) version: u32,
+    pub(crate) clients: BTreeMap<ClientId, Client>,
+    /// Keyed like the records of the engine, so the key includes the client
+    /// with per-client transaction ids
+    pub(crate) transaction_records: BTreeMap<u64, TransactionRecord>,
+    /// Keys of transactions without a stored record, dropped by settle rows
+    /// or rejected, whose ids can not be used again
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) retired_transactions: BTreeSet<u64>,
+    /// Open disputes of version 1 snapshots, newer versions keep the
+    /// dispute state on the records
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) disputed_transactions: BTreeSet<u32>,
+    /// Line of the followed input the state includes the rows up to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) input_line: Option<u64>,
+}
+
+impl EngineSnapshot {
+    /// Line of the input up to which the rows are included in the state,
+    /// if it was saved while following an input
+    pub fn input_line(&self) -> Option<u64> {
+        self.input_line
+    }
+
+    /// Record that the state includes the rows of the input up to `line`
+    pub fn with_input_line(mut self, line: u64) -> Self {
+        self.input_line = Some(line);
+        self
+    }
+
+    /// The snapshot in the layout of `version`, with the names of what was
+    /// left out because that version can not hold it
+    pub fn to_version(
+        &self,
+        version: SnapshotVersion,
+    ) -> serde_json::Result<(Value, BTreeSet<String>)> {
+        let mut dropped = BTreeSet::new();
+        if version == SnapshotVersion::V2 {
+            return Ok((serde_json::to_value(self)?, dropped));
+        }
+        let mut clients = Map::new();
+        for (client_id, client) in &self.clients {
+            let client = known_fields(client, &V1_CLIENT_FIELDS, "clients", &mut dropped)?;
+            clients.insert(client_id.to_string(), client);
+        }
+        let mut records = Map::new();
+        let mut disputed = BTreeSet::new();
+        for (&key, record) in &self.transaction_records {
+            let Ok(tx) = u32::try_from(key) else {
+                dropped.insert("transactions with per-client ids".to_string());
+                continue;
+            };
+            let kind = record.transaction_type;
+            if !matches!(kind, TransactionType::Deposit | TransactionType::Withdrawal) {
+                dropped.insert(format!("{kind} transactions"));
+                continue;
+            }
+            match record.state {
+                DisputeState::Undisputed => {}
+                DisputeState::Disputed => {
+                    disputed.insert(tx);
+                }
+                _ => {
+                    dropped.insert("transaction_records.state".to_string());
+                }
+            }
+            let mut record = known_fields(
+                record,
+                &V1_RECORD_FIELDS,
+                "transaction_records",
+                &mut dropped,
+            )?;
+            if let Value::Object(fields) = &mut record {
+                fields.remove("state");
+            }
+            records.insert(tx.to_string(), record);
+        }
+        if !self.retired_transactions.is_empty() {
+            dropped.insert("retired_transactions".to_string());
+        }
+        if self.input_line.is_some() {
+            dropped.insert("input_line".to_string());
+        }
+        let snapshot = json!({
+            "version": 1,
+            "clients": clients,
+            "transaction_records": records,
+            "disputed_transactions": disputed,
+        });
+        Ok((snapshot, dropped))
+    }
+}
+
+/// `value` serialized with only its `known` fields, the others are added to
+/// `dropped` as `{kind}.{field}`
+fn known_fields(
+    value: &impl Serialize,
+    known: &[&str],
+    kind: &str,
+    dropped: &mut BTreeSet<String>,
+) -> serde_json::Result<Value> {
+    let mut value = serde_json::to_value(value)?;
+    if let Value::Object(fields) = &mut value {
+        fields.retain(|field, _| {
+            let keep = known.contains(&field.as_str());
+            if !keep {
+                dropped.insert(format!("{kind}.{field}"));
+            }
+            keep
+        });
+    }
+    Ok(value)
+}
+
+/// Reason a snapshot could not be restored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    UnsupportedVersion(u32),
+    /// Client whose total is not available plus held funds
+    UnbalancedClient(ClientId),
+    /// Disputed transaction without a stored record
+    UnknownDisputedTx(u32),
+    /// Client with more open disputes than the configured maximum
+    DisputeLimitExceeded(ClientId),
+    /// Transaction stored under a different transaction id scope than the
+    /// configured one
+    TxIdScopeMismatch(u32),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported snapshot version {version}, supported versions: 1 to {SNAPSHOT_VERSION}"
+            ),
+            SnapshotError::UnbalancedClient(client) => {
+                write!(
+                    f,
+                    "client {client} total does not match available plus held"
+                )
+            }
+            SnapshotError::UnknownDisputedTx(tx) => {
+                write!(f, "disputed transaction {tx} has no record")
+            }
+            SnapshotError::DisputeLimitExceeded(client) => {
+                write!(f, "client {client} has more open disputes than allowed")
+            }
+            SnapshotError::TxIdScopeMismatch(tx) => {
+                write!(
+                    f,
+                    "transaction {tx} was saved with a different transaction id scope"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::EngineConfig;
+    use crate::engine::{Engine, RejectReason};
+    use crate::io::{CsvAccountsWriter, CsvSource, OutputSink, OutputVersion, TransactionSource};
+    use crate::model::DisputeState;
+    use crate::snapshot::{EngineSnapshot, SNAPSHOT_VERSION, SnapshotError, SnapshotVersion};
+    use rust_decimal::{Decimal, dec};
+    use serde::Deserialize;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    const FILE_A: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+dispute,2,2,
+";
+
+    const FILE_B: &str = "type,client,tx,amount
+deposit,1,1,7.0
+resolve,2,2,
+deposit,3,4,1.0
+dispute,3,4,
+chargeback,3,4,
+";
+
+    fn process(engine: &mut Engine, data: &str) -> Vec<RejectReason> {
+        let rejections = engine.process(CsvSource::new(data.as_bytes()).transactions());
+        rejections.iter().map(|r| r.reason).collect()
+    }
+
+    #[test]
+    fn test_restored_run_matches_single_run() {
+        let mut first = Engine::new(EngineConfig::default());
+        process(&mut first, FILE_A);
+        let json = serde_json::to_string(&first.snapshot()).unwrap();
+        let snapshot: EngineSnapshot = serde_json::from_str(&json).unwrap();
+        let mut restored = Engine::from_snapshot(snapshot, EngineConfig::default()).unwrap();
+        let restored_rejections = process(&mut restored, FILE_B);
+
+        let mut single = Engine::new(EngineConfig::default());
+        let combined = format!("{FILE_A}{}", FILE_B.split_once('\n').unwrap().1);
+        let single_rejections = process(&mut single, &combined);
+
+        // Tx 1 of file B reuses the id of a deposit from file A
+        assert_eq!(restored_rejections, vec![RejectReason::DuplicateTxId]);
+        assert_eq!(single_rejections, restored_rejections);
+        assert_eq!(restored.snapshot(), single.snapshot());
+        assert!(restored.account(3).unwrap().is_locked());
+    }
+
+    fn accounts_output(engine: Engine) -> Vec<String> {
+        let mut writer = CsvAccountsWriter::new(Vec::new(), OutputVersion::V1);
+        writer.write_accounts(&engine.into_accounts()).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let mut lines: Vec<String> = output.lines().map(String::from).collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn test_replay_after_restore_is_idempotent() {
+        let combined = format!("{FILE_A}{}", FILE_B.split_once('\n').unwrap().1);
+        let mut first = Engine::new(EngineConfig::default());
+        process(&mut first, &combined);
+        let mut restored =
+            Engine::from_snapshot(first.snapshot(), EngineConfig::default()).unwrap();
+
+        let rejections = process(&mut restored, &combined);
+
+        assert_eq!(
+            rejections,
+            vec![
+                RejectReason::DuplicateTxId,
+                RejectReason::DuplicateTxId,
+                RejectReason::DuplicateTxId,
+                // Reuses the id of tx 1 with another amount, so no replay
+                RejectReason::DuplicateTxId,
+                RejectReason::AccountLocked,
+                RejectReason::AlreadyChargedBack,
+                RejectReason::NotDisputed,
+            ]
+        );
+        assert_eq!(restored.replayed(), 3);
+        assert_eq!(restored.snapshot(), first.snapshot());
+        assert_eq!(accounts_output(restored), accounts_output(first));
+    }
+
+    #[test]
+    fn test_replay_keeps_open_disputes() {
+        let mut first = Engine::new(EngineConfig::default());
+        process(&mut first, FILE_A);
+        let mut restored =
+            Engine::from_snapshot(first.snapshot(), EngineConfig::default()).unwrap();
+
+        assert_eq!(
+            process(&mut restored, FILE_A),
+            vec![
+                RejectReason::DuplicateTxId,
+                RejectReason::DuplicateTxId,
+                RejectReason::DuplicateTxId,
+                RejectReason::AlreadyDisputed,
+            ]
+        );
+        assert_eq!(restored.account(2).unwrap().held(), dec!(5));
+        assert_eq!(restored.snapshot(), first.snapshot());
+    }
+
+    #[test]
+    fn test_restore_rejects_excess_disputes() {
+        let mut engine = Engine::new(EngineConfig::default());
+        process(&mut engine, FILE_A);
+
+        let config = EngineConfig::builder()
+            .max_open_disputes_per_client(Some(1))
+            .build();
+        assert!(Engine::from_snapshot(engine.snapshot(), config.clone()).is_ok());
+
+        process(
+            &mut engine,
+            "type,client,tx,amount\ndeposit,2,5,1.0\ndispute,2,5,\n",
+        );
+        assert_eq!(
+            Engine::from_snapshot(engine.snapshot(), config).unwrap_err(),
+            SnapshotError::DisputeLimitExceeded(2)
+        );
+    }
+
+    #[test]
+    fn test_restore_version_1_disputes() {
+        let json = r#"{
+            "version": 1,
+            "clients": {"2": {"available": "0", "held": "5", "total": "5", "locked": false}},
+            "transaction_records": {
+                "2": {"client_id": 2, "amount": "5", "transaction_type": "deposit"}
+            },
+            "disputed_transactions": [2]
+        }"#;
+        let snapshot: EngineSnapshot = serde_json::from_str(json).unwrap();
+        let mut engine = Engine::from_snapshot(snapshot, EngineConfig::default()).unwrap();
+
+        assert_eq!(
+            engine.transaction(2, 2).unwrap().dispute_state(),
+            DisputeState::Disputed
+        );
+        assert!(
+            process(
+                &mut engine,
+                "type,client,tx,amount
+resolve,2,2,
+"
+            )
+            .is_empty()
+        );
+        assert_eq!(engine.account(2).unwrap().available(), dec!(5));
+        assert_eq!(engine.snapshot().version, SNAPSHOT_VERSION);
+    }
+
+    /// Snapshot layout read by engines before version 2
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct SnapshotV1 {
+        version: u32,
+        clients: BTreeMap<u32, ClientV1>,
+        transaction_records: BTreeMap<u32, RecordV1>,
+        disputed_transactions: BTreeSet<u32>,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct ClientV1 {
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+        locked: bool,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct RecordV1 {
+        client_id: u32,
+        amount: Decimal,
+        transaction_type: KindV1,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum KindV1 {
+        Deposit,
+        Withdrawal,
+    }
+
+    #[test]
+    fn test_version_1_snapshot_loads_in_version_1_loader() {
+        let mut engine = Engine::new(EngineConfig::default());
+        process(
+            &mut engine,
+            "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,3,20.0
+deposit,2,2,5.0
+dispute,2,2,
+deposit,3,4,1.0
+dispute,3,4,
+resolve,3,4,
+adjustment,3,5,-0.5
+",
+        );
+        let snapshot = engine.snapshot();
+
+        let (value, dropped) = snapshot.to_version(SnapshotVersion::V1).unwrap();
+        assert_eq!(
+            dropped.into_iter().collect::<Vec<_>>(),
+            [
+                "adjustment transactions",
+                "clients.failed_withdrawals",
+                "transaction_records.state",
+            ]
+        );
+        let client = |available, held| ClientV1 {
+            available,
+            held,
+            total: available + held,
+            locked: false,
+        };
+        let deposit = |client_id, amount| RecordV1 {
+            client_id,
+            amount,
+            transaction_type: KindV1::Deposit,
+        };
+        assert_eq!(
+            serde_json::from_value::<SnapshotV1>(value.clone()).unwrap(),
+            SnapshotV1 {
+                version: 1,
+                clients: BTreeMap::from([
+                    (1, client(dec!(10), dec!(0))),
+                    (2, client(dec!(0), dec!(5))),
+                    (3, client(dec!(0.5), dec!(0))),
+                ]),
+                transaction_records: BTreeMap::from([
+                    (1, deposit(1, dec!(10))),
+                    (2, deposit(2, dec!(5))),
+                    (4, deposit(3, dec!(1))),
+                ]),
+                disputed_transactions: BTreeSet::from([2]),
+            }
+        );
+
+        // The open dispute survives the round trip
+        let snapshot: EngineSnapshot = serde_json::from_value(value).unwrap();
+        let mut restored = Engine::from_snapshot(snapshot, EngineConfig::default()).unwrap();
+        assert!(process(&mut restored, "type,client,tx,amount\nresolve,2,2,\n").is_empty());
+        assert_eq!(restored.account(2).unwrap().available(), dec!(5));
+
+        // The current version leaves nothing out
+        let (value, dropped) = engine.snapshot().to_version(SnapshotVersion::V2).unwrap();
+        assert!(dropped.is_empty());
+        assert_eq!(
+            serde_json::from_value::<EngineSnapshot>(value).unwrap(),
+            engine.snapshot()
+        );
+    }
+
+    #[test]
+    fn test_corrupted_held_funds_are_not_released() {
+        // Tx 2 is disputed, but its amount is not held
+        let json = r#"{
+            "version": 2,
+            "clients": {"2": {"available": "5", "held": "1", "total": "6", "locked": false}},
+            "transaction_records": {
+                "2": {"client_id": 2, "amount": "5", "transaction_type": "deposit", "state": "disputed"}
+            }
+        }"#;
+        let snapshot: EngineSnapshot = serde_json::from_str(json).unwrap();
+        let mut engine = Engine::from_snapshot(snapshot.clone(), EngineConfig::default()).unwrap();
+
+        assert_eq!(
+            process(
+                &mut engine,
+                "type,client,tx,amount
+resolve,2,2,
+chargeback,2,2,
+"
+            ),
+            vec![
+                RejectReason::InconsistentState,
+                RejectReason::InconsistentState
+            ]
+        );
+        assert_eq!(engine.snapshot(), snapshot);
+    }
+
+    #[test]
+    fn test_restore_rejects_unknown_version() {
+        let mut snapshot = Engine::new(EngineConfig::default()).snapshot();
+        snapshot.version = 3;
+
+        assert_eq!(
+            Engine::from_snapshot(snapshot, EngineConfig::default()).unwrap_err(),
+            SnapshotError::UnsupportedVersion(3)
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use crate::model::{Client, ClientId, FourPlaces};
+use rust_decimal::Decimal;
+use std::fmt;
+
+/// Rule every client account is expected to follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invariant {
+    /// `available + held == total`
+    BalancedTotal,
+    NonNegativeHeld,
+    /// Only checked unless negative available funds are allowed, clients
+    /// with a credit line may go below zero by up to their limit
+    NonNegativeAvailable,
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Invariant::BalancedTotal => "available + held == total",
+            Invariant::NonNegativeHeld => "held >= 0",
+            Invariant::NonNegativeAvailable => "available >= 0",
+        })
+    }
+}
+
+/// Client account breaking an invariant, with the observed balances
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvariantViolation {
+    pub client_id: ClientId,
+    pub invariant: Invariant,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+impl InvariantViolation {
+    pub(crate) fn new(client_id: ClientId, invariant: Invariant, client: &Client) -> Self {
+        Self {
+            client_id,
+            invariant,
+            available: client.available(),
+            held: client.held(),
+            total: client.total(),
+        }
+    }
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client {} violates {}: available {}, held {}, total {}",
+            self.client_id,
+            self.invariant,
+            FourPlaces(self.available),
+            FourPlaces(self.held),
+            FourPlaces(self.total)
+        )
+    }
+}
@@ -0,0 +1,203 @@
+//! Watching a directory for input files as they are dropped into it.
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Subdirectory completed files are moved to
+pub const PROCESSED_DIR: &str = "processed";
+
+/// Subdirectory files which could not be processed are moved to
+pub const FAILED_DIR: &str = "failed";
+
+/// Suffix of the marker announcing that the file named like it without
+/// the suffix is complete
+pub const DONE_SUFFIX: &str = ".done";
+
+/// Longest wait between two looks at the directory, events of the file
+/// system cut it short
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Files of a directory, handed out in name order once they are complete.
+///
+/// A file is complete once a marker named like it plus [`DONE_SUFFIX`]
+/// exists, or else once its size and modification time stayed the same
+/// for the settle period. Hidden files are skipped, so writers can also
+/// create a file under a hidden name and rename it when done. Completed
+/// files are moved to the [`PROCESSED_DIR`] subdirectory along with their
+/// marker, so they are not picked up again, failed ones to [`FAILED_DIR`].
+pub struct DirWatcher {
+    dir: PathBuf,
+    settle: Duration,
+    stop: Arc<AtomicBool>,
+    events: Receiver<()>,
+    _watcher: RecommendedWatcher,
+    /// Size and modification time a file had when last seen, and since
+    /// when it has them
+    seen: HashMap<PathBuf, (u64, Option<SystemTime>, Instant)>,
+}
+
+impl DirWatcher {
+    /// Start watching `dir`. Once `stop` is set no further files are
+    /// handed out.
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        settle: Duration,
+        stop: Arc<AtomicBool>,
+    ) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(PROCESSED_DIR))?;
+        fs::create_dir_all(dir.join(FAILED_DIR))?;
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |_| {
+            let _ = sender.send(());
+        })
+        .map_err(io::Error::other)?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            dir,
+            settle,
+            stop,
+            events,
+            _watcher: watcher,
+            seen: HashMap::new(),
+        })
+    }
+
+    /// Next complete file, waiting for one if there is none. `None` once
+    /// stopped.
+    pub fn next_file(&mut self) -> io::Result<Option<PathBuf>> {
+        loop {
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            if let Some(path) = self.first_complete()? {
+                return Ok(Some(path));
+            }
+            let _ = self.events.recv_timeout(POLL_INTERVAL);
+            while self.events.try_recv().is_ok() {}
+        }
+    }
+
+    /// Move a file handed out by [`DirWatcher::next_file`] and its marker
+    /// out of the way
+    pub fn complete(&mut self, path: &Path) -> io::Result<()> {
+        self.move_to(path, PROCESSED_DIR)
+    }
+
+    /// Move a file handed out by [`DirWatcher::next_file`] which could not
+    /// be processed and its marker aside, so later files are not held up
+    pub fn fail(&mut self, path: &Path) -> io::Result<()> {
+        self.move_to(path, FAILED_DIR)
+    }
+
+    fn move_to(&mut self, path: &Path, subdir: &str) -> io::Result<()> {
+        self.seen.remove(path);
+        let target = self.dir.join(subdir);
+        let marker = marker(path);
+        for path in [path, marker.as_path()] {
+            if let Some(name) = path.file_name().filter(|_| path.exists()) {
+                fs::rename(path, target.join(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// First file by name if it is complete. Later files wait for it, so
+    /// files are processed in name order.
+    fn first_complete(&mut self) -> io::Result<Option<PathBuf>> {
+        let Some(path) = self.pending()?.into_iter().next() else {
+            return Ok(None);
+        };
+        if marker(&path).exists() {
+            return Ok(Some(path));
+        }
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            // Removed since the directory was listed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (len, modified) = (metadata.len(), metadata.modified().ok());
+        match self.seen.get(&path) {
+            Some(&(seen_len, seen_modified, since))
+                if (seen_len, seen_modified) == (len, modified) =>
+            {
+                Ok((since.elapsed() >= self.settle).then_some(path))
+            }
+            _ => {
+                self.seen.insert(path, (len, modified, Instant::now()));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Files waiting to be processed, sorted by name
+    fn pending(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with('.') || name.ends_with(DONE_SUFFIX) {
+                continue;
+            }
+            if entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+/// Marker announcing that `path` is complete
+fn marker(path: &Path) -> PathBuf {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(DONE_SUFFIX);
+    PathBuf::from(marker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_handed_out_in_name_order_once_complete() {
+        let dir = std::env::temp_dir().join("transaction_engine_watch");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("02.csv"), "b").unwrap();
+        fs::write(dir.join("01.csv"), "a").unwrap();
+        fs::write(dir.join("01.csv.done"), "").unwrap();
+        fs::write(dir.join(".03.csv"), "c").unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut watcher = DirWatcher::new(&dir, Duration::from_secs(60), stop.clone()).unwrap();
+        // The marker makes the first file complete right away
+        let first = watcher.next_file().unwrap().unwrap();
+        assert_eq!(first, dir.join("01.csv"));
+        watcher.complete(&first).unwrap();
+        assert!(dir.join(PROCESSED_DIR).join("01.csv").exists());
+        assert!(dir.join(PROCESSED_DIR).join("01.csv.done").exists());
+
+        // Without a marker the second file has to settle first
+        assert_eq!(watcher.first_complete().unwrap(), None);
+        watcher.settle = Duration::ZERO;
+        let second = watcher.next_file().unwrap().unwrap();
+        assert_eq!(second, dir.join("02.csv"));
+        watcher.fail(&second).unwrap();
+        assert!(dir.join(FAILED_DIR).join("02.csv").exists());
+
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(watcher.next_file().unwrap(), None);
+    }
+}
@@ -0,0 +1,1496 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+fn fixture(name: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Run the binary from the test tmp dir, so its log file does not end up
+/// in the source tree.
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_transaction_engine"))
+        .args(args)
+        .current_dir(env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_output_compat_v1_golden() {
+    let expected = std::fs::read(fixture("single_client_v1.csv")).unwrap();
+
+    let output = run(&[&fixture("single_client.csv"), "--output-compat", "1"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    // Default output matches v1 as long as no newer columns are enabled
+    let output = run(&[&fixture("single_client.csv")]);
+    assert_eq!(output.stdout, expected);
+}
+
+/// Run the binary like [`run`], feeding `input` on stdin
+fn run_with_stdin(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_transaction_engine"))
+        .args(args)
+        .current_dir(env!("CARGO_TARGET_TMPDIR"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_read_transactions_from_stdin() {
+    let input = std::fs::read(fixture("single_client.csv")).unwrap();
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+
+    for args in [&[][..], &["-"][..]] {
+        let output = run_with_stdin(args, &input);
+        assert!(output.status.success());
+        assert_eq!(output.stdout, expected);
+    }
+}
+
+#[test]
+fn test_unlock_leaves_no_negative_zero() {
+    let unlock_row = &b"type,client,tx,amount\ndeposit,1,1,10.0\nlock,1,2,\nunlock,1,3,\n"[..];
+    let auto_unlock = &b"type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\n\
+                        dispute,1,2,\nchargeback,1,2,\n"[..];
+    for (args, input) in [(&[][..], unlock_row), (&["--auto-unlock"][..], auto_unlock)] {
+        let output = run_with_stdin(args, input);
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+        );
+    }
+}
+
+#[test]
+fn test_locked_deposits_dropped() {
+    let input = b"type,client,tx,amount\ndeposit,1,1,10.0\nlock,1,2,\ndeposit,1,3,5.0\n";
+    let output = run_with_stdin(
+        &["--locked-deposit-policy", "drop", "--verbose-rejects"],
+        input,
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,true\n"
+    );
+    assert!(
+        !String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("deposit,1,3")
+    );
+}
+
+#[test]
+fn test_other_currencies_need_output_version_4() {
+    let input = b"type,client,tx,amount,currency\ndeposit,1,1,10.0,\ndeposit,1,2,5.0,EUR\n";
+    for args in [&[][..], &["--output-format", "json"][..]] {
+        let output = run_with_stdin(args, input);
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("--output-compat 4"), "{stderr}");
+    }
+
+    let output = run_with_stdin(&["--output-compat", "4"], input);
+    assert!(output.status.success());
+    assert!(
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .contains("1,5.0000,0.0000,5.0000,false,0,0.0000,EUR")
+    );
+}
+
+#[test]
+fn test_gzip_input() {
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+
+    let output = run(&[&fixture("single_client.csv.gz")]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    // Stdin has no extension, gzip is recognised by its magic bytes
+    let input = std::fs::read(fixture("single_client.csv.gz")).unwrap();
+    let output = run_with_stdin(&[], &input);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+}
+
+#[test]
+fn test_truncated_gzip_input_names_the_file() {
+    let path = fixture("truncated.csv.gz");
+    let output = run(&[&path]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(&format!("{path}: ")), "{stderr}");
+}
+
+#[test]
+fn test_large_zstd_input_matches_uncompressed() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("large_zstd");
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 1..=100_000u32 {
+        let client = tx % 1000;
+        match tx % 10 {
+            0 => csv.push_str(&format!("dispute,{client},{},\n", tx - 10)),
+            3 | 7 => csv.push_str(&format!("withdrawal,{client},{tx},{}.25\n", tx % 7)),
+            _ => csv.push_str(&format!("deposit,{client},{tx},{}.5\n", tx % 13)),
+        }
+    }
+    let plain = dir.join("transactions.csv");
+    let compressed = dir.join("transactions.csv.zst");
+    std::fs::write(&plain, &csv).unwrap();
+    std::fs::write(&compressed, zstd::encode_all(csv.as_bytes(), 3).unwrap()).unwrap();
+
+    let sorted_lines = |output: Output| {
+        assert!(output.status.success());
+        let mut lines: Vec<_> = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        lines.sort();
+        lines
+    };
+    let expected = sorted_lines(run(&[plain.to_str().unwrap()]));
+    // Clients with ids divisible by ten only have disputes of other
+    // clients, which are rejected without opening an account
+    assert_eq!(expected.len(), 901);
+    assert_eq!(sorted_lines(run(&[compressed.to_str().unwrap()])), expected);
+}
+
+#[test]
+fn test_multiple_files_share_state() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("multiple_files");
+    std::fs::create_dir_all(&dir).unwrap();
+    let first = dir.join("part1.csv");
+    let second = dir.join("part2.csv");
+    std::fs::write(&first, "type,client,tx,amount\ndeposit,1,1,5.0\n").unwrap();
+    std::fs::write(
+        &second,
+        "type,client,tx,amount\ndispute,1,1,\ndeposit,1,1,1.0\n",
+    )
+    .unwrap();
+    let (first, second) = (first.to_str().unwrap(), second.to_str().unwrap());
+
+    let output = run(&[first, second, "--verbose-rejects"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.0000,5.0000,5.0000,false\n"
+    );
+    // The duplicate tx 1 is reported for the second file
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with(&format!("{second} row 3: duplicate transaction id: ")));
+}
+
+#[test]
+fn test_monotonic_timestamps_per_file() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("monotonic_files");
+    std::fs::create_dir_all(&dir).unwrap();
+    let first = dir.join("part1.csv");
+    let second = dir.join("part2.csv");
+    std::fs::write(
+        &first,
+        "type,client,tx,amount,ts\n\
+         deposit,1,1,5.0,2024-03-02T10:00:00Z\n\
+         deposit,1,2,1.0,2024-03-02T09:00:00Z\n",
+    )
+    .unwrap();
+    // Starts before the end of the first file, but is ordered on its own
+    std::fs::write(
+        &second,
+        "type,client,tx,amount,ts\n\
+         deposit,1,3,2.0,2024-03-01T10:00:00Z\n\
+         deposit,1,4,4.0,2024-03-01T08:00:00Z\n",
+    )
+    .unwrap();
+    let (first, second) = (first.to_str().unwrap(), second.to_str().unwrap());
+
+    let output = run(&[
+        first,
+        second,
+        "--enforce-monotonic-timestamps",
+        "--verbose-rejects",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,7.0000,0.0000,7.0000,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let rejected: Vec<_> = stderr.lines().map(|line| line.split(':').next()).collect();
+    assert_eq!(
+        rejected,
+        vec![
+            Some(format!("{first} row 3").as_str()),
+            Some(format!("{second} row 3").as_str())
+        ]
+    );
+}
+
+#[test]
+fn test_zip_members_share_state() {
+    let archive = fixture("daily.zip");
+    let output = run(&[&archive, "--verbose-rejects"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.0000,5.0000,5.0000,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with(&format!(
+        "{archive}/2_disputes.csv row 3: duplicate transaction id: "
+    )));
+
+    let output = run(&[&fixture("encrypted.zip")]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("transactions.csv is password protected"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_glob_orders_files_by_name() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("glob_order");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("2024-02")).unwrap();
+    std::fs::create_dir_all(dir.join("2024-01")).unwrap();
+    // Created out of order, the withdrawal only succeeds after the deposit
+    let withdrawal = dir.join("2024-02/b.csv");
+    let deposit = dir.join("2024-01/a.csv");
+    std::fs::write(&withdrawal, "type,client,tx,amount\nwithdrawal,1,2,1.0\n").unwrap();
+    std::fs::write(&deposit, "type,client,tx,amount\ndeposit,1,1,3.0\n").unwrap();
+    let pattern = dir.join("2024-*/*.csv");
+
+    let output = run(&["--glob", pattern.to_str().unwrap(), "--verbose"]);
+    assert!(output.status.success());
+    let explicit = run(&[deposit.to_str().unwrap(), withdrawal.to_str().unwrap()]);
+    assert_eq!(output.stdout, explicit.stdout);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        format!(
+            "Processing {}\nProcessing {}\n",
+            deposit.display(),
+            withdrawal.display()
+        )
+    );
+
+    let output = run(&["--glob", dir.join("*.json").to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("no files match")
+    );
+}
+
+#[test]
+fn test_output_compat_unknown_version() {
+    let output = run(&[&fixture("single_client.csv"), "--output-compat", "9"]);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("--output-compat"));
+    assert!(stderr.contains("unsupported output version 9"));
+}
+
+#[test]
+fn test_json_lines_input_matches_csv() {
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+
+    let output = run(&[&fixture("single_client.jsonl"), "--format", "jsonl"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+}
+
+#[test]
+fn test_bom_and_crlf_input() {
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+
+    let output = run(&[&fixture("single_client_bom_crlf.csv"), "--strict"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    let jsonl = std::fs::read_to_string(fixture("single_client.jsonl")).unwrap();
+    let input = format!("\u{feff}{}", jsonl.replace('\n', "\r\n"));
+    let output = run_with_stdin(&["--format", "jsonl", "--strict"], input.as_bytes());
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+}
+
+#[test]
+fn test_comments_and_blank_lines_skipped() {
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+
+    let output = run(&[&fixture("single_client_annotated.csv")]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+    assert!(output.stderr.is_empty(), "{:?}", output.stderr);
+
+    // Garbage among them is still counted
+    let mut input = std::fs::read(fixture("single_client_annotated.csv")).unwrap();
+    input.extend_from_slice(b"\n# done\ngarbage\n");
+    let output = run_with_stdin(&[], &input);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Skipped 1 malformed rows"), "{stderr}");
+}
+
+#[test]
+fn test_malformed_json_line_skipped_unless_strict() {
+    let mut input = std::fs::read(fixture("single_client.jsonl")).unwrap();
+    input.extend_from_slice(b"{\"type\":\"deposit\",\"client\":1,\n");
+
+    let output = run_with_stdin(&["--format", "jsonl"], &input);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, run(&[&fixture("single_client.csv")]).stdout);
+
+    let output = run_with_stdin(&["--format", "jsonl", "--strict"], &input);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("malformed row 6: EOF while parsing"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_parquet_input_matches_csv() {
+    use arrow_array::{ArrayRef, Decimal128Array, RecordBatch, StringArray, UInt32Array};
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("parquet");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("single_client.parquet");
+    let batch = RecordBatch::try_from_iter([
+        (
+            "type",
+            Arc::new(StringArray::from(vec![
+                "deposit",
+                "deposit",
+                "withdrawal",
+                "dispute",
+            ])) as ArrayRef,
+        ),
+        ("client", Arc::new(UInt32Array::from(vec![1, 1, 1, 1]))),
+        ("tx", Arc::new(UInt32Array::from(vec![1, 2, 3, 2]))),
+        (
+            "amount",
+            Arc::new(
+                Decimal128Array::from(vec![Some(10_000), Some(25_000), Some(1_234), None])
+                    .with_precision_and_scale(18, 4)
+                    .unwrap(),
+            ),
+        ),
+    ])
+    .unwrap();
+    let mut writer =
+        ArrowWriter::try_new(std::fs::File::create(&path).unwrap(), batch.schema(), None).unwrap();
+    writer.write(&batch).unwrap();
+    writer.close().unwrap();
+
+    let output = run(&[path.to_str().unwrap(), "--format", "parquet"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, run(&[&fixture("single_client.csv")]).stdout);
+
+    // A CSV file is no Parquet file, the error names it
+    let csv = fixture("single_client.csv");
+    let output = run(&[&csv, "--format", "parquet"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(&format!("can not read {csv}")), "{stderr}");
+}
+
+#[test]
+fn test_arrow_input_matches_csv() {
+    use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, UInt32Array};
+    use arrow_ipc::writer::StreamWriter;
+    use std::sync::Arc;
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("arrow");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("single_client.arrows");
+    let batch = RecordBatch::try_from_iter([
+        (
+            "type",
+            Arc::new(StringArray::from(vec![
+                "deposit",
+                "deposit",
+                "withdrawal",
+                "dispute",
+            ])) as ArrayRef,
+        ),
+        ("client", Arc::new(UInt32Array::from(vec![1, 1, 1, 1]))),
+        ("tx", Arc::new(UInt32Array::from(vec![1, 2, 3, 2]))),
+        (
+            "amount",
+            Arc::new(Float64Array::from(vec![
+                Some(1.0),
+                Some(2.5),
+                Some(0.1234),
+                None,
+            ])),
+        ),
+    ])
+    .unwrap();
+    let file = std::fs::File::create(&path).unwrap();
+    let mut writer = StreamWriter::try_new(file, &batch.schema()).unwrap();
+    writer.write(&batch).unwrap();
+    writer.finish().unwrap();
+
+    let output = run(&[path.to_str().unwrap(), "--format", "arrow"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, run(&[&fixture("single_client.csv")]).stdout);
+
+    let output = run(&[
+        path.to_str().unwrap(),
+        "--format",
+        "arrow",
+        "--reject-float-amounts",
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("unusable columns amount (Float64)"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_avro_input_matches_csv() {
+    let long = |value: i64| {
+        let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+        let mut bytes = Vec::new();
+        while zigzag >= 0x80 {
+            bytes.push(zigzag as u8 | 0x80);
+            zigzag >>= 7;
+        }
+        bytes.push(zigzag as u8);
+        bytes
+    };
+    let bytes = |value: &str| [long(value.len() as i64), value.as_bytes().to_vec()].concat();
+    let schema = r#"{"type": "record", "name": "Transaction", "fields": [
+            {"name": "type", "type": "string"},
+            {"name": "client", "type": "int"},
+            {"name": "tx", "type": "int"},
+            {"name": "amount", "type": ["null", "string"]}
+        ]}"#;
+    let record = |kind: &str, tx: i64, amount: Option<&str>| {
+        let amount = match amount {
+            Some(amount) => [long(1), bytes(amount)].concat(),
+            None => long(0),
+        };
+        [bytes(kind), long(1), long(tx), amount].concat()
+    };
+    let records = [
+        record("deposit", 1, Some("1.0")),
+        record("deposit", 2, Some("2.5")),
+        record("withdrawal", 3, Some("0.1234")),
+        record("dispute", 2, None),
+    ]
+    .concat();
+    let sync = b"0123456789abcdef";
+    let file = [
+        b"Obj\x01".to_vec(),
+        long(1),
+        bytes("avro.schema"),
+        bytes(schema),
+        long(0),
+        sync.to_vec(),
+        long(4),
+        long(records.len() as i64),
+        records,
+        sync.to_vec(),
+    ]
+    .concat();
+
+    let output = run_with_stdin(&["--format", "avro"], &file);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, run(&[&fixture("single_client.csv")]).stdout);
+}
+
+#[test]
+fn test_msgpack_input_matches_csv() {
+    let row = |kind: &str, tx: u32, amount: Option<&str>| {
+        let mut entries = vec![
+            (rmpv::Value::from("type"), rmpv::Value::from(kind)),
+            ("client".into(), 1.into()),
+            ("tx".into(), tx.into()),
+        ];
+        if let Some(amount) = amount {
+            entries.push(("amount".into(), amount.into()));
+        }
+        rmp_serde::to_vec(&rmpv::Value::Map(entries)).unwrap()
+    };
+    let input = [
+        row("deposit", 1, Some("1.0")),
+        row("deposit", 2, Some("2.5")),
+        row("withdrawal", 3, Some("0.1234")),
+        row("dispute", 2, None),
+    ]
+    .concat();
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+
+    let output = run_with_stdin(&["--format", "msgpack"], &input);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    // A value which is no transaction is skipped, unless strict
+    let invalid = [rmp_serde::to_vec(&"deposit").unwrap(), input.clone()].concat();
+    let output = run_with_stdin(&["--format", "msgpack"], &invalid);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+    let output = run_with_stdin(&["--format", "msgpack", "--strict"], &invalid);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("malformed row 1: expected a map"),
+        "{stderr}"
+    );
+
+    let output = run_with_stdin(&["--format", "msgpack"], &input[..input.len() - 3]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("stream ends within a value"), "{stderr}");
+}
+
+#[test]
+fn test_proto_input_matches_csv() {
+    use prost::Message;
+    use transaction_engine::proto::{Transaction, transaction::Type};
+
+    let messages = [
+        (Type::Deposit, 1, Some("1.0")),
+        (Type::Deposit, 2, Some("2.5")),
+        (Type::Withdrawal, 3, Some("0.1234")),
+        (Type::Dispute, 2, None),
+    ];
+    let mut input = Vec::new();
+    for (kind, tx, amount) in messages {
+        let message = Transaction {
+            r#type: kind as i32,
+            client: 1,
+            tx,
+            amount: amount.map(String::from),
+        };
+        message.encode_length_delimited(&mut input).unwrap();
+    }
+
+    let output = run_with_stdin(&["--format", "proto"], &input);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, run(&[&fixture("single_client.csv")]).stdout);
+}
+
+#[test]
+fn test_semicolon_delimited_input() {
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+
+    let output = run(&[&fixture("single_client_semicolon.csv"), "--delimiter", ";"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    let output = run(&[&fixture("single_client.csv"), "--output-delimiter", "\\t"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(expected).unwrap().replace(',', "\t")
+    );
+}
+
+#[test]
+fn test_semicolon_rejects_keep_delimiter() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("semicolon_rejects");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("input.csv");
+    let quarantine = dir.join("quarantine.csv");
+    std::fs::write(
+        &input,
+        "type;client;tx;amount\ndeposit;1;1;\"1,5\"\nwithdrawal;1;2;\"9.0\"\n",
+    )
+    .unwrap();
+
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--delimiter",
+        ";",
+        "--verbose-rejects",
+        "--quarantine",
+        quarantine.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("row 2: CSV deserialize error: "));
+    assert!(stderr.contains(": deposit;1;1;\"1,5\"\n"));
+    assert!(stderr.contains("row 3: insufficient funds: withdrawal;1;2;\"9.0\"\n"));
+    assert_eq!(
+        std::fs::read_to_string(&quarantine).unwrap(),
+        "type;client;tx;amount\ndeposit;1;1;\"1,5\"\nwithdrawal;1;2;\"9.0\"\n"
+    );
+}
+
+#[test]
+fn test_headerless_input() {
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+
+    let output = run(&[&fixture("single_client_headerless.csv"), "--no-header"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+    assert!(output.stderr.is_empty());
+
+    // Taken for a header, the first line lacks the required columns
+    let output = run(&[&fixture("single_client_headerless.csv")]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("missing columns type, client, tx"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_misspelled_header_fails() {
+    let output = run_with_stdin(&[], b"type,cleint,tx,amount\ndeposit,1,1,1.0\n");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "missing columns client, expected type, client, tx and optionally amount, \
+             found header type,cleint,tx,amount"
+        ),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_mapped_columns() {
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+    let partner = fixture("single_client_partner.csv");
+    let mappings = [
+        "--map-column",
+        "txn_type=type",
+        "--map-column",
+        "customer=client",
+        "--map-column",
+        "txn_id=tx",
+        "--map-column",
+        "value=amount",
+    ];
+
+    let output = run(&[&[partner.as_str()][..], &mappings].concat());
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    let output = run(&[&[partner.as_str()][..], &mappings[..4]].concat());
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("missing columns tx after mapping, found type,client,txn_id,value,branch"),
+        "{stderr}"
+    );
+
+    let output = run(&[&partner, "--no-header", "--map-column", "txn_type=type"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+}
+
+#[test]
+fn test_lenient_amounts() {
+    let output = run(&[
+        &fixture("single_client_formatted.csv"),
+        "--delimiter",
+        ";",
+        "--lenient-amounts",
+        "--currency-symbol",
+        "€",
+        "--decimal-comma",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.8766,2500.5000,2501.3766,false\n"
+    );
+
+    let input = b"type,client,tx,amount\ndeposit,1,1,\"$1,234.56\"\ndeposit,1,2,\" 1 000 \"\n";
+    let output = run_with_stdin(&["--lenient-amounts", "--strict"], input);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,2234.5600,0.0000,2234.5600,false\n"
+    );
+
+    let input = b"type,client,tx,amount\ndeposit,1,1,\"1.234,56\"\n";
+    let output = run_with_stdin(&["--lenient-amounts", "--strict"], input);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("ambiguous amount 1.234,56, ',' follows the decimal point '.'"),
+        "{stderr}"
+    );
+
+    // Without the flag such amounts are malformed
+    let input = b"type,client,tx,amount\ndeposit,1,1,\"$1,234.56\"\n";
+    let output = run_with_stdin(&["--strict"], input);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_scientific_amounts() {
+    let input = b"type,client,tx,amount\ndeposit,1,1,1.2E3\ndeposit,1,2,5e-5\n";
+
+    let output = run_with_stdin(&[], input);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Skipped 2 malformed rows"), "{stderr}");
+
+    // Expanded amounts go through the precision policy
+    let args = [
+        "--scientific-amounts",
+        "accept",
+        "--precision-policy",
+        "reject-excess",
+    ];
+    let output = run_with_stdin(&args, input);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1200.0000,0.0000,1200.0000,false\n"
+    );
+
+    // JSON numbers and strings follow the same policy
+    let input = b"{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.2E3}\n\
+                  {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":\"1.5E1\"}\n";
+    let output = run_with_stdin(&["--format", "jsonl"], input);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Skipped 2 malformed rows"), "{stderr}");
+    let output = run_with_stdin(
+        &["--format", "jsonl", "--scientific-amounts", "accept"],
+        input,
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1215.0000,0.0000,1215.0000,false\n"
+    );
+}
+
+#[test]
+fn test_mmap_output_identical() {
+    for name in ["single_client.csv", "single_client.csv.gz", "daily.zip"] {
+        let expected = run(&[&fixture(name)]);
+        assert!(expected.status.success());
+        let output = run(&[&fixture(name), "--mmap"]);
+        assert!(output.status.success());
+        assert_eq!(output.stdout, expected.stdout);
+    }
+}
+
+#[test]
+fn test_latin1_input() {
+    let latin1 = fixture("single_client_latin1.csv");
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+    let output = run(&[&latin1, "--encoding", "latin1", "--verbose-rejects"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+    // Rows are reported with their notes intact
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "row 5: insufficient funds: withdrawal,1,4,9.0,réclamation\n"
+    );
+
+    // Read as UTF-8, reports pass the Windows-1252 bytes on unchanged
+    let output = run(&[&latin1, "--verbose-rejects"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+    assert!(output.stderr.ends_with(b",r\xe9clamation\n"));
+}
+
+#[test]
+fn test_utf16_input_detected() {
+    let csv = std::fs::read_to_string(fixture("single_client.csv")).unwrap();
+    let utf16: Vec<u8> = "\u{feff}"
+        .encode_utf16()
+        .chain(csv.encode_utf16())
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+
+    let output = run_with_stdin(&["--encoding", "auto"], &utf16);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    let output = run_with_stdin(&[], &utf16);
+    assert!(!output.status.success());
+
+    let output = run(&[
+        &fixture("single_client.csv"),
+        "--format",
+        "parquet",
+        "--encoding",
+        "latin1",
+    ]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_output_file_matches_stdout() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("output");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("accounts.csv");
+    let input = fixture("single_client.csv");
+
+    let output = run(&[&input, "--output", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(std::fs::read(&path).unwrap(), run(&[&input]).stdout);
+
+    // A failed run leaves the previous output alone, and no partial file
+    let output = run_with_stdin(
+        &["--output", path.to_str().unwrap()],
+        b"type,cleint,tx,amount\n",
+    );
+    assert!(!output.status.success());
+    assert_eq!(std::fs::read(&path).unwrap(), run(&[&input]).stdout);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    let missing = dir.join("missing").join("accounts.csv");
+    let output = run(&[&input, "--output", missing.to_str().unwrap()]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("can not write output"), "{stderr}");
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+}
+
+#[test]
+fn test_json_output() {
+    let input = fixture("single_client.csv");
+    let expected = serde_json::json!({
+        "client": 1,
+        "available": "0.8766",
+        "held": "2.5",
+        "total": "3.3766",
+        "locked": false,
+    });
+
+    let output = run(&[&input, "--output-format", "json"]);
+    assert!(output.status.success());
+    let accounts: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(accounts, serde_json::json!([expected]));
+
+    let output = run(&[&input, "--output-format", "jsonl"]);
+    assert!(output.status.success());
+    let line: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(line, expected);
+
+    for flag in [
+        ["--extended-output"].as_slice(),
+        &["--output-compat", "2"],
+        &["--output-delimiter", ";"],
+    ] {
+        let output = run(&[&[input.as_str(), "--output-format", "json"], flag].concat());
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains(&format!("{} only applies to CSV output", flag[0])));
+    }
+}
+
+#[test]
+fn test_output_sorted() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("many_clients.csv");
+    let mut data = String::from("type,client,tx,amount\n");
+    for client in 1..=200 {
+        data.push_str(&format!("deposit,{client},{client},{}.5\n", client % 7));
+    }
+    std::fs::write(&path, data).unwrap();
+    let path = path.to_str().unwrap();
+
+    let first = run(&[path]);
+    assert!(first.status.success());
+    assert_eq!(run(&[path]).stdout, first.stdout);
+    let clients: Vec<u32> = String::from_utf8(first.stdout)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(clients, (1..=200).collect::<Vec<_>>());
+
+    let output = run(&[path, "--sort", "total", "--desc"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines[1], "6,6.5000,0.0000,6.5000,false");
+    assert_eq!(lines[200], "196,0.5000,0.0000,0.5000,false");
+}
+
+#[test]
+fn test_table_output() {
+    let input = fixture("overdrawn_client.csv");
+    let output = run(&[&input, "--output-format", "table"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "\
++--------+-----------+--------+--------+--------+
+| client | available |   held |  total | locked |
++--------+-----------+--------+--------+--------+
+|      1 |    3.0000 | 0.0000 | 3.0000 | no     |
+|      2 |    1.0000 | 0.0000 | 1.0000 | no     |
++--------+-----------+--------+--------+--------+
+|  total |    4.0000 | 0.0000 | 4.0000 |        |
++--------+-----------+--------+--------+--------+
+"
+    );
+
+    let input = b"type,client,tx,amount
+deposit,1,1,1234567.5
+deposit,2,2,12.25
+dispute,2,2,
+chargeback,2,2,
+";
+    let output = run_with_stdin(&["--output-format", "table", "--human"], input);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "\
++--------+----------------+--------+----------------+--------+
+| client |      available |   held |          total | locked |
++--------+----------------+--------+----------------+--------+
+|      1 | 1,234,567.5000 | 0.0000 | 1,234,567.5000 | no     |
+|      2 |         0.0000 | 0.0000 |         0.0000 | yes    |
++--------+----------------+--------+----------------+--------+
+|  total | 1,234,567.5000 | 0.0000 | 1,234,567.5000 |        |
++--------+----------------+--------+----------------+--------+
+"
+    );
+
+    // A table is no CSV file
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("table.csv");
+    let output = run(&[
+        &fixture("overdrawn_client.csv"),
+        "--output-format",
+        "table",
+        "--output",
+        path.to_str().unwrap(),
+    ]);
+    assert!(!output.status.success());
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_fast_parse_output_identical() {
+    let mut paths: Vec<String> = std::fs::read_dir(fixture(""))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".csv") || name.ends_with(".gz") || name.ends_with(".zip"))
+        .map(|name| fixture(&name))
+        .collect();
+    paths.sort();
+    // Amounts a float can not hold exactly
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("fast_parse");
+    std::fs::create_dir_all(&dir).unwrap();
+    let precise = dir.join("precise.csv");
+    std::fs::write(
+        &precise,
+        "type,client,tx,amount
+deposit,1,1,12345678901234567.1234
+deposit,2,2,1000000000000000000000000
+deposit,3,3,0.1
+withdrawal,1,4,0.0001
+deposit,4,5,79228162514264337593543950335
+",
+    )
+    .unwrap();
+    paths.push(precise.to_str().unwrap().to_string());
+    for path in paths {
+        let expected = run(&[&path, "--verbose-rejects"]);
+        let output = run(&[&path, "--verbose-rejects", "--fast-parse"]);
+        assert_eq!(output.status.code(), expected.status.code(), "{path}");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&expected.stdout),
+            "{path}"
+        );
+        // Messages of malformed rows differ, their number does not
+        assert_eq!(
+            output.stderr.split(|&b| b == b'\n').count(),
+            expected.stderr.split(|&b| b == b'\n').count(),
+            "{path}"
+        );
+    }
+
+    let output = run(&[precise.to_str().unwrap()]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("\n1,12345678901234567.1233,0.0000,12345678901234567.1233,false\n"),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("\n2,1000000000000000000000000.0000,"),
+        "{stdout}"
+    );
+}
+
+/// Follow `path`, checkpointing to `state` after every row
+#[test]
+fn test_version_1_state() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("state_v1");
+    std::fs::create_dir_all(&dir).unwrap();
+    let state = dir.join("state.json");
+    let state = state.to_str().unwrap();
+    let rows = b"type,client,tx,amount\nresolve,2,2,\nwithdrawal,1,4,20.0\n";
+    let expected = "client,available,held,total,locked\n\
+                    1,7.5000,0.0000,7.5000,false\n\
+                    2,5.0000,0.0000,5.0000,false\n";
+
+    let output = run_with_stdin(
+        &[
+            "-",
+            "--load-state",
+            &fixture("state_v1.json"),
+            "--save-state",
+            state,
+        ],
+        rows,
+    );
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    assert!(
+        std::fs::read_to_string(state)
+            .unwrap()
+            .contains("\"version\": 2")
+    );
+
+    // Written for an older engine, the count of failed withdrawals and the
+    // resolved dispute are left out
+    let output = run_with_stdin(
+        &[
+            "-",
+            "--load-state",
+            &fixture("state_v1.json"),
+            "--save-state",
+            state,
+            "--snapshot-compat",
+            "1",
+        ],
+        rows,
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        format!(
+            "The snapshot version can not hold clients.failed_withdrawals, \
+             transaction_records.state, left out of {state}\n"
+        )
+    );
+    let saved = std::fs::read_to_string(state).unwrap();
+    assert!(saved.contains("\"version\": 1"));
+    assert!(!saved.contains("failed_withdrawals"));
+
+    let output = run_with_stdin(&["-", "--load-state", state], b"type,client,tx,amount\n");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+#[cfg(unix)]
+fn spawn_follow(path: &Path, state: &Path, load: bool) -> std::process::Child {
+    let mut args = vec![
+        path.to_str().unwrap(),
+        "--follow",
+        "--follow-interval",
+        "10",
+        "--checkpoint-every",
+        "1",
+        "--save-state",
+        state.to_str().unwrap(),
+    ];
+    if load {
+        args.extend(["--load-state", state.to_str().unwrap()]);
+    }
+    Command::new(env!("CARGO_BIN_EXE_transaction_engine"))
+        .args(args)
+        .current_dir(env!("CARGO_TARGET_TMPDIR"))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+/// Wait until the saved state includes the rows up to `line`
+#[cfg(unix)]
+fn await_checkpoint(state: &Path, line: u64) {
+    for _ in 0..500 {
+        let saved = std::fs::read_to_string(state).unwrap_or_default();
+        if saved.contains(&format!("\"input_line\": {line}")) {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("no checkpoint of line {line}");
+}
+
+#[cfg(unix)]
+fn interrupt(child: std::process::Child) -> Output {
+    let status = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    child.wait_with_output().unwrap()
+}
+
+#[cfg(unix)]
+#[test]
+fn test_follow_growing_file() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("follow");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("live.csv");
+    let state = dir.join("state.json");
+    let _ = std::fs::remove_file(&state);
+    std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+    let append = |data: &str| {
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    };
+
+    let child = spawn_follow(&path, &state, false);
+    await_checkpoint(&state, 2);
+    // The withdrawal is only read once its line is complete
+    append("deposit,1,2,2.5\nwithdrawal,1,3,0.");
+    await_checkpoint(&state, 3);
+    append("5\n");
+    await_checkpoint(&state, 4);
+    let output = interrupt(child);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n"
+    );
+
+    // A restart picks up after the rows of the saved state
+    append("dispute,1,2,\n");
+    let child = spawn_follow(&path, &state, true);
+    await_checkpoint(&state, 5);
+    let output = interrupt(child);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.5000,2.5000,3.0000,false\n"
+    );
+}
+
+/// Wait until the watcher moved `name` out of the way
+#[cfg(unix)]
+fn await_processed(dir: &Path, name: &str) {
+    for _ in 0..500 {
+        if dir.join("processed").join(name).exists() {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("{name} was not processed");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watch_directory() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("watch");
+    let _ = std::fs::remove_dir_all(&dir);
+    let incoming = dir.join("incoming");
+    std::fs::create_dir_all(&incoming).unwrap();
+    let state = dir.join("state.json");
+    // Fails to read, and is moved aside rather than stopping the watch
+    std::fs::copy(fixture("truncated.csv.gz"), incoming.join("00.csv.gz")).unwrap();
+    std::fs::write(
+        incoming.join("01.csv"),
+        "type,client,tx,amount\ndeposit,1,1,5.0\n",
+    )
+    .unwrap();
+    let spawn = |load: bool| {
+        let mut args = vec![
+            "--watch",
+            incoming.to_str().unwrap(),
+            "--watch-settle",
+            "50",
+            "--verbose-rejects",
+            "--save-state",
+            state.to_str().unwrap(),
+        ];
+        if load {
+            args.extend(["--load-state", state.to_str().unwrap()]);
+        }
+        Command::new(env!("CARGO_BIN_EXE_transaction_engine"))
+            .args(args)
+            .current_dir(env!("CARGO_TARGET_TMPDIR"))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    };
+
+    let child = spawn(false);
+    await_processed(&incoming, "01.csv");
+    // Announced complete by a marker
+    std::fs::write(
+        incoming.join("02.csv"),
+        "type,client,tx,amount\nwithdrawal,1,2,1.5\n",
+    )
+    .unwrap();
+    std::fs::write(incoming.join("02.csv.done"), "").unwrap();
+    await_processed(&incoming, "02.csv");
+    // Complete once it stopped changing
+    std::fs::write(
+        incoming.join("03.csv"),
+        "type,client,tx,amount\ndeposit,2,3,2.0\nwithdrawal,2,5,9.0\n",
+    )
+    .unwrap();
+    await_processed(&incoming, "03.csv");
+    let output = interrupt(child);
+    assert!(output.status.success());
+    assert!(incoming.join("failed").join("00.csv.gz").exists());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let failed = incoming.join("00.csv.gz");
+    assert!(
+        stderr.contains(&format!("Moved {} to failed: ", failed.display())),
+        "{stderr}"
+    );
+    // Reports name the file of a row
+    let third = incoming.join("03.csv");
+    assert!(
+        stderr.contains(&format!(
+            "{} row 3: insufficient funds: withdrawal,2,5,9.0\n",
+            third.display()
+        )),
+        "{stderr}"
+    );
+    let mut lines: Vec<_> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect();
+    lines.sort();
+    assert_eq!(
+        lines,
+        [
+            "1,3.5000,0.0000,3.5000,false",
+            "2,2.0000,0.0000,2.0000,false",
+            "client,available,held,total,locked"
+        ]
+    );
+
+    // Processed files are not read again after a restart
+    let child = spawn(true);
+    std::fs::write(
+        incoming.join("04.csv"),
+        "type,client,tx,amount\ndeposit,1,4,1.0\n",
+    )
+    .unwrap();
+    await_processed(&incoming, "04.csv");
+    let output = interrupt(child);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("1,4.5000,0.0000,4.5000,false"), "{stdout}");
+}
+
+#[test]
+fn test_multi_byte_delimiter_rejected() {
+    let output = run(&[&fixture("single_client.csv"), "--delimiter", ";;"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("invalid value"), "{stderr}");
+    assert!(
+        stderr.contains("is not a single byte character"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_extended_output_counts_failed_withdrawals() {
+    let output = run(&[&fixture("overdrawn_client.csv"), "--extended-output"]);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines: Vec<&str> = stdout.lines().collect();
+    lines.sort();
+    // The duplicate tx 3 does not count as a failed withdrawal
+    assert_eq!(
+        lines,
+        vec![
+            "1,3.0000,0.0000,3.0000,false,3,0.0000,USD,false,,false",
+            "2,1.0000,0.0000,1.0000,false,0,0.0000,USD,false,,false",
+            "client,available,held,total,locked,failed_withdrawals,fees_paid,currency,flagged,credit_limit,overdrawn",
+        ]
+    );
+}
+
+/// Answer `requests` requests on a local server with the fixture named by
+/// the path, or 404. Returns the URL of the server.
+#[cfg(feature = "http")]
+fn serve_fixtures(requests: usize) -> String {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+    std::thread::spawn(move || {
+        for request in server.incoming_requests().take(requests) {
+            let _ = match std::fs::read(fixture(request.url().trim_start_matches('/'))) {
+                Ok(data) => request.respond(tiny_http::Response::from_data(data)),
+                Err(_) => request.respond(tiny_http::Response::empty(404)),
+            };
+        }
+    });
+    url
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn test_http_input() {
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+    let url = serve_fixtures(3);
+
+    let output = run(&[&format!("{url}/single_client.csv")]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    // Compression is still detected
+    let output = run(&[&format!("{url}/single_client.csv.gz")]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    let missing = format!("{url}/missing.csv");
+    let output = run(&[&missing]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(&format!("{missing}: server responded with status 404")),
+        "{stderr}"
+    );
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn test_http_disconnect_mid_stream() {
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://{}/single_client.csv",
+        listener.local_addr().unwrap()
+    );
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        // Promise more than is sent, then hang up
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\ntype,client,tx,amount\n")
+            .unwrap();
+    });
+
+    let output = run(&[&url]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(&url), "{stderr}");
+}
+
+/// Answer `requests` requests of a local S3 mock: signed requests for
+/// `/transactions/<fixture>` get the fixture, unsigned ones 403 and
+/// anything else 404. Returns the endpoint of the mock.
+#[cfg(feature = "s3")]
+fn serve_bucket(requests: usize) -> String {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", server.server_addr().to_ip().unwrap());
+    std::thread::spawn(move || {
+        for request in server.incoming_requests().take(requests) {
+            let signed = request.headers().iter().any(|h| {
+                h.field.equiv("authorization")
+                    && h.value
+                        .as_str()
+                        .starts_with("AWS4-HMAC-SHA256 Credential=test/")
+            });
+            // The SDK names the operation in the query
+            let path = request.url().split('?').next().unwrap_or_default();
+            let data = path
+                .strip_prefix("/transactions/")
+                .and_then(|name| std::fs::read(fixture(name)).ok());
+            let _ = match (signed, data) {
+                (false, _) => request.respond(tiny_http::Response::empty(403)),
+                (true, Some(data)) => request.respond(tiny_http::Response::from_data(data)),
+                (true, None) => request.respond(tiny_http::Response::empty(404)),
+            };
+        }
+    });
+    endpoint
+}
+
+#[cfg(feature = "s3")]
+#[test]
+fn test_s3_input() {
+    let expected = run(&[&fixture("single_client.csv")]).stdout;
+    let endpoint = serve_bucket(3);
+    let run_s3 = |key: &str, access_key_id: &str| {
+        Command::new(env!("CARGO_BIN_EXE_transaction_engine"))
+            .arg(format!("s3://transactions/{key}"))
+            .current_dir(env!("CARGO_TARGET_TMPDIR"))
+            .env("AWS_ENDPOINT_URL", &endpoint)
+            .env("AWS_ACCESS_KEY_ID", access_key_id)
+            .env("AWS_SECRET_ACCESS_KEY", "secret")
+            .env_remove("AWS_SESSION_TOKEN")
+            .output()
+            .unwrap()
+    };
+
+    let output = run_s3("single_client.csv.gz", "test");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    let output = run_s3("missing.csv", "test");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("s3://transactions/missing.csv: no such object"),
+        "{stderr}"
+    );
+
+    let output = run_s3("single_client.csv", "other");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("s3://transactions/single_client.csv: access denied"),
+        "{stderr}"
+    );
+}